@@ -5,6 +5,7 @@ use std::fs::{OpenOptions, self, File};
 use rand_core::RngCore;
 use super::*;
 use std::collections::HashMap;
+use std::mem::size_of;
 use std::os::unix::fs::MetadataExt;
 use eccfs::crypto::*;
 use eccfs::htree::*;
@@ -12,10 +13,11 @@ use eccfs::htree::*;
 pub struct HTreeBuilder {
     key_gen: KeyGen,
     encrypted: bool,
+    shape: mht::Shape,
 }
 
 impl HTreeBuilder {
-    pub fn new(encrypted: bool) -> FsResult<Self> {
+    pub fn new(encrypted: bool, bsize: usize) -> FsResult<Self> {
         // init kdk
         let mut kdk = [0u8; 16];
         rand::thread_rng().fill_bytes(&mut kdk);
@@ -23,6 +25,7 @@ impl HTreeBuilder {
         Ok(Self {
             key_gen: KeyGen::new(),
             encrypted,
+            shape: mht::Shape::full(bsize),
         })
     }
 
@@ -46,7 +49,7 @@ impl HTreeBuilder {
         from: &PathBuf,
     ) -> FsResult<(usize, KeyEntry)> {
         // get file logical size
-        let logi_nr_blk = io_try!(fs::symlink_metadata(from)).size().div_ceil(BLK_SZ as u64);
+        let logi_nr_blk = io_try!(fs::symlink_metadata(from)).size().div_ceil(self.shape.blk_sz() as u64);
         // open source file
         let mut f = io_try!(OpenOptions::new().read(true).open(from));
 
@@ -60,31 +63,36 @@ impl HTreeBuilder {
         from: &mut File,
         logi_nr_blk: u64,
     ) -> FsResult<(usize, KeyEntry)> {
-        assert!(logi_nr_blk > 0);
+        // an empty file has no htree at all
+        if logi_nr_blk == 0 {
+            return Ok((0, [0u8; size_of::<KeyEntry>()]));
+        }
 
+        let shape = self.shape;
+        let bsize = shape.blk_sz();
         // get the htree start (in blocks)
         let mut to_start_blk = get_file_pos(to)?;
-        assert!(to_start_blk % BLK_SZ as u64 == 0);
-        to_start_blk /= BLK_SZ as u64;
-        let htree_nr_blk = mht::get_phy_nr_blk(logi_nr_blk);
+        assert!(to_start_blk % bsize as u64 == 0);
+        to_start_blk /= bsize as u64;
+        let htree_nr_blk = shape.get_phy_nr_blk(logi_nr_blk);
 
-        let mut idx_blk = [0u8; BLK_SZ] as Block;
+        let mut idx_blk = new_blk(bsize);
         // map idx_phy_pos to its ke
         let mut idx_ke = HashMap::new();
 
         for logi_pos in (0..logi_nr_blk).rev() {
             // read plain data block, padding 0 to integral block
-            let mut d = [0u8; BLK_SZ] as Block;
-            let _read = read_file_at(from, blk2byte!(logi_pos), &mut d)?;
+            let mut d = new_blk(bsize);
+            let _read = read_file_at(from, blk2byte!(logi_pos, bsize), &mut d)?;
             // process crypto
-            let phy_pos = mht::logi2phy(logi_pos);
+            let phy_pos = shape.logi2phy(logi_pos);
             let ke = self.crypto_process_blk(&mut d, phy_pos)?;
             // write data block
-            write_file_at(to, blk2byte!(to_start_blk + phy_pos), &d)?;
+            write_file_at(to, blk2byte!(to_start_blk + phy_pos, bsize), &d)?;
 
             // write ke to idx_blk
-            let ke_idx = mht::logi2dataidx(logi_pos);
-            mht::set_ke(
+            let ke_idx = shape.logi2dataidx(logi_pos);
+            shape.set_ke(
                 &mut idx_blk,
                 mht::Data(ke_idx),
                 &ke,
@@ -97,12 +105,12 @@ impl HTreeBuilder {
             }
 
             // all data blk of the idx_blk are filled, now process idx_blk
-            let idx_phy_pos = mht::phy2idxphy(phy_pos);
+            let idx_phy_pos = shape.phy2idxphy(phy_pos);
             // fill child ke
-            let mut child_phy = mht::get_first_idx_child_phy(idx_phy_pos);
-            for i in 0..mht::CHILD_PER_BLK {
+            let mut child_phy = shape.get_first_idx_child_phy(idx_phy_pos);
+            for i in 0..shape.child_per_blk() {
                 if let Some(ke) = idx_ke.remove(&child_phy) {
-                    mht::set_ke(
+                    shape.set_ke(
                         &mut idx_blk,
                         mht::Index(i),
                         &ke,
@@ -110,16 +118,16 @@ impl HTreeBuilder {
                 } else {
                     break;
                 }
-                child_phy = mht::next_idx_sibling_phy(child_phy);
+                child_phy = shape.next_idx_sibling_phy(child_phy);
             }
             // process crypto
             let ke = self.crypto_process_blk(&mut idx_blk, idx_phy_pos)?;
             // add this idx_blk ke to the hashmap, for use of its father
             assert!(idx_ke.insert(idx_phy_pos, ke).is_none());
             // write idx block
-            write_file_at(to, blk2byte!(to_start_blk + idx_phy_pos), &idx_blk)?;
+            write_file_at(to, blk2byte!(to_start_blk + idx_phy_pos, bsize), &idx_blk)?;
             // switch to a new idx block
-            idx_blk = [0u8; BLK_SZ];
+            idx_blk = new_blk(bsize);
         }
 
         let root_ke = idx_ke.remove(&HTREE_ROOT_BLK_PHY_POS).unwrap();
@@ -127,7 +135,7 @@ impl HTreeBuilder {
         //     debug!("idx_ke keys:");
         //     let mut l: Vec<_> = idx_ke.keys().map(
         //         |k| {
-        //             (*k, mht::idxphy2number(*k))
+        //             (*k, shape.idxphy2number(*k))
         //         }
         //     ).collect();
         //     l.sort();
@@ -136,7 +144,7 @@ impl HTreeBuilder {
         assert!(idx_ke.is_empty());
 
         // seek to end of this htree
-        let file_end = blk2byte!(to_start_blk + htree_nr_blk);
+        let file_end = blk2byte!(to_start_blk + htree_nr_blk, bsize);
         assert_eq!(io_try!(to.seek(SeekFrom::End(0))), file_end);
 
         // return size of htree in block, root block keys
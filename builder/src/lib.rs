@@ -3,6 +3,8 @@ pub mod rw;
 pub(crate) mod htree;
 extern crate alloc;
 pub(crate) use eccfs::*;
+#[cfg(test)]
+pub(crate) mod test_util;
 
 pub mod io_wrapper {
     use std::io::prelude::*;
@@ -41,10 +43,34 @@ pub mod io_wrapper {
         Ok(io_try!(f.seek(SeekFrom::Current(0))))
     }
 
-    pub fn round_file_up_to_blk(f: &mut File) -> FsResult<u64> {
-        let len = io_try!(f.seek(SeekFrom::End(0))).next_multiple_of(BLK_SZ as u64);
+    pub fn round_file_up_to_blk(f: &mut File, bsize: usize) -> FsResult<u64> {
+        let len = io_try!(f.seek(SeekFrom::End(0))).next_multiple_of(bsize as u64);
         io_try!(f.set_len(len));
-        Ok(len / BLK_SZ as u64)
+        Ok(len / bsize as u64)
+    }
+
+    // not every platform records birth time, fall back to ctime
+    pub fn get_btime(m: &std::fs::Metadata) -> u32 {
+        use std::os::unix::fs::MetadataExt;
+        m.created().ok()
+            .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+            .map_or(m.ctime() as u32, |d| d.as_secs() as u32)
+    }
+
+    // flags set by chattr, 0 if the source fs does not keep them or it is a symlink
+    pub fn get_inode_flags(p: &std::path::Path, m: &std::fs::Metadata) -> u32 {
+        use std::os::fd::AsRawFd;
+        if m.is_symlink() {
+            return 0;
+        }
+        let Ok(f) = File::open(p) else {
+            return 0;
+        };
+        let mut flags: libc::c_int = 0;
+        if unsafe { libc::ioctl(f.as_raw_fd(), libc::FS_IOC_GETFLAGS, &mut flags) } != 0 {
+            return 0;
+        }
+        InodeFlags::from_bits_truncate(flags as u32).bits()
     }
 
     pub fn get_file_sz(f: &mut File) -> FsResult<u64> {
@@ -62,3 +88,11 @@ pub mod io_wrapper {
     }
 }
 pub use io_wrapper::*;
+
+/// a random uuid for a newly built image
+pub fn new_image_uuid() -> ImageUuid {
+    use rand_core::RngCore;
+    let mut uuid = ImageUuid::default();
+    rand::thread_rng().fill_bytes(&mut uuid);
+    uuid
+}
@@ -10,7 +10,7 @@ use eccfs_builder::{ro, rw};
 use eccfs::*;
 
 
-fn build_ro(mode: String, target: String) {
+fn build_ro(mode: String, target: String, bsize: usize) {
     debug!("Building ROFS {}", target);
 
     let from = format!("test/{}", &target);
@@ -30,12 +30,13 @@ fn build_ro(mode: String, target: String) {
         _ => panic!("unrecognized fsmode"),
     };
 
-    let mode = ro::build_from_dir(
+    let mode = ro::build_from_dir_with_blk_sz(
         Path::new(&from),
         Path::new(&to_dir),
         Path::new(&image),
         Path::new(work_dir),
         k,
+        bsize,
     ).unwrap();
     match &mode {
         FSMode::IntegrityOnly(hash) => {
@@ -65,7 +66,7 @@ fn build_ro(mode: String, target: String) {
     assert_eq!(written, std::mem::size_of::<FSMode>());
 }
 
-fn build_rw(mode: String, target: String) {
+fn build_rw(mode: String, target: String, bsize: usize) {
     debug!("Building RWFS {}", target);
 
     let from = format!("test/{}", &target);
@@ -83,10 +84,11 @@ fn build_rw(mode: String, target: String) {
         _ => panic!("unrecognized fsmode"),
     };
 
-    let mode = rw::build_from_dir(
+    let mode = rw::build_from_dir_with_blk_sz(
         Path::new(&from),
         Path::new(&to),
         k,
+        bsize,
     ).unwrap();
     match &mode {
         FSMode::IntegrityOnly(hash) => {
@@ -116,7 +118,7 @@ fn build_rw(mode: String, target: String) {
     assert_eq!(written, std::mem::size_of::<FSMode>());
 }
 
-fn build_empty(mode: String, target: String) {
+fn build_empty(mode: String, target: String, bsize: usize) {
     debug!("Creating empty RWFS {}", target);
 
     let to = format!("test/{}.rwimage", &target);
@@ -133,9 +135,10 @@ fn build_empty(mode: String, target: String) {
         _ => panic!("unrecognized fsmode"),
     };
 
-    let mode = rw::create_empty(
+    let mode = rw::create_empty_with_blk_sz(
         Path::new(&to),
         k,
+        bsize,
     ).unwrap();
     match &mode {
         FSMode::IntegrityOnly(hash) => {
@@ -165,6 +168,36 @@ fn build_empty(mode: String, target: String) {
     assert_eq!(written, std::mem::size_of::<FSMode>());
 }
 
+fn check(target: String) {
+    debug!("Checking RWFS {}", target);
+
+    let image = format!("test/{}.rwimage", &target);
+    let buf = fs::read(format!("test/{}.mode", target)).unwrap();
+    assert_eq!(buf.len(), std::mem::size_of::<FSMode>());
+    let mode = unsafe {
+        std::ptr::read_unaligned(buf.as_ptr() as *const FSMode)
+    };
+
+    let bsize = rw_image_blk_sz(Path::new(&image), &mode).unwrap();
+    let rwfs = eccfs::rw::RWFS::open_readonly(
+        mode,
+        None,
+        0,
+        std::sync::Arc::new(FileDevice::new(Path::new(&image), bsize).unwrap()),
+        &SYSTEM_TIME,
+    ).unwrap();
+    let report = rwfs.check().unwrap();
+    if report.is_clean() {
+        println!("{} is clean", image);
+        return;
+    }
+    for issue in report.issues.iter() {
+        println!("{:?}", issue);
+    }
+    println!("{} issues found in {}", report.issues.len(), image);
+    std::process::exit(1);
+}
+
 fn main() {
     if cfg!(debug_assertions) {
         env::set_var("RUST_BACKTRACE", "1");
@@ -174,15 +207,22 @@ fn main() {
     }
 
     let args: Vec<String> = env::args().collect();
+    // check <target>
+    if args.len() >= 3 && args[1] == "check" {
+        check(args[2].clone());
+        return;
+    }
     assert!(args.len() >= 4);
     let tp = args[1].clone();
     let mode = args[2].clone();
     let target = args[3].clone();
+    // <tp> <mode> <target> [bsize]
+    let bsize = args.get(4).map_or(BLK_SZ, |s| s.parse().expect("bad block size"));
 
     match tp.as_str() {
-        "ro" => build_ro(mode, target),
-        "rw" => build_rw(mode, target),
-        "empty" => build_empty(mode, target),
+        "ro" => build_ro(mode, target, bsize),
+        "rw" => build_rw(mode, target, bsize),
+        "empty" => build_empty(mode, target, bsize),
         _ => panic!("unrecognized type {}", tp),
     }
 }
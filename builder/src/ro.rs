@@ -16,8 +16,6 @@ use eccfs::ro::*;
 use eccfs::htree::*;
 
 
-const MAX_ENTRY_GROUP_LEN: usize = 16;
-
 #[derive(Clone, Debug)]
 enum DotDotPos {
     InodeTable(u64),
@@ -33,10 +31,157 @@ pub fn build_from_dir(
     image: &Path,
     work_dir: &Path,
     encrypted: Option<Key128>,
+) -> FsResult<FSMode> {
+    build_from_dir_impl(from, to_dir, image, work_dir, encrypted, BuildOptions::default())
+}
+
+/// same as [`build_from_dir`], and for a regular file `name`, files `name.gz` and
+/// `name.zst` beside it are stored along as its precompressed representations,
+/// see [`Encoding`] and [`ROFS::read_compressed`]. they are still files of their own
+pub fn build_from_dir_with_precompressed(
+    from: &Path,
+    to_dir: &Path,
+    image: &Path,
+    work_dir: &Path,
+    encrypted: Option<Key128>,
+) -> FsResult<FSMode> {
+    build_from_dir_impl(from, to_dir, image, work_dir, encrypted, BuildOptions {
+        precompressed: true,
+        ..Default::default()
+    })
+}
+
+/// same as [`build_from_dir`], but names in dir entries are hashed with `name_hash`,
+/// which is recorded in the superblock and used by lookups of the image
+pub fn build_from_dir_with_name_hash(
+    from: &Path,
+    to_dir: &Path,
+    image: &Path,
+    work_dir: &Path,
+    encrypted: Option<Key128>,
+    name_hash: NameHashAlgo,
+) -> FsResult<FSMode> {
+    build_from_dir_impl(from, to_dir, image, work_dir, encrypted, BuildOptions {
+        name_hash,
+        ..Default::default()
+    })
+}
+
+/// same as [`build_from_dir`], but data of regular files that are not inline is
+/// compressed block by block with `compression`, a block that does not get smaller
+/// is stored as it is. the image is read with feature `zstd` of eccfs
+pub fn build_from_dir_with_compression(
+    from: &Path,
+    to_dir: &Path,
+    image: &Path,
+    work_dir: &Path,
+    encrypted: Option<Key128>,
+    compression: Compression,
+) -> FsResult<FSMode> {
+    build_from_dir_impl(from, to_dir, image, work_dir, encrypted, BuildOptions {
+        compression,
+        ..Default::default()
+    })
+}
+
+/// same as [`build_from_dir`] in integrity only mode, but with short key entries
+/// in hash trees, see [`eccfs::crypto::SHORT_KEY_ENTRY_SZ`] for what it gives up
+pub fn build_from_dir_with_short_ke(
+    from: &Path,
+    to_dir: &Path,
+    image: &Path,
+    work_dir: &Path,
+) -> FsResult<FSMode> {
+    build_from_dir_impl(from, to_dir, image, work_dir, None, BuildOptions {
+        shape: mht::Shape::short(BLK_SZ),
+        ..Default::default()
+    })
+}
+
+/// same as [`build_from_dir`], with blocks of `bsize` bytes, see [`eccfs::check_blk_sz`],
+/// the size is kept in the public header so that the image is opened with it
+pub fn build_from_dir_with_blk_sz(
+    from: &Path,
+    to_dir: &Path,
+    image: &Path,
+    work_dir: &Path,
+    encrypted: Option<Key128>,
+    bsize: usize,
+) -> FsResult<FSMode> {
+    check_blk_sz(bsize)?;
+    build_from_dir_impl(from, to_dir, image, work_dir, encrypted, BuildOptions {
+        shape: mht::Shape::full(bsize),
+        ..Default::default()
+    })
+}
+
+/// what to do with a source regular file that can not be opened
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum BuildErrorPolicy {
+    /// fail the whole build
+    #[default]
+    Abort,
+    /// leave it out of the image
+    Skip,
+    /// store an empty file with its metadata
+    Placeholder,
+}
+
+/// same as [`build_from_dir`], but a source file that can not be opened is handled
+/// by `on_error`, errors after a file is opened still fail the build
+pub fn build_from_dir_with_error_policy(
+    from: &Path,
+    to_dir: &Path,
+    image: &Path,
+    work_dir: &Path,
+    encrypted: Option<Key128>,
+    on_error: BuildErrorPolicy,
+) -> FsResult<FSMode> {
+    build_from_dir_impl(from, to_dir, image, work_dir, encrypted, BuildOptions {
+        on_error,
+        ..Default::default()
+    })
+}
+
+/// same as [`build_from_dir`], but the image takes `uuid` and `generation` rather than
+/// a new uuid and generation 0, so that a rebuild of an image can keep its uuid
+pub fn build_from_dir_with_image_id(
+    from: &Path,
+    to_dir: &Path,
+    image: &Path,
+    work_dir: &Path,
+    encrypted: Option<Key128>,
+    uuid: ImageUuid,
+    generation: u64,
+) -> FsResult<FSMode> {
+    build_from_dir_impl(from, to_dir, image, work_dir, encrypted, BuildOptions {
+        image_id: Some((uuid, generation)),
+        ..Default::default()
+    })
+}
+
+// everything the build_from_dir_with_* variants may change
+#[derive(Clone, Copy, Default)]
+struct BuildOptions {
+    precompressed: bool,
+    name_hash: NameHashAlgo,
+    on_error: BuildErrorPolicy,
+    image_id: Option<(ImageUuid, u64)>,
+    compression: Compression,
+    shape: mht::Shape,
+}
+
+fn build_from_dir_impl(
+    from: &Path,
+    to_dir: &Path,
+    image: &Path,
+    work_dir: &Path,
+    encrypted: Option<Key128>,
+    opts: BuildOptions,
 ) -> FsResult<FSMode> {
     // check from
     if !io_try!(fs::metadata(from)).is_dir() {
-        return Err(new_error!(FsError::NotADirectory));
+        return Err(FsError::NotADirectory);
     }
 
     let mut builder = ROBuilder::new(
@@ -45,8 +190,9 @@ pub fn build_from_dir(
         work_dir,
         io_try!(fs::read_dir(from)).count(),
         encrypted.clone(),
+        &opts,
     )?;
-    let mut ht_builder = HTreeBuilder::new(encrypted.is_some())?;
+    let mut ht_builder = HTreeBuilder::new(encrypted.is_some(), opts.shape)?;
 
     // stack holds full paths
     let mut stack = vec![Some((from.to_path_buf(), 0usize))];
@@ -81,15 +227,30 @@ pub fn build_from_dir(
                     )
                 );
             } else if m.is_file() {
-                let iid = builder.handle_reg(&pb, &mut ht_builder)?;
-                push_child_info(
-                    &mut de_info,
-                    fpb,
-                    (
-                        pb.file_name().unwrap().to_os_string().into(),
-                        FileType::Reg, iid, None
-                    )
-                );
+                let iid = match File::open(&pb) {
+                    Ok(_) => Some(builder.handle_reg(&pb, &mut ht_builder)?),
+                    Err(e) => match opts.on_error {
+                        BuildErrorPolicy::Abort => return Err(FsError::IOError(e)),
+                        BuildErrorPolicy::Skip => {
+                            warn!("Unreadable file {}, skip: {}", pb.display(), e);
+                            None
+                        }
+                        BuildErrorPolicy::Placeholder => {
+                            warn!("Unreadable file {}, store as empty: {}", pb.display(), e);
+                            Some(builder.handle_placeholder(&pb)?)
+                        }
+                    },
+                };
+                if let Some(iid) = iid {
+                    push_child_info(
+                        &mut de_info,
+                        fpb,
+                        (
+                            pb.file_name().unwrap().to_os_string().into(),
+                            FileType::Reg, iid, None
+                        )
+                    );
+                }
             } else if m.is_symlink() {
                 let iid = builder.handle_sym(&pb)?;
                 push_child_info(
@@ -122,6 +283,141 @@ pub fn build_from_dir(
     Ok(ret)
 }
 
+/// a problem found by [`validate_source`]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SourceIssue {
+    /// neither a regular file, dir nor symlink, build skips it
+    UnsupportedType(PathBuf),
+    /// name longer than [`NAME_MAX`]
+    NameTooLong(PathBuf),
+    /// name or symlink target is not valid utf-8
+    NonUtf8(PathBuf),
+    /// name longer than inline size, stored in path table
+    LongName(PathBuf),
+    /// symlink target longer than inline size, stored in path table
+    LongSymlink(PathBuf),
+    /// failed to read metadata, dir or file
+    Unreadable(PathBuf, std::io::ErrorKind),
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct SourceReport {
+    pub nr_reg: usize,
+    pub nr_dir: usize,
+    pub nr_lnk: usize,
+    /// total size of regular files in bytes
+    pub data_bytes: u64,
+    /// rough estimate of image size in bytes
+    pub est_image_bytes: u64,
+    /// build goes on with these
+    pub warnings: Vec<SourceIssue>,
+    /// build fails on these
+    pub errors: Vec<SourceIssue>,
+}
+
+impl SourceReport {
+    pub fn is_ok(&self) -> bool {
+        self.errors.is_empty()
+    }
+}
+
+/// check all files under [`from`] without writing anything,
+/// so that problems show up before a long build
+pub fn validate_source(from: &Path) -> FsResult<SourceReport> {
+    if !io_try!(fs::metadata(from)).is_dir() {
+        return Err(FsError::NotADirectory);
+    }
+
+    // as if it's built with the default block size
+    let shape = mht::Shape::default();
+    let bsize = shape.blk_sz();
+    let mut report = SourceReport::default();
+    let mut data_blks = 0;
+    let mut itbl_bytes = 0;
+    let mut dtbl_bytes = 0;
+    let mut ptbl_bytes = 0;
+
+    let mut stack = vec![Some((from.to_path_buf(), 0usize))];
+    while let Some(Some((pb, _))) = stack.pop() {
+        let m = match fs::symlink_metadata(&pb) {
+            Ok(m) => m,
+            Err(e) => {
+                report.errors.push(SourceIssue::Unreadable(pb, e.kind()));
+                continue;
+            }
+        };
+        let tp = match ROBuilder::gen_inode_tp(&pb) {
+            Ok(tp) => tp,
+            Err(_) => {
+                report.warnings.push(SourceIssue::UnsupportedType(pb));
+                continue;
+            }
+        };
+
+        if pb != from {
+            let name = pb.file_name().unwrap();
+            if name.len() > NAME_MAX as usize {
+                report.errors.push(SourceIssue::NameTooLong(pb.clone()));
+            } else if name.to_str().is_none() {
+                report.errors.push(SourceIssue::NonUtf8(pb.clone()));
+            } else if name.len() > DE_MAX_INLINE_NAME {
+                report.warnings.push(SourceIssue::LongName(pb.clone()));
+                ptbl_bytes += name.len();
+            }
+            dtbl_bytes += size_of::<DirEntry>();
+        }
+
+        match tp {
+            FileType::Reg => {
+                report.nr_reg += 1;
+                report.data_bytes += m.len();
+                if m.len() > DI_REG_INLINE_DATA_MAX {
+                    data_blks += shape.get_phy_nr_blk(m.len().div_ceil(bsize as u64));
+                }
+                itbl_bytes += size_of::<DInodeReg>();
+                if let Err(e) = File::open(&pb) {
+                    report.errors.push(SourceIssue::Unreadable(pb, e.kind()));
+                }
+            }
+            FileType::Dir => {
+                report.nr_dir += 1;
+                // dot and dotdot
+                dtbl_bytes += 2 * size_of::<DirEntry>();
+                itbl_bytes += size_of::<DInodeDirBaseNoInline>();
+                if let Err(e) = push_all_children(&mut stack, &pb, 0) {
+                    let kind = match e {
+                        FsError::IOError(e) => e.kind(),
+                        _ => std::io::ErrorKind::Other,
+                    };
+                    report.errors.push(SourceIssue::Unreadable(pb, kind));
+                }
+            }
+            FileType::Lnk => {
+                report.nr_lnk += 1;
+                itbl_bytes += size_of::<DInodeLnk>();
+                match fs::read_link(&pb) {
+                    Ok(target) if target.to_str().is_none() => {
+                        report.errors.push(SourceIssue::NonUtf8(pb));
+                    }
+                    Ok(target) if target.as_os_str().len() > DI_LNK_MAX_INLINE_NAME => {
+                        ptbl_bytes += target.as_os_str().len();
+                        report.warnings.push(SourceIssue::LongSymlink(pb));
+                    }
+                    Ok(_) => {}
+                    Err(e) => report.errors.push(SourceIssue::Unreadable(pb, e.kind())),
+                }
+            }
+        }
+    }
+
+    // public header, superblock, three tables, and file section
+    let tbl_blks = [itbl_bytes, dtbl_bytes, ptbl_bytes].into_iter().map(
+        |b| shape.get_phy_nr_blk(b.div_ceil(bsize) as u64)
+    ).sum::<u64>();
+    report.est_image_bytes = blk2byte!(TBL_START + tbl_blks + data_blks, bsize);
+    Ok(report)
+}
+
 fn push_all_children(
     stack: &mut Vec<Option<(PathBuf, usize)>>,
     path: &Path,
@@ -166,12 +462,23 @@ struct ROBuilder {
     next_inode: InodeID,
     root_inode_max_sz: u16,
     files: u64,
+    precompressed: bool,
+    name_hash: NameHashAlgo,
+    name_hash_salt: Key128,
+    name_hasher: NameHasher,
+    uuid: ImageUuid,
+    generation: u64,
+    compression: Compression,
+    shape: mht::Shape,
+    bsize: usize,
 }
 
 const ITBL_TEMP_FILE: &str = ".inode.eccfs";
 const DTBL_TEMP_FILE: &str = ".dirent.eccfs";
 const PTBL_TEMP_FILE: &str = ".path.eccfs";
 const DATA_TEMP_FILE: &str = ".data.eccfs";
+// file data, compressed or not, followed by its precompressed representations
+const PRECOMPRESSED_TEMP_FILE: &str = ".precompressed.eccfs";
 
 impl ROBuilder {
     fn new(
@@ -180,16 +487,17 @@ impl ROBuilder {
         work_dir: &Path,
         root_dir_nr_entry: usize,
         encrypted: Option<Key128>,
+        opts: &BuildOptions,
     ) -> FsResult<Self> {
         if !io_try!(fs::metadata(to_dir)).is_dir() {
-            return Err(new_error!(FsError::NotADirectory));
+            return Err(FsError::NotADirectory);
         }
         let mut to_dir = to_dir.to_path_buf();
         to_dir.push(image);
 
         // check to
         if to_dir.as_path().exists() {
-            return Err(new_error!(FsError::AlreadyExists));
+            return Err(FsError::AlreadyExists);
         }
         let image = io_try!(OpenOptions::new().write(true).create_new(true).open(&to_dir));
         to_dir.pop();
@@ -238,6 +546,15 @@ impl ROBuilder {
         };
         assert_eq!(root_inode_max_sz as usize % INODE_ALIGN, 0);
 
+        let name_hash = opts.name_hash;
+        let mut name_hash_salt = [0u8; size_of::<Key128>()];
+        if name_hash != NameHashAlgo::HalfMd4 {
+            use rand_core::RngCore;
+            rand::thread_rng().fill_bytes(&mut name_hash_salt);
+        }
+        let name_hasher = NameHasher::new(name_hash, &name_hash_salt, encrypted.as_ref())?;
+        let (uuid, generation) = opts.image_id.unwrap_or_else(|| (new_image_uuid(), 0));
+
         Ok(Self {
             encrypted,
             image,
@@ -253,6 +570,15 @@ impl ROBuilder {
             next_inode: pos64_join(0, INODE_ALIGN as u16),
             root_inode_max_sz,
             files: 0,
+            precompressed: opts.precompressed,
+            name_hash,
+            name_hash_salt,
+            name_hasher,
+            uuid,
+            generation,
+            compression: opts.compression,
+            shape: opts.shape,
+            bsize: opts.shape.blk_sz(),
         })
     }
 
@@ -275,7 +601,7 @@ impl ROBuilder {
         // every pos and off is filter by this funciton,
         // so they can not be inside root_inode
         assert!(!(pos == 1 && off < self.root_inode_max_sz && off > 0));
-        if pos == 0 && off as usize + sz > BLK_SZ {
+        if pos == 0 && off as usize + sz > self.bsize {
             (1, self.root_inode_max_sz)
         } else if pos == 1 && off == 0 {
             (1, self.root_inode_max_sz)
@@ -289,7 +615,7 @@ impl ROBuilder {
             assert!(inode.len() <= self.root_inode_max_sz as usize);
             write_file_at(
                 &mut self.itbl,
-                blk2byte!(1),
+                blk2byte!(1, self.bsize),
                 inode,
             )?;
             return Ok(ROOT_INODE_ID);
@@ -302,14 +628,14 @@ impl ROBuilder {
 
         write_file_at(
             &mut self.itbl,
-            pos64_to_byte(pos, off),
+            pos64_to_byte(pos, off, self.bsize),
             inode,
         )?;
 
         let ret = pos64_join(pos, off);
 
         // set new next_inode
-        (pos, off) = pos64_add((pos, off), inode.len() as u64);
+        (pos, off) = pos64_add((pos, off), inode.len() as u64, self.bsize);
         self.next_inode = pos64_join(pos, off);
 
         Ok(ret)
@@ -324,7 +650,7 @@ impl ROBuilder {
         } else if m.is_symlink() {
             FileType::Lnk
         } else {
-            panic!("Unsupported file type!");
+            return Err(FsError::NotSupported);
         })
     }
 
@@ -339,7 +665,10 @@ impl ROBuilder {
             atime: m.atime() as u32,
             mtime: m.mtime() as u32,
             ctime: m.ctime() as u32,
+            btime: get_btime(&m),
+            flags: get_inode_flags(pb, &m),
             size: m.size(),
+            ..Default::default()
         })
 
     }
@@ -423,8 +752,8 @@ impl ROBuilder {
 
         let de_start_raw = get_file_pos(&mut self.dtbl)?;
         assert!(de_start_raw as usize % size_of::<DirEntry>() == 0);
-        let de_start_pos = de_start_raw / BLK_SZ as u64;
-        let de_start_off = de_start_raw % BLK_SZ as u64;
+        let de_start_pos = de_start_raw / self.bsize as u64;
+        let de_start_off = de_start_raw % self.bsize as u64;
 
         let de_list = self.gen_dir_entries(mytp, de_list_raw)?;
 
@@ -493,7 +822,9 @@ impl ROBuilder {
                 let name = name.into_os_string();
                 assert!(name.len() < NAME_MAX as usize);
                 DirEntryRaw {
-                    hash: half_md4(name.as_os_str().to_str().unwrap().as_bytes()).unwrap(),
+                    hash: self.name_hasher.hash(
+                        name.as_os_str().to_str().unwrap().as_bytes()
+                    ).unwrap(),
                     ipos: iid,
                     tp: tp.into(),
                     name,
@@ -591,7 +922,7 @@ impl ROBuilder {
             // inline de
             let (pos, off) = pos64_split(iid);
             let di_inline_start =
-                pos64_to_byte(pos, off)
+                pos64_to_byte(pos, off, self.bsize)
                 + size_of::<DInodeBase>() as u64;
             let dotdot = di_inline_start + size_of::<DirEntry>() as u64 + 8;
             let self_dot = di_inline_start + 8;
@@ -658,15 +989,25 @@ impl ROBuilder {
             self.write_inode(&dinode_bytes, false)?
         } else {
             let data_start = get_file_pos(&mut self.data)?;
-            assert!(data_start % BLK_SZ as u64 == 0);
+            assert!(data_start % self.bsize as u64 == 0);
 
             // generate hash tree
-            let (nr_blk, ke) = ht.build_htree(&mut self.data, path)?;
+            let mut dinode_base = dinode_base;
+            let sidecars = if self.precompressed {
+                Self::find_precompressed(path)?
+            } else {
+                Vec::new()
+            };
+            let (nr_blk, ke) = if sidecars.is_empty() && self.compression == Compression::None {
+                ht.build_htree(&mut self.data, path)?
+            } else {
+                self.build_htree_precompressed(path, &sidecars, &mut dinode_base, ht)?
+            };
 
             let dinode_reg = DInodeReg {
                 base: dinode_base,
                 key_entry: ke,
-                data_start: data_start / BLK_SZ as u64,
+                data_start: data_start / self.bsize as u64,
                 data_len: nr_blk as u64,
             };
             self.write_inode(dinode_reg.as_ref(), false)?
@@ -676,6 +1017,98 @@ impl ROBuilder {
         Ok(iid)
     }
 
+    // an empty reg file in place of one that can not be read
+    fn handle_placeholder(&mut self, path: &PathBuf) -> FsResult<InodeID> {
+        let dinode_base = DInodeBase {
+            size: 0,
+            ..Self::gen_inode_base(path)?
+        };
+        let iid = self.write_inode(dinode_base.as_ref(), false)?;
+        self.files += 1;
+        Ok(iid)
+    }
+
+    // files beside path in each encoding
+    fn find_precompressed(path: &Path) -> FsResult<Vec<(Encoding, PathBuf)>> {
+        let mut found = Vec::new();
+        for enc in Encoding::ALL {
+            let mut name = path.as_os_str().to_os_string();
+            name.push(".");
+            name.push(enc.extension());
+            let pb = PathBuf::from(name);
+            match fs::metadata(&pb) {
+                Ok(m) if m.is_file() && m.len() > u32::MAX as u64 => {
+                    warn!("{} is too large to be stored along, skip.", pb.display());
+                }
+                Ok(m) if m.is_file() && m.len() > 0 => found.push((enc, pb)),
+                _ => {}
+            }
+        }
+        Ok(found)
+    }
+
+    // put file data, compressed if configured, and precompressed ones in a temp file,
+    // each padded to blocks, and build hash tree on it
+    fn build_htree_precompressed(
+        &mut self,
+        path: &Path,
+        sidecars: &[(Encoding, PathBuf)],
+        dinode_base: &mut DInodeBase,
+        ht: &mut HTreeBuilder,
+    ) -> FsResult<(usize, KeyEntry)> {
+        let tmp_path = self.data_path.with_file_name(PRECOMPRESSED_TEMP_FILE);
+        let mut tmp = io_try!(OpenOptions::new()
+                            .read(true).write(true).create_new(true)
+                            .open(&tmp_path));
+        let bsize = self.bsize as u64;
+        let mut pos = if self.compression == Compression::None {
+            0
+        } else {
+            Self::compress_file(path, &mut tmp, self.bsize)?.next_multiple_of(bsize)
+        };
+        let mut copy_in = |from: &Path| -> FsResult<u64> {
+            io_try!(tmp.seek(SeekFrom::Start(pos)));
+            let copied = io_try!(std::io::copy(&mut io_try!(File::open(from)), &mut tmp));
+            pos += copied.next_multiple_of(bsize);
+            Ok(copied)
+        };
+        if self.compression == Compression::None {
+            copy_in(path)?;
+        }
+        for (enc, pb) in sidecars {
+            dinode_base.precompressed[*enc as usize] = u32::try_from(copy_in(pb)?)
+                                                        .map_err(|_| FsError::FileTooLarge)?;
+        }
+
+        let ret = ht.build_htree_file(&mut self.data, &mut tmp, pos / bsize);
+        drop(tmp);
+        io_try!(fs::remove_file(&tmp_path));
+        ret
+    }
+
+    // chunk table and chunks of file data from the start of `to`, see ChunkEnd,
+    // return bytes written
+    fn compress_file(path: &Path, to: &mut File, bsize: usize) -> FsResult<u64> {
+        let mut from = io_try!(File::open(path));
+        let nr_blk = io_try!(from.metadata()).len().div_ceil(bsize as u64);
+        let tbl_sz = nr_blk * size_of::<ChunkEnd>() as u64;
+        let mut tbl = Vec::with_capacity(tbl_sz as usize);
+        let mut end = 0;
+        for i in 0..nr_blk {
+            let mut blk = new_blk(bsize);
+            read_file_at(&mut from, blk2byte!(i, bsize), &mut blk)?;
+            let packed = ruzstd::encoding::compress_to_vec(
+                &blk[..], ruzstd::encoding::CompressionLevel::Fastest,
+            );
+            let chunk = if packed.len() < bsize { &packed[..] } else { &blk[..] };
+            write_file_at(to, tbl_sz + end, chunk)?;
+            end += chunk.len() as u64;
+            tbl.extend_from_slice(&end.to_le_bytes());
+        }
+        write_file_at(to, 0, &tbl)?;
+        Ok(tbl_sz + end)
+    }
+
     fn handle_sym(&mut self, path: &PathBuf) -> FsResult<InodeID> {
         let mut dinode_base = Self::gen_inode_base(path)?;
 
@@ -695,59 +1128,42 @@ impl ROBuilder {
         Ok(iid)
     }
 
-    fn round_file_up_to_blk(f: &mut File) -> FsResult<u64> {
-        let len = io_try!(f.seek(SeekFrom::End(0))).next_multiple_of(BLK_SZ as u64);
-        io_try!(f.set_len(len));
-        Ok(len / BLK_SZ as u64)
-    }
-
     fn finalize(mut self) -> FsResult<FSMode> {
-        // round all file sizes up to multiple of BLK_SZ
-        let itbl_nr_blk = Self::round_file_up_to_blk(&mut self.itbl)?;
-        let dtbl_nr_blk = Self::round_file_up_to_blk(&mut self.dtbl)?;
-        let ptbl_nr_blk = Self::round_file_up_to_blk(&mut self.ptbl)?;
+        let bsize = self.bsize;
+        // round all file sizes up to multiple of the block size
+        let itbl_nr_blk = round_file_up_to_blk(&mut self.itbl, bsize)?;
+        let dtbl_nr_blk = round_file_up_to_blk(&mut self.dtbl, bsize)?;
+        let ptbl_nr_blk = round_file_up_to_blk(&mut self.ptbl, bsize)?;
         let file_sec_len = get_file_pos(&mut self.data)?;
-        assert!(file_sec_len % BLK_SZ as u64 == 0);
-        let file_nr_blk = file_sec_len / BLK_SZ as u64;
+        assert!(file_sec_len % bsize as u64 == 0);
+        let file_nr_blk = file_sec_len / bsize as u64;
 
-        // jumpover superblock in image file
-        io_try!(self.image.set_len(BLK_SZ as u64));
-        if io_try!(self.image.seek(SeekFrom::End(0))) != BLK_SZ as u64 {
+        // jumpover public header and superblock in image file
+        io_try!(self.image.set_len(blk2byte!(TBL_START, bsize)));
+        if io_try!(self.image.seek(SeekFrom::End(0))) != blk2byte!(TBL_START, bsize) {
             return Err(new_error!(FsError::UnexpectedEof));
         }
 
         // filter all meta files through hash tree, append to image file
-        let mut ht = HTreeBuilder::new(self.encrypted.is_some())?;
+        let mut ht = HTreeBuilder::new(self.encrypted.is_some(), self.shape)?;
         // inode table
         debug!("Building itbl htree size {} blocks", itbl_nr_blk);
-        let (itbl_htree_nr_blk, itbl_ke) = if itbl_nr_blk == 0 {
-            (0, [0u8; size_of::<KeyEntry>()])
-        } else {
-            assert_eq!(io_try!(self.itbl.seek(SeekFrom::Start(0))), 0);
-            ht.build_htree_file(
-                &mut self.image, &mut self.itbl, itbl_nr_blk
-            )?
-        };
+        assert_eq!(io_try!(self.itbl.seek(SeekFrom::Start(0))), 0);
+        let (itbl_htree_nr_blk, itbl_ke) = ht.build_htree_file(
+            &mut self.image, &mut self.itbl, itbl_nr_blk
+        )?;
         // dirent table
         debug!("Building dtbl htree size {} blocks", dtbl_nr_blk);
-        let (dtbl_htree_nr_blk, dtbl_ke) = if dtbl_nr_blk == 0 {
-            (0, [0u8; size_of::<KeyEntry>()])
-        } else {
-            assert_eq!(io_try!(self.dtbl.seek(SeekFrom::Start(0))), 0);
-            ht.build_htree_file(
-                &mut self.image, &mut self.dtbl, dtbl_nr_blk
-            )?
-        };
+        assert_eq!(io_try!(self.dtbl.seek(SeekFrom::Start(0))), 0);
+        let (dtbl_htree_nr_blk, dtbl_ke) = ht.build_htree_file(
+            &mut self.image, &mut self.dtbl, dtbl_nr_blk
+        )?;
         // path table
         debug!("Building ptbl htree size {} blocks", ptbl_nr_blk);
-        let (ptbl_htree_nr_blk, ptbl_ke) = if ptbl_nr_blk == 0 {
-            (0, [0u8; size_of::<KeyEntry>()])
-        } else {
-            assert_eq!(io_try!(self.ptbl.seek(SeekFrom::Start(0))), 0);
-            ht.build_htree_file(
-                &mut self.image, &mut self.ptbl, ptbl_nr_blk
-            )?
-        };
+        assert_eq!(io_try!(self.ptbl.seek(SeekFrom::Start(0))), 0);
+        let (ptbl_htree_nr_blk, ptbl_ke) = ht.build_htree_file(
+            &mut self.image, &mut self.ptbl, ptbl_nr_blk
+        )?;
 
         // append data temp file to image file
         if file_nr_blk != 0 {
@@ -761,33 +1177,44 @@ impl ROBuilder {
         let dtbl_htree_nr_blk = dtbl_htree_nr_blk as u64;
         let ptbl_htree_nr_blk = ptbl_htree_nr_blk as u64;
 
-        let mut sb_blk = [0u8; BLK_SZ];
-        assert!(size_of::<DSuperBlock>() <= BLK_SZ);
-        let dsb = unsafe {
-            &mut *(sb_blk.as_mut_ptr() as *mut DSuperBlock)
-        };
-        *dsb = DSuperBlock {
+        let mut sb_blk = new_blk(bsize);
+        assert!(size_of::<DSuperBlock>() <= bsize);
+        let dsb = DSuperBlock {
             magic: ROFS_MAGIC,
-            bsize: BLK_SZ as u64,
+            bsize: bsize as u64,
             files: self.files,
             namemax: NAME_MAX,
             inode_tbl_key: itbl_ke,
             dirent_tbl_key: dtbl_ke,
             path_tbl_key: ptbl_ke,
-            inode_tbl_start: 1,
+            inode_tbl_start: TBL_START,
             inode_tbl_len: itbl_htree_nr_blk,
-            dirent_tbl_start: 1 + itbl_htree_nr_blk,
+            dirent_tbl_start: TBL_START + itbl_htree_nr_blk,
             dirent_tbl_len: dtbl_htree_nr_blk,
-            path_tbl_start: 1 + itbl_htree_nr_blk + dtbl_htree_nr_blk,
+            path_tbl_start: TBL_START + itbl_htree_nr_blk + dtbl_htree_nr_blk,
             path_tbl_len: ptbl_htree_nr_blk,
-            file_sec_start: 1 + itbl_htree_nr_blk + dtbl_htree_nr_blk + ptbl_htree_nr_blk,
+            file_sec_start: TBL_START + itbl_htree_nr_blk + dtbl_htree_nr_blk + ptbl_htree_nr_blk,
             file_sec_len: file_nr_blk,
-            blocks: 1 + itbl_htree_nr_blk + dtbl_htree_nr_blk + ptbl_htree_nr_blk + file_nr_blk,
+            blocks: TBL_START + itbl_htree_nr_blk + dtbl_htree_nr_blk + ptbl_htree_nr_blk + file_nr_blk,
             encrypted: self.encrypted.is_some(),
+            ke_sz: self.shape.ke_sz() as u64,
+            name_hash: self.name_hash as u64,
+            name_hash_salt: self.name_hash_salt,
+            uuid: self.uuid,
+            generation: self.generation,
+            compression: self.compression as u8,
+            version: FS_LAYOUT_VERSION as u64,
         };
+        // a boxed block may not be aligned for DSuperBlock
+        unsafe {
+            std::ptr::write_unaligned(sb_blk.as_mut_ptr() as *mut DSuperBlock, dsb.clone());
+        }
 
+        let sb: SuperBlock = dsb.into();
+        let hdr_blk = sb.public_header().to_blk();
+        write_file_at(&mut self.image, blk2byte!(PUBLIC_HEADER_POS, bsize), &hdr_blk)?;
         let ret = crypto_out(&mut sb_blk, self.encrypted, SUPERBLOCK_POS)?;
-        write_file_at(&mut self.image, 0, &sb_blk)?;
+        write_file_at(&mut self.image, blk2byte!(SUPERBLOCK_POS, bsize), &sb_blk)?;
 
         // close files
         drop(self.image);
@@ -808,18 +1235,25 @@ impl ROBuilder {
 struct HTreeBuilder {
     key_gen: KeyGen,
     encrypted: bool,
+    shape: mht::Shape,
 }
 
 impl HTreeBuilder {
-    fn new(encrypted: bool) -> FsResult<Self> {
+    fn new(encrypted: bool, shape: mht::Shape) -> FsResult<Self> {
+        // short key entries are integrity only
+        assert!(!(encrypted && shape.is_short()));
 
         Ok(Self {
             key_gen: KeyGen::new(),
             encrypted,
+            shape,
         })
     }
 
     fn crypto_process_blk(&mut self, blk: &mut Block, pos: u64) -> FsResult<KeyEntry> {
+        if self.shape.is_short() {
+            return Ok(crypto_out_short(blk)?.into_key_entry());
+        }
         let mode = crypto_out(blk,
             if self.encrypted {
                 Some(self.key_gen.gen_key(pos)?)
@@ -838,7 +1272,7 @@ impl HTreeBuilder {
         from: &PathBuf,
     ) -> FsResult<(usize, KeyEntry)> {
         // get file logical size
-        let logi_nr_blk = io_try!(fs::symlink_metadata(from)).size().div_ceil(BLK_SZ as u64);
+        let logi_nr_blk = io_try!(fs::symlink_metadata(from)).size().div_ceil(self.shape.blk_sz() as u64);
         // open source file
         let mut f = io_try!(OpenOptions::new().read(true).open(from));
 
@@ -852,31 +1286,35 @@ impl HTreeBuilder {
         from_nr_blk: u64,
     ) -> FsResult<(usize, KeyEntry)> {
         let logi_nr_blk = from_nr_blk;
-        assert!(logi_nr_blk > 0);
+        // an empty table has no htree at all
+        if logi_nr_blk == 0 {
+            return Ok((0, [0u8; size_of::<KeyEntry>()]));
+        }
 
+        let bsize = self.shape.blk_sz();
         // get the htree start (in blocks)
         let mut to_start_blk = get_file_pos(to)?;
-        assert!(to_start_blk % BLK_SZ as u64 == 0);
-        to_start_blk /= BLK_SZ as u64;
-        let htree_nr_blk = mht::get_phy_nr_blk(logi_nr_blk);
+        assert!(to_start_blk % bsize as u64 == 0);
+        to_start_blk /= bsize as u64;
+        let htree_nr_blk = self.shape.get_phy_nr_blk(logi_nr_blk);
 
-        let mut idx_blk = [0u8; BLK_SZ] as Block;
+        let mut idx_blk = new_blk(bsize);
         // map idx_phy_pos to its ke
         let mut idx_ke = HashMap::new();
 
         for logi_pos in (0..logi_nr_blk).rev() {
             // read plain data block, padding 0 to integral block
-            let mut d = [0u8; BLK_SZ] as Block;
-            let _read = read_file_at(from, blk2byte!(logi_pos), &mut d)?;
+            let mut d = new_blk(bsize);
+            let _read = read_file_at(from, blk2byte!(logi_pos, bsize), &mut d)?;
             // process crypto
-            let phy_pos = mht::logi2phy(logi_pos);
+            let phy_pos = self.shape.logi2phy(logi_pos);
             let ke = self.crypto_process_blk(&mut d, phy_pos)?;
             // write data block
-            write_file_at(to, blk2byte!(to_start_blk + phy_pos), &d)?;
+            write_file_at(to, blk2byte!(to_start_blk + phy_pos, bsize), &d)?;
 
             // write ke to idx_blk
-            let ke_idx = mht::logi2dataidx(logi_pos);
-            mht::set_ke(
+            let ke_idx = self.shape.logi2dataidx(logi_pos);
+            self.shape.set_ke(
                 &mut idx_blk,
                 mht::Data(ke_idx),
                 &ke,
@@ -889,12 +1327,12 @@ impl HTreeBuilder {
             }
 
             // all data blk of the idx_blk are filled, now process idx_blk
-            let idx_phy_pos = mht::phy2idxphy(phy_pos);
+            let idx_phy_pos = self.shape.phy2idxphy(phy_pos);
             // fill child ke
-            let mut child_phy = mht::get_first_idx_child_phy(idx_phy_pos);
-            for i in 0..mht::CHILD_PER_BLK {
+            let mut child_phy = self.shape.get_first_idx_child_phy(idx_phy_pos);
+            for i in 0..self.shape.child_per_blk() {
                 if let Some(ke) = idx_ke.remove(&child_phy) {
-                    mht::set_ke(
+                    self.shape.set_ke(
                         &mut idx_blk,
                         mht::Index(i),
                         &ke,
@@ -902,16 +1340,16 @@ impl HTreeBuilder {
                 } else {
                     break;
                 }
-                child_phy = mht::next_idx_sibling_phy(child_phy);
+                child_phy = self.shape.next_idx_sibling_phy(child_phy);
             }
             // process crypto
             let ke = self.crypto_process_blk(&mut idx_blk, idx_phy_pos)?;
             // add this idx_blk ke to the hashmap, for use of its father
             assert!(idx_ke.insert(idx_phy_pos, ke).is_none());
             // write idx block
-            write_file_at(to, blk2byte!(to_start_blk + idx_phy_pos), &idx_blk)?;
+            write_file_at(to, blk2byte!(to_start_blk + idx_phy_pos, bsize), &idx_blk)?;
             // switch to a new idx block
-            idx_blk = [0u8; BLK_SZ];
+            idx_blk = new_blk(bsize);
         }
 
         let root_ke = idx_ke.remove(&HTREE_ROOT_BLK_PHY_POS).unwrap();
@@ -928,7 +1366,7 @@ impl HTreeBuilder {
         assert!(idx_ke.is_empty());
 
         // seek to end of this htree
-        let file_end = blk2byte!(to_start_blk + htree_nr_blk);
+        let file_end = blk2byte!(to_start_blk + htree_nr_blk, bsize);
         assert_eq!(io_try!(to.seek(SeekFrom::End(0))), file_end);
 
         // return size of htree in block, root block keys
@@ -938,6 +1376,11 @@ impl HTreeBuilder {
 
 #[cfg(test)]
 mod test {
+    use crate::*;
+    use crate::test_util::*;
+    use std::fs;
+    use std::path::Path;
+
     #[test]
     fn build_ro() {
         use std::path::Path;
@@ -1012,4 +1455,233 @@ mod test {
         }).unwrap();
         assert_eq!(written, std::mem::size_of::<FSMode>());
     }
+
+    #[test]
+    fn ro_compressed_file_data() {
+        let base = temp_dir("ro-zstd");
+        let src = base.join("src");
+        fs::create_dir_all(src.join("d")).unwrap();
+        let text = "eccfs compresses this line again and again\n".repeat(BLK_SZ / 8);
+        fs::write(src.join("text"), &text).unwrap();
+        // incompressible, ending in a partial block
+        let mut x = 0x9e3779b97f4a7c15u64;
+        let noise: Vec<u8> = (0..3 * BLK_SZ + 100).map(|_| {
+            x ^= x << 13;
+            x ^= x >> 7;
+            x ^= x << 17;
+            x as u8
+        }).collect();
+        fs::write(src.join("d/noise"), &noise).unwrap();
+        fs::write(src.join("small"), b"inline").unwrap();
+
+        ro::build_from_dir(&src, &base, Path::new("plain.image"), &base, None).unwrap();
+        let mode = ro::build_from_dir_with_compression(
+            &src, &base, Path::new("zstd.image"), &base, Some([3u8; 16]), eccfs::ro::Compression::Zstd,
+        ).unwrap();
+        let len = |name: &str| fs::metadata(base.join(name)).unwrap().len();
+        assert!(len("zstd.image") < len("plain.image"));
+
+        let rofs = eccfs::ro::ROFS::new(
+            mode, DEFAULT_CACHE_CAP, None, 0,
+            std::sync::Arc::new(FileStorage::new(&base.join("zstd.image"), false, BLK_SZ).unwrap()),
+        ).unwrap().verified().unwrap();
+        let caps = rofs.capabilities().unwrap();
+        assert!(caps.compression);
+        assert_eq!(caps.version, eccfs::vfs::FS_LAYOUT_VERSION);
+        let tree = read_tree(&rofs);
+        assert_eq!(tree["text"], text.as_bytes());
+        assert_eq!(tree["d/noise"], noise);
+        assert_eq!(tree["small"], b"inline");
+
+        // reads across blocks and past the end
+        let d = rofs.lookup(ROOT_INODE_ID, "d").unwrap().unwrap();
+        let iid = rofs.lookup(d, "noise").unwrap().unwrap();
+        let mut buf = vec![0u8; BLK_SZ];
+        assert_eq!(rofs.iread(iid, BLK_SZ - 3, &mut buf[..10]).unwrap(), 10);
+        assert_eq!(buf[..10], noise[BLK_SZ - 3..BLK_SZ + 7]);
+        assert_eq!(rofs.iread(iid, 3 * BLK_SZ, &mut buf).unwrap(), 100);
+        assert_eq!(buf[..100], noise[3 * BLK_SZ..]);
+        drop(rofs);
+
+        fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn validate_source_report() {
+        use ro::SourceIssue;
+
+        let base = temp_dir("validate");
+        let src = base.join("src");
+        fs::create_dir_all(src.join("sub")).unwrap();
+        fs::write(src.join("sub/f"), vec![1u8; 3 * BLK_SZ]).unwrap();
+        let long = src.join("sub").join("n".repeat(eccfs::ro::disk::DE_MAX_INLINE_NAME + 1));
+        fs::write(&long, b"").unwrap();
+        let fifo = src.join("fifo");
+        let c = std::ffi::CString::new(fifo.to_str().unwrap()).unwrap();
+        assert_eq!(unsafe { libc::mkfifo(c.as_ptr(), 0o644) }, 0);
+        let lnk = src.join("lnk");
+        std::os::unix::fs::symlink("t".repeat(64), &lnk).unwrap();
+
+        let report = ro::validate_source(&src).unwrap();
+        assert!(report.is_ok());
+        assert_eq!((report.nr_reg, report.nr_dir, report.nr_lnk), (2, 2, 1));
+        assert_eq!(report.data_bytes, 3 * BLK_SZ as u64);
+        assert!(report.warnings.contains(&SourceIssue::UnsupportedType(fifo)));
+        assert!(report.warnings.contains(&SourceIssue::LongName(long)));
+        assert!(report.warnings.contains(&SourceIssue::LongSymlink(lnk)));
+        assert_eq!(report.warnings.len(), 3);
+        // nothing is written
+        assert_eq!(fs::read_dir(&base).unwrap().count(), 1);
+
+        ro::build_from_dir(&src, &base, Path::new("ro.image"), &base, None).unwrap();
+        let size = fs::metadata(base.join("ro.image")).unwrap().len();
+        assert!(report.est_image_bytes >= size, "estimate {} < actual {}", report.est_image_bytes, size);
+
+        // a bad source path is the caller's mistake, it is reported, not a bug
+        assert!(matches!(
+            ro::validate_source(&base.join("ro.image")),
+            Err(FsError::NotADirectory)
+        ));
+        assert!(matches!(
+            ro::build_from_dir(&src, &base, Path::new("ro.image"), &base, None),
+            Err(FsError::AlreadyExists)
+        ));
+
+        fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn empty_tree() {
+        let base = temp_dir("empty");
+        let src = base.join("src");
+        fs::create_dir(&src).unwrap();
+
+        let mode = ro::build_from_dir(&src, &base, Path::new("ro.image"), &base, None).unwrap();
+        let rofs = mount_ro(&base.join("ro.image"), mode);
+        let mut names: Vec<_> = rofs.listdir(ROOT_INODE_ID, 0, 0).unwrap()
+            .into_iter().map(|(_, name, _)| name).collect();
+        names.sort();
+        assert_eq!(names, [".", ".."]);
+        assert_eq!(rofs.lookup(ROOT_INODE_ID, "f").unwrap(), None);
+        assert_eq!(rofs.finfo().unwrap().files, 0);
+        drop(rofs);
+
+        let image = base.join("rw.image");
+        let mode = rw::build_from_dir(&src, &image, None).unwrap();
+        let rwfs = mount_rw(&image, mode);
+        let mut names: Vec<_> = rwfs.listdir(ROOT_INODE_ID, 0, 0).unwrap()
+            .into_iter().map(|(_, name, _)| name).collect();
+        names.sort();
+        assert_eq!(names, [".", ".."]);
+        rwfs.create(ROOT_INODE_ID, "f", FileType::Reg, 0, 0, FilePerm::from_bits(0o644).unwrap()).unwrap();
+        let mode = rwfs.fsync().unwrap();
+        drop(rwfs);
+        let rwfs = mount_rw(&image, mode);
+        assert!(rwfs.lookup(ROOT_INODE_ID, "f").unwrap().is_some());
+        drop(rwfs);
+
+        fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn ro_siphash_names() {
+        use eccfs::crypto::{NameHashAlgo, NameHasher};
+
+        // reference vector of SipHash-2-4, key 00..0f, message 00..0e
+        let key: Vec<u8> = (0..16).collect();
+        let sip = NameHasher::SipHash(
+            u64::from_le_bytes(key[..8].try_into().unwrap()),
+            u64::from_le_bytes(key[8..].try_into().unwrap()),
+        );
+        assert_eq!(sip.hash(&(0..15).collect::<Vec<u8>>()).unwrap(), 0xa129ca6149be45e5);
+
+        let base = temp_dir("siphash");
+        let src = base.join("src");
+        fs::create_dir(&src).unwrap();
+        for i in 0..300 {
+            fs::write(src.join(format!("entry_{}", i)), format!("{}", i)).unwrap();
+        }
+
+        let mut images = Vec::new();
+        for name in ["a.image", "b.image"] {
+            let mode = ro::build_from_dir_with_name_hash(
+                &src, &base, Path::new(name), &base, None, NameHashAlgo::SipHash,
+            ).unwrap();
+            let rofs = mount_ro(&base.join(name), mode);
+            for i in 0..300 {
+                let iid = rofs.lookup(ROOT_INODE_ID, &format!("entry_{}", i)).unwrap().unwrap();
+                let mut buf = [0u8; 8];
+                let len = rofs.iread(iid, 0, &mut buf).unwrap();
+                assert_eq!(&buf[..len], format!("{}", i).as_bytes());
+            }
+            assert!(rofs.lookup(ROOT_INODE_ID, "entry_300").unwrap().is_none());
+            images.push(rofs.raw_entries(ROOT_INODE_ID).unwrap());
+        }
+
+        // no collisions among similar names, and hashes differ from image to image
+        let hashes = |entries: &Vec<eccfs::ro::RawDirEntry>| -> std::collections::HashMap<String, u64> {
+            entries.iter().filter(|de| de.name.starts_with("entry_"))
+                .map(|de| (de.name.clone(), de.hash)).collect()
+        };
+        let (a, b) = (hashes(&images[0]), hashes(&images[1]));
+        assert_eq!(a.values().collect::<std::collections::HashSet<_>>().len(), 300);
+        for (name, hash) in a.iter() {
+            assert_ne!(*hash, eccfs::crypto::half_md4(name.as_bytes()).unwrap());
+            assert_ne!(hash, b.get(name).unwrap());
+        }
+
+        fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn ro_build_error_policy() {
+        use std::os::unix::fs::PermissionsExt;
+        use ro::BuildErrorPolicy;
+
+        let base = temp_dir("error_policy");
+        let src = base.join("src");
+        fs::create_dir(&src).unwrap();
+        fs::write(src.join("ok"), b"ok").unwrap();
+        fs::write(src.join("secret"), b"secret").unwrap();
+        fs::set_permissions(src.join("secret"), fs::Permissions::from_mode(0o000)).unwrap();
+        // a failed build leaves its temp files, so every build has its own dir
+        let policies = [BuildErrorPolicy::Abort, BuildErrorPolicy::Skip, BuildErrorPolicy::Placeholder];
+        let dirs: Vec<_> = (0..policies.len()).map(|i| base.join(i.to_string())).collect();
+        for dir in dirs.iter() {
+            fs::create_dir(dir).unwrap();
+            fs::set_permissions(dir, fs::Permissions::from_mode(0o777)).unwrap();
+        }
+
+        // root reads anything, so build as another fs user, fsuid is per thread
+        let fsuid = unsafe { libc::setfsuid(65534) };
+        let modes: Vec<_> = policies.iter().zip(dirs.iter()).map(|(policy, dir)| {
+            ro::build_from_dir_with_error_policy(
+                &src, dir, Path::new("ro.image"), dir, None, *policy,
+            )
+        }).collect();
+        unsafe { libc::setfsuid(fsuid as u32) };
+
+        let mut modes = modes.into_iter();
+        assert!(matches!(modes.next().unwrap(), Err(FsError::IOError(_))));
+        for (i, mode) in modes.enumerate() {
+            let rofs = eccfs::ro::ROFS::new(
+                mode.unwrap(), DEFAULT_CACHE_CAP, None, 0,
+                std::sync::Arc::new(
+                    FileStorage::new(&dirs[i + 1].join("ro.image"), false, BLK_SZ).unwrap()
+                ),
+            ).unwrap();
+            assert!(rofs.lookup(ROOT_INODE_ID, "ok").unwrap().is_some());
+            let secret = rofs.lookup(ROOT_INODE_ID, "secret").unwrap();
+            if policies[i + 1] == BuildErrorPolicy::Skip {
+                assert!(secret.is_none());
+                assert_eq!(rofs.finfo().unwrap().files, 1);
+            } else {
+                let meta = rofs.get_meta(secret.unwrap()).unwrap();
+                assert_eq!((meta.size, meta.perm.bits()), (0, 0o000));
+                assert_eq!(rofs.finfo().unwrap().files, 2);
+            }
+        }
+
+        fs::remove_dir_all(&base).unwrap();
+    }
 }
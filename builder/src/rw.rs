@@ -14,6 +14,7 @@ use std::time::*;
 use eccfs::rw::inode::*;
 use eccfs::rw::bitmap::BitMap;
 use crate::htree::*;
+use crate::{get_btime, new_image_uuid};
 
 
 type ChildInfo = (PathBuf, FileType, InodeID);
@@ -22,10 +23,21 @@ const DATA_TEMP_FILE: &str = ".data.eccfs";
 const ITBL_IID: InodeID = InodeID::MAX;
 
 pub fn create_empty(to: &Path, encrypted: Option<Key128>) -> FsResult<FSMode> {
+    create_empty_with_blk_sz(to, encrypted, BLK_SZ)
+}
+
+/// same as [`create_empty`], with blocks of `bsize` bytes, see [`eccfs::check_blk_sz`]
+pub fn create_empty_with_blk_sz(
+    to: &Path,
+    encrypted: Option<Key128>,
+    bsize: usize,
+) -> FsResult<FSMode> {
+    check_blk_sz(bsize)?;
+
     // check to
     if to.exists() {
         if io_try!(fs::read_dir(to)).next().is_some() {
-            return Err(new_error!(FsError::DirectoryNotEmpty));
+            return Err(FsError::DirectoryNotEmpty);
         }
     } else {
         info!("{} not found, create dir", to.display());
@@ -33,7 +45,7 @@ pub fn create_empty(to: &Path, encrypted: Option<Key128>) -> FsResult<FSMode> {
     }
 
     let mut builder = RWBuilder::new(
-        to, encrypted,
+        to, encrypted, INODE_SZ, bsize,
     )?;
 
     builder.handle_empty_root_dir()?;
@@ -48,10 +60,46 @@ pub fn build_from_dir(
     to: &Path,
     encrypted: Option<Key128>,
 ) -> FsResult<FSMode> {
+    build_from_dir_impl(from, to, encrypted, INODE_SZ, BLK_SZ)
+}
+
+/// same as [`build_from_dir`], with inodes of [`inode_sz`] bytes,
+/// larger inodes hold more data and link names inline
+pub fn build_from_dir_with_inode_sz(
+    from: &Path,
+    to: &Path,
+    encrypted: Option<Key128>,
+    inode_sz: usize,
+) -> FsResult<FSMode> {
+    build_from_dir_impl(from, to, encrypted, inode_sz, BLK_SZ)
+}
+
+/// same as [`build_from_dir`], with blocks of `bsize` bytes, see [`eccfs::check_blk_sz`]
+pub fn build_from_dir_with_blk_sz(
+    from: &Path,
+    to: &Path,
+    encrypted: Option<Key128>,
+    bsize: usize,
+) -> FsResult<FSMode> {
+    build_from_dir_impl(from, to, encrypted, INODE_SZ, bsize)
+}
+
+fn build_from_dir_impl(
+    from: &Path,
+    to: &Path,
+    encrypted: Option<Key128>,
+    inode_sz: usize,
+    bsize: usize,
+) -> FsResult<FSMode> {
+    if !is_valid_inode_sz(inode_sz) {
+        return Err(FsError::InvalidParameter);
+    }
+    check_blk_sz(bsize)?;
+
     // check to
     if to.exists() {
         if io_try!(fs::read_dir(to)).next().is_some() {
-            return Err(new_error!(FsError::DirectoryNotEmpty));
+            return Err(FsError::DirectoryNotEmpty);
         }
     } else {
         info!("{} not found, create dir", to.display());
@@ -60,12 +108,14 @@ pub fn build_from_dir(
 
     // check from
     if !io_try!(fs::metadata(from)).is_dir() {
-        return Err(new_error!(FsError::NotADirectory));
+        return Err(FsError::NotADirectory);
     }
 
     let mut builder = RWBuilder::new(
         to,
         encrypted.clone(),
+        inode_sz,
+        bsize,
     )?;
 
     // stack holds (full paths, father_idx, inode id)
@@ -176,12 +226,16 @@ struct RWBuilder {
     files: usize,
     blocks: usize,
     nr_data_file: usize,
+    inode_sz: usize,
+    bsize: usize,
 }
 
 impl RWBuilder {
     fn new(
         to: &Path,
         encrypted: Option<Key128>,
+        inode_sz: usize,
+        bsize: usize,
     ) -> FsResult<Self> {
         Ok(Self {
             encrypted,
@@ -190,8 +244,10 @@ impl RWBuilder {
             files: 0,
             blocks: 0,
             key_gen: KeyGen::new(),
-            ht: HTreeBuilder::new(encrypted.is_some())?,
+            ht: HTreeBuilder::new(encrypted.is_some(), bsize)?,
             nr_data_file: 2, // sb file and itbl
+            inode_sz,
+            bsize,
         })
     }
 
@@ -211,7 +267,9 @@ impl RWBuilder {
             atime: m.atime() as u32,
             mtime: m.mtime() as u32,
             ctime: m.ctime() as u32,
+            btime: get_btime(&m),
             size: m.size(),
+            ..Default::default()
         })
 
     }
@@ -220,10 +278,12 @@ impl RWBuilder {
         &mut self,
         cinfo: Vec<ChildInfo>
     ) -> FsResult<Vec<DiskDirEntry>> {
-        Ok(cinfo.into_iter().map(
+        cinfo.into_iter().map(
             |(name, tp, iid)| {
                 let bname = name.as_os_str();
-                assert!(bname.len() < NAME_MAX as usize);
+                if bname.len() > NAME_MAX as usize {
+                    return Err(FsError::NameTooLong);
+                }
 
                 let mut dde = DiskDirEntry {
                     ipos: iid,
@@ -232,9 +292,9 @@ impl RWBuilder {
                     name: [0u8; DIRENT_NAME_MAX],
                 };
                 dde.name[..bname.len()].copy_from_slice(bname.to_str().unwrap().as_bytes());
-                dde
+                Ok(dde)
             }
-        ).collect())
+        ).collect()
     }
 
     fn create_data_file_from_iid(&self, iid: InodeID) -> FsResult<(Hash256, File)> {
@@ -291,7 +351,9 @@ impl RWBuilder {
             atime: now,
             ctime: now,
             mtime: now,
+            btime: now,
             size: 2 * DIRENT_SZ as u64,
+            ..Default::default()
         };
         // for dir inodes, size represents entry data size
         dibase.size = (dde_list.len() * DIRENT_SZ) as u64;
@@ -311,10 +373,10 @@ impl RWBuilder {
             data_file,
             data_file_ke,
             len,
-            _padding: [0u8; 24],
+            _padding: [0u8; 16],
         };
 
-        self.write_inode(ROOT_INODE_ID, ino.into());
+        self.write_inode(ROOT_INODE_ID, ino.to_inode_bytes(self.inode_sz));
         self.blocks += len as usize;
         self.nr_data_file += 1;
 
@@ -352,10 +414,10 @@ impl RWBuilder {
             data_file,
             data_file_ke,
             len,
-            _padding: [0u8; 24],
+            _padding: [0u8; 16],
         };
 
-        self.write_inode(iid, ino.into());
+        self.write_inode(iid, ino.to_inode_bytes(self.inode_sz));
         self.blocks += len as usize;
         self.nr_data_file += 1;
 
@@ -370,22 +432,18 @@ impl RWBuilder {
         let dibase = Self::gen_inode_base(path)?;
         let sz = dibase.size;
 
-        let inode = if sz <= REG_INLINE_DATA_MAX as u64 {
+        let inode = if sz <= inline_max(self.inode_sz) as u64 {
             // inline data
-            let mut inode = DInodeRegInline {
-                base: dibase,
-                data: [0u8; REG_INLINE_DATA_MAX],
-            };
-
+            let mut data = vec![0u8; sz as usize];
             if sz > 0 {
                 // read all bytes from source file
                 let mut f = io_try!(File::open(path));
-                if io_try!(f.read(&mut inode.data[..sz as usize])) != sz as usize {
+                if io_try!(f.read(&mut data)) != sz as usize {
                     return Err(new_error!(FsError::UnexpectedEof));
                 }
             }
 
-            inode.into()
+            inline_inode_bytes(dibase, &data, self.inode_sz)
         } else {
             let (data_file, mut f) = self.create_data_file_from_iid(iid)?;
             // generate hash tree
@@ -399,8 +457,9 @@ impl RWBuilder {
                 data_file_ke,
                 data_file,
                 len: nr_blk as u64,
-                _padding: [0u8; 24],
-            }.into()
+                nr_hole: 0,
+                _padding: [0u8; 8],
+            }.to_inode_bytes(self.inode_sz)
         };
         self.write_inode(iid, inode);
         self.files += 1;
@@ -416,18 +475,15 @@ impl RWBuilder {
         let size = target.as_os_str().len();
         dibase.size = size as u64;
 
-        let dinode = if size <= LNK_INLINE_MAX {
+        let dinode = if size <= inline_max(self.inode_sz) {
             // inline name
-            let mut d = DInodeLnkInline {
-                base: dibase,
-                name: [0u8; LNK_INLINE_MAX],
-            };
-            d.name[..size].copy_from_slice(target.as_os_str().to_str().unwrap().as_bytes());
-            d.into()
+            inline_inode_bytes(
+                dibase, target.as_os_str().to_str().unwrap().as_bytes(), self.inode_sz,
+            )
         } else {
             // single block file
             let (data_file, mut f) = self.create_data_file_from_iid(iid)?;
-            let mut blk = [0u8; BLK_SZ];
+            let mut blk = new_blk(self.bsize);
             blk[..size].copy_from_slice(target.as_os_str().to_str().unwrap().as_bytes());
             let name_file_ke = crypto_out(
                 &mut blk,
@@ -448,8 +504,8 @@ impl RWBuilder {
                 name_file_ke,
                 data_file,
                 len: 1,
-                _padding: [0u8; 24],
-            }.into()
+                _padding: [0u8; 16],
+            }.to_inode_bytes(self.inode_sz)
         };
 
         self.write_inode(iid, dinode);
@@ -461,7 +517,7 @@ impl RWBuilder {
         max_iid: InodeID,
         itbl_info: (u64, KeyEntry, Hash256),
     ) -> FsResult<FSMode> {
-        let mut bm_blks = BitMap::write_from_list((0..=max_iid).collect())?;
+        let mut bm_blks = BitMap::write_from_list((0..=max_iid).collect(), self.bsize)?;
         let mut bm_ke = vec![];
         for (i, blk) in bm_blks.iter_mut().enumerate() {
             let pos = 1 + i as u64;
@@ -480,16 +536,30 @@ impl RWBuilder {
             nr_data_file: self.nr_data_file,
             encrypted: self.encrypted.is_some(),
             magic: RWFS_MAGIC,
-            bsize: BLK_SZ,
+            version: FS_LAYOUT_VERSION,
+            bsize: self.bsize,
             blocks: self.blocks + bm_blks.len() + 1, // + bitmap + sb
             files: self.files,
             namemax: NAME_MAX as usize,
             ibitmap_start: 1,
             ibitmap_len: bm_blks.len() as usize,
             ibitmap_ke: bm_ke,
+            bbitmap_len: 0,
+            bbitmap_ke: vec![],
+            lbitmap_len: 0,
+            lbitmap_ke: vec![],
             itbl_name: itbl_info.2,
             itbl_len: itbl_info.0 as usize,
             itbl_ke: itbl_info.1,
+            ke_digest: None,
+            inode_sz: self.inode_sz,
+            uuid: new_image_uuid(),
+            generation: 0,
+            ltbl_len: 0,
+            ltbl_ke: KeyEntry::default(),
+            xtbl_len: 0,
+            xtbl_ke: KeyEntry::default(),
+            replaced: vec![],
         };
         let mut sb_blk = sb.write()?;
         let root_mode = crypto_out(
@@ -511,23 +581,20 @@ impl RWBuilder {
     }
 
     fn finalize(mut self, max_iid: InodeID) -> FsResult<FSMode> {
-        let mut itbl = vec![[0u8; INODE_SZ]; max_iid as usize + 1];
+        let mut itbl = vec![0u8; (max_iid as usize + 1) * self.inode_sz];
         self.itbl.iter().for_each(
             |(iid, ib)| {
                 assert!(*iid <= max_iid);
                 assert!(*iid != 0);
-                itbl[*iid as usize] = ib.clone();
+                assert_eq!(ib.len(), self.inode_sz);
+                let pos = iid_to_htree_logi_pos(*iid, self.inode_sz);
+                itbl[pos..pos + self.inode_sz].copy_from_slice(ib);
             }
         );
 
         let itbl_info = self.build_htree_from_data(
             self.to_dir.clone(),
-            unsafe {
-                std::slice::from_raw_parts(
-                    itbl.as_ptr() as *const u8,
-                    itbl.len() * INODE_SZ,
-                )
-            },
+            &itbl,
             ITBL_IID,
         )?;
         self.blocks += itbl_info.0 as usize;
@@ -680,4 +747,30 @@ mod test {
         }).unwrap();
         assert_eq!(written, std::mem::size_of::<FSMode>());
     }
+
+    #[test]
+    fn build_rejects_long_names_and_bad_inode_sz() {
+        use crate::*;
+        use crate::test_util::temp_dir;
+        use eccfs::rw::NAME_MAX;
+        use eccfs::rw::disk::INODE_SZ;
+        use std::fs;
+
+        let base = temp_dir("rw-reject");
+        let src = base.join("src");
+        fs::create_dir(&src).unwrap();
+        fs::write(src.join("a".repeat(NAME_MAX as usize)), b"").unwrap();
+        assert!(matches!(
+            super::build_from_dir_with_inode_sz(&src, &base.join("bad_sz"), None, INODE_SZ + 1),
+            Err(FsError::InvalidParameter)
+        ));
+        super::build_from_dir(&src, &base.join("ok"), None).unwrap();
+        fs::write(src.join("b".repeat(NAME_MAX as usize + 1)), b"").unwrap();
+        assert!(matches!(
+            super::build_from_dir(&src, &base.join("long"), None),
+            Err(FsError::NameTooLong)
+        ));
+
+        fs::remove_dir_all(&base).unwrap();
+    }
 }
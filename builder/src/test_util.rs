@@ -0,0 +1,47 @@
+//! shared setup of unit tests
+use crate::*;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+pub fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(
+        format!("eccfs-builder-{}-{}", name, std::process::id())
+    );
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+pub fn mount_ro(image: &Path, mode: FSMode) -> eccfs::ro::ROFS {
+    eccfs::ro::ROFS::new(
+        mode, DEFAULT_CACHE_CAP, None, 0,
+        std::sync::Arc::new(FileStorage::new(image, false, BLK_SZ).unwrap()),
+    ).unwrap()
+}
+
+pub fn mount_rw(image: &Path, mode: FSMode) -> eccfs::rw::RWFS {
+    eccfs::rw::RWFS::new(
+        false, mode, None, 0,
+        std::sync::Arc::new(FileDevice::new(image, BLK_SZ).unwrap()),
+        &SYSTEM_TIME,
+    ).unwrap()
+}
+
+// every path with its content or link target, read through the fs
+pub fn read_tree(fs: &dyn FileSystem) -> std::collections::BTreeMap<String, Vec<u8>> {
+    let mut tree = std::collections::BTreeMap::new();
+    fs.walk(ROOT_INODE_ID, &mut |e| {
+        let content = match e.meta.ftype {
+            FileType::Reg => {
+                let mut buf = vec![0u8; e.meta.size as usize];
+                assert_eq!(fs.iread(e.iid, 0, &mut buf)?, buf.len());
+                buf
+            }
+            FileType::Lnk => fs.iread_link(e.iid)?.into_bytes(),
+            FileType::Dir => vec![],
+        };
+        tree.insert(e.path, content);
+        Ok(WalkControl::Continue)
+    }).unwrap();
+    tree
+}
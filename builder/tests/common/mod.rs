@@ -0,0 +1,196 @@
+//! shared setup of tests on images built by eccfs-builder
+#![allow(dead_code)]
+use eccfs::*;
+use eccfs::crypto::*;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+pub fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(
+        format!("eccfs-test-{}-{}", name, std::process::id())
+    );
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+pub fn copy_image(from: &Path, to: &Path) {
+    fs::create_dir(to).unwrap();
+    for e in fs::read_dir(from).unwrap() {
+        let e = e.unwrap();
+        fs::copy(e.path(), to.join(e.file_name())).unwrap();
+    }
+}
+
+// crash injection for rw write paths: every storage op that changes the
+// image counts as a write, once armed the device fails all writes after
+// the given number of them, as if the backing store went away, or takes
+// them without keeping any, as if it lost power with them in its cache
+pub struct FailState {
+    armed: std::sync::atomic::AtomicBool,
+    lose: std::sync::atomic::AtomicBool,
+    left: std::sync::atomic::AtomicUsize,
+}
+
+impl FailState {
+    pub fn new() -> std::sync::Arc<Self> {
+        std::sync::Arc::new(Self {
+            armed: std::sync::atomic::AtomicBool::new(false),
+            lose: std::sync::atomic::AtomicBool::new(false),
+            left: std::sync::atomic::AtomicUsize::new(0),
+        })
+    }
+
+    pub fn fail_after(&self, nr_write: usize) {
+        self.lose.store(false, std::sync::atomic::Ordering::SeqCst);
+        self.left.store(nr_write, std::sync::atomic::Ordering::SeqCst);
+        self.armed.store(true, std::sync::atomic::Ordering::SeqCst);
+    }
+
+    // writes after nr_write still succeed, but nothing reaches the image
+    pub fn stop_after(&self, nr_write: usize) {
+        self.fail_after(nr_write);
+        self.lose.store(true, std::sync::atomic::Ordering::SeqCst);
+    }
+
+    pub fn heal(&self) {
+        self.armed.store(false, std::sync::atomic::Ordering::SeqCst);
+    }
+
+    // writes left before it fails, none once it's tripped
+    pub fn left(&self) -> usize {
+        self.left.load(std::sync::atomic::Ordering::SeqCst)
+    }
+
+    // whether the write goes to the image
+    fn write(&self) -> FsResult<bool> {
+        use std::sync::atomic::Ordering;
+        if self.armed.load(Ordering::SeqCst)
+            && self.left.fetch_update(
+                Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1),
+            ).is_err() {
+            if self.lose.load(Ordering::SeqCst) {
+                return Ok(false);
+            }
+            return Err(FsError::IOError(std::io::ErrorKind::BrokenPipe.into()));
+        }
+        Ok(true)
+    }
+}
+
+pub struct FailStorage(pub std::sync::Arc<dyn RWStorage>, pub std::sync::Arc<FailState>);
+
+impl ROStorage for FailStorage {
+    fn blk_sz(&self) -> usize {
+        self.0.blk_sz()
+    }
+    fn read_blk_to(&self, pos: u64, to: &mut Block) -> FsResult<()> {
+        self.0.read_blk_to(pos, to)
+    }
+}
+
+impl RWStorage for FailStorage {
+    fn write_blk(&self, pos: u64, from: &Block) -> FsResult<()> {
+        if !self.1.write()? {
+            return Ok(());
+        }
+        self.0.write_blk(pos, from)
+    }
+    fn get_len(&self) -> FsResult<u64> {
+        self.0.get_len()
+    }
+    fn set_len(&self, nr_blk: u64) -> FsResult<()> {
+        if !self.1.write()? {
+            return Ok(());
+        }
+        self.0.set_len(nr_blk)
+    }
+}
+
+pub struct FailDevice(pub FileDevice, pub std::sync::Arc<FailState>);
+
+impl FailDevice {
+    pub fn new(dir: &Path) -> Self {
+        Self(FileDevice::new(dir, BLK_SZ).unwrap(), FailState::new())
+    }
+}
+
+impl Device for FailDevice {
+    fn blk_sz(&self) -> usize {
+        self.0.blk_sz()
+    }
+    fn open_rw_storage(&self, path: &str) -> FsResult<std::sync::Arc<dyn RWStorage>> {
+        Ok(std::sync::Arc::new(FailStorage(self.0.open_rw_storage(path)?, self.1.clone())))
+    }
+    fn create_rw_storage(&self, path: &str) -> FsResult<std::sync::Arc<dyn RWStorage>> {
+        // a lost one is still made, empty, as none of its writes are kept
+        self.1.write()?;
+        Ok(std::sync::Arc::new(FailStorage(self.0.create_rw_storage(path)?, self.1.clone())))
+    }
+    fn remove_storage(&self, path: &str) -> FsResult<()> {
+        if !self.1.write()? {
+            return Ok(());
+        }
+        self.0.remove_storage(path)
+    }
+    fn rename_storage(&self, from: &str, to: &str) -> FsResult<()> {
+        if !self.1.write()? {
+            return Ok(());
+        }
+        self.0.rename_storage(from, to)
+    }
+    fn get_storage_len(&self, path: &str) -> FsResult<u64> {
+        self.0.get_storage_len(path)
+    }
+    fn nr_storage(&self) -> FsResult<usize> {
+        self.0.nr_storage()
+    }
+}
+
+// every path with its content or link target, read through the fs,
+// so that every htree on the way is checked
+pub fn read_tree(fs: &dyn FileSystem) -> std::collections::BTreeMap<String, Vec<u8>> {
+    let mut tree = std::collections::BTreeMap::new();
+    fs.walk(ROOT_INODE_ID, &mut |e| {
+        let content = match e.meta.ftype {
+            FileType::Reg => {
+                let mut buf = vec![0u8; e.meta.size as usize];
+                assert_eq!(fs.iread(e.iid, 0, &mut buf)?, buf.len());
+                buf
+            }
+            FileType::Lnk => fs.iread_link(e.iid)?.into_bytes(),
+            FileType::Dir => vec![],
+        };
+        tree.insert(e.path, content);
+        Ok(WalkControl::Continue)
+    }).unwrap();
+    tree
+}
+
+pub fn build_ro(src: &Path, base: &Path, name: &str, key: Option<Key128>) -> FSMode {
+    eccfs_builder::ro::build_from_dir(src, base, Path::new(name), base, key).unwrap()
+}
+
+pub fn build_rw(src: &Path, image: &Path, key: Option<Key128>) -> FSMode {
+    eccfs_builder::rw::build_from_dir(src, image, key).unwrap()
+}
+
+// opened with the block size in its public header
+pub fn mount_ro(image: &Path, mode: FSMode) -> ro::ROFS {
+    let hdr = ro::ROFS::read_public_header(
+        &FileStorage::new(image, false, MIN_BLK_SZ).unwrap(),
+    ).unwrap();
+    ro::ROFS::new(
+        mode, DEFAULT_CACHE_CAP, None, 0,
+        std::sync::Arc::new(FileStorage::new(image, false, hdr.bsize).unwrap()),
+    ).unwrap()
+}
+
+pub fn mount_rw(image: &Path, mode: FSMode) -> rw::RWFS {
+    let bsize = rw_image_blk_sz(image, &mode).unwrap();
+    rw::RWFS::new(
+        false, mode, None, 0,
+        std::sync::Arc::new(FileDevice::new(image, bsize).unwrap()),
+        &SYSTEM_TIME,
+    ).unwrap()
+}
@@ -0,0 +1,50 @@
+//! tests of errors seen through filesystems on built images
+// the error panics where it is made in debug builds, trace is for release ones
+#![cfg(all(feature = "err_trace", not(debug_assertions)))]
+mod common;
+
+use common::*;
+use eccfs::*;
+use eccfs::error::trace;
+use std::fs;
+
+#[test]
+fn integrity_error_carries_trace() {
+    let base = temp_dir("err-trace");
+    let src = base.join("src");
+    fs::create_dir(&src).unwrap();
+    let content = b"find me in the image!".repeat(BLK_SZ / 8);
+    fs::write(src.join("file"), &content).unwrap();
+    let mode = build_ro(&src, &base, "ro.image", None);
+
+    // break one byte of file data
+    let image = base.join("ro.image");
+    let mut raw = fs::read(&image).unwrap();
+    let at = raw.windows(64).position(|w| w == &content[..64]).unwrap();
+    raw[at] ^= 1;
+    fs::write(&image, raw).unwrap();
+
+    let rofs = mount_ro(&image, mode);
+    let iid = rofs.lookup(ROOT_INODE_ID, "file").unwrap().unwrap();
+    trace::take();
+    let mut buf = vec![0u8; content.len()];
+    // the first data block of the file is where the byte is broken
+    let first = eccfs::htree::mht::logi2phy(0);
+    assert!(matches!(
+        rofs.iread(iid, 0, &mut buf),
+        Err(FsError::IntegrityCheckFailed { pos }) if pos == first
+    ));
+
+    // the ring is per thread, but errors before this on it may be left
+    let integrity = core::mem::discriminant(&FsError::IntegrityCheckFailed { pos: 0 });
+    let files: Vec<_> = trace::take().into_iter()
+        .filter(|f| f.err == integrity).map(|f| f.file).collect();
+    let expected = ["crypto.rs", "bcache.rs", "htree/ro.rs", "ro/inode.rs", "ro/mod.rs"];
+    assert_eq!(files.len(), expected.len(), "{:?}", files);
+    for (file, exp) in files.iter().zip(expected) {
+        assert!(file.ends_with(exp), "{} is not {}", file, exp);
+    }
+    drop(rofs);
+
+    fs::remove_dir_all(&base).unwrap();
+}
@@ -0,0 +1,93 @@
+//! tests of opening images of either type by their superblock magic
+mod common;
+
+use common::*;
+use eccfs::*;
+use std::fs;
+
+#[test]
+fn open_fs_by_magic() {
+    let base = temp_dir("open-fs");
+    let src = base.join("src");
+    fs::create_dir(&src).unwrap();
+    fs::write(src.join("hello"), b"hello eccfs").unwrap();
+
+    // rofs
+    let mode = build_ro(&src, &base, "ro.image", Some([1u8; 16]));
+    let rofs = eccfs::open_fs(&base.join("ro.image"), mode).unwrap();
+    assert!(!rofs.capabilities().unwrap().writable);
+    let iid = rofs.lookup(ROOT_INODE_ID, "hello").unwrap().unwrap();
+    let mut buf = [0u8; 11];
+    assert_eq!(rofs.iread(iid, 0, &mut buf).unwrap(), 11);
+    assert_eq!(&buf, b"hello eccfs");
+    drop(rofs);
+
+    // rwfs
+    let mode = build_rw(&src, &base.join("rw.image"), None);
+    let rwfs = eccfs::open_fs(&base.join("rw.image"), mode).unwrap();
+    assert!(rwfs.capabilities().unwrap().writable);
+    assert!(rwfs.lookup(ROOT_INODE_ID, "hello").unwrap().is_some());
+    drop(rwfs);
+
+    fs::remove_dir_all(&base).unwrap();
+}
+
+#[test]
+fn open_fs_of_blk_sz() {
+    use eccfs_builder::{ro, rw};
+
+    let base = temp_dir("open-fs-bsize");
+    let src = base.join("src");
+    fs::create_dir(&src).unwrap();
+    // spans index blocks of the smallest block size
+    let big: Vec<u8> = (0..600 * BLK_SZ + 5).map(|i| (i % 251) as u8).collect();
+    fs::write(src.join("big"), &big).unwrap();
+    fs::write(src.join("small"), b"small").unwrap();
+
+    for bsize in [8192, 16384] {
+        let name = format!("ro-{}.image", bsize);
+        let mode = ro::build_from_dir_with_blk_sz(
+            &src, &base, std::path::Path::new(&name), &base, Some([2u8; 16]), bsize,
+        ).unwrap();
+        let hdr = eccfs::ro::ROFS::read_public_header(
+            &FileStorage::new(&base.join(&name), false, MIN_BLK_SZ).unwrap(),
+        ).unwrap();
+        assert_eq!(hdr.bsize, bsize);
+        // not opened with the block size it's built with
+        assert!(matches!(eccfs::ro::ROFS::new(
+            mode.clone(), DEFAULT_CACHE_CAP, None, 0,
+            std::sync::Arc::new(FileStorage::new(&base.join(&name), false, BLK_SZ).unwrap()),
+        ), Err(FsError::InvalidParameter)));
+        let rofs = eccfs::open_fs(&base.join(&name), mode).unwrap();
+        assert_eq!(rofs.get_meta(ROOT_INODE_ID).unwrap().blksize as usize, bsize);
+        let tree = read_tree(rofs.as_ref());
+        assert_eq!(tree["big"], big);
+        assert_eq!(tree["small"], b"small");
+        drop(rofs);
+
+        let image = base.join(format!("rw-{}", bsize));
+        let mode = rw::build_from_dir_with_blk_sz(&src, &image, None, bsize).unwrap();
+        let rwfs = eccfs::open_fs(&image, mode).unwrap();
+        let iid = rwfs.lookup(ROOT_INODE_ID, "small").unwrap().unwrap();
+        rwfs.iwrite(iid, bsize - 1, &big[..3 * bsize]).unwrap();
+        let mode = rwfs.fsync().unwrap();
+        drop(rwfs);
+        assert_eq!(rw_image_blk_sz(&image, &mode).unwrap(), bsize);
+        let rwfs = eccfs::open_fs(&image, mode).unwrap();
+        let tree = read_tree(rwfs.as_ref());
+        assert_eq!(tree["big"], big);
+        assert_eq!(&tree["small"][..5], b"small");
+        assert_eq!(&tree["small"][bsize - 1..], &big[..3 * bsize]);
+        drop(rwfs);
+    }
+
+    for bsize in [2048, 3000, 2 * MAX_BLK_SZ] {
+        assert!(matches!(check_blk_sz(bsize), Err(FsError::NotSupported)));
+        assert!(matches!(
+            rw::build_from_dir_with_blk_sz(&src, &base.join("bad"), None, bsize),
+            Err(FsError::NotSupported),
+        ));
+    }
+
+    fs::remove_dir_all(&base).unwrap();
+}
@@ -0,0 +1,372 @@
+//! tests of overlay of ro and rw layers built by eccfs-builder
+mod common;
+
+use common::*;
+use eccfs::*;
+use std::fs;
+
+#[test]
+fn name_len_limits() {
+    use eccfs::rw::NAME_MAX;
+
+    let base = temp_dir("namemax");
+    let src = base.join("src");
+    fs::create_dir(&src).unwrap();
+    fs::write(src.join("a".repeat(NAME_MAX as usize)), b"").unwrap();
+    let mode = build_rw(&src, &base.join("rw.image"), None);
+    // too long for a RW image, fine for a RO one
+    fs::write(src.join("b".repeat(NAME_MAX as usize + 1)), b"").unwrap();
+    let ro_mode = build_ro(&src, &base, "ro.image", None);
+
+    let rwfs: std::sync::Arc<dyn FileSystem> = std::sync::Arc::new(mount_rw(&base.join("rw.image"), mode));
+    assert_eq!(rwfs.finfo().unwrap().namemax, NAME_MAX as usize);
+    let perm = FilePerm::from_bits(0o644).unwrap();
+    let max = "c".repeat(NAME_MAX as usize);
+    let over = "c".repeat(NAME_MAX as usize + 1);
+    let f = rwfs.create(ROOT_INODE_ID, &max, FileType::Reg, 0, 0, perm).unwrap();
+    assert!(matches!(
+        rwfs.create(ROOT_INODE_ID, &over, FileType::Reg, 0, 0, perm),
+        Err(FsError::NameTooLong)
+    ));
+    assert!(matches!(rwfs.symlink(ROOT_INODE_ID, &over, "x", 0, 0), Err(FsError::NameTooLong)));
+    assert!(matches!(rwfs.link(ROOT_INODE_ID, &over, f), Err(FsError::NameTooLong)));
+    assert!(matches!(
+        rwfs.rename(ROOT_INODE_ID, &max, ROOT_INODE_ID, &over),
+        Err(FsError::NameTooLong)
+    ));
+    assert_eq!(rwfs.lookup(ROOT_INODE_ID, &max).unwrap(), Some(f));
+    rwfs.unlink(ROOT_INODE_ID, &max).unwrap();
+
+    // overlay leaves room for black out files in RW layer
+    let rofs: std::sync::Arc<dyn FileSystem> = std::sync::Arc::new(mount_ro(&base.join("ro.image"), ro_mode));
+    assert_eq!(rofs.finfo().unwrap().namemax, eccfs::ro::NAME_MAX as usize);
+    let ovl = eccfs::overlay::OverlayFS::new(rwfs, vec![rofs]).unwrap();
+    let namemax = ovl.finfo().unwrap().namemax;
+    assert!(namemax < NAME_MAX as usize);
+    ovl.create(ROOT_INODE_ID, &"d".repeat(namemax), FileType::Reg, 0, 0, perm).unwrap();
+    assert!(matches!(
+        ovl.create(ROOT_INODE_ID, &"d".repeat(namemax + 1), FileType::Reg, 0, 0, perm),
+        Err(FsError::NameTooLong)
+    ));
+    drop(ovl);
+
+    fs::remove_dir_all(&base).unwrap();
+}
+
+#[test]
+fn init_destroy_lifecycle() {
+    let base = temp_dir("lifecycle");
+    let src = base.join("src");
+    fs::create_dir(&src).unwrap();
+    fs::write(src.join("lower"), b"lower").unwrap();
+    let ro_mode = build_ro(&src, &base, "ro.image", None);
+    let empty = base.join("empty");
+    fs::create_dir(&empty).unwrap();
+    let mode = build_rw(&empty, &base.join("rw.image"), None);
+
+    let mount_rw = |mode: FSMode| -> std::sync::Arc<dyn FileSystem> {
+        std::sync::Arc::new(mount_rw(&base.join("rw.image"), mode))
+    };
+    let rwfs = mount_rw(mode);
+    let rofs: std::sync::Arc<dyn FileSystem> = std::sync::Arc::new(mount_ro(&base.join("ro.image"), ro_mode));
+    let ovl = eccfs::overlay::OverlayFS::new(rwfs.clone(), vec![rofs.clone()]).unwrap();
+
+    ovl.init().unwrap();
+    ovl.init().unwrap();
+    let perm = FilePerm::from_bits(0o644).unwrap();
+    let f = ovl.create(ROOT_INODE_ID, "upper", FileType::Reg, 0, 0, perm).unwrap();
+    ovl.iwrite(f, 0, b"upper").unwrap();
+
+    // no fsync before destroy
+    let mode = ovl.destroy().unwrap();
+    assert!(matches!(ovl.destroy(), Err(FsError::AlreadyDestroyed)));
+    assert!(matches!(ovl.init(), Err(FsError::AlreadyDestroyed)));
+    // all layers are destroyed with the overlay
+    assert!(matches!(rofs.destroy(), Err(FsError::AlreadyDestroyed)));
+    assert!(matches!(rwfs.destroy(), Err(FsError::AlreadyDestroyed)));
+    drop(ovl);
+    drop(rwfs);
+
+    let rwfs = mount_rw(mode);
+    let f = rwfs.lookup(ROOT_INODE_ID, "upper").unwrap().unwrap();
+    let mut buf = [0u8; 5];
+    assert_eq!(rwfs.iread(f, 0, &mut buf).unwrap(), 5);
+    assert_eq!(&buf, b"upper");
+    drop(rwfs);
+
+    fs::remove_dir_all(&base).unwrap();
+}
+
+#[test]
+fn overlay_link_lower_is_cross_device() {
+    let base = temp_dir("overlay-link");
+    let src = base.join("src");
+    fs::create_dir(&src).unwrap();
+    fs::write(src.join("lower"), b"lower").unwrap();
+    let ro_mode = build_ro(&src, &base, "ro.image", None);
+    let empty = base.join("empty");
+    fs::create_dir(&empty).unwrap();
+    let mode = build_rw(&empty, &base.join("rw.image"), None);
+
+    let rwfs: std::sync::Arc<dyn FileSystem> = std::sync::Arc::new(mount_rw(&base.join("rw.image"), mode));
+    let rofs: std::sync::Arc<dyn FileSystem> = std::sync::Arc::new(mount_ro(&base.join("ro.image"), ro_mode));
+    let ovl = eccfs::overlay::OverlayFS::new(rwfs, vec![rofs]).unwrap();
+
+    let lower = ovl.lookup(ROOT_INODE_ID, "lower").unwrap().unwrap();
+    let r = ovl.link(ROOT_INODE_ID, "hard", lower);
+    assert!(matches!(r, Err(FsError::CrossDevice)));
+    assert_eq!(ovl.lookup(ROOT_INODE_ID, "hard").unwrap(), None);
+
+    // once copied up, it's in one layer
+    ovl.iwrite(lower, 0, b"upper").unwrap();
+    ovl.link(ROOT_INODE_ID, "hard", lower).unwrap();
+    let hard = ovl.lookup(ROOT_INODE_ID, "hard").unwrap().unwrap();
+    let mut buf = [0u8; 5];
+    assert_eq!(ovl.iread(hard, 0, &mut buf).unwrap(), 5);
+    assert_eq!(&buf, b"upper");
+    drop(ovl);
+
+    fs::remove_dir_all(&base).unwrap();
+}
+
+#[test]
+fn overlay_concurrent_copy_up() {
+    use std::sync::{Arc, Barrier};
+
+    let base = temp_dir("overlay-copy-up");
+    let src = base.join("src");
+    fs::create_dir(&src).unwrap();
+    let content: Vec<u8> = (0..64 * BLK_SZ).map(|i| (i / BLK_SZ) as u8).collect();
+    fs::write(src.join("big"), &content).unwrap();
+    let ro_mode = build_ro(&src, &base, "ro.image", None);
+    let empty = base.join("empty");
+    fs::create_dir(&empty).unwrap();
+    let mode = build_rw(&empty, &base.join("rw.image"), None);
+
+    let rwfs: Arc<dyn FileSystem> = Arc::new(mount_rw(&base.join("rw.image"), mode));
+    let rofs: Arc<dyn FileSystem> = Arc::new(mount_ro(&base.join("ro.image"), ro_mode));
+    let ovl = Arc::new(eccfs::overlay::OverlayFS::new(rwfs, vec![rofs]).unwrap());
+    let big = ovl.lookup(ROOT_INODE_ID, "big").unwrap().unwrap();
+
+    // all threads copy up at once, each write lands only after content is copied
+    let nr_thread = 8;
+    let barrier = Arc::new(Barrier::new(nr_thread));
+    let handles: Vec<_> = (0..nr_thread).map(|t| {
+        let ovl = ovl.clone();
+        let barrier = barrier.clone();
+        std::thread::spawn(move || {
+            barrier.wait();
+            ovl.iwrite(big, t * BLK_SZ, &[0xff; 16]).unwrap();
+        })
+    }).collect();
+    for h in handles {
+        h.join().unwrap();
+    }
+
+    let mut expected = content.clone();
+    for t in 0..nr_thread {
+        expected[t * BLK_SZ..t * BLK_SZ + 16].fill(0xff);
+    }
+    let mut buf = vec![0u8; content.len()];
+    assert_eq!(ovl.iread(big, 0, &mut buf).unwrap(), buf.len());
+    assert_eq!(buf, expected);
+    drop(ovl);
+
+    fs::remove_dir_all(&base).unwrap();
+}
+
+#[test]
+fn overlay_finfo_free_inodes() {
+    let base = temp_dir("overlay_ffree");
+    let src = base.join("src");
+    fs::create_dir(&src).unwrap();
+    fs::write(src.join("f"), b"f").unwrap();
+    let mode = build_rw(&src, &base.join("rw.image"), None);
+    let ro_mode = build_ro(&src, &base, "ro.image", None);
+    let rwfs: std::sync::Arc<dyn FileSystem> = std::sync::Arc::new(mount_rw(&base.join("rw.image"), mode));
+    let rofs: std::sync::Arc<dyn FileSystem> = std::sync::Arc::new(mount_ro(&base.join("ro.image"), ro_mode));
+    // nothing is free in an immutable image
+    assert_eq!(rofs.finfo().unwrap().ffree, 0);
+
+    let rw_info = rwfs.finfo().unwrap();
+    let ovl = eccfs::overlay::OverlayFS::new(rwfs, vec![rofs]).unwrap();
+    let info = ovl.finfo().unwrap();
+    assert!(info.ffree > 0);
+    assert_eq!(info.ffree, rw_info.ffree);
+    let perm = FilePerm::from_bits(0o644).unwrap();
+    ovl.create(ROOT_INODE_ID, "g", FileType::Reg, 0, 0, perm).unwrap();
+    assert_eq!(ovl.finfo().unwrap().ffree, info.ffree - 1);
+    drop(ovl);
+
+    fs::remove_dir_all(&base).unwrap();
+}
+
+#[test]
+fn overlay_multiple_rw_layers() {
+    let base = temp_dir("overlay_multi_rw");
+    let src = base.join("src");
+    fs::create_dir_all(src.join("d")).unwrap();
+    fs::write(src.join("a"), b"base").unwrap();
+    fs::write(src.join("b"), b"base").unwrap();
+    fs::write(src.join("d/x"), b"base").unwrap();
+    let ro_mode = build_ro(&src, &base, "ro.image", None);
+    // cache layer overrides a, and blacks out b and d of base
+    let cache_src = base.join("cache_src");
+    fs::create_dir_all(cache_src.join("d")).unwrap();
+    fs::write(cache_src.join("a"), b"cache").unwrap();
+    fs::write(cache_src.join("c"), b"cache").unwrap();
+    fs::write(cache_src.join(".blacked.b"), b"").unwrap();
+    fs::write(cache_src.join(".blacked.d"), b"").unwrap();
+    let cache_mode = build_rw(&cache_src, &base.join("cache.image"), None);
+    let empty = base.join("empty");
+    fs::create_dir(&empty).unwrap();
+    let scratch_mode = build_rw(&empty, &base.join("scratch.image"), None);
+
+    let mount_rw = |mode: FSMode, image: &str| -> std::sync::Arc<dyn FileSystem> {
+        std::sync::Arc::new(mount_rw(&base.join(image), mode))
+    };
+    let scratch = mount_rw(scratch_mode, "scratch.image");
+    let cache = mount_rw(cache_mode, "cache.image");
+    let rofs: std::sync::Arc<dyn FileSystem> = std::sync::Arc::new(mount_ro(&base.join("ro.image"), ro_mode));
+    let layers = vec![scratch.clone(), cache.clone(), rofs.clone()];
+
+    let ovl = eccfs::overlay::OverlayFS::new_with_rw_layers(layers.clone(), 2).unwrap();
+    let tree = read_tree(&ovl);
+    assert_eq!(tree.keys().collect::<Vec<_>>(), ["", "a", "c", "d"]);
+    assert_eq!(tree["a"], b"cache");
+
+    // copy up goes to scratch, cache stays as it is
+    let c = ovl.lookup(ROOT_INODE_ID, "c").unwrap().unwrap();
+    ovl.iwrite(c, 0, b"CACHE").unwrap();
+    ovl.unlink(ROOT_INODE_ID, "a").unwrap();
+    ovl.fsync().unwrap();
+    drop(ovl);
+    let tree = read_tree(&*scratch);
+    assert_eq!(tree["c"], b"CACHE");
+    assert!(tree.contains_key(".blacked.a"));
+    assert_eq!(read_tree(&*cache)["c"], b"cache");
+
+    let ovl = eccfs::overlay::OverlayFS::new_with_rw_layers(layers, 2).unwrap();
+    let tree = read_tree(&ovl);
+    assert_eq!(tree.keys().collect::<Vec<_>>(), ["", "c", "d"]);
+    assert_eq!(tree["c"], b"CACHE");
+    drop(ovl);
+
+    fs::remove_dir_all(&base).unwrap();
+}
+
+#[test]
+fn overlay_keeps_flags() {
+    let base = temp_dir("overlay-flags");
+    let src = base.join("src");
+    fs::create_dir_all(src.join("d")).unwrap();
+    fs::write(src.join("d/f"), b"f").unwrap();
+    fs::write(src.join("log"), b"log").unwrap();
+    let lower_mode = build_rw(&src, &base.join("lower.image"), None);
+    let empty = base.join("empty");
+    fs::create_dir(&empty).unwrap();
+    let mode = build_rw(&empty, &base.join("rw.image"), None);
+
+    let lower: std::sync::Arc<dyn FileSystem> = std::sync::Arc::new(mount_rw(&base.join("lower.image"), lower_mode));
+    let d = lower.lookup(ROOT_INODE_ID, "d").unwrap().unwrap();
+    let log = lower.lookup(ROOT_INODE_ID, "log").unwrap().unwrap();
+    lower.set_flags(d, InodeFlags::IMMUTABLE).unwrap();
+    lower.set_flags(log, InodeFlags::APPEND_ONLY).unwrap();
+    let rwfs: std::sync::Arc<dyn FileSystem> = std::sync::Arc::new(mount_rw(&base.join("rw.image"), mode));
+    let ovl = eccfs::overlay::OverlayFS::new(rwfs.clone(), vec![lower]).unwrap();
+
+    let d = ovl.lookup(ROOT_INODE_ID, "d").unwrap().unwrap();
+    let log = ovl.lookup(ROOT_INODE_ID, "log").unwrap().unwrap();
+    assert_eq!(ovl.get_flags(d).unwrap(), InodeFlags::IMMUTABLE);
+    assert_eq!(ovl.get_flags(log).unwrap(), InodeFlags::APPEND_ONLY);
+
+    // flags of lower inodes hold in the overlay, and are copied up along with them
+    let denied = |r: FsResult<()>| assert!(matches!(r, Err(FsError::PermissionDenied)));
+    let perm = FilePerm::from_bits(0o644).unwrap();
+    denied(ovl.create(d, "new", FileType::Reg, 0, 0, perm).map(|_| ()));
+    denied(ovl.unlink(d, "f"));
+    denied(ovl.unlink(ROOT_INODE_ID, "log"));
+    denied(ovl.rename(ROOT_INODE_ID, "log", ROOT_INODE_ID, "log2"));
+    assert_eq!(ovl.iappend(log, b"!").unwrap(), (3, 1));
+    denied(ovl.iwrite(log, 0, b"x").map(|_| ()));
+    let rw_d = rwfs.lookup(ROOT_INODE_ID, "d").unwrap().unwrap();
+    let rw_log = rwfs.lookup(ROOT_INODE_ID, "log").unwrap().unwrap();
+    assert_eq!(rwfs.get_flags(rw_d).unwrap(), InodeFlags::IMMUTABLE);
+    assert_eq!(rwfs.get_flags(rw_log).unwrap(), InodeFlags::APPEND_ONLY);
+
+    ovl.set_flags(log, InodeFlags::empty()).unwrap();
+    assert_eq!(ovl.get_flags(log).unwrap(), InodeFlags::empty());
+    ovl.unlink(ROOT_INODE_ID, "log").unwrap();
+    drop(ovl);
+
+    fs::remove_dir_all(&base).unwrap();
+}
+
+#[test]
+fn overlay_metacopy_keeps_data_below() {
+    use std::sync::Arc;
+
+    let base = temp_dir("overlay-metacopy");
+    let src = base.join("src");
+    fs::create_dir(&src).unwrap();
+    let content: Vec<u8> = (0..64 * BLK_SZ).map(|i| (i / BLK_SZ) as u8).collect();
+    fs::write(src.join("big"), &content).unwrap();
+    let ro_mode = build_ro(&src, &base, "ro.image", None);
+    let empty = base.join("empty");
+    fs::create_dir(&empty).unwrap();
+    let mode = build_rw(&empty, &base.join("rw.image"), None);
+
+    let mount = |mode: FSMode| {
+        let rwfs: Arc<dyn FileSystem> = Arc::new(mount_rw(&base.join("rw.image"), mode));
+        let rofs: Arc<dyn FileSystem> = Arc::new(mount_ro(&base.join("ro.image"), ro_mode.clone()));
+        (rwfs.clone(), eccfs::overlay::OverlayFS::new(rwfs, vec![rofs]).unwrap())
+    };
+    let read = |ovl: &eccfs::overlay::OverlayFS, name: &str| {
+        let iid = ovl.lookup(ROOT_INODE_ID, name).unwrap().unwrap();
+        let mut buf = vec![0u8; content.len()];
+        assert_eq!(ovl.iread(iid, 0, &mut buf).unwrap(), buf.len());
+        buf
+    };
+    // size of the file in RW layer itself
+    let rw_size = |rwfs: &Arc<dyn FileSystem>, name: &str| {
+        let iid = rwfs.lookup(ROOT_INODE_ID, name).unwrap().unwrap();
+        rwfs.get_meta(iid).unwrap().size
+    };
+
+    let (rwfs, ovl) = mount(mode);
+    let big = ovl.lookup(ROOT_INODE_ID, "big").unwrap().unwrap();
+    ovl.set_meta(big, SetMetadata::Permission(FilePerm::from_bits(0o600).unwrap())).unwrap();
+    ovl.rename(ROOT_INODE_ID, "big", ROOT_INODE_ID, "moved").unwrap();
+    ovl.fsync().unwrap();
+    // no content is copied, neither on sync nor on destroy
+    assert_eq!(rw_size(&rwfs, "moved"), 0);
+    let mode = ovl.destroy().unwrap();
+    drop(ovl);
+    assert_eq!(rw_size(&rwfs, "moved"), 0);
+    drop(rwfs);
+
+    let (rwfs, ovl) = mount(mode);
+    let moved = ovl.lookup(ROOT_INODE_ID, "moved").unwrap().unwrap();
+    let meta = ovl.get_meta(moved).unwrap();
+    assert_eq!(meta.size, content.len() as u64);
+    assert_eq!(meta.perm.bits(), 0o600);
+    assert_eq!(ovl.lookup(ROOT_INODE_ID, "big").unwrap(), None);
+    assert_eq!(read(&ovl, "moved"), content);
+
+    // the first write copies it all
+    ovl.iwrite(moved, 0, b"upper").unwrap();
+    assert_eq!(rw_size(&rwfs, "moved"), content.len() as u64);
+    let iid = rwfs.lookup(ROOT_INODE_ID, "moved").unwrap().unwrap();
+    assert_eq!(rwfs.iget_xattr(iid, "overlay.metacopy").unwrap(), None);
+    let mode = ovl.destroy().unwrap();
+    drop(ovl);
+    drop(rwfs);
+
+    let (_, ovl) = mount(mode);
+    let mut expected = content.clone();
+    expected[..5].copy_from_slice(b"upper");
+    assert_eq!(read(&ovl, "moved"), expected);
+    drop(ovl);
+
+    fs::remove_dir_all(&base).unwrap();
+}
@@ -0,0 +1,54 @@
+//! tests of rwfs on a packed device
+mod common;
+
+use common::*;
+use eccfs::*;
+use std::fs;
+
+#[test]
+fn rw_on_packed_device() {
+    use std::sync::Arc;
+
+    let base = temp_dir("packed");
+    let src = base.join("src");
+    fs::create_dir(&src).unwrap();
+    let image = base.join("rw.image");
+    let mode = build_rw(&src, &image, None);
+
+    // move every storage of the image dir into one packed file
+    let packed_path = base.join("packed");
+    fs::File::create(&packed_path).unwrap();
+    let backend = Arc::new(FileStorage::new(&packed_path, true, BLK_SZ).unwrap());
+    let dev = PackedDevice::format(backend.clone()).unwrap();
+    for ent in fs::read_dir(&image).unwrap() {
+        let ent = ent.unwrap();
+        let from = FileStorage::new(&ent.path(), false, BLK_SZ).unwrap();
+        let nr_blk = from.get_len().unwrap() / BLK_SZ as u64;
+        let to = dev.create_rw_storage(ent.file_name().to_str().unwrap()).unwrap();
+        to.set_len(nr_blk).unwrap();
+        for pos in 0..nr_blk {
+            to.write_blk(pos, &from.read_blk(pos).unwrap()).unwrap();
+        }
+    }
+
+    let rwfs = eccfs::rw::RWFS::new(false, mode, None, 0, Arc::new(dev), &SYSTEM_TIME).unwrap();
+    let perm = FilePerm::from_bits(0o644).unwrap();
+    for i in 0..100 {
+        let iid = rwfs.create(ROOT_INODE_ID, &format!("f{}", i), FileType::Reg, 0, 0, perm).unwrap();
+        rwfs.iwrite(iid, 0, &vec![i as u8; 100 * i]).unwrap();
+    }
+    let mode = rwfs.fsync().unwrap();
+    drop(rwfs);
+
+    let dev = PackedDevice::new(backend).unwrap();
+    let rwfs = eccfs::rw::RWFS::new(false, mode, None, 0, Arc::new(dev), &SYSTEM_TIME).unwrap();
+    for i in 0..100 {
+        let iid = rwfs.lookup(ROOT_INODE_ID, &format!("f{}", i)).unwrap().unwrap();
+        let mut buf = vec![0u8; 100 * i];
+        assert_eq!(rwfs.iread(iid, 0, &mut buf).unwrap(), buf.len());
+        assert!(buf.iter().all(|b| *b == i as u8));
+    }
+    drop(rwfs);
+
+    fs::remove_dir_all(&base).unwrap();
+}
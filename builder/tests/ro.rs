@@ -0,0 +1,713 @@
+//! tests of rofs on images built by eccfs-builder
+mod common;
+
+use common::*;
+use eccfs::*;
+use std::fs;
+use std::path::Path;
+
+#[test]
+fn ro_public_header_without_key() {
+    use eccfs::ro::superblock::TBL_START;
+
+    let base = temp_dir("public-header");
+    let src = base.join("src");
+    fs::create_dir(&src).unwrap();
+    fs::write(src.join("hello"), vec![7u8; 3 * BLK_SZ]).unwrap();
+    let image = base.join("ro.image");
+    let mode = build_ro(&src, &base, "ro.image", Some([1u8; 16]));
+
+    // no mode is given here
+    let storage = FileStorage::new(&image, false, BLK_SZ).unwrap();
+    let hdr = eccfs::ro::ROFS::read_public_header(&storage).unwrap();
+    assert_eq!(hdr.magic, eccfs::ro::ROFS_MAGIC);
+    assert_eq!(hdr.version, eccfs::vfs::FS_LAYOUT_VERSION);
+    assert!(hdr.encrypted);
+    assert_eq!(hdr.bsize, BLK_SZ);
+    assert_eq!(hdr.blocks as u64 * BLK_SZ as u64, fs::metadata(&image).unwrap().len());
+    assert_eq!(hdr.inode_tbl_start, TBL_START);
+    assert_eq!(hdr.file_sec_start + hdr.file_sec_len, hdr.blocks as u64);
+    assert!(hdr.file_sec_len > 0);
+
+    // and it agrees with what is seen with the key
+    let rofs = eccfs::ro::ROFS::new(
+        mode, DEFAULT_CACHE_CAP, None, 0, std::sync::Arc::new(storage),
+    ).unwrap();
+    let caps = rofs.capabilities().unwrap();
+    assert_eq!((caps.magic, caps.version, caps.bsize), (hdr.magic, hdr.version, hdr.bsize));
+    assert!(!caps.compression);
+    assert_eq!(rofs.finfo().unwrap().blocks, hdr.blocks);
+    drop(rofs);
+
+    fs::remove_dir_all(&base).unwrap();
+}
+
+#[test]
+fn ro_header_block_size() {
+    let base = temp_dir("ro-bsize");
+    let src = base.join("src");
+    fs::create_dir(&src).unwrap();
+    fs::write(src.join("hello"), b"hello").unwrap();
+    let image = base.join("ro.image");
+    let mode = build_ro(&src, &base, "ro.image", None);
+    let raw = fs::read(&image).unwrap();
+
+    // bsize is the 4th u64 in public header
+    let with_bsize = |bsize: u64| {
+        let mut raw = raw.clone();
+        raw[24..32].copy_from_slice(&bsize.to_le_bytes());
+        fs::write(&image, raw).unwrap();
+        std::sync::Arc::new(FileStorage::new(&image, false, BLK_SZ).unwrap())
+    };
+
+    // no such block size
+    let storage = with_bsize(3000);
+    assert!(matches!(
+        eccfs::ro::ROFS::read_public_header(storage.as_ref()),
+        Err(FsError::NotSupported)
+    ));
+    assert!(matches!(
+        eccfs::ro::ROFS::new(mode.clone(), DEFAULT_CACHE_CAP, None, 0, storage),
+        Err(FsError::NotSupported)
+    ));
+
+    // as if built with twice the block size, not the one storage is opened with
+    let storage = with_bsize(2 * BLK_SZ as u64);
+    assert_eq!(eccfs::ro::ROFS::read_public_header(storage.as_ref()).unwrap().bsize, 2 * BLK_SZ);
+    assert!(matches!(
+        eccfs::ro::ROFS::new(mode, DEFAULT_CACHE_CAP, None, 0, storage),
+        Err(FsError::InvalidParameter)
+    ));
+
+    fs::remove_dir_all(&base).unwrap();
+}
+
+#[test]
+fn ro_layout_version_range() {
+    use eccfs::ro::superblock::RO_LAYOUT_VERSION_MIN;
+
+    let base = temp_dir("ro-version");
+    let src = base.join("src");
+    fs::create_dir(&src).unwrap();
+    fs::write(src.join("hello"), b"hello").unwrap();
+    let image = base.join("ro.image");
+    let mode = build_ro(&src, &base, "ro.image", Some([3u8; 16]));
+    let raw = fs::read(&image).unwrap();
+
+    // version is the 2nd u64 in public header, no key is needed to tell it's unsupported
+    for version in [RO_LAYOUT_VERSION_MIN - 1, FS_LAYOUT_VERSION + 1] {
+        let mut raw = raw.clone();
+        raw[8..16].copy_from_slice(&(version as u64).to_le_bytes());
+        fs::write(&image, raw).unwrap();
+        let storage = std::sync::Arc::new(FileStorage::new(&image, false, BLK_SZ).unwrap());
+        assert_eq!(eccfs::ro::ROFS::read_public_header(storage.as_ref()).unwrap().version, version);
+        assert!(matches!(
+            eccfs::ro::ROFS::new(mode.clone(), DEFAULT_CACHE_CAP, None, 0, storage),
+            Err(FsError::NotSupported)
+        ));
+    }
+
+    fs::remove_dir_all(&base).unwrap();
+}
+
+#[test]
+fn ro_dir_contains() {
+    let base = temp_dir("dir-contains");
+    let src = base.join("src");
+    fs::create_dir(&src).unwrap();
+    for i in 0..200 {
+        fs::write(src.join(format!("file_{}", i)), b"").unwrap();
+    }
+
+    let mode = build_ro(&src, &base, "ro.image", None);
+    let rofs = mount_ro(&base.join("ro.image"), mode);
+    for i in 0..200 {
+        assert!(rofs.dir_contains(ROOT_INODE_ID, &format!("file_{}", i)).unwrap());
+    }
+    assert!(!rofs.dir_contains(ROOT_INODE_ID, "file_200").unwrap());
+    assert!(!rofs.dir_contains(ROOT_INODE_ID, "a_name_not_in_this_dir").unwrap());
+    drop(rofs);
+
+    fs::remove_dir_all(&base).unwrap();
+}
+
+#[test]
+fn ro_raw_entries_hash() {
+    let base = temp_dir("raw-entries");
+    let src = base.join("src");
+    let sub = src.join("sub");
+    fs::create_dir_all(&sub).unwrap();
+    fs::write(src.join("short"), b"").unwrap();
+    fs::write(src.join("quite_a_long_file_name"), b"").unwrap();
+    for i in 0..40 {
+        fs::write(sub.join(format!("entry_{}", i)), b"").unwrap();
+    }
+
+    let mode = build_ro(&src, &base, "ro.image", None);
+    let rofs = mount_ro(&base.join("ro.image"), mode);
+    let sub_iid = rofs.lookup(ROOT_INODE_ID, "sub").unwrap().unwrap();
+    for iid in [ROOT_INODE_ID, sub_iid] {
+        let entries = rofs.raw_entries(iid).unwrap();
+        assert_eq!(entries.len(), rofs.listdir(iid, 0, 0).unwrap().len());
+        for de in entries.iter().filter(|de| de.name != "." && de.name != "..") {
+            assert_eq!(de.hash, eccfs::crypto::half_md4(de.name.as_bytes()).unwrap());
+            assert_eq!(de.name_pos.is_some(), de.name.len() > eccfs::ro::disk::DE_MAX_INLINE_NAME);
+        }
+    }
+    drop(rofs);
+
+    fs::remove_dir_all(&base).unwrap();
+}
+
+struct CountROStorage {
+    inner: FileStorage,
+    reads: std::sync::atomic::AtomicUsize,
+}
+
+impl ROStorage for CountROStorage {
+    fn read_blk_to(&self, pos: u64, to: &mut Block) -> FsResult<()> {
+        self.reads.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        self.inner.read_blk_to(pos, to)
+    }
+
+    fn blk_sz(&self) -> usize {
+        self.inner.blk_sz()
+    }
+}
+
+#[test]
+fn ro_listdir_prefetch_inodes() {
+    let base = temp_dir("prefetch");
+    let src = base.join("src");
+    let sub = src.join("sub");
+    fs::create_dir_all(&sub).unwrap();
+    for i in 0..40 {
+        fs::write(sub.join(format!("entry_{}", i)), b"").unwrap();
+    }
+    fs::create_dir(sub.join("dir")).unwrap();
+
+    let mode = build_ro(&src, &base, "ro.image", None);
+    let storage = std::sync::Arc::new(CountROStorage {
+        inner: FileStorage::new(&base.join("ro.image"), false, BLK_SZ).unwrap(),
+        reads: Default::default(),
+    });
+    // tiny block cache, so that inode table misses go to the backend
+    let rofs = eccfs::ro::ROFS::new(
+        mode, 2, Some(64), 0, storage.clone(),
+    ).unwrap();
+    let sub_iid = rofs.lookup(ROOT_INODE_ID, "sub").unwrap().unwrap();
+    let list = rofs.listdir(sub_iid, 0, 0).unwrap();
+    assert_eq!(list.len(), 43);
+
+    let reads = storage.reads.load(std::sync::atomic::Ordering::SeqCst);
+    for (iid, _, tp) in list {
+        assert_eq!(rofs.get_meta(iid).unwrap().ftype, tp);
+    }
+    assert_eq!(storage.reads.load(std::sync::atomic::Ordering::SeqCst), reads);
+    drop(rofs);
+
+    fs::remove_dir_all(&base).unwrap();
+}
+
+#[test]
+fn ro_get_meta_many_batches_reads() {
+    let base = temp_dir("meta-many");
+    let src = base.join("src");
+    fs::create_dir(&src).unwrap();
+    // inline data makes inodes large, so that they span many blocks
+    for i in 0..200 {
+        fs::write(src.join(format!("entry_{}", i)), vec![i as u8; 300]).unwrap();
+    }
+    let mode = build_ro(&src, &base, "ro.image", None);
+    let mount = || {
+        let storage = std::sync::Arc::new(CountROStorage {
+            inner: FileStorage::new(&base.join("ro.image"), false, BLK_SZ).unwrap(),
+            reads: Default::default(),
+        });
+        let rofs = eccfs::ro::ROFS::new(
+            mode.clone(), 2, Some(256), 0, storage.clone(),
+        ).unwrap();
+        (rofs, storage)
+    };
+    let reads_of = |storage: &CountROStorage| storage.reads.load(std::sync::atomic::Ordering::SeqCst);
+
+    // stat in listing order, which is not the order in inode table,
+    // listdir prefetches inodes, so remount before stat
+    let mut iids: Vec<_> = mount().0.listdir(ROOT_INODE_ID, 0, 0).unwrap()
+        .into_iter().map(|(iid, _, _)| iid).collect();
+    iids.push(iids[3]);
+    let (rofs, storage) = mount();
+    let reads = reads_of(&storage);
+    let metas: Vec<_> = iids.iter().map(|iid| rofs.get_meta(*iid).unwrap()).collect();
+    let loop_reads = reads_of(&storage) - reads;
+    drop(rofs);
+
+    let (rofs, storage) = mount();
+    let reads = reads_of(&storage);
+    let many = rofs.get_meta_many(&iids).unwrap();
+    let many_reads = reads_of(&storage) - reads;
+    assert!(many_reads < loop_reads, "batched {} >= loop {}", many_reads, loop_reads);
+    assert_eq!(many.len(), iids.len());
+    for ((iid, meta), (exp_iid, exp)) in many.into_iter().zip(iids.iter().zip(metas)) {
+        assert_eq!(iid, *exp_iid);
+        assert_eq!(meta.unwrap(), exp);
+    }
+    drop(rofs);
+
+    fs::remove_dir_all(&base).unwrap();
+}
+
+#[test]
+fn ro_inode_table_in_memory() {
+    let base = temp_dir("itbl-mem");
+    let src = base.join("src");
+    fs::create_dir_all(src.join("sub")).unwrap();
+    for i in 0..100 {
+        fs::write(src.join(format!("entry_{}", i)), vec![i as u8; 200]).unwrap();
+    }
+    let mode = build_ro(&src, &base, "ro.image", None);
+    // no inode cache, and a data cache too small to hold the table
+    let mount = || {
+        let storage = std::sync::Arc::new(CountROStorage {
+            inner: FileStorage::new(&base.join("ro.image"), false, BLK_SZ).unwrap(),
+            reads: Default::default(),
+        });
+        let rofs = eccfs::ro::ROFS::new(
+            mode.clone(), 2, None, 0, storage.clone(),
+        ).unwrap();
+        (rofs, storage)
+    };
+    let reads_of = |storage: &CountROStorage| storage.reads.load(std::sync::atomic::Ordering::SeqCst);
+
+    let (rofs, storage) = mount();
+    let iids: Vec<_> = rofs.listdir(ROOT_INODE_ID, 0, 0).unwrap()
+        .into_iter().map(|(iid, _, _)| iid).collect();
+    let reads = reads_of(&storage);
+    let metas: Vec<_> = iids.iter().map(|iid| rofs.get_meta(*iid).unwrap()).collect();
+    assert!(reads_of(&storage) > reads);
+    // over the limit, it stays on the cache
+    assert!(!rofs.load_inode_table(BLK_SZ).unwrap());
+    drop(rofs);
+
+    let (rofs, storage) = mount();
+    assert!(rofs.load_inode_table(usize::MAX).unwrap());
+    let reads = reads_of(&storage);
+    for (iid, exp) in iids.iter().zip(metas) {
+        assert_eq!(rofs.get_meta(*iid).unwrap(), exp);
+    }
+    assert_eq!(rofs.get_meta_many(&iids).unwrap().len(), iids.len());
+    assert_eq!(reads_of(&storage), reads);
+    drop(rofs);
+
+    fs::remove_dir_all(&base).unwrap();
+}
+
+#[test]
+fn ro_missing_path_tbl() {
+    use eccfs::ro::superblock::{DSuperBlock, PublicHeader, PUBLIC_HEADER_POS, SUPERBLOCK_POS};
+    use std::os::unix::fs::FileExt;
+
+    let base = temp_dir("missing-ptbl");
+    let src = base.join("src");
+    fs::create_dir(&src).unwrap();
+    fs::write(src.join("a_name_longer_than_inline"), b"").unwrap();
+    std::os::unix::fs::symlink("t".repeat(64), src.join("lnk")).unwrap();
+
+    let image = base.join("ro.image");
+    build_ro(&src, &base, "ro.image", None);
+
+    // drop path table from superblock, re-hash it to get a valid root mode
+    let f = fs::OpenOptions::new().read(true).write(true).open(&image).unwrap();
+    let mut blk = [0u8; BLK_SZ];
+    let sb_off = SUPERBLOCK_POS * BLK_SZ as u64;
+    f.read_exact_at(&mut blk, sb_off).unwrap();
+    let off = std::mem::offset_of!(DSuperBlock, path_tbl_len);
+    assert!(blk[off..off+8] != [0u8; 8]);
+    blk[off..off+8].fill(0);
+    let mode = eccfs::crypto::crypto_out(&mut blk, None, SUPERBLOCK_POS).unwrap();
+    f.write_all_at(&blk, sb_off).unwrap();
+    // so does the public header
+    let hdr_off = PUBLIC_HEADER_POS * BLK_SZ as u64;
+    f.read_exact_at(&mut blk, hdr_off).unwrap();
+    let mut hdr = PublicHeader::new(&blk).unwrap();
+    hdr.path_tbl_len = 0;
+    f.write_all_at(&hdr.to_blk(), hdr_off).unwrap();
+
+    let rofs = mount_ro(&image, mode);
+    assert!(matches!(rofs.listdir(ROOT_INODE_ID, 0, 0), Err(FsError::Corrupted)));
+    let lnk = rofs.lookup(ROOT_INODE_ID, "lnk").unwrap().unwrap();
+    assert!(matches!(rofs.iread_link(lnk), Err(FsError::Corrupted)));
+    drop(rofs);
+
+    fs::remove_dir_all(&base).unwrap();
+}
+
+struct BatchROStorage {
+    inner: FileStorage,
+    // nr of blocks of every backend read
+    reads: std::sync::Mutex<Vec<usize>>,
+}
+
+impl ROStorage for BatchROStorage {
+    fn read_blk_to(&self, pos: u64, to: &mut Block) -> FsResult<()> {
+        self.reads.lock().unwrap().push(1);
+        self.inner.read_blk_to(pos, to)
+    }
+
+    fn read_blks_to(&self, pos: u64, to: &mut [u8]) -> FsResult<()> {
+        self.reads.lock().unwrap().push(to.len() / self.blk_sz());
+        self.inner.read_blks_to(pos, to)
+    }
+
+    fn blk_sz(&self) -> usize {
+        self.inner.blk_sz()
+    }
+}
+
+#[test]
+fn ro_read_batches_adjacent_blks() {
+    let base = temp_dir("read-batch");
+    let src = base.join("src");
+    fs::create_dir(&src).unwrap();
+    let content: Vec<u8> = (0..10 * BLK_SZ).map(|i| (i / 7) as u8).collect();
+    fs::write(src.join("file"), &content).unwrap();
+    let mode = build_ro(&src, &base, "ro.image", None);
+
+    let read_file = |max_batch: usize| {
+        let storage = std::sync::Arc::new(BatchROStorage {
+            inner: FileStorage::new(&base.join("ro.image"), false, BLK_SZ).unwrap(),
+            reads: Default::default(),
+        });
+        let rofs = eccfs::ro::ROFS::new(
+            mode.clone(), 64, Some(16), 0, storage.clone(),
+        ).unwrap();
+        rofs.set_read_batch(max_batch);
+        let iid = rofs.lookup(ROOT_INODE_ID, "file").unwrap().unwrap();
+        storage.reads.lock().unwrap().clear();
+        let mut buf = vec![0u8; content.len()];
+        assert_eq!(rofs.iread(iid, 0, &mut buf).unwrap(), content.len());
+        assert_eq!(buf, content);
+        let reads = storage.reads.lock().unwrap().clone();
+        reads
+    };
+
+    // the whole data extent at once
+    let batched = read_file(16);
+    assert_eq!(batched.iter().filter(|&&n| n > 1).collect::<Vec<_>>(), vec![&10]);
+
+    // batch smaller than the extent splits it
+    let reads = read_file(4);
+    assert_eq!(reads.iter().filter(|&&n| n > 1).collect::<Vec<_>>(), vec![&4, &4, &2]);
+
+    // no batching, one read per blk, same blks in total
+    let single = read_file(1);
+    assert!(single.iter().all(|&n| n == 1));
+    assert_eq!(single.len(), batched.iter().sum::<usize>());
+    assert_eq!(single.len() - 9, batched.len());
+
+    fs::remove_dir_all(&base).unwrap();
+}
+
+#[test]
+fn ro_sequential_read_ahead() {
+    let base = temp_dir("readahead");
+    let src = base.join("src");
+    fs::create_dir(&src).unwrap();
+    let content: Vec<u8> = (0..40 * BLK_SZ).map(|i| (i / 11) as u8).collect();
+    fs::write(src.join("file"), &content).unwrap();
+    let mode = build_ro(&src, &base, "ro.image", None);
+
+    let open = |cache_data: usize| {
+        let storage = std::sync::Arc::new(BatchROStorage {
+            inner: FileStorage::new(&base.join("ro.image"), false, BLK_SZ).unwrap(),
+            reads: Default::default(),
+        });
+        let rofs = eccfs::ro::ROFS::new(
+            mode.clone(), cache_data, Some(16), 0, storage.clone(),
+        ).unwrap();
+        let iid = rofs.lookup(ROOT_INODE_ID, "file").unwrap().unwrap();
+        (rofs, iid, storage)
+    };
+    // backend reads of more than one block, single ones are of index blocks
+    let batches = |storage: &BatchROStorage| -> Vec<usize> {
+        storage.reads.lock().unwrap().iter().copied().filter(|n| *n > 1).collect()
+    };
+    let mut buf = vec![0u8; 8 * BLK_SZ];
+
+    // reading 8 blocks pulls in the next 8, which are then read from cache,
+    // while their own next 8 are pulled in
+    let (rofs, iid, storage) = open(64);
+    assert_eq!(rofs.iread(iid, 0, &mut buf).unwrap(), buf.len());
+    assert_eq!(batches(&storage), [8, 8]);
+    assert_eq!(rofs.iread(iid, 8 * BLK_SZ, &mut buf).unwrap(), buf.len());
+    assert_eq!(buf, content[8 * BLK_SZ..16 * BLK_SZ]);
+    assert_eq!(batches(&storage), [8, 8, 8]);
+
+    // nothing beyond the end of file, nor after small reads
+    assert_eq!(rofs.iread(iid, 32 * BLK_SZ, &mut buf).unwrap(), buf.len());
+    assert_eq!(batches(&storage), [8, 8, 8, 8]);
+    assert_eq!(rofs.iread(iid, 26 * BLK_SZ, &mut buf[..2 * BLK_SZ]).unwrap(), 2 * BLK_SZ);
+    assert_eq!(batches(&storage), [8, 8, 8, 8, 2]);
+    drop(rofs);
+
+    // nor without data cache
+    let (rofs, iid, storage) = open(0);
+    assert_eq!(rofs.iread(iid, 0, &mut buf).unwrap(), buf.len());
+    assert_eq!(batches(&storage), [8]);
+    drop(rofs);
+
+    fs::remove_dir_all(&base).unwrap();
+}
+
+#[test]
+fn ro_file_digest() {
+    use eccfs::crypto::sha3_256_any;
+
+    let base = temp_dir("file-digest");
+    let src = base.join("src");
+    fs::create_dir(&src).unwrap();
+    // longer than one read batch, not aligned to blk
+    let content: Vec<u8> = (0..40 * BLK_SZ + 123).map(|i| (i % 251) as u8).collect();
+    fs::write(src.join("file"), &content).unwrap();
+    fs::write(src.join("small"), b"small").unwrap();
+    fs::write(src.join("empty"), b"").unwrap();
+    let mode = build_ro(&src, &base, "ro.image", None);
+    let rofs = mount_ro(&base.join("ro.image"), mode);
+
+    for (name, data) in [("file", &content[..]), ("small", b"small"), ("empty", b"")] {
+        let iid = rofs.lookup(ROOT_INODE_ID, name).unwrap().unwrap();
+        assert_eq!(
+            rofs.file_digest(iid, HashAlgo::Sha3_256).unwrap(),
+            sha3_256_any(data).unwrap(),
+        );
+    }
+    let iid = rofs.lookup(ROOT_INODE_ID, "file").unwrap().unwrap();
+    let trunc = rofs.file_digest(iid, HashAlgo::Sha3_256Trunc128).unwrap();
+    assert_eq!(trunc[..16], sha3_256_any(&content).unwrap()[..16]);
+    assert!(matches!(
+        rofs.file_digest(ROOT_INODE_ID, HashAlgo::Sha3_256),
+        Err(FsError::IsADirectory)
+    ));
+    drop(rofs);
+
+    fs::remove_dir_all(&base).unwrap();
+}
+
+#[test]
+fn ro_index_only_skips_data_check() {
+    use eccfs::htree::VerifyPolicy;
+
+    let base = temp_dir("verify-policy");
+    let src = base.join("src");
+    fs::create_dir(&src).unwrap();
+    let content = b"find me in the image!".repeat(BLK_SZ / 8);
+    fs::write(src.join("file"), &content).unwrap();
+    let mode = build_ro(&src, &base, "ro.image", None);
+
+    // break one byte of file data
+    let image = base.join("ro.image");
+    let mut raw = fs::read(&image).unwrap();
+    let at = raw.windows(64).position(|w| w == &content[..64]).unwrap();
+    raw[at] ^= 1;
+    fs::write(&image, raw).unwrap();
+
+    let rofs = eccfs::ro::ROFS::new(
+        mode, DEFAULT_CACHE_CAP, Some(0), 0,
+        std::sync::Arc::new(FileStorage::new(&image, false, BLK_SZ).unwrap()),
+    ).unwrap();
+    let iid = rofs.lookup(ROOT_INODE_ID, "file").unwrap().unwrap();
+    assert!(matches!(
+        rofs.set_verify_policy(VerifyPolicy::Sampled(101), Some(1)),
+        Err(FsError::InvalidParameter)
+    ));
+    for policy in [VerifyPolicy::IndexOnly, VerifyPolicy::Sampled(0)] {
+        rofs.set_verify_policy(policy, None).unwrap();
+        let mut buf = vec![0u8; content.len()];
+        assert_eq!(rofs.iread(iid, 0, &mut buf).unwrap(), content.len());
+        let diff: Vec<_> = buf.iter().zip(content.iter()).filter(|(a, b)| a != b).collect();
+        assert_eq!(diff.len(), 1);
+    }
+    drop(rofs);
+
+    fs::remove_dir_all(&base).unwrap();
+}
+
+// the integrity error panics where it is made in debug builds
+#[test]
+#[cfg_attr(debug_assertions, should_panic(expected = "IntegrityCheckFailed"))]
+fn ro_full_policy_drops_unchecked_blocks() {
+    use eccfs::htree::VerifyPolicy;
+
+    let base = temp_dir("verify-policy-cache");
+    let src = base.join("src");
+    fs::create_dir(&src).unwrap();
+    let content = b"cached but never checked".repeat(BLK_SZ / 8);
+    fs::write(src.join("file"), &content).unwrap();
+    let mode = build_ro(&src, &base, "ro.image", None);
+
+    let image = base.join("ro.image");
+    let mut raw = fs::read(&image).unwrap();
+    let at = raw.windows(64).position(|w| w == &content[..64]).unwrap();
+    raw[at] ^= 1;
+    fs::write(&image, raw).unwrap();
+
+    // broken data goes into block cache unchecked
+    let rofs = mount_ro(&image, mode);
+    let iid = rofs.lookup(ROOT_INODE_ID, "file").unwrap().unwrap();
+    rofs.set_verify_policy(VerifyPolicy::IndexOnly, None).unwrap();
+    let mut buf = vec![0u8; content.len()];
+    assert_eq!(rofs.iread(iid, 0, &mut buf).unwrap(), content.len());
+
+    // and it is read again from the image with full check
+    rofs.set_verify_policy(VerifyPolicy::Full, None).unwrap();
+    let res = rofs.iread(iid, 0, &mut buf);
+    fs::remove_dir_all(&base).unwrap();
+    assert!(matches!(res, Err(FsError::IntegrityCheckFailed { .. })));
+}
+
+// the integrity error panics where it is made in debug builds
+#[test]
+#[cfg_attr(debug_assertions, should_panic(expected = "IntegrityCheckFailed"))]
+fn ro_verified_mount_fails_on_tampered_image() {
+    let base = temp_dir("verify-on-mount");
+    let src = base.join("src");
+    fs::create_dir(&src).unwrap();
+    let content = b"never read after mount".repeat(BLK_SZ / 8);
+    fs::write(src.join("file"), &content).unwrap();
+    let mode = build_ro(&src, &base, "ro.image", None);
+
+    // break one byte of file data, which a plain mount never reads
+    let image = base.join("ro.image");
+    let mut raw = fs::read(&image).unwrap();
+    let at = raw.windows(64).position(|w| w == &content[..64]).unwrap();
+    raw[at] ^= 1;
+    fs::write(&image, raw).unwrap();
+
+    let mount = || eccfs::ro::ROFS::new(
+        mode.clone(), DEFAULT_CACHE_CAP, None, 0,
+        std::sync::Arc::new(FileStorage::new(&image, false, BLK_SZ).unwrap()),
+    ).unwrap();
+    drop(mount());
+    let res = mount().verified();
+    fs::remove_dir_all(&base).unwrap();
+    assert!(matches!(res, Err(FsError::IntegrityCheckFailed { .. })));
+}
+
+#[test]
+fn ro_precompressed_served_as_stored() {
+    use eccfs::ro::Encoding;
+
+    let base = temp_dir("precompressed");
+    let src = base.join("src");
+    fs::create_dir(&src).unwrap();
+    let page = b"<p>served as is</p>".repeat(BLK_SZ / 8);
+    fs::write(src.join("index.html"), &page).unwrap();
+    // not really gzip, it's stored as it is anyway
+    let gz: Vec<u8> = [0x1fu8, 0x8b].into_iter().chain((0..BLK_SZ + 7).map(|i| i as u8)).collect();
+    fs::write(src.join("index.html.gz"), &gz).unwrap();
+    fs::write(src.join("tiny.txt"), b"inline").unwrap();
+    fs::write(src.join("tiny.txt.gz"), &gz).unwrap();
+
+    let build = |name: &str, precompressed: bool| {
+        let f = if precompressed {
+            eccfs_builder::ro::build_from_dir_with_precompressed
+        } else {
+            eccfs_builder::ro::build_from_dir
+        };
+        let mode = f(&src, &base, Path::new(name), &base, None).unwrap();
+        eccfs::ro::ROFS::new(
+            mode, DEFAULT_CACHE_CAP, None, 0,
+            std::sync::Arc::new(FileStorage::new(&base.join(name), false, BLK_SZ).unwrap()),
+        ).unwrap().verified().unwrap()
+    };
+
+    let rofs = build("pre.image", true);
+    let iid = rofs.lookup(ROOT_INODE_ID, "index.html").unwrap().unwrap();
+    assert_eq!(
+        rofs.read_compressed(iid, &[Encoding::Zstd, Encoding::Gzip]).unwrap(),
+        Some((gz.clone(), Encoding::Gzip)),
+    );
+    assert_eq!(rofs.read_compressed(iid, &[Encoding::Zstd]).unwrap(), None);
+    // plain content is untouched
+    let mut buf = vec![0u8; page.len() + 1];
+    assert_eq!(rofs.iread(iid, 0, &mut buf).unwrap(), page.len());
+    assert_eq!(&buf[..page.len()], &page[..]);
+    assert_eq!(rofs.get_meta(iid).unwrap().size, page.len() as u64);
+    assert!(rofs.lookup(ROOT_INODE_ID, "index.html.gz").unwrap().is_some());
+    // inline files keep none
+    let tiny = rofs.lookup(ROOT_INODE_ID, "tiny.txt").unwrap().unwrap();
+    assert_eq!(rofs.read_compressed(tiny, &Encoding::ALL).unwrap(), None);
+    drop(rofs);
+
+    let rofs = build("plain.image", false);
+    let iid = rofs.lookup(ROOT_INODE_ID, "index.html").unwrap().unwrap();
+    assert_eq!(rofs.read_compressed(iid, &Encoding::ALL).unwrap(), None);
+    drop(rofs);
+
+    fs::remove_dir_all(&base).unwrap();
+}
+
+#[test]
+fn ro_close() {
+    let base = temp_dir("ro_close");
+    let src = base.join("src");
+    fs::create_dir(&src).unwrap();
+    fs::write(src.join("f"), vec![1u8; 3 * BLK_SZ]).unwrap();
+
+    let mode = build_ro(&src, &base, "ro.image", None);
+    let mount = || eccfs::ro::ROFS::new(
+        mode.clone(), DEFAULT_CACHE_CAP, Some(0), 16,
+        std::sync::Arc::new(FileStorage::new(&base.join("ro.image"), false, BLK_SZ).unwrap()),
+    ).unwrap();
+
+    let rofs = mount();
+    let f = rofs.lookup(ROOT_INODE_ID, "f").unwrap().unwrap();
+    let mut buf = vec![0u8; 3 * BLK_SZ];
+    assert_eq!(rofs.iread(f, 0, &mut buf).unwrap(), buf.len());
+    assert_eq!(rofs.close().unwrap(), mode);
+
+    // also fine after destroy
+    let rofs = mount();
+    assert_eq!(rofs.destroy().unwrap(), mode);
+    assert!(matches!(rofs.init(), Err(FsError::AlreadyDestroyed)));
+    assert_eq!(rofs.close().unwrap(), mode);
+
+    fs::remove_dir_all(&base).unwrap();
+}
+
+#[test]
+fn ro_dirents_across_blocks() {
+    use eccfs::ro::disk::DirEntry;
+
+    let base = temp_dir("ro_dirents_across_blocks");
+    let src = base.join("src");
+    // entries of the second dir start in the middle of a block and span several
+    fs::create_dir_all(src.join("a")).unwrap();
+    fs::create_dir_all(src.join("b")).unwrap();
+    for i in 0..50 {
+        fs::write(src.join("a").join(format!("a_{}", i)), b"").unwrap();
+    }
+    for i in 0..400 {
+        fs::write(src.join("b").join(format!("b_{}", i)), b"").unwrap();
+    }
+    let mode = build_ro(&src, &base, "ro.image", None);
+    let rofs = mount_ro(&base.join("ro.image"), mode);
+
+    let b = rofs.lookup(ROOT_INODE_ID, "b").unwrap().unwrap();
+    let all = rofs.read_dirents(b, 0, 0).unwrap();
+    assert_eq!(all.len(), 402);
+    // windows around every block boundary of the table
+    let per_blk = BLK_SZ / std::mem::size_of::<DirEntry>();
+    for boundary in (per_blk..all.len()).step_by(per_blk) {
+        for off in boundary - 3..boundary + 3 {
+            assert_eq!(rofs.read_dirents(b, off, 5).unwrap(), all[off..(off + 5).min(all.len())]);
+        }
+    }
+    for i in 0..400 {
+        let name = format!("b_{}", i);
+        let iid = rofs.lookup(b, &name).unwrap().unwrap();
+        assert!(all.iter().any(|de| de.name == name && de.ipos == iid));
+    }
+
+    fs::remove_dir_all(&base).unwrap();
+}
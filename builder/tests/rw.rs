@@ -0,0 +1,1870 @@
+//! tests of rwfs on images built by eccfs-builder
+mod common;
+
+use common::*;
+use eccfs::*;
+use std::fs;
+use std::path::Path;
+
+#[test]
+fn estimate_ro_size_upper_bound() {
+    let base = temp_dir("estimate-ro");
+    let src = base.join("src");
+    fs::create_dir(&src).unwrap();
+    fs::write(src.join("small"), b"tiny").unwrap();
+    fs::write(src.join("a_rather_long_file_name"), vec![7u8; 300 * 1024]).unwrap();
+    let sub = src.join("sub");
+    fs::create_dir(&sub).unwrap();
+    for i in 0..20 {
+        fs::write(sub.join(format!("f{}", i)), vec![i as u8; 1000]).unwrap();
+    }
+    std::os::unix::fs::symlink("x".repeat(40), src.join("lnk")).unwrap();
+
+    build_ro(&src, &base, "ro.image", None);
+    let ro_size = fs::metadata(base.join("ro.image")).unwrap().len();
+
+    let rw_mode = build_rw(&src, &base.join("rw.image"), None);
+    let rwfs = mount_rw(&base.join("rw.image"), rw_mode);
+    let est = rwfs.estimate_ro_size().unwrap();
+    assert!(est >= ro_size, "estimate {} < actual {}", est, ro_size);
+    drop(rwfs);
+
+    fs::remove_dir_all(&base).unwrap();
+}
+
+struct TickTime(std::sync::atomic::AtomicU32);
+
+impl TimeSource for TickTime {
+    fn now(&self) -> u32 {
+        self.0.fetch_add(1, std::sync::atomic::Ordering::SeqCst)
+    }
+}
+
+static TICK_TIME: TickTime = TickTime(std::sync::atomic::AtomicU32::new(1000));
+
+#[test]
+fn btime_fixed_after_create() {
+    let base = temp_dir("btime");
+    let src = base.join("src");
+    fs::create_dir(&src).unwrap();
+
+    let mode = build_rw(&src, &base.join("rw.image"), None);
+    let rwfs = eccfs::rw::RWFS::new(
+        false, mode, None, 0,
+        std::sync::Arc::new(FileDevice::new(&base.join("rw.image"), BLK_SZ).unwrap()),
+        &TICK_TIME,
+    ).unwrap();
+    let iid = rwfs.create(
+        ROOT_INODE_ID, "f", FileType::Reg, 0, 0, FilePerm::from_bits(0o644).unwrap(),
+    ).unwrap();
+    let created = rwfs.get_meta(iid).unwrap();
+    assert_eq!(created.btime, created.mtime);
+
+    rwfs.iwrite(iid, 0, b"later").unwrap();
+    let meta = rwfs.get_meta(iid).unwrap();
+    assert!(meta.mtime > created.mtime && meta.ctime > created.ctime);
+    assert_eq!(meta.btime, created.btime);
+
+    fs::remove_dir_all(&base).unwrap();
+}
+
+#[test]
+fn link_times() {
+    let base = temp_dir("link-times");
+    let src = base.join("src");
+    fs::create_dir(&src).unwrap();
+
+    let mode = build_rw(&src, &base.join("rw.image"), None);
+    let rwfs = eccfs::rw::RWFS::new(
+        false, mode, None, 0,
+        std::sync::Arc::new(FileDevice::new(&base.join("rw.image"), BLK_SZ).unwrap()),
+        &TICK_TIME,
+    ).unwrap();
+    let perm = FilePerm::from_bits(0o755).unwrap();
+    let d = rwfs.create(ROOT_INODE_ID, "d", FileType::Dir, 0, 0, perm).unwrap();
+    let f = rwfs.create(ROOT_INODE_ID, "f", FileType::Reg, 0, 0, perm).unwrap();
+    let (dir, file) = (rwfs.get_meta(d).unwrap(), rwfs.get_meta(f).unwrap());
+
+    rwfs.link(d, "f", f).unwrap();
+    let meta = rwfs.get_meta(d).unwrap();
+    assert!(meta.ctime > dir.ctime && meta.mtime > dir.mtime);
+    assert_eq!(meta.atime, dir.atime);
+    let meta = rwfs.get_meta(f).unwrap();
+    assert!(meta.ctime > file.ctime);
+    assert_eq!((meta.atime, meta.mtime), (file.atime, file.mtime));
+    drop(rwfs);
+
+    fs::remove_dir_all(&base).unwrap();
+}
+
+#[test]
+fn rw_rename_self_and_cycle() {
+    let base = temp_dir("rename");
+    let src = base.join("src");
+    fs::create_dir(&src).unwrap();
+    let mode = build_rw(&src, &base.join("rw.image"), None);
+    let rwfs = mount_rw(&base.join("rw.image"), mode);
+    let perm = FilePerm::from_bits(0o755).unwrap();
+    let a = rwfs.create(ROOT_INODE_ID, "a", FileType::Dir, 0, 0, perm).unwrap();
+    let b = rwfs.create(a, "b", FileType::Dir, 0, 0, perm).unwrap();
+    let f = rwfs.create(b, "f", FileType::Reg, 0, 0, perm).unwrap();
+
+    // self rename keeps everything in place
+    rwfs.rename(ROOT_INODE_ID, "a", ROOT_INODE_ID, "a").unwrap();
+    rwfs.rename(b, "f", b, "f").unwrap();
+    assert_eq!(rwfs.lookup(ROOT_INODE_ID, "a").unwrap(), Some(a));
+    assert_eq!(rwfs.lookup(b, "f").unwrap(), Some(f));
+
+    // moving a dir under itself or its descendant
+    assert!(matches!(rwfs.rename(ROOT_INODE_ID, "a", b, "a"), Err(FsError::InvalidParameter)));
+    assert!(matches!(rwfs.rename(ROOT_INODE_ID, "a", a, "x"), Err(FsError::InvalidParameter)));
+    assert_eq!(rwfs.lookup(ROOT_INODE_ID, "a").unwrap(), Some(a));
+
+    // moving a descendant up is fine
+    rwfs.rename(a, "b", ROOT_INODE_ID, "b").unwrap();
+    assert_eq!(rwfs.lookup(ROOT_INODE_ID, "b").unwrap(), Some(b));
+    assert_eq!(rwfs.lookup(b, "..").unwrap(), Some(ROOT_INODE_ID));
+    // a is no longer above b
+    rwfs.rename(ROOT_INODE_ID, "a", b, "a").unwrap();
+    drop(rwfs);
+
+    fs::remove_dir_all(&base).unwrap();
+}
+
+#[test]
+fn rw_de_cache_invalidate() {
+    let base = temp_dir("decache");
+    let src = base.join("src");
+    fs::create_dir(&src).unwrap();
+    let mode = build_rw(&src, &base.join("rw.image"), None);
+    let rwfs = eccfs::rw::RWFS::new(
+        false, mode, None, 16,
+        std::sync::Arc::new(FileDevice::new(&base.join("rw.image"), BLK_SZ).unwrap()),
+        &SYSTEM_TIME,
+    ).unwrap();
+    let perm = FilePerm::from_bits(0o644).unwrap();
+    let a = rwfs.create(ROOT_INODE_ID, "a", FileType::Reg, 0, 0, perm).unwrap();
+    let d = rwfs.create(ROOT_INODE_ID, "d", FileType::Dir, 0, 0, perm).unwrap();
+
+    // cached by the first lookup
+    assert_eq!(rwfs.lookup(ROOT_INODE_ID, "a").unwrap(), Some(a));
+    assert_eq!(rwfs.lookup(ROOT_INODE_ID, "a").unwrap(), Some(a));
+
+    // rename in the same dir and into another dir
+    rwfs.rename(ROOT_INODE_ID, "a", ROOT_INODE_ID, "b").unwrap();
+    assert_eq!(rwfs.lookup(ROOT_INODE_ID, "a").unwrap(), None);
+    assert_eq!(rwfs.lookup(ROOT_INODE_ID, "b").unwrap(), Some(a));
+    rwfs.rename(ROOT_INODE_ID, "b", d, "c").unwrap();
+    assert_eq!(rwfs.lookup(ROOT_INODE_ID, "b").unwrap(), None);
+    assert_eq!(rwfs.lookup(d, "c").unwrap(), Some(a));
+
+    // an overwritten target is not returned
+    let e = rwfs.create(d, "e", FileType::Reg, 0, 0, perm).unwrap();
+    assert_eq!(rwfs.lookup(d, "e").unwrap(), Some(e));
+    rwfs.rename(d, "c", d, "e").unwrap();
+    assert_eq!(rwfs.lookup(d, "e").unwrap(), Some(a));
+
+    // unlink, and entries are dropped by fsync
+    rwfs.unlink(d, "e").unwrap();
+    assert_eq!(rwfs.lookup(d, "e").unwrap(), None);
+    assert_eq!(rwfs.lookup(ROOT_INODE_ID, "d").unwrap(), Some(d));
+    rwfs.fsync().unwrap();
+    assert_eq!(rwfs.lookup(ROOT_INODE_ID, "d").unwrap(), Some(d));
+    rwfs.unlink(ROOT_INODE_ID, "d").unwrap();
+    assert_eq!(rwfs.lookup(ROOT_INODE_ID, "d").unwrap(), None);
+    drop(rwfs);
+
+    fs::remove_dir_all(&base).unwrap();
+}
+
+#[test]
+fn rw_ke_digest_detects_swapped_data_file() {
+    use eccfs::rw::superblock::{SuperBlock, SUPERBLOCK_POS};
+    use eccfs::rw::disk::{DInodeBase, INODE_SZ};
+    use eccfs::rw::SB_FILE_NAME;
+    use eccfs::htree::{RWHashTree, mht};
+    use eccfs::crypto::crypto_out;
+
+    let base = temp_dir("ke-digest");
+    let images = ["a", "b"].map(|name| {
+        let src = base.join(format!("src_{}", name));
+        fs::create_dir(&src).unwrap();
+        fs::write(src.join("f"), name.repeat(3 * BLK_SZ)).unwrap();
+        let image = base.join(format!("rw_{}", name));
+        let mode = build_rw(&src, &image, None);
+        (image, mode)
+    });
+    let mount = |image: &Path, mode: FSMode| eccfs::rw::RWFS::new(
+        false, mode, None, 0,
+        std::sync::Arc::new(FileDevice::new(image, BLK_SZ).unwrap()),
+        &SYSTEM_TIME,
+    );
+
+    // enable digest on image a, and it's checked on mount
+    let rwfs = mount(&images[0].0, images[0].1.clone()).unwrap();
+    let f = rwfs.lookup(ROOT_INODE_ID, "f").unwrap().unwrap();
+    rwfs.enable_ke_digest().unwrap();
+    let mode = rwfs.fsync().unwrap();
+    drop(rwfs);
+    drop(mount(&images[0].0, mode).unwrap());
+
+    // (image dir, inode table, superblock) of an image
+    let open_itbl = |image: &Path| {
+        let dev = FileDevice::new(image, BLK_SZ).unwrap();
+        let sb_blk = dev.open_rw_storage(SB_FILE_NAME).unwrap().read_blk(SUPERBLOCK_POS).unwrap();
+        let sb = SuperBlock::new(&sb_blk).unwrap();
+        let itbl = RWHashTree::new(
+            None,
+            dev.open_rw_storage(&hex::encode_upper(sb.itbl_name)).unwrap(),
+            mht::Shape::full(BLK_SZ).get_logi_nr_blk(sb.itbl_len as u64),
+            Some(FSMode::from_key_entry(sb.itbl_ke, false)),
+            false,
+        );
+        (dev, itbl, sb)
+    };
+
+    // take data file of f and its key entry from image b
+    let (dev_b, mut itbl_b, _) = open_itbl(&images[1].0);
+    let mut ib_b = [0u8; INODE_SZ];
+    itbl_b.read_exact(f as usize * INODE_SZ, &mut ib_b).unwrap();
+    let ke_b = &ib_b[size_of::<DInodeBase>()..][..32];
+    let data_name = hex::encode_upper(&ib_b[size_of::<DInodeBase>() + 32..][..32]);
+    let data_b = dev_b.open_rw_storage(&data_name).unwrap();
+
+    // put them into image a, and re-sign inode table and superblock
+    let (dev_a, mut itbl_a, mut sb_a) = open_itbl(&images[0].0);
+    itbl_a.write_exact(f as usize * INODE_SZ + size_of::<DInodeBase>(), ke_b).unwrap();
+    sb_a.itbl_ke = itbl_a.flush().unwrap().into_key_entry();
+    let data_a = dev_a.open_rw_storage(&data_name).unwrap();
+    for pos in 0..data_b.get_len().unwrap() / BLK_SZ as u64 {
+        data_a.write_blk(pos, &data_b.read_blk(pos).unwrap()).unwrap();
+    }
+    let sign = |sb: &SuperBlock| {
+        let mut blk = sb.write().unwrap();
+        let mode = crypto_out(&mut blk, None, SUPERBLOCK_POS).unwrap();
+        dev_a.open_rw_storage(SB_FILE_NAME).unwrap().write_blk(SUPERBLOCK_POS, &blk).unwrap();
+        mode
+    };
+
+    let mode = sign(&sb_a);
+    assert!(matches!(mount(&images[0].0, mode), Err(FsError::IntegrityCheckError)));
+
+    // the same forgery passes without digest
+    sb_a.ke_digest = None;
+    let mode = sign(&sb_a);
+    let rwfs = mount(&images[0].0, mode).unwrap();
+    let mut buf = [0u8; 3];
+    rwfs.iread(f, 0, &mut buf).unwrap();
+    assert_eq!(&buf, b"bbb");
+    drop(rwfs);
+
+    fs::remove_dir_all(&base).unwrap();
+}
+
+#[test]
+fn icac_evict_wb_failure_keeps_inode() {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use eccfs::rw::disk::LNK_INLINE_MAX;
+
+    // fails the next storage creation once armed
+    struct FailOnce(FileDevice, AtomicBool);
+    impl Device for FailOnce {
+        fn open_rw_storage(&self, path: &str) -> FsResult<Arc<dyn RWStorage>> {
+            self.0.open_rw_storage(path)
+        }
+        fn create_rw_storage(&self, path: &str) -> FsResult<Arc<dyn RWStorage>> {
+            if self.1.swap(false, Ordering::SeqCst) {
+                return Err(FsError::IOError(std::io::ErrorKind::Interrupted.into()));
+            }
+            self.0.create_rw_storage(path)
+        }
+        fn remove_storage(&self, path: &str) -> FsResult<()> {
+            self.0.remove_storage(path)
+        }
+        fn rename_storage(&self, from: &str, to: &str) -> FsResult<()> {
+            self.0.rename_storage(from, to)
+        }
+        fn get_storage_len(&self, path: &str) -> FsResult<u64> {
+            self.0.get_storage_len(path)
+        }
+        fn nr_storage(&self) -> FsResult<usize> {
+            self.0.nr_storage()
+        }
+        fn blk_sz(&self) -> usize {
+            self.0.blk_sz()
+        }
+    }
+
+    let base = temp_dir("icac-wb-fail");
+    let src = base.join("src");
+    fs::create_dir(&src).unwrap();
+    fs::write(src.join("f"), b"f").unwrap();
+    let image = base.join("rw.image");
+    let mode = build_rw(&src, &image, None);
+    let dev = Arc::new(FailOnce(FileDevice::new(&image, BLK_SZ).unwrap(), AtomicBool::new(false)));
+    let rwfs = eccfs::rw::RWFS::new(
+        false, mode, Some(2), 0, dev.clone(), &SYSTEM_TIME,
+    ).unwrap();
+    let f = rwfs.lookup(ROOT_INODE_ID, "f").unwrap().unwrap();
+    rwfs.fsync().unwrap();
+
+    // a long symlink gets its name file created on write back,
+    // cache holds root and lnk, then f evicts root
+    let target = "t".repeat(LNK_INLINE_MAX + 1);
+    let lnk = rwfs.symlink(ROOT_INODE_ID, "lnk", &target, 0, 0).unwrap();
+    rwfs.get_meta(f).unwrap();
+    // lnk is evicted and fails to write back
+    dev.1.store(true, Ordering::SeqCst);
+    assert!(matches!(rwfs.get_meta(ROOT_INODE_ID), Err(FsError::IOError(_))));
+
+    // the evicted inode is still there and written back later
+    assert_eq!(rwfs.iread_link(lnk).unwrap(), target);
+    let mode = rwfs.fsync().unwrap();
+    drop(rwfs);
+    let rwfs = eccfs::rw::RWFS::new(
+        false, mode, Some(2), 0,
+        Arc::new(FileDevice::new(&image, BLK_SZ).unwrap()), &SYSTEM_TIME,
+    ).unwrap();
+    assert_eq!(rwfs.iread_link(lnk).unwrap(), target);
+    drop(rwfs);
+
+    fs::remove_dir_all(&base).unwrap();
+}
+
+#[test]
+fn iappend_concurrent() {
+    const NR_THREAD: usize = 8;
+    const NR_REC: usize = 50;
+    const REC_SZ: usize = 100;
+
+    let base = temp_dir("append");
+    let src = base.join("src");
+    fs::create_dir(&src).unwrap();
+    fs::write(src.join("log"), b"head").unwrap();
+    let mode = build_rw(&src, &base.join("rw.image"), None);
+    let rwfs = mount_rw(&base.join("rw.image"), mode);
+    let log = rwfs.lookup(ROOT_INODE_ID, "log").unwrap().unwrap();
+
+    let offsets: Vec<Vec<u64>> = std::thread::scope(|s| {
+        let handles: Vec<_> = (0..NR_THREAD).map(|t| {
+            let rwfs = &rwfs;
+            s.spawn(move || (0..NR_REC).map(|_| {
+                let (off, written) = rwfs.iappend(log, &[t as u8; REC_SZ]).unwrap();
+                assert_eq!(written, REC_SZ);
+                off
+            }).collect())
+        }).collect();
+        handles.into_iter().map(|h| h.join().unwrap()).collect()
+    });
+
+    let total = 4 + NR_THREAD * NR_REC * REC_SZ;
+    assert_eq!(rwfs.get_meta(log).unwrap().size, total as u64);
+    let mut buf = vec![0u8; total];
+    assert_eq!(rwfs.iread(log, 0, &mut buf).unwrap(), total);
+    assert_eq!(&buf[..4], b"head");
+    let mut all = Vec::new();
+    for (t, offs) in offsets.iter().enumerate() {
+        for off in offs {
+            let off = *off as usize;
+            assert!(buf[off..off + REC_SZ].iter().all(|b| *b == t as u8));
+            all.push(off);
+        }
+    }
+    // records are packed one after another right behind the head
+    all.sort();
+    assert!(all.iter().enumerate().all(|(i, off)| *off == 4 + i * REC_SZ));
+    drop(rwfs);
+
+    fs::remove_dir_all(&base).unwrap();
+}
+
+#[test]
+fn rw_other_layout_version_not_supported() {
+    use eccfs::rw::superblock::{DSuperBlockBase, SUPERBLOCK_POS};
+    use eccfs::crypto::crypto_out;
+
+    let base = temp_dir("rw-version");
+    let src = base.join("src");
+    fs::create_dir(&src).unwrap();
+    let image = base.join("rw.image");
+    build_rw(&src, &image, None);
+
+    // as if written by an older version, hashed anew so that only the version is off
+    let dev = std::sync::Arc::new(FileDevice::new(&image, BLK_SZ).unwrap());
+    let sb_storage = dev.open_rw_storage(eccfs::rw::SB_FILE_NAME).unwrap();
+    let mut blk = sb_storage.read_blk(SUPERBLOCK_POS).unwrap();
+    let off = std::mem::offset_of!(DSuperBlockBase, version);
+    blk[off..off + 8].copy_from_slice(&(FS_LAYOUT_VERSION as u64 - 1).to_ne_bytes());
+    let mode = crypto_out(&mut blk, None, SUPERBLOCK_POS).unwrap();
+    sb_storage.write_blk(SUPERBLOCK_POS, &blk).unwrap();
+
+    assert!(matches!(
+        eccfs::rw::RWFS::new(false, mode, None, 0, dev, &SYSTEM_TIME),
+        Err(FsError::NotSupported)
+    ));
+
+    fs::remove_dir_all(&base).unwrap();
+}
+
+#[test]
+fn rw_sync_dir_on_read() {
+    use eccfs::htree::{RWHashTree, mht};
+    use eccfs::rw::inode::iid_hash_name;
+
+    let base = temp_dir("sync-dir");
+    let src = base.join("src");
+    fs::create_dir(&src).unwrap();
+    let image = base.join("rw.image");
+    let mode = build_rw(&src, &image, Some([3u8; 16]));
+
+    let rwfs = mount_rw(&image, mode);
+    rwfs.set_sync_dir_on_read(true);
+    let perm = FilePerm::from_bits(0o644).unwrap();
+    let names: Vec<_> = (0..3).map(|i| format!("new_file_{}", i)).collect();
+    for name in names.iter() {
+        rwfs.create(ROOT_INODE_ID, name, FileType::Reg, 0, 0, perm).unwrap();
+    }
+    assert_eq!(rwfs.listdir(ROOT_INODE_ID, 0, 0).unwrap().len(), 2 + names.len());
+
+    // what listdir left on disk, before anything else syncs the root dir
+    let path = image.join(iid_hash_name(ROOT_INODE_ID).unwrap());
+    let on_read = fs::read(&path).unwrap();
+
+    // an independent fsync finds nothing more to write for the root dir,
+    // and the root mode it records verifies what listdir left
+    rwfs.fsync().unwrap();
+    assert_eq!(fs::read(&path).unwrap(), on_read);
+    let ke = rwfs.export_key_material().unwrap()[&KeyOwner::Inode(ROOT_INODE_ID)];
+    let storage = std::sync::Arc::new(MemStorage::new(BLK_SZ));
+    let nr_phy = (on_read.len() / BLK_SZ) as u64;
+    storage.set_len(nr_phy).unwrap();
+    for (pos, blk) in on_read.chunks_exact(BLK_SZ).enumerate() {
+        storage.write_blk(pos as u64, blk).unwrap();
+    }
+    let nr_blk = mht::Shape::full(BLK_SZ).get_logi_nr_blk(nr_phy);
+    let mut data = RWHashTree::new(
+        None, storage, nr_blk,
+        Some(FSMode::from_key_entry(ke, true)), true,
+    );
+    let mut buf = vec![0u8; nr_blk as usize * BLK_SZ];
+    data.read_exact(0, &mut buf).unwrap();
+    for name in names.iter() {
+        assert!(buf.windows(name.len()).any(|w| w == name.as_bytes()));
+    }
+    drop(rwfs);
+
+    fs::remove_dir_all(&base).unwrap();
+}
+
+#[test]
+fn rw_rename_replace_rollback() {
+    let base = temp_dir("rename-replace");
+    let src = base.join("src");
+    fs::create_dir(&src).unwrap();
+    fs::write(src.join("conf"), b"old").unwrap();
+    fs::write(src.join("conf.new"), b"new").unwrap();
+    let image = base.join("rw.image");
+    let mode = build_rw(&src, &image, None);
+    let mount = |mode: FSMode| mount_rw(&image, mode);
+    let read = |fs: &dyn FileSystem, iid: InodeID| {
+        let mut buf = [0u8; 3];
+        assert_eq!(fs.iread(iid, 0, &mut buf).unwrap(), 3);
+        buf
+    };
+
+    let rwfs = mount(mode);
+    let old = rwfs.lookup(ROOT_INODE_ID, "conf").unwrap().unwrap();
+    let new = rwfs.lookup(ROOT_INODE_ID, "conf.new").unwrap().unwrap();
+    let replaced = rwfs.rename_replace(ROOT_INODE_ID, "conf.new", ROOT_INODE_ID, "conf").unwrap();
+    assert_eq!(replaced, Some(old));
+    assert_eq!(rwfs.lookup(ROOT_INODE_ID, "conf").unwrap(), Some(new));
+    // replaced inode has no name but is still there, its link is dropped at once
+    assert_eq!(&read(&rwfs, old), b"old");
+    assert_eq!(rwfs.get_meta(old).unwrap().nlinks, 0);
+
+    // roll back
+    rwfs.rename(ROOT_INODE_ID, "conf", ROOT_INODE_ID, "conf.new").unwrap();
+    rwfs.link(ROOT_INODE_ID, "conf", old).unwrap();
+    rwfs.release_replaced(old).unwrap();
+    assert_eq!(rwfs.get_meta(old).unwrap().nlinks, 1);
+    assert_eq!(&read(&rwfs, rwfs.lookup(ROOT_INODE_ID, "conf").unwrap().unwrap()), b"old");
+
+    // commit
+    let replaced = rwfs.rename_replace(ROOT_INODE_ID, "conf.new", ROOT_INODE_ID, "conf").unwrap();
+    assert_eq!(replaced, Some(old));
+    rwfs.release_replaced(old).unwrap();
+    let mode = rwfs.destroy().unwrap();
+    drop(rwfs);
+
+    let rwfs = mount(mode);
+    let names: Vec<_> = rwfs.listdir(ROOT_INODE_ID, 0, 0).unwrap()
+        .into_iter().map(|(_, name, _)| name).collect();
+    assert_eq!(names.len(), 3);
+    assert!(names.contains(&"conf".to_string()));
+    let new = rwfs.lookup(ROOT_INODE_ID, "conf").unwrap().unwrap();
+    assert_eq!(&read(&rwfs, new), b"new");
+    assert!(matches!(rwfs.release_replaced(old), Err(FsError::InvalidParameter)));
+
+    // a replaced inode never released is not left behind after remount
+    rwfs.create_with_data(ROOT_INODE_ID, "conf.new", b"nxt", 0, 0, FilePerm::from_bits(0o644).unwrap()).unwrap();
+    let replaced = rwfs.rename_replace(ROOT_INODE_ID, "conf.new", ROOT_INODE_ID, "conf").unwrap();
+    assert_eq!(replaced, Some(new));
+    let mode = rwfs.fsync().unwrap();
+    drop(rwfs);
+    let rwfs = mount(mode);
+    assert!(rwfs.get_meta(new).is_err());
+    assert!(rwfs.check().unwrap().is_clean());
+    assert_eq!(&read(&rwfs, rwfs.lookup(ROOT_INODE_ID, "conf").unwrap().unwrap()), b"nxt");
+    drop(rwfs);
+
+    fs::remove_dir_all(&base).unwrap();
+}
+
+#[test]
+fn rw_get_meta_keeps_atime() {
+    let base = temp_dir("get-meta-atime");
+    let src = base.join("src");
+    fs::create_dir(&src).unwrap();
+    fs::write(src.join("f"), b"data").unwrap();
+    let image = base.join("rw.image");
+
+    let mode = build_rw(&src, &image, None);
+    let rwfs = eccfs::rw::RWFS::new(
+        false, mode, None, 0,
+        std::sync::Arc::new(FileDevice::new(&image, BLK_SZ).unwrap()),
+        &TICK_TIME,
+    ).unwrap();
+    let f = rwfs.lookup(ROOT_INODE_ID, "f").unwrap().unwrap();
+    let before = rwfs.get_meta(f).unwrap();
+    let synced = rwfs.fsync().unwrap();
+
+    // nothing dirty, write back leaves the image as it was
+    let meta = rwfs.get_meta(f).unwrap();
+    assert_eq!(meta.atime, before.atime);
+    assert_eq!(rwfs.fsync().unwrap(), synced);
+
+    rwfs.touch_atime(f).unwrap();
+    assert_ne!(rwfs.get_meta(f).unwrap().atime, before.atime);
+    assert_ne!(rwfs.fsync().unwrap(), synced);
+    drop(rwfs);
+
+    fs::remove_dir_all(&base).unwrap();
+}
+
+#[test]
+fn rw_large_inode_round_trip() {
+    use eccfs::rw::disk::{INODE_SZ, REG_INLINE_DATA_MAX, LNK_INLINE_MAX};
+
+    let base = temp_dir("large-inode");
+    let src = base.join("src");
+    fs::create_dir(&src).unwrap();
+    let data = vec![7u8; REG_INLINE_DATA_MAX * 4];
+    fs::write(src.join("f"), &data).unwrap();
+    let target = "t".repeat(LNK_INLINE_MAX * 4);
+    std::os::unix::fs::symlink(&target, src.join("lnk")).unwrap();
+    let image = base.join("rw.image");
+
+    let mode = eccfs_builder::rw::build_from_dir_with_inode_sz(&src, &image, None, INODE_SZ * 8).unwrap();
+    // only sb, itbl and root dir, the rest is inline
+    assert_eq!(fs::read_dir(&image).unwrap().count(), 3);
+
+    let mount = |mode| mount_rw(&image, mode);
+    let rwfs = mount(mode);
+    let f = rwfs.lookup(ROOT_INODE_ID, "f").unwrap().unwrap();
+    let lnk = rwfs.lookup(ROOT_INODE_ID, "lnk").unwrap().unwrap();
+    assert!(rwfs.is_inline(f).unwrap() && rwfs.is_inline(lnk).unwrap());
+    let mut buf = vec![0u8; data.len()];
+    assert_eq!(rwfs.iread(f, 0, &mut buf).unwrap(), data.len());
+    assert_eq!(buf, data);
+    assert_eq!(rwfs.iread_link(lnk).unwrap(), target);
+
+    // new inodes get the image's size too
+    let g = rwfs.create(
+        ROOT_INODE_ID, "g", FileType::Reg, 0, 0, FilePerm::from_bits(0o644).unwrap(),
+    ).unwrap();
+    rwfs.iwrite(g, 0, &data).unwrap();
+    let mode = rwfs.fsync().unwrap();
+    drop(rwfs);
+
+    let rwfs = mount(mode);
+    assert!(rwfs.is_inline(g).unwrap());
+    assert_eq!(rwfs.iread(g, 0, &mut buf).unwrap(), data.len());
+    assert_eq!(buf, data);
+    assert_eq!(fs::read_dir(&image).unwrap().count(), 3);
+    drop(rwfs);
+
+    fs::remove_dir_all(&base).unwrap();
+}
+
+#[test]
+fn rw_block_bitmap_alloc_free() {
+    use std::collections::BTreeSet;
+
+    let base = temp_dir("block-bitmap");
+    let src = base.join("src");
+    fs::create_dir(&src).unwrap();
+    let image = base.join("rw.image");
+    let mode = build_rw(&src, &image, None);
+    let mount = |mode| mount_rw(&image, mode);
+
+    let rwfs = mount(mode);
+    assert_eq!(rwfs.nr_used_block(), 0);
+    let bfree = rwfs.finfo().unwrap().bfree;
+    // more than one bitmap block
+    let nr = BLK_SZ * 8 + 100;
+    let mut used = BTreeSet::new();
+    for _ in 0..nr {
+        assert!(used.insert(rwfs.alloc_block().unwrap()));
+    }
+    for pos in [3, 10, 4000, BLK_SZ as u64 * 8 + 7] {
+        rwfs.free_block(pos).unwrap();
+        used.remove(&pos);
+    }
+    assert!(matches!(rwfs.free_block(3), Err(FsError::NotFound)));
+    let mode = rwfs.fsync().unwrap();
+    drop(rwfs);
+
+    // allocation survives remount, freed blocks are reused first
+    let rwfs = mount(mode);
+    assert_eq!(rwfs.nr_used_block(), used.len());
+    // the region is not backed by storage, so it's not counted in
+    assert_eq!(rwfs.finfo().unwrap().bfree, bfree);
+    let mut again = BTreeSet::new();
+    for _ in 0..5 {
+        let pos = rwfs.alloc_block().unwrap();
+        assert!(!used.contains(&pos) && again.insert(pos));
+    }
+    assert_eq!(
+        again.into_iter().collect::<Vec<_>>(),
+        vec![3, 10, 4000, BLK_SZ as u64 * 8 + 7, nr as u64],
+    );
+    drop(rwfs);
+
+    fs::remove_dir_all(&base).unwrap();
+}
+
+// changes touching inline and htree data, dirs, links and removal
+fn crash_workload(rwfs: &dyn FileSystem) {
+    let perm = FilePerm::from_bits(0o644).unwrap();
+    let big = rwfs.create(ROOT_INODE_ID, "big", FileType::Reg, 0, 0, perm).unwrap();
+    rwfs.iwrite(big, 0, &vec![5u8; BLK_SZ * 3 + 5]).unwrap();
+    let f = rwfs.lookup(ROOT_INODE_ID, "f").unwrap().unwrap();
+    rwfs.iwrite(f, BLK_SZ, b"overwritten").unwrap();
+    let d = rwfs.create(ROOT_INODE_ID, "d", FileType::Dir, 0, 0, perm).unwrap();
+    let x = rwfs.create(d, "x", FileType::Reg, 0, 0, perm).unwrap();
+    rwfs.iwrite(x, 0, b"inline").unwrap();
+    rwfs.symlink(d, "lnk", &"t".repeat(200), 0, 0).unwrap();
+    rwfs.unlink(ROOT_INODE_ID, "gone").unwrap();
+}
+
+// mount an image as it's left on disk, None if it's found broken,
+// which panics in debug builds
+fn remount(
+    image: &std::path::Path, mode: FSMode,
+) -> Option<(std::collections::BTreeMap<String, Vec<u8>>, bool)> {
+    std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        let rwfs = eccfs::rw::RWFS::new(
+            false, mode, None, 0,
+            std::sync::Arc::new(FileDevice::new(image, BLK_SZ).unwrap()),
+            &SYSTEM_TIME,
+        ).ok()?;
+        let tree = read_tree(&rwfs);
+        let clean = rwfs.check().ok()?.is_clean();
+        Some((tree, clean))
+    })).ok().flatten()
+}
+
+// fail the sync op at every write it makes, it must fail cleanly,
+// and succeed once the device heals, leaving a consistent image,
+// and when the device never comes back, or drops all writes from then on,
+// the image left is either the old one, the new one, or found broken
+fn crash_at_every_write(name: &str, sync: fn(&eccfs::rw::RWFS) -> FsResult<FSMode>) {
+    let base = temp_dir(name);
+    let src = base.join("src");
+    fs::create_dir(&src).unwrap();
+    fs::write(src.join("f"), vec![1u8; BLK_SZ * 2]).unwrap();
+    fs::write(src.join("gone"), vec![2u8; BLK_SZ * 2]).unwrap();
+    let image0 = base.join("rw.image0");
+    let mode0 = build_rw(&src, &image0, None);
+    let old = read_tree(&mount_rw(&image0, mode0.clone()));
+
+    let open = |image: &std::path::Path| {
+        copy_image(&image0, image);
+        let dev = std::sync::Arc::new(FailDevice::new(image));
+        let rwfs = eccfs::rw::RWFS::new(
+            false, mode0.clone(), None, 0, dev.clone(), &SYSTEM_TIME,
+        ).unwrap();
+        crash_workload(&rwfs);
+        let tree = read_tree(&rwfs);
+        (dev, rwfs, tree)
+    };
+    // what is left after a sync that did not finish
+    let check_left = |image: &std::path::Path, mode: Option<FSMode>, new| {
+        let left = match mode {
+            Some(mode) => remount(image, mode).map(|(tree, clean)| (tree == new, clean)),
+            None => remount(image, mode0.clone()).map(|(tree, clean)| (tree == old, clean)),
+        };
+        if let Some((same, clean)) = left {
+            assert!(same && clean);
+        }
+        left.is_some()
+    };
+
+    let mut expected = None;
+    for nr_write in 0.. {
+        assert!(nr_write < 1000, "sync never finishes");
+        let image = base.join(format!("rw.image{}", nr_write + 1));
+
+        let (dev, rwfs, tree) = open(&image);
+        dev.1.fail_after(nr_write);
+        let done = sync(&rwfs);
+        dev.1.heal();
+        let finished = done.is_ok();
+        let mode = match done {
+            Ok(mode) => mode,
+            Err(e) => {
+                assert!(matches!(e, FsError::IOError(_)));
+                sync(&rwfs).unwrap()
+            }
+        };
+        drop(rwfs);
+
+        let rwfs = mount_rw(&image, mode);
+        assert_eq!(read_tree(&rwfs), tree);
+        expected.get_or_insert(tree.clone());
+        assert_eq!(expected.as_ref(), Some(&tree));
+        drop(rwfs);
+        fs::remove_dir_all(&image).unwrap();
+
+        // crash, the device is gone with the fs
+        let (dev, rwfs, tree) = open(&image);
+        dev.1.fail_after(nr_write);
+        let done = sync(&rwfs).ok();
+        drop(rwfs);
+        let synced = done.is_some();
+        assert!(check_left(&image, done, tree) || !synced);
+        fs::remove_dir_all(&image).unwrap();
+
+        // power loss, writes succeed but are not kept
+        let (dev, rwfs, tree) = open(&image);
+        dev.1.stop_after(nr_write);
+        let done = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| sync(&rwfs)));
+        let kept = dev.1.left() > 0;
+        drop(rwfs);
+        let done = done.ok().and_then(|done| done.ok());
+        assert!(check_left(&image, done, tree) || !kept);
+        fs::remove_dir_all(&image).unwrap();
+
+        // the budget is left with some, so nothing is lost
+        if kept {
+            assert!(finished);
+            break;
+        }
+    }
+
+    fs::remove_dir_all(&base).unwrap();
+}
+
+#[test]
+fn crash_in_fsync_is_retryable() {
+    crash_at_every_write("crash-fsync", |rwfs| rwfs.fsync());
+}
+
+#[test]
+fn crash_in_destroy_is_retryable() {
+    crash_at_every_write("crash-destroy", |rwfs| rwfs.destroy());
+}
+
+#[test]
+fn rw_inode_flags_enforced() {
+    let base = temp_dir("inode-flags");
+    let src = base.join("src");
+    fs::create_dir(&src).unwrap();
+    fs::write(src.join("f"), b"data").unwrap();
+    fs::write(src.join("log"), b"data").unwrap();
+    let image = base.join("rw.image");
+
+    let mode = build_rw(&src, &image, None);
+    let rwfs = mount_rw(&image, mode);
+    let f = rwfs.lookup(ROOT_INODE_ID, "f").unwrap().unwrap();
+    let log = rwfs.lookup(ROOT_INODE_ID, "log").unwrap().unwrap();
+    assert_eq!(rwfs.get_flags(f).unwrap(), InodeFlags::empty());
+
+    rwfs.set_flags(f, InodeFlags::IMMUTABLE | InodeFlags::NO_DUMP).unwrap();
+    rwfs.set_flags(log, InodeFlags::APPEND_ONLY).unwrap();
+    let mode = rwfs.destroy().unwrap();
+    drop(rwfs);
+
+    let rwfs = mount_rw(&image, mode);
+    assert_eq!(rwfs.get_flags(f).unwrap(), InodeFlags::IMMUTABLE | InodeFlags::NO_DUMP);
+    assert_eq!(rwfs.get_flags(log).unwrap(), InodeFlags::APPEND_ONLY);
+
+    let denied = |r: FsResult<()>| assert!(matches!(r, Err(FsError::PermissionDenied)));
+    denied(rwfs.iwrite(f, 0, b"x").map(|_| ()));
+    denied(rwfs.iappend(f, b"x").map(|_| ()));
+    denied(rwfs.set_meta(f, SetMetadata::Size(0)));
+    denied(rwfs.set_meta(f, SetMetadata::Uid(1)));
+    denied(rwfs.unlink(ROOT_INODE_ID, "f"));
+    denied(rwfs.rename(ROOT_INODE_ID, "f", ROOT_INODE_ID, "g"));
+    let mut buf = [0u8; 4];
+    assert_eq!(rwfs.iread(f, 0, &mut buf).unwrap(), 4);
+    assert_eq!(&buf, b"data");
+
+    denied(rwfs.iwrite(log, 0, b"x").map(|_| ()));
+    denied(rwfs.set_meta(log, SetMetadata::Size(0)));
+    denied(rwfs.unlink(ROOT_INODE_ID, "log"));
+    assert_eq!(rwfs.iwrite(log, 4, b"more").unwrap(), 4);
+    assert_eq!(rwfs.iappend(log, b"!").unwrap(), (8, 1));
+
+    // clearing the flag lifts it
+    rwfs.set_flags(f, InodeFlags::empty()).unwrap();
+    assert_eq!(rwfs.iwrite(f, 0, b"x").unwrap(), 1);
+    rwfs.unlink(ROOT_INODE_ID, "f").unwrap();
+    drop(rwfs);
+
+    fs::remove_dir_all(&base).unwrap();
+}
+
+#[test]
+fn rename_over_empty_dir_races_create() {
+    let base = temp_dir("rename-race");
+    let src = base.join("src");
+    fs::create_dir(&src).unwrap();
+    let image = base.join("rw.image");
+
+    let mode = build_rw(&src, &image, None);
+    let rwfs = mount_rw(&image, mode);
+    let perm = FilePerm::from_bits(0o755).unwrap();
+    for _ in 0..200 {
+        let target = rwfs.create(ROOT_INODE_ID, "target", FileType::Dir, 0, 0, perm).unwrap();
+        let moved = rwfs.create(ROOT_INODE_ID, "src", FileType::Dir, 0, 0, perm).unwrap();
+        let (renamed, created) = std::thread::scope(|s| {
+            let h = s.spawn(|| rwfs.rename(ROOT_INODE_ID, "src", ROOT_INODE_ID, "target"));
+            let created = rwfs.create(target, "child", FileType::Reg, 0, 0, perm);
+            (h.join().unwrap(), created)
+        });
+        // either the dir is replaced before the child lands, or the child stays
+        match (renamed, created) {
+            (Ok(()), Err(FsError::NotFound)) => {
+                assert_eq!(rwfs.lookup(ROOT_INODE_ID, "target").unwrap(), Some(moved));
+                assert_eq!(rwfs.lookup(ROOT_INODE_ID, "src").unwrap(), None);
+                rwfs.unlink(ROOT_INODE_ID, "target").unwrap();
+            }
+            (Err(FsError::DirectoryNotEmpty), Ok(child)) => {
+                assert_eq!(rwfs.lookup(ROOT_INODE_ID, "target").unwrap(), Some(target));
+                assert_eq!(rwfs.lookup(target, "child").unwrap(), Some(child));
+                rwfs.unlink(target, "child").unwrap();
+                rwfs.unlink(ROOT_INODE_ID, "target").unwrap();
+                rwfs.unlink(ROOT_INODE_ID, "src").unwrap();
+            }
+            (r, c) => panic!("rename {:?}, create {:?}", r, c),
+        }
+    }
+    rwfs.destroy().unwrap();
+}
+
+#[test]
+fn rename_checks_target_type() {
+    let base = temp_dir("rename-type");
+    let src = base.join("src");
+    fs::create_dir_all(src.join("e")).unwrap();
+    fs::create_dir_all(src.join("p/s")).unwrap();
+    fs::create_dir_all(src.join("q/t")).unwrap();
+    fs::write(src.join("f"), b"data").unwrap();
+    let image = base.join("rw.image");
+
+    let mode = build_rw(&src, &image, None);
+    let rwfs = mount_rw(&image, mode);
+    let perm = FilePerm::from_bits(0o644).unwrap();
+    assert!(matches!(rwfs.rename(ROOT_INODE_ID, "f", ROOT_INODE_ID, "e"), Err(FsError::IsADirectory)));
+    assert!(matches!(rwfs.rename(ROOT_INODE_ID, "e", ROOT_INODE_ID, "f"), Err(FsError::NotADirectory)));
+    let tree = read_tree(&rwfs);
+    assert_eq!(tree["f"], b"data");
+
+    // a dir target is put back if src can't be moved
+    let p = rwfs.lookup(ROOT_INODE_ID, "p").unwrap().unwrap();
+    let q = rwfs.lookup(ROOT_INODE_ID, "q").unwrap().unwrap();
+    let t = rwfs.lookup(q, "t").unwrap().unwrap();
+    rwfs.set_flags(p, InodeFlags::IMMUTABLE).unwrap();
+    assert!(matches!(rwfs.rename(p, "s", q, "t"), Err(FsError::PermissionDenied)));
+    assert_eq!(rwfs.lookup(q, "t").unwrap(), Some(t));
+    rwfs.create(t, "x", FileType::Reg, 0, 0, perm).unwrap();
+    assert!(matches!(rwfs.rename(ROOT_INODE_ID, "e", q, "t"), Err(FsError::DirectoryNotEmpty)));
+    rwfs.unlink(t, "x").unwrap();
+
+    rwfs.set_flags(p, InodeFlags::empty()).unwrap();
+    let s = rwfs.lookup(p, "s").unwrap().unwrap();
+    rwfs.rename(p, "s", q, "t").unwrap();
+    assert_eq!(rwfs.lookup(q, "t").unwrap(), Some(s));
+    assert!(rwfs.check().unwrap().is_clean());
+    drop(rwfs);
+
+    fs::remove_dir_all(&base).unwrap();
+}
+
+#[test]
+fn rw_dir_flags_enforced() {
+    let base = temp_dir("dir-flags");
+    let src = base.join("src");
+    fs::create_dir_all(src.join("d")).unwrap();
+    fs::create_dir_all(src.join("a")).unwrap();
+    fs::write(src.join("d/f"), b"data").unwrap();
+    fs::write(src.join("a/g"), b"data").unwrap();
+    fs::write(src.join("x"), b"data").unwrap();
+    let image = base.join("rw.image");
+
+    let mode = build_rw(&src, &image, None);
+    let rwfs = mount_rw(&image, mode);
+    let perm = FilePerm::from_bits(0o644).unwrap();
+    let d = rwfs.lookup(ROOT_INODE_ID, "d").unwrap().unwrap();
+    let a = rwfs.lookup(ROOT_INODE_ID, "a").unwrap().unwrap();
+    let x = rwfs.lookup(ROOT_INODE_ID, "x").unwrap().unwrap();
+    rwfs.set_flags(d, InodeFlags::IMMUTABLE).unwrap();
+    rwfs.set_flags(a, InodeFlags::APPEND_ONLY).unwrap();
+
+    let denied = |r: FsResult<()>| assert!(matches!(r, Err(FsError::PermissionDenied)));
+    // an immutable dir takes no change of entries
+    denied(rwfs.create(d, "new", FileType::Reg, 0, 0, perm).map(|_| ()));
+    denied(rwfs.symlink(d, "lnk", "f", 0, 0).map(|_| ()));
+    denied(rwfs.link(d, "x", x));
+    denied(rwfs.unlink(d, "f"));
+    denied(rwfs.rename(d, "f", d, "f2"));
+    denied(rwfs.rename(d, "f", ROOT_INODE_ID, "f"));
+    denied(rwfs.rename(ROOT_INODE_ID, "x", d, "f"));
+    assert_eq!(rwfs.listdir(d, 0, 0).unwrap().len(), 3);
+    assert!(rwfs.lookup(ROOT_INODE_ID, "x").unwrap().is_some());
+
+    // an append-only dir only takes new entries
+    rwfs.create(a, "new", FileType::Reg, 0, 0, perm).unwrap();
+    rwfs.rename(ROOT_INODE_ID, "x", a, "x").unwrap();
+    denied(rwfs.unlink(a, "g"));
+    denied(rwfs.rename(a, "g", a, "g2"));
+    denied(rwfs.rename(a, "g", ROOT_INODE_ID, "g"));
+    assert_eq!(rwfs.listdir(a, 0, 0).unwrap().len(), 5);
+
+    // nor is a flagged inode linked
+    let g = rwfs.lookup(a, "g").unwrap().unwrap();
+    rwfs.set_flags(g, InodeFlags::APPEND_ONLY).unwrap();
+    denied(rwfs.link(ROOT_INODE_ID, "g", g));
+    assert_eq!(rwfs.get_meta(g).unwrap().nlinks, 1);
+
+    rwfs.set_flags(d, InodeFlags::empty()).unwrap();
+    rwfs.unlink(d, "f").unwrap();
+    drop(rwfs);
+
+    fs::remove_dir_all(&base).unwrap();
+}
+
+#[test]
+fn rename_across_dirs_both_ways() {
+    let base = temp_dir("rename-cross");
+    let src = base.join("src");
+    fs::create_dir_all(src.join("p/c")).unwrap();
+    fs::write(src.join("p/x"), b"x").unwrap();
+    fs::write(src.join("p/c/y"), b"y").unwrap();
+    fs::write(src.join("p/c/z"), b"z").unwrap();
+    let image = base.join("rw.image");
+
+    let mode = build_rw(&src, &image, None);
+    let rwfs = mount_rw(&image, mode);
+    let p = rwfs.lookup(ROOT_INODE_ID, "p").unwrap().unwrap();
+    let c = rwfs.lookup(p, "c").unwrap().unwrap();
+    // moves go both ways between a dir and its child at once, none of them waits
+    // on another for good, whichever dir it locks first
+    std::thread::scope(|s| {
+        s.spawn(|| for _ in 0..200 {
+            rwfs.rename(p, "x", c, "x").unwrap();
+            rwfs.rename(c, "x", p, "x").unwrap();
+        });
+        s.spawn(|| for _ in 0..200 {
+            rwfs.rename(c, "y", p, "y").unwrap();
+            rwfs.rename(p, "y", c, "y").unwrap();
+        });
+        s.spawn(|| for _ in 0..200 {
+            assert!(matches!(rwfs.unlink(p, "c"), Err(FsError::DirectoryNotEmpty)));
+        });
+    });
+    assert!(rwfs.lookup(p, "x").unwrap().is_some());
+    assert!(rwfs.lookup(c, "y").unwrap().is_some());
+    // nor is a dir moved onto its own ancestor
+    assert!(matches!(rwfs.rename(c, "y", ROOT_INODE_ID, "p"), Err(FsError::DirectoryNotEmpty)));
+    assert!(rwfs.check().unwrap().is_clean());
+    drop(rwfs);
+
+    fs::remove_dir_all(&base).unwrap();
+}
+
+#[test]
+fn rw_unlink_busy_or_not_empty() {
+    let base = temp_dir("unlink-busy");
+    let src = base.join("src");
+    fs::create_dir_all(src.join("d")).unwrap();
+    fs::write(src.join("d/f"), b"f").unwrap();
+    fs::write(src.join("g"), b"g").unwrap();
+    let image = base.join("rw.image");
+
+    let mode = build_rw(&src, &image, None);
+    let rwfs = mount_rw(&image, mode);
+    let d = rwfs.lookup(ROOT_INODE_ID, "d").unwrap().unwrap();
+    assert!(matches!(rwfs.unlink(ROOT_INODE_ID, "d"), Err(FsError::DirectoryNotEmpty)));
+    assert!(matches!(rwfs.unlink(d, ".."), Err(FsError::InvalidParameter)));
+    rwfs.unlink(ROOT_INODE_ID, "g").unwrap();
+
+    rwfs.unlink(d, "f").unwrap();
+    rwfs.unlink(ROOT_INODE_ID, "d").unwrap();
+    let mode = rwfs.destroy().unwrap();
+    drop(rwfs);
+    let rwfs = mount_rw(&image, mode);
+    assert!(rwfs.check().unwrap().is_clean());
+    drop(rwfs);
+
+    fs::remove_dir_all(&base).unwrap();
+}
+
+#[test]
+fn rw_checkpoint_detects_change() {
+    let base = temp_dir("checkpoint");
+    let src = base.join("src");
+    fs::create_dir(&src).unwrap();
+    fs::write(src.join("f"), b"before").unwrap();
+    let image = base.join("rw.image");
+
+    let mode = build_rw(&src, &image, None);
+    let device = || std::sync::Arc::new(FileDevice::new(&image, BLK_SZ).unwrap());
+    let rwfs = eccfs::rw::RWFS::new(false, mode, None, 0, device(), &SYSTEM_TIME).unwrap();
+    let old = rwfs.checkpoint().unwrap();
+    // nothing changed in between
+    assert_eq!(rwfs.checkpoint().unwrap(), old);
+    assert_eq!(old.block_count, rwfs.finfo().unwrap().blocks as u64);
+    rwfs.destroy().unwrap();
+    drop(rwfs);
+
+    let rwfs = eccfs::rw::RWFS::open_checkpoint(&old, None, 0, device(), &SYSTEM_TIME).unwrap();
+    let f = rwfs.lookup(ROOT_INODE_ID, "f").unwrap().unwrap();
+    rwfs.iwrite(f, 0, b"after!").unwrap();
+    let new = rwfs.checkpoint().unwrap();
+    assert_ne!(new.digest, old.digest);
+    rwfs.destroy().unwrap();
+    drop(rwfs);
+
+    // the old digest does not match what is on disk now
+    let stale = eccfs::rw::Checkpoint { mode: new.mode.clone(), ..old };
+    assert!(matches!(
+        eccfs::rw::RWFS::open_checkpoint(&stale, None, 0, device(), &SYSTEM_TIME),
+        Err(FsError::IntegrityCheckError)
+    ));
+    let rwfs = eccfs::rw::RWFS::open_checkpoint(&new, None, 0, device(), &SYSTEM_TIME).unwrap();
+    let mut buf = [0u8; 6];
+    assert_eq!(rwfs.iread(f, 0, &mut buf).unwrap(), 6);
+    assert_eq!(&buf, b"after!");
+    drop(rwfs);
+
+    fs::remove_dir_all(&base).unwrap();
+}
+
+#[test]
+fn rw_verify_detects_rolled_back_data_file() {
+    let base = temp_dir("verify_against");
+    let src = base.join("src");
+    fs::create_dir(&src).unwrap();
+    fs::write(src.join("f"), vec![1u8; 2 * BLK_SZ]).unwrap();
+    let image = base.join("rw.image");
+
+    let mode = build_rw(&src, &image, None);
+    let device = || std::sync::Arc::new(FileDevice::new(&image, BLK_SZ).unwrap());
+    let rwfs = eccfs::rw::RWFS::new(false, mode, None, 0, device(), &SYSTEM_TIME).unwrap();
+    let f = rwfs.lookup(ROOT_INODE_ID, "f").unwrap().unwrap();
+    let data_file = image.join(&rwfs.inode_storages(f).unwrap()[0]);
+    rwfs.checkpoint().unwrap();
+    let old = fs::read(&data_file).unwrap();
+
+    rwfs.iwrite(f, 0, &[2u8; BLK_SZ]).unwrap();
+    let cp = rwfs.checkpoint().unwrap();
+    assert!(rwfs.verify_against(&cp).unwrap());
+    rwfs.destroy().unwrap();
+    drop(rwfs);
+
+    // an older data file is valid on its own and the digest only covers the inode table
+    fs::write(&data_file, &old).unwrap();
+    let rwfs = eccfs::rw::RWFS::open_checkpoint(&cp, None, 0, device(), &SYSTEM_TIME).unwrap();
+    assert!(!rwfs.verify_against(&cp).unwrap());
+    drop(rwfs);
+
+    fs::remove_dir_all(&base).unwrap();
+}
+
+#[test]
+fn rw_collapse_and_insert_range() {
+    let base = temp_dir("shift_range");
+    let src = base.join("src");
+    fs::create_dir(&src).unwrap();
+    // 8 blocks, each filled with its own index, plus a short tail
+    let mut content: Vec<u8> = (0..8u8).flat_map(|i| vec![i + 1; BLK_SZ]).collect();
+    content.extend_from_slice(b"tail");
+    fs::write(src.join("f"), &content).unwrap();
+    let image = base.join("rw.image");
+
+    let mode = build_rw(&src, &image, None);
+    let device = || std::sync::Arc::new(FileDevice::new(&image, BLK_SZ).unwrap());
+    let rwfs = eccfs::rw::RWFS::new(false, mode, None, 0, device(), &SYSTEM_TIME).unwrap();
+    let f = rwfs.lookup(ROOT_INODE_ID, "f").unwrap().unwrap();
+
+    assert!(matches!(
+        rwfs.fallocate(f, FallocateMode::CollapseRange, 100, BLK_SZ),
+        Err(FsError::InvalidParameter)
+    ));
+    assert!(matches!(
+        rwfs.fallocate(f, FallocateMode::InsertRange, content.len() + 10, BLK_SZ),
+        Err(FsError::InvalidParameter)
+    ));
+
+    rwfs.fallocate(f, FallocateMode::CollapseRange, 2 * BLK_SZ, 3 * BLK_SZ).unwrap();
+    content.drain(2 * BLK_SZ..5 * BLK_SZ);
+    assert_eq!(rwfs.get_meta(f).unwrap().blocks, 6);
+    // holes move as holes, and inserted blocks are holes
+    rwfs.fallocate(f, FallocateMode::PunchHole, 3 * BLK_SZ, BLK_SZ).unwrap();
+    content[3 * BLK_SZ..4 * BLK_SZ].fill(0);
+    rwfs.fallocate(f, FallocateMode::InsertRange, BLK_SZ, 2 * BLK_SZ).unwrap();
+    content.splice(BLK_SZ..BLK_SZ, vec![0u8; 2 * BLK_SZ]);
+    assert_eq!(rwfs.get_meta(f).unwrap().blocks, 5);
+    let mode = rwfs.destroy().unwrap();
+    drop(rwfs);
+
+    let rwfs = eccfs::rw::RWFS::new(false, mode, None, 0, device(), &SYSTEM_TIME).unwrap();
+    assert_eq!(rwfs.get_meta(f).unwrap().size as usize, content.len());
+    assert_eq!(rwfs.get_meta(f).unwrap().blocks, 5);
+    assert!(rwfs.check().unwrap().is_clean());
+    let mut buf = vec![0u8; content.len()];
+    assert_eq!(rwfs.iread(f, 0, &mut buf).unwrap(), content.len());
+    assert!(buf == content);
+    drop(rwfs);
+
+    fs::remove_dir_all(&base).unwrap();
+}
+
+#[test]
+fn rw_create_with_data() {
+    let base = temp_dir("rw_create_with_data");
+    let src = base.join("src");
+    fs::create_dir(&src).unwrap();
+    let image = base.join("rw.image");
+    let mode = build_rw(&src, &image, None);
+    let dev = std::sync::Arc::new(FailDevice::new(&image));
+    let rwfs = eccfs::rw::RWFS::new(
+        false, mode, None, 0, dev.clone(), &SYSTEM_TIME,
+    ).unwrap();
+    let perm = FilePerm::from_bits(0o644).unwrap();
+
+    let small = rwfs.create_with_data(ROOT_INODE_ID, "small", b"key = 1", 0, 0, perm).unwrap();
+    assert!(rwfs.is_inline(small).unwrap());
+    let big_data = vec![7u8; BLK_SZ * 3 + 1];
+    let big = rwfs.create_with_data(ROOT_INODE_ID, "big", &big_data, 0, 0, perm).unwrap();
+    assert!(!rwfs.is_inline(big).unwrap());
+
+    // a failed write leaves neither the name nor the inode or its storage
+    let files = rwfs.finfo().unwrap().files;
+    let nr_storage = dev.nr_storage().unwrap();
+    dev.1.fail_after(0);
+    assert!(matches!(
+        rwfs.create_with_data(ROOT_INODE_ID, "bad", &big_data, 0, 0, perm),
+        Err(FsError::IOError(_))
+    ));
+    dev.1.heal();
+    assert_eq!(rwfs.lookup(ROOT_INODE_ID, "bad").unwrap(), None);
+    assert_eq!(rwfs.finfo().unwrap().files, files);
+    assert_eq!(dev.nr_storage().unwrap(), nr_storage);
+
+    let mode = rwfs.fsync().unwrap();
+    drop(rwfs);
+    let rwfs = mount_rw(&image, mode);
+    let tree = read_tree(&rwfs);
+    assert_eq!(tree.get("small").map(|v| v.as_slice()), Some(&b"key = 1"[..]));
+    assert_eq!(tree.get("big"), Some(&big_data));
+    assert!(!tree.contains_key("bad"));
+
+    fs::remove_dir_all(&base).unwrap();
+}
+
+#[test]
+fn rw_inode_storages() {
+    let base = temp_dir("rw_inode_storages");
+    let src = base.join("src");
+    fs::create_dir_all(src.join("d")).unwrap();
+    fs::write(src.join("small"), b"tiny").unwrap();
+    fs::write(src.join("d/big"), vec![3u8; BLK_SZ * 2]).unwrap();
+    let image = base.join("rw.image");
+    let mode = build_rw(&src, &image, None);
+    let rwfs = mount_rw(&image, mode);
+    let small = rwfs.lookup(ROOT_INODE_ID, "small").unwrap().unwrap();
+    assert!(rwfs.inode_storages(small).unwrap().is_empty());
+    let short = rwfs.symlink(ROOT_INODE_ID, "short", "small", 0, 0).unwrap();
+    assert!(rwfs.inode_storages(short).unwrap().is_empty());
+    // a long link gets its name file when it's synced
+    let long = rwfs.symlink(ROOT_INODE_ID, "long", &"t".repeat(300), 0, 0).unwrap();
+    rwfs.fsync().unwrap();
+    assert_eq!(rwfs.inode_storages(long).unwrap().len(), 1);
+
+    let mut owned = std::collections::BTreeSet::new();
+    rwfs.walk(ROOT_INODE_ID, &mut |e| {
+        for name in rwfs.inode_storages(e.iid)? {
+            assert!(owned.insert(name));
+        }
+        Ok(WalkControl::Continue)
+    }).unwrap();
+    // root, d, d/big and the long link
+    assert_eq!(owned.len(), 4);
+
+    // the rest on device are the superblock and inode table files
+    let on_device: std::collections::BTreeSet<_> = fs::read_dir(&image).unwrap()
+        .map(|e| e.unwrap().file_name().into_string().unwrap())
+        .collect();
+    assert!(owned.is_subset(&on_device));
+    assert_eq!(on_device.len(), owned.len() + 2);
+
+    fs::remove_dir_all(&base).unwrap();
+}
+
+#[test]
+fn rw_bulk_write() {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::time::Duration;
+
+    let base = temp_dir("rw_bulk_write");
+    let src = base.join("src");
+    fs::create_dir(&src).unwrap();
+    let image = base.join("rw.image");
+    let mode = build_rw(&src, &image, None);
+    let rwfs = mount_rw(&image, mode);
+    let perm = FilePerm::from_bits(0o644).unwrap();
+    let chunks: Vec<Vec<u8>> = (0..2048u32).map(|i| vec![(i % 251) as u8; 300]).collect();
+
+    let naive = rwfs.create(ROOT_INODE_ID, "naive", FileType::Reg, 0, 0, perm).unwrap();
+    let mut off = 0;
+    for c in chunks.iter() {
+        off += rwfs.iwrite(naive, off, c).unwrap();
+    }
+
+    let bulk = rwfs.create(ROOT_INODE_ID, "bulk", FileType::Reg, 0, 0, perm).unwrap();
+    let mut w = rwfs.bulk_write(bulk).unwrap();
+    let mut off = 0;
+    for c in chunks.iter() {
+        off += w.write_at(off, c).unwrap();
+    }
+    w.finish().unwrap();
+
+    assert!(matches!(rwfs.bulk_write(ROOT_INODE_ID), Err(FsError::IsADirectory)));
+
+    // others wait for the writer
+    let done = AtomicBool::new(false);
+    std::thread::scope(|s| {
+        let w = rwfs.bulk_write(bulk).unwrap();
+        s.spawn(|| {
+            rwfs.get_meta(bulk).unwrap();
+            done.store(true, Ordering::SeqCst);
+        });
+        std::thread::sleep(Duration::from_millis(50));
+        assert!(!done.load(Ordering::SeqCst));
+        drop(w);
+    });
+    assert!(done.load(Ordering::SeqCst));
+
+    let mode = rwfs.fsync().unwrap();
+    drop(rwfs);
+    let rwfs = mount_rw(&image, mode);
+    let tree = read_tree(&rwfs);
+    assert_eq!(tree["bulk"].len(), off);
+    assert_eq!(tree["bulk"], tree["naive"]);
+    assert_eq!(tree["bulk"], chunks.concat());
+
+    fs::remove_dir_all(&base).unwrap();
+}
+
+#[test]
+fn rw_open_readonly() {
+    let base = temp_dir("rw_open_readonly");
+    let src = base.join("src");
+    fs::create_dir_all(src.join("d")).unwrap();
+    fs::write(src.join("d/f"), vec![9u8; BLK_SZ * 2 + 3]).unwrap();
+    std::os::unix::fs::symlink("d/f", src.join("l")).unwrap();
+    let image = base.join("rw.image");
+    let mode = build_rw(&src, &image, None);
+    let snapshot = || -> std::collections::BTreeMap<_, _> {
+        fs::read_dir(&image).unwrap().map(|e| {
+            let e = e.unwrap();
+            (e.file_name(), fs::read(e.path()).unwrap())
+        }).collect()
+    };
+    let before = snapshot();
+
+    let rofs = eccfs::rw::RWFS::open_readonly(
+        mode.clone(), None, 0,
+        std::sync::Arc::new(FileDevice::new(&image, BLK_SZ).unwrap()), &SYSTEM_TIME,
+    ).unwrap();
+    let tree = read_tree(&rofs);
+    assert_eq!(tree["d/f"], vec![9u8; BLK_SZ * 2 + 3]);
+    assert_eq!(tree["l"], b"d/f");
+    let caps = rofs.capabilities().unwrap();
+    assert!(!caps.writable && !caps.compression);
+    assert_eq!(caps.version, eccfs::vfs::FS_LAYOUT_VERSION);
+
+    let d = rofs.lookup(ROOT_INODE_ID, "d").unwrap().unwrap();
+    let f = rofs.lookup(d, "f").unwrap().unwrap();
+    let perm = FilePerm::from_bits(0o644).unwrap();
+    let denied = |r: FsResult<()>| assert!(matches!(r, Err(FsError::PermissionDenied)));
+    denied(rofs.iwrite(f, 0, b"x").map(|_| ()));
+    denied(rofs.iappend(f, b"x").map(|_| ()));
+    denied(rofs.set_meta(f, SetMetadata::Size(0)));
+    denied(rofs.create(d, "new", FileType::Reg, 0, 0, perm).map(|_| ()));
+    denied(rofs.symlink(d, "lnk", "f", 0, 0).map(|_| ()));
+    denied(rofs.unlink(d, "f"));
+    denied(rofs.rename(d, "f", ROOT_INODE_ID, "g"));
+    denied(rofs.touch_atime(f));
+    assert_eq!(rofs.fsync().unwrap(), mode);
+    rofs.isync_meta(f).unwrap();
+    rofs.destroy().unwrap();
+    drop(rofs);
+
+    // not even atime is written back
+    assert!(snapshot() == before);
+
+    fs::remove_dir_all(&base).unwrap();
+}
+
+#[test]
+fn rw_without_inode_cache() {
+    let base = temp_dir("rw_no_icac");
+    let src = base.join("src");
+    fs::create_dir(&src).unwrap();
+    fs::write(src.join("old"), b"old").unwrap();
+    let image = base.join("rw.image");
+    let mode = build_rw(&src, &image, None);
+    let device = || std::sync::Arc::new(FileDevice::new(&image, BLK_SZ).unwrap());
+    let rwfs = eccfs::rw::RWFS::new(false, mode, Some(0), 0, device(), &SYSTEM_TIME).unwrap();
+    let perm = FilePerm::from_bits(0o644).unwrap();
+
+    let d = rwfs.create(ROOT_INODE_ID, "d", FileType::Dir, 0, 0, perm).unwrap();
+    let small = rwfs.create(d, "small", FileType::Reg, 0, 0, perm).unwrap();
+    rwfs.iwrite(small, 0, b"small").unwrap();
+    let big = rwfs.create(d, "big", FileType::Reg, 0, 0, perm).unwrap();
+    let data = vec![7u8; 3 * BLK_SZ];
+    rwfs.iwrite(big, 0, &data).unwrap();
+    let mut buf = vec![0u8; data.len()];
+    assert_eq!(rwfs.iread(big, 0, &mut buf).unwrap(), data.len());
+    assert_eq!(buf, data);
+    assert_eq!(rwfs.get_meta(big).unwrap().size, data.len() as u64);
+
+    rwfs.rename(d, "small", ROOT_INODE_ID, "moved").unwrap();
+    let gone = rwfs.create(d, "gone", FileType::Reg, 0, 0, perm).unwrap();
+    rwfs.iwrite(gone, 0, b"gone").unwrap();
+    rwfs.unlink(d, "gone").unwrap();
+    rwfs.unlink(ROOT_INODE_ID, "old").unwrap();
+    assert_eq!(rwfs.lookup(d, "gone").unwrap(), None);
+
+    let mode = rwfs.destroy().unwrap();
+    drop(rwfs);
+    let rwfs = eccfs::rw::RWFS::new(false, mode, None, 0, device(), &SYSTEM_TIME).unwrap();
+    let tree = read_tree(&rwfs);
+    assert_eq!(tree.len(), 4);
+    assert_eq!(tree.get("moved").map(|v| v.as_slice()), Some(&b"small"[..]));
+    assert_eq!(tree.get("d/big"), Some(&data));
+
+    fs::remove_dir_all(&base).unwrap();
+}
+
+#[test]
+fn rw_parallel_flush_matches_serial() {
+    struct FixedTime;
+    impl TimeSource for FixedTime {
+        fn now(&self) -> u32 {
+            1000
+        }
+    }
+    static FIXED_TIME: FixedTime = FixedTime;
+
+    let base = temp_dir("rw_parallel_flush");
+    let src = base.join("src");
+    fs::create_dir(&src).unwrap();
+    let nr = 16;
+    for i in 0..nr {
+        fs::write(src.join(format!("f{}", i)), vec![i as u8; 2 * BLK_SZ]).unwrap();
+    }
+    let serial = base.join("serial.image");
+    let mode = build_rw(&src, &serial, None);
+    let parallel = base.join("parallel.image");
+    fs::create_dir(&parallel).unwrap();
+    for entry in fs::read_dir(&serial).unwrap() {
+        let entry = entry.unwrap();
+        fs::copy(entry.path(), parallel.join(entry.file_name())).unwrap();
+    }
+
+    for (image, threads) in [(&serial, 1), (&parallel, 4)] {
+        let device = std::sync::Arc::new(FileDevice::new(image, BLK_SZ).unwrap());
+        let rwfs = eccfs::rw::RWFS::new(false, mode.clone(), None, 0, device, &FIXED_TIME).unwrap();
+        rwfs.set_flush_threads(threads);
+        let perm = FilePerm::from_bits(0o644).unwrap();
+        for i in 0..nr {
+            let f = rwfs.lookup(ROOT_INODE_ID, &format!("f{}", i)).unwrap().unwrap();
+            rwfs.iwrite(f, BLK_SZ / 2 * i, &vec![0xff; BLK_SZ * 3]).unwrap();
+        }
+        let big = rwfs.create(ROOT_INODE_ID, "big", FileType::Reg, 0, 0, perm).unwrap();
+        rwfs.iwrite(big, 0, &vec![1u8; BLK_SZ * 5]).unwrap();
+        rwfs.symlink(ROOT_INODE_ID, "lnk", &"t".repeat(100), 0, 0).unwrap();
+        rwfs.fsync().unwrap();
+        rwfs.destroy().unwrap();
+    }
+
+    let names: Vec<_> = fs::read_dir(&serial).unwrap().map(|e| e.unwrap().file_name()).collect();
+    assert_eq!(names.len(), fs::read_dir(&parallel).unwrap().count());
+    for name in names {
+        assert_eq!(
+            fs::read(serial.join(&name)).unwrap(),
+            fs::read(parallel.join(&name)).unwrap(),
+            "{:?} differs", name,
+        );
+    }
+
+    fs::remove_dir_all(&base).unwrap();
+}
+
+#[test]
+fn rw_trim_inode_table() {
+    let base = temp_dir("rw_trim_itbl");
+    let src = base.join("src");
+    fs::create_dir(&src).unwrap();
+    let image = base.join("rw.image");
+    let mode = build_rw(&src, &image, None);
+    let device = || std::sync::Arc::new(FileDevice::new(&image, BLK_SZ).unwrap());
+    let rwfs = eccfs::rw::RWFS::new(false, mode, None, 0, device(), &SYSTEM_TIME).unwrap();
+    let perm = FilePerm::from_bits(0o644).unwrap();
+
+    let keep = rwfs.create(ROOT_INODE_ID, "keep", FileType::Reg, 0, 0, perm).unwrap();
+    rwfs.iwrite(keep, 0, b"kept").unwrap();
+    let nr = BLK_SZ * 4 / 128;
+    for i in 0..nr {
+        rwfs.create(ROOT_INODE_ID, &format!("f{}", i), FileType::Reg, 0, 0, perm).unwrap();
+    }
+    // nothing to trim yet
+    assert_eq!(rwfs.trim_inode_table().unwrap(), 0);
+
+    for i in 0..nr {
+        rwfs.unlink(ROOT_INODE_ID, &format!("f{}", i)).unwrap();
+    }
+    rwfs.fsync().unwrap();
+    let blocks = rwfs.finfo().unwrap().blocks;
+    let trimmed = rwfs.trim_inode_table().unwrap();
+    assert!(trimmed > 0);
+    rwfs.fsync().unwrap();
+    assert_eq!(rwfs.finfo().unwrap().blocks, blocks - trimmed);
+
+    // the table grows back on demand
+    let again = rwfs.create(ROOT_INODE_ID, "again", FileType::Reg, 0, 0, perm).unwrap();
+    rwfs.iwrite(again, 0, b"new").unwrap();
+    let mode = rwfs.destroy().unwrap();
+    drop(rwfs);
+    let rwfs = eccfs::rw::RWFS::new(false, mode, None, 0, device(), &SYSTEM_TIME).unwrap();
+    let tree = read_tree(&rwfs);
+    assert_eq!(tree.len(), 3);
+    assert_eq!(tree.get("keep").map(|v| v.as_slice()), Some(&b"kept"[..]));
+    assert_eq!(tree.get("again").map(|v| v.as_slice()), Some(&b"new"[..]));
+
+    fs::remove_dir_all(&base).unwrap();
+}
+
+#[test]
+fn rw_finfo_free_inodes() {
+    let base = temp_dir("rw_finfo_ffree");
+    let src = base.join("src");
+    fs::create_dir(&src).unwrap();
+    fs::write(src.join("f"), b"f").unwrap();
+    let image = base.join("rw.image");
+    let mode = build_rw(&src, &image, None);
+    let rwfs = mount_rw(&image, mode);
+    let perm = FilePerm::from_bits(0o644).unwrap();
+
+    // the reserved inode 0, root and f
+    let info = rwfs.finfo().unwrap();
+    assert_eq!(info.files, BLK_SZ * 8);
+    assert_eq!(info.ffree, info.files - 3);
+
+    for name in ["a", "b", "c"] {
+        rwfs.create(ROOT_INODE_ID, name, FileType::Reg, 0, 0, perm).unwrap();
+    }
+    rwfs.create(ROOT_INODE_ID, "d", FileType::Dir, 0, 0, perm).unwrap();
+    let after = rwfs.finfo().unwrap();
+    assert_eq!(after.files, info.files);
+    assert_eq!(after.ffree, info.ffree - 4);
+
+    rwfs.unlink(ROOT_INODE_ID, "b").unwrap();
+    assert_eq!(rwfs.finfo().unwrap().ffree, info.ffree - 3);
+    drop(rwfs);
+
+    fs::remove_dir_all(&base).unwrap();
+}
+
+#[test]
+fn rw_sparse_file_holes() {
+    let base = temp_dir("rw_sparse");
+    let src = base.join("src");
+    fs::create_dir_all(&src).unwrap();
+    fs::write(src.join("f"), b"head").unwrap();
+    let image = base.join("rw.image");
+    let mode = build_rw(&src, &image, None);
+    let mount = |mode: FSMode| mount_rw(&image, mode);
+
+    // only the first and the last block are written
+    let far = 64 << 20;
+    let rwfs = mount(mode);
+    let f = rwfs.lookup(ROOT_INODE_ID, "f").unwrap().unwrap();
+    rwfs.iwrite(f, far, b"x").unwrap();
+    assert_eq!(rwfs.get_meta(f).unwrap().blocks, 2);
+    let mut buf = vec![1u8; 2 * BLK_SZ];
+    rwfs.iread(f, far / 2, &mut buf).unwrap();
+    assert!(buf.iter().all(|b| *b == 0));
+    let mode = rwfs.destroy().unwrap();
+    drop(rwfs);
+
+    // holes are found again, and filled by writes
+    let rwfs = mount(mode);
+    assert_eq!(rwfs.get_meta(f).unwrap().blocks, 2);
+    rwfs.iread(f, far / 2, &mut buf).unwrap();
+    assert!(buf.iter().all(|b| *b == 0));
+    rwfs.iwrite(f, far / 2, &[5u8; BLK_SZ]).unwrap();
+    assert_eq!(rwfs.get_meta(f).unwrap().blocks, 3);
+    let mut head = [0u8; 4];
+    rwfs.iread(f, 0, &mut head).unwrap();
+    assert_eq!(&head, b"head");
+    rwfs.iread(f, far, &mut head[..1]).unwrap();
+    assert_eq!(head[0], b'x');
+    rwfs.set_meta(f, SetMetadata::Size(far / 2)).unwrap();
+    assert_eq!(rwfs.get_meta(f).unwrap().blocks, 1);
+    let mode = rwfs.destroy().unwrap();
+    drop(rwfs);
+
+    let rwfs = mount(mode);
+    assert_eq!(rwfs.get_meta(f).unwrap().blocks, 1);
+    assert!(rwfs.check().unwrap().is_clean());
+    rwfs.destroy().unwrap();
+
+    fs::remove_dir_all(&base).unwrap();
+}
+
+#[test]
+fn rw_punch_hole() {
+    let base = temp_dir("rw_punch_hole");
+    let src = base.join("src");
+    fs::create_dir_all(&src).unwrap();
+    let content: Vec<u8> = (0..8 * BLK_SZ).map(|i| (i % 251) as u8 + 1).collect();
+    fs::write(src.join("f"), &content).unwrap();
+    let image = base.join("rw.image");
+    let mode = build_rw(&src, &image, None);
+    let mount = |mode: FSMode| mount_rw(&image, mode);
+
+    // blocks 1 to 4 become holes, parts of block 0 and 5 are zeroed
+    let (start, end) = (BLK_SZ / 2, 5 * BLK_SZ + 10);
+    let mut expected = content.clone();
+    expected[start..end].fill(0);
+    let rwfs = mount(mode);
+    let f = rwfs.lookup(ROOT_INODE_ID, "f").unwrap().unwrap();
+    rwfs.fallocate(f, FallocateMode::PunchHole, start, end - start).unwrap();
+    let check = |rwfs: &eccfs::rw::RWFS, expected: &[u8], blocks: u64| {
+        let meta = rwfs.get_meta(f).unwrap();
+        assert_eq!(meta.size, content.len() as u64);
+        assert_eq!(meta.blocks, blocks);
+        let mut buf = vec![0u8; content.len()];
+        assert_eq!(rwfs.iread(f, 0, &mut buf).unwrap(), content.len());
+        assert_eq!(buf, expected);
+    };
+    check(&rwfs, &expected, 4);
+    // nothing beyond size is touched
+    rwfs.fallocate(f, FallocateMode::PunchHole, 7 * BLK_SZ, 4 * BLK_SZ).unwrap();
+    expected[7 * BLK_SZ..].fill(0);
+    let mode = rwfs.destroy().unwrap();
+    drop(rwfs);
+
+    let rwfs = mount(mode);
+    check(&rwfs, &expected, 3);
+    assert!(rwfs.check().unwrap().is_clean());
+    rwfs.destroy().unwrap();
+
+    fs::remove_dir_all(&base).unwrap();
+}
+
+#[test]
+fn rw_truncated_dir_file() {
+    let base = temp_dir("rw_truncated_dir");
+    let src = base.join("src");
+    fs::create_dir_all(src.join("sub")).unwrap();
+    for i in 0..3 * eccfs::rw::disk::dirent_per_blk(BLK_SZ) {
+        fs::write(src.join("sub").join(format!("f{}", i)), b"").unwrap();
+    }
+    let image = base.join("rw.image");
+    let mode = build_rw(&src, &image, None);
+    let mount = || mount_rw(&image, mode.clone());
+
+    let rwfs = mount();
+    let sub = rwfs.lookup(ROOT_INODE_ID, "sub").unwrap().unwrap();
+    let names = rwfs.inode_storages(sub).unwrap();
+    assert_eq!(names.len(), 1);
+    drop(rwfs);
+    let f = fs::OpenOptions::new().write(true).open(image.join(&names[0])).unwrap();
+    let len = f.metadata().unwrap().len();
+    f.set_len(len - BLK_SZ as u64).unwrap();
+    drop(f);
+
+    let rwfs = mount();
+    assert!(matches!(rwfs.listdir(sub, 0, 0), Err(FsError::Corrupted)));
+    assert!(matches!(rwfs.lookup(sub, "f0"), Err(FsError::Corrupted)));
+    // the rest is still there
+    assert_eq!(rwfs.listdir(ROOT_INODE_ID, 0, 0).unwrap().len(), 3);
+    drop(rwfs);
+
+    fs::remove_dir_all(&base).unwrap();
+}
+
+#[test]
+fn rw_check_finds_discrepancies() {
+    use eccfs::rw::check::CheckIssue;
+
+    let base = temp_dir("rw_check");
+    let src = base.join("src");
+    fs::create_dir_all(src.join("d")).unwrap();
+    fs::write(src.join("small"), b"small").unwrap();
+    fs::write(src.join("d/big"), vec![1u8; 3 * BLK_SZ]).unwrap();
+    std::os::unix::fs::symlink("t".repeat(2048), src.join("lnk")).unwrap();
+    let image = base.join("rw.image");
+    let mode = build_rw(&src, &image, None);
+    let device = || std::sync::Arc::new(FileDevice::new(&image, BLK_SZ).unwrap());
+
+    let rwfs = eccfs::rw::RWFS::new(false, mode, None, 0, device(), &SYSTEM_TIME).unwrap();
+    assert!(rwfs.check().unwrap().is_clean());
+    let d = rwfs.lookup(ROOT_INODE_ID, "d").unwrap().unwrap();
+    let big = rwfs.lookup(d, "big").unwrap().unwrap();
+    let lnk = rwfs.lookup(ROOT_INODE_ID, "lnk").unwrap().unwrap();
+    rwfs.iset_xattr(big, "user.a", b"a").unwrap();
+    rwfs.iset_link(lnk, &"u".repeat(eccfs::rw::disk::LNK_SLOT_SZ - 1)).unwrap();
+    let mode = rwfs.destroy().unwrap();
+    drop(rwfs);
+
+    let rwfs = eccfs::rw::RWFS::open_readonly(mode, None, 0, device(), &SYSTEM_TIME).unwrap();
+    assert!(rwfs.check().unwrap().is_clean());
+
+    // a lost data file and one cut short after mount
+    let big_file = image.join(&rwfs.inode_storages(big).unwrap()[0]);
+    let big_len = fs::metadata(&big_file).unwrap().len() / BLK_SZ as u64;
+    fs::remove_file(&big_file).unwrap();
+    let dir_file = image.join(&rwfs.inode_storages(d).unwrap()[0]);
+    let dir_len = fs::metadata(&dir_file).unwrap().len() / BLK_SZ as u64;
+    fs::OpenOptions::new().write(true).open(&dir_file).unwrap()
+        .set_len((dir_len + 1) * BLK_SZ as u64).unwrap();
+    let before: Vec<_> = fs::read_dir(&image).unwrap()
+        .map(|e| fs::read(e.unwrap().path()).unwrap()).collect();
+
+    let issues = rwfs.check().unwrap().issues;
+    assert!(issues.contains(&CheckIssue::MissingDataFile(big)));
+    assert!(issues.contains(&CheckIssue::DataFileLen { iid: d, expected: dir_len, actual: dir_len + 1 }));
+    assert!(issues.iter().any(|i| matches!(i, CheckIssue::DataFileCount { .. })));
+    assert!(issues.iter().any(|i| matches!(
+        i, CheckIssue::BlockCount { sb, found } if *sb - *found == big_len as usize - 1
+    )));
+    assert_eq!(issues.len(), 4, "{:?}", issues);
+    drop(rwfs);
+    // nothing is written by the check
+    let after: Vec<_> = fs::read_dir(&image).unwrap()
+        .map(|e| fs::read(e.unwrap().path()).unwrap()).collect();
+    assert_eq!(before, after);
+
+    fs::remove_dir_all(&base).unwrap();
+}
+
+#[test]
+fn rw_symlinks_share_link_table() {
+    let base = temp_dir("rw_lnk_table");
+    let src = base.join("src");
+    fs::create_dir(&src).unwrap();
+    let image = base.join("rw.image");
+    let mode = build_rw(&src, &image, None);
+    let device = || std::sync::Arc::new(FileDevice::new(&image, BLK_SZ).unwrap());
+    let dev = device();
+    let rwfs = eccfs::rw::RWFS::new(false, mode, None, 0, dev.clone(), &SYSTEM_TIME).unwrap();
+    let nr_storage = dev.nr_storage().unwrap();
+
+    let target = |i: usize| format!("../../node_modules/.store/pkg-{}/{}", i, "x".repeat(100));
+    let nr = 100;
+    let mut links = Vec::new();
+    for i in 0..nr {
+        links.push(rwfs.symlink(ROOT_INODE_ID, &format!("l{}", i), &target(i), 0, 0).unwrap());
+    }
+    // too long for a slot, still a data file of its own
+    let long = "y".repeat(1000);
+    let l = rwfs.symlink(ROOT_INODE_ID, "long", &long, 0, 0).unwrap();
+    rwfs.fsync().unwrap();
+    // the link table and the long one
+    assert_eq!(dev.nr_storage().unwrap(), nr_storage + 2);
+    assert!(rwfs.inode_storages(links[0]).unwrap().is_empty());
+    assert_eq!(rwfs.inode_storages(l).unwrap().len(), 1);
+
+    // a freed slot is taken again, a link moves between shapes
+    rwfs.unlink(ROOT_INODE_ID, "l0").unwrap();
+    rwfs.symlink(ROOT_INODE_ID, "again", &target(nr), 0, 0).unwrap();
+    rwfs.iset_link(links[1], "short").unwrap();
+    rwfs.iset_link(l, &target(nr + 1)).unwrap();
+    rwfs.iset_link(links[2], &long).unwrap();
+    let mode = rwfs.destroy().unwrap();
+    drop(rwfs);
+    assert_eq!(dev.nr_storage().unwrap(), nr_storage + 2);
+
+    let rwfs = eccfs::rw::RWFS::new(false, mode, None, 0, device(), &SYSTEM_TIME).unwrap();
+    assert_eq!(rwfs.lookup(ROOT_INODE_ID, "l0").unwrap(), None);
+    let read = |name: &str| {
+        let iid = rwfs.lookup(ROOT_INODE_ID, name).unwrap().unwrap();
+        rwfs.iread_link(iid).unwrap()
+    };
+    assert_eq!(read("again"), target(nr));
+    assert_eq!(read("l1"), "short");
+    assert_eq!(read("long"), target(nr + 1));
+    assert_eq!(read("l2"), long);
+    for i in 3..nr {
+        assert_eq!(read(&format!("l{}", i)), target(i));
+    }
+    drop(rwfs);
+
+    fs::remove_dir_all(&base).unwrap();
+}
+
+#[test]
+fn rw_rekey() {
+    let base = temp_dir("rw_rekey");
+    let src = base.join("src");
+    fs::create_dir_all(src.join("d")).unwrap();
+    fs::write(src.join("big"), vec![7u8; 3 * BLK_SZ + 5]).unwrap();
+    fs::write(src.join("d/small"), b"small").unwrap();
+    std::os::unix::fs::symlink("t".repeat(2048), src.join("lnk")).unwrap();
+    let image = base.join("rw.image");
+    let mode = build_rw(&src, &image, Some([3u8; 16]));
+    let mount = |mode: FSMode| eccfs::rw::RWFS::new(
+        false, mode, None, 0,
+        std::sync::Arc::new(FileDevice::new(&image, BLK_SZ).unwrap()),
+        &SYSTEM_TIME,
+    );
+
+    let rwfs = mount(mode).unwrap();
+    rwfs.enable_ke_digest().unwrap();
+    let big = rwfs.lookup(ROOT_INODE_ID, "big").unwrap().unwrap();
+    rwfs.iset_xattr(big, "user.k", b"v").unwrap();
+    rwfs.symlink(ROOT_INODE_ID, "slot", &"s".repeat(200), 0, 0).unwrap();
+    let tree = read_tree(&rwfs);
+    let old_mode = rwfs.fsync().unwrap();
+
+    let new_mode = rwfs.rekey([9u8; 16]).unwrap();
+    assert_eq!(new_mode.get_key(), Some([9u8; 16]));
+    assert!(matches!(rwfs.destroy(), Err(FsError::AlreadyDestroyed)));
+    drop(rwfs);
+
+    let rwfs = mount(new_mode).unwrap();
+    assert_eq!(read_tree(&rwfs), tree);
+    assert_eq!(rwfs.iget_xattr(big, "user.k").unwrap(), Some(b"v".to_vec()));
+    assert!(rwfs.check().unwrap().is_clean());
+    drop(rwfs);
+
+    // wrong keys panic in debug builds
+    let old = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| mount(old_mode)));
+    assert!(!matches!(old, Ok(Ok(_))));
+
+    fs::remove_dir_all(&base).unwrap();
+}
+
+// rekey fails at every write it makes and the device stays broken as in a crash,
+// the image is then mounted as it was under the old mode, or under the new one
+#[test]
+fn rw_rekey_cut_short() {
+    let base = temp_dir("rw_rekey_cut_short");
+    let src = base.join("src");
+    fs::create_dir_all(src.join("d")).unwrap();
+    fs::write(src.join("big"), vec![7u8; 3 * BLK_SZ + 5]).unwrap();
+    fs::write(src.join("d/small"), b"small").unwrap();
+    let image0 = base.join("rw.image0");
+    let mode0 = build_rw(&src, &image0, Some([3u8; 16]));
+    let tree = read_tree(&mount_rw(&image0, mode0.clone()));
+
+    for nr_write in 0.. {
+        assert!(nr_write < 1000, "rekey never finishes");
+        let image = base.join(format!("rw.image{}", nr_write + 1));
+        copy_image(&image0, &image);
+        let dev = std::sync::Arc::new(FailDevice::new(&image));
+        let rwfs = eccfs::rw::RWFS::new(
+            false, mode0.clone(), None, 0, dev.clone(), &SYSTEM_TIME,
+        ).unwrap();
+        let old_mode = rwfs.fsync().unwrap();
+        dev.1.fail_after(nr_write);
+        let ret = rwfs.rekey([9u8; 16]).ok();
+        let done = dev.1.left() > 0;
+        drop(rwfs);
+
+        let rwfs = mount_rw(&image, ret.clone().unwrap_or(old_mode));
+        assert_eq!(read_tree(&rwfs), tree);
+        assert!(rwfs.check().unwrap().is_clean());
+        drop(rwfs);
+        fs::remove_dir_all(&image).unwrap();
+        if done {
+            assert!(ret.is_some());
+            break;
+        }
+    }
+
+    fs::remove_dir_all(&base).unwrap();
+}
+
+#[test]
+fn rw_xattr_round_trip() {
+    let base = temp_dir("rw_xattr");
+    let src = base.join("src");
+    fs::create_dir(&src).unwrap();
+    fs::write(src.join("f"), b"f").unwrap();
+    let image = base.join("rw.image");
+    let mode = build_rw(&src, &image, None);
+    let device = || std::sync::Arc::new(FileDevice::new(&image, BLK_SZ).unwrap());
+    let nr_storage = || fs::read_dir(&image).unwrap().count();
+    let rwfs = eccfs::rw::RWFS::new(false, mode, None, 0, device(), &SYSTEM_TIME).unwrap();
+    let f = rwfs.lookup(ROOT_INODE_ID, "f").unwrap().unwrap();
+    let perm = FilePerm::from_bits(0o644).unwrap();
+
+    assert_eq!(rwfs.ilist_xattr(f).unwrap(), Vec::<String>::new());
+    assert_eq!(rwfs.iget_xattr(f, "user.none").unwrap(), None);
+    let label = b"system_u:object_r:etc_t:s0".to_vec();
+    rwfs.iset_xattr(f, "security.selinux", &label).unwrap();
+    rwfs.iset_xattr(f, "user.empty", b"").unwrap();
+    rwfs.iset_xattr(f, "security.capability", &[1, 0, 0, 2]).unwrap();
+    // replacing keeps the order
+    rwfs.iset_xattr(f, "user.empty", b"").unwrap();
+    let long = "u".repeat(eccfs::vfs::XATTR_NAME_MAX + 1);
+    assert!(matches!(rwfs.iset_xattr(f, &long, b"v"), Err(FsError::NameTooLong)));
+    assert!(matches!(rwfs.iset_xattr(f, "", b"v"), Err(FsError::InvalidParameter)));
+    assert!(matches!(rwfs.iremove_xattr(f, "user.none"), Err(FsError::NotFound)));
+    let max = "m".repeat(eccfs::vfs::XATTR_NAME_MAX);
+    rwfs.iset_xattr(ROOT_INODE_ID, &max, &vec![3u8; 2 * BLK_SZ]).unwrap();
+
+    let names = vec!["security.selinux", "user.empty", "security.capability"];
+    let mode = rwfs.destroy().unwrap();
+    drop(rwfs);
+    let rwfs = eccfs::rw::RWFS::new(false, mode, None, 0, device(), &SYSTEM_TIME).unwrap();
+    assert_eq!(rwfs.ilist_xattr(f).unwrap(), names);
+    assert_eq!(rwfs.iget_xattr(f, "security.selinux").unwrap(), Some(label));
+    assert_eq!(rwfs.iget_xattr(f, "user.empty").unwrap(), Some(vec![]));
+    assert_eq!(rwfs.iget_xattr(ROOT_INODE_ID, &max).unwrap(), Some(vec![3u8; 2 * BLK_SZ]));
+
+    // xattr files go with the last xattr or the inode
+    let with_xattrs = nr_storage();
+    rwfs.iremove_xattr(ROOT_INODE_ID, &max).unwrap();
+    assert_eq!(rwfs.ilist_xattr(ROOT_INODE_ID).unwrap(), Vec::<String>::new());
+    rwfs.unlink(ROOT_INODE_ID, "f").unwrap();
+    assert_eq!(nr_storage(), with_xattrs - 2);
+    let g = rwfs.create(ROOT_INODE_ID, "g", FileType::Reg, 0, 0, perm).unwrap();
+    assert_eq!(g, f);
+    assert_eq!(rwfs.ilist_xattr(g).unwrap(), Vec::<String>::new());
+    let mode = rwfs.destroy().unwrap();
+    drop(rwfs);
+    eccfs::rw::RWFS::new(false, mode, None, 0, device(), &SYSTEM_TIME).unwrap();
+
+    fs::remove_dir_all(&base).unwrap();
+}
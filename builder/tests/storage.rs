@@ -0,0 +1,233 @@
+//! tests of storages and devices under mounted images
+mod common;
+
+use common::*;
+use eccfs::*;
+use std::fs;
+
+#[test]
+fn rw_on_mem_device() {
+    use std::sync::Arc;
+
+    // an empty image, moved into memory
+    let base = temp_dir("mem");
+    let image = base.join("rw.image");
+    let mode = eccfs_builder::rw::create_empty(&image, Some([5u8; 16])).unwrap();
+    let dev = Arc::new(MemDevice::new(BLK_SZ));
+    for ent in fs::read_dir(&image).unwrap() {
+        let ent = ent.unwrap();
+        let from = FileStorage::new(&ent.path(), false, BLK_SZ).unwrap();
+        let nr_blk = from.get_len().unwrap() / BLK_SZ as u64;
+        let to = dev.create_rw_storage(ent.file_name().to_str().unwrap()).unwrap();
+        to.set_len(nr_blk).unwrap();
+        for pos in 0..nr_blk {
+            to.write_blk(pos, &from.read_blk(pos).unwrap()).unwrap();
+        }
+    }
+    fs::remove_dir_all(&base).unwrap();
+
+    let rwfs = eccfs::rw::RWFS::new(false, mode, None, 0, dev.clone(), &SYSTEM_TIME).unwrap();
+    let perm = FilePerm::from_bits(0o644).unwrap();
+    let d = rwfs.create(ROOT_INODE_ID, "d", FileType::Dir, 0, 0, perm).unwrap();
+    let small = rwfs.create(d, "small", FileType::Reg, 0, 0, perm).unwrap();
+    rwfs.iwrite(small, 0, b"small").unwrap();
+    let big = rwfs.create(ROOT_INODE_ID, "big", FileType::Reg, 0, 0, perm).unwrap();
+    rwfs.iwrite(big, 0, &vec![9u8; 5 * BLK_SZ + 1]).unwrap();
+    rwfs.symlink(ROOT_INODE_ID, "lnk", "d/small", 0, 0).unwrap();
+    let expected = read_tree(&rwfs);
+    let mode = rwfs.destroy().unwrap();
+    drop(rwfs);
+
+    let rwfs = eccfs::rw::RWFS::new(false, mode, None, 0, dev, &SYSTEM_TIME).unwrap();
+    let tree = read_tree(&rwfs);
+    assert_eq!(tree, expected);
+    assert_eq!(tree["big"], vec![9u8; 5 * BLK_SZ + 1]);
+    assert!(rwfs.check().unwrap().is_clean());
+}
+
+#[test]
+fn ro_on_lazy_storage() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    let base = temp_dir("lazy");
+    let src = base.join("src");
+    fs::create_dir(&src).unwrap();
+    let big: Vec<u8> = (0..16 * BLK_SZ).map(|i| (i / 7) as u8).collect();
+    fs::write(src.join("big"), &big).unwrap();
+    for i in 0..8 {
+        fs::write(src.join(format!("other_{}", i)), vec![i as u8; 4 * BLK_SZ]).unwrap();
+    }
+    let mode = build_ro(&src, &base, "ro.image", None);
+    let remote = Arc::new(FileStorage::new(&base.join("ro.image"), false, BLK_SZ).unwrap());
+    let total = fs::metadata(base.join("ro.image")).unwrap().len() / BLK_SZ as u64;
+    let cache = base.join("cache");
+    fs::create_dir(&cache).unwrap();
+
+    let nr_fetch = Arc::new(AtomicUsize::new(0));
+    let (r, n) = (remote.clone(), nr_fetch.clone());
+    let lazy = Arc::new(LazyStorage::new(&cache, Box::new(move |pos, to| {
+        n.fetch_add(1, Ordering::SeqCst);
+        r.read_blk_to(pos, to)
+    }), BLK_SZ).unwrap());
+    let rofs = eccfs::ro::ROFS::new(
+        mode.clone(), DEFAULT_CACHE_CAP, None, 0, lazy.clone(),
+    ).unwrap();
+    let f = rofs.lookup(ROOT_INODE_ID, "big").unwrap().unwrap();
+    let mut buf = vec![0u8; big.len()];
+    assert_eq!(rofs.iread(f, 0, &mut buf).unwrap(), big.len());
+    assert_eq!(buf, big);
+    drop(rofs);
+
+    // only what is read is fetched, and each block once
+    let fetched = nr_fetch.load(Ordering::SeqCst);
+    assert!(fetched < total as usize);
+    assert_eq!(fetched, lazy.nr_present().unwrap());
+    drop(lazy);
+
+    // fetched blocks stay in cache dir
+    let lazy = Arc::new(LazyStorage::new(&cache, Box::new(
+        |_, _| Err(FsError::NotFound)
+    ), BLK_SZ).unwrap());
+    let rofs = eccfs::ro::ROFS::new(
+        mode.clone(), DEFAULT_CACHE_CAP, None, 0, lazy,
+    ).unwrap();
+    let mut buf = vec![0u8; big.len()];
+    rofs.iread(f, 0, &mut buf).unwrap();
+    assert_eq!(buf, big);
+    let other = rofs.lookup(ROOT_INODE_ID, "other_0").unwrap().unwrap();
+    assert!(matches!(rofs.iread(other, 0, &mut buf[..BLK_SZ]), Err(FsError::NotFound)));
+    drop(rofs);
+
+    // a fetched block is kept only once confirmed, one failing its check is fetched again
+    let cache = base.join("cache_confirm");
+    fs::create_dir(&cache).unwrap();
+    let nr_fetch = Arc::new(AtomicUsize::new(0));
+    let (r, n) = (remote.clone(), nr_fetch.clone());
+    let lazy = LazyStorage::new(&cache, Box::new(move |pos, to| {
+        n.fetch_add(1, Ordering::SeqCst);
+        r.read_blk_to(pos, to)
+    }), BLK_SZ).unwrap();
+    let expected = remote.read_blk(1).unwrap();
+    assert_eq!(lazy.read_blk(1).unwrap(), expected);
+    assert_eq!(lazy.nr_present().unwrap(), 0);
+    assert_eq!(lazy.read_blk(1).unwrap(), expected);
+    assert_eq!(nr_fetch.load(Ordering::SeqCst), 2);
+    lazy.confirm_blk(1).unwrap();
+    assert_eq!(lazy.nr_present().unwrap(), 1);
+    assert_eq!(lazy.read_blk(1).unwrap(), expected);
+    assert_eq!(nr_fetch.load(Ordering::SeqCst), 2);
+
+    // blocks taken unchecked are not kept, and fetched again for a full check
+    let cache = base.join("cache_unchecked");
+    fs::create_dir(&cache).unwrap();
+    let nr_fetch = Arc::new(AtomicUsize::new(0));
+    let (r, n) = (remote.clone(), nr_fetch.clone());
+    let lazy = Arc::new(LazyStorage::new(&cache, Box::new(move |pos, to| {
+        n.fetch_add(1, Ordering::SeqCst);
+        r.read_blk_to(pos, to)
+    }), BLK_SZ).unwrap());
+    let mount = || eccfs::ro::ROFS::new(
+        mode.clone(), DEFAULT_CACHE_CAP, None, 0, lazy.clone(),
+    ).unwrap();
+    let rofs = mount();
+    rofs.set_verify_policy(eccfs::htree::VerifyPolicy::IndexOnly, None).unwrap();
+    let mut buf = vec![0u8; big.len()];
+    rofs.iread(f, 0, &mut buf).unwrap();
+    drop(rofs);
+    let fetched = nr_fetch.load(Ordering::SeqCst);
+    let present = lazy.nr_present().unwrap();
+    assert_eq!(fetched - present, 16);
+
+    let rofs = mount();
+    rofs.iread(f, 0, &mut buf).unwrap();
+    assert_eq!(buf, big);
+    drop(rofs);
+    assert_eq!(nr_fetch.load(Ordering::SeqCst), fetched + 16);
+    assert_eq!(lazy.nr_present().unwrap(), present + 16);
+
+    fs::remove_dir_all(&base).unwrap();
+}
+
+#[test]
+fn ro_mount_mmap_storage() {
+    let base = temp_dir("mmap");
+    let src = base.join("src");
+    fs::create_dir_all(src.join("dir")).unwrap();
+    let big: Vec<u8> = (0..5 * BLK_SZ + 77).map(|i| (i % 253) as u8).collect();
+    fs::write(src.join("dir/big"), &big).unwrap();
+    fs::write(src.join("small"), b"small").unwrap();
+
+    for key in [None, Some([7u8; 16])] {
+        let mode = build_ro(&src, &base, "ro.image", key);
+        let rofs = eccfs::ro::ROFS::new(
+            mode, DEFAULT_CACHE_CAP, None, 0,
+            std::sync::Arc::new(eccfs::MmapStorage::new(&base.join("ro.image"), BLK_SZ).unwrap()),
+        ).unwrap().verified().unwrap();
+        let dir = rofs.lookup(ROOT_INODE_ID, "dir").unwrap().unwrap();
+        let iid = rofs.lookup(dir, "big").unwrap().unwrap();
+        let mut buf = vec![0u8; big.len()];
+        assert_eq!(rofs.iread(iid, 0, &mut buf).unwrap(), big.len());
+        assert_eq!(buf, big);
+        let iid = rofs.lookup(ROOT_INODE_ID, "small").unwrap().unwrap();
+        let mut buf = [0u8; 5];
+        assert_eq!(rofs.iread(iid, 0, &mut buf).unwrap(), 5);
+        assert_eq!(&buf, b"small");
+        drop(rofs);
+
+        // positions from a broken idx don't wrap around
+        let mmap = eccfs::MmapStorage::new(&base.join("ro.image"), BLK_SZ).unwrap();
+        assert!(mmap.read_blk(0).is_ok());
+        assert!(matches!(mmap.read_blk(u64::MAX / BLK_SZ as u64 + 1), Err(FsError::UnexpectedEof)));
+        let mut blks = vec![0u8; 2 * BLK_SZ];
+        assert!(matches!(mmap.read_blks_to(u64::MAX, &mut blks), Err(FsError::UnexpectedEof)));
+        drop(mmap);
+        fs::remove_file(base.join("ro.image")).unwrap();
+    }
+
+    fs::remove_dir_all(&base).unwrap();
+}
+
+#[test]
+fn rw_bounded_open_files() {
+    let base = temp_dir("bounded-open");
+    let src = base.join("src");
+    fs::create_dir(&src).unwrap();
+    for i in 0..20u8 {
+        fs::write(src.join(format!("f{}", i)), vec![i; BLK_SZ * 2]).unwrap();
+    }
+    let image = base.join("rw.image");
+    let mode = build_rw(&src, &image, None);
+
+    let dev = std::sync::Arc::new(FileDevice::with_max_open(&image, BLK_SZ, 4).unwrap());
+    let rwfs = eccfs::rw::RWFS::new(
+        false, mode, None, 0, dev.clone(), &SYSTEM_TIME,
+    ).unwrap();
+    // twice, so that closed files are reopened
+    for round in 0..2u8 {
+        for i in 0..20u8 {
+            let iid = rwfs.lookup(ROOT_INODE_ID, &format!("f{}", i)).unwrap().unwrap();
+            let mut buf = vec![0u8; BLK_SZ * 2];
+            assert_eq!(rwfs.iread(iid, 0, &mut buf).unwrap(), buf.len());
+            assert!(buf.iter().all(|b| *b == i + round));
+            rwfs.iwrite(iid, 0, &vec![i + 1; BLK_SZ * 2]).unwrap();
+            rwfs.isync_data(iid).unwrap();
+            assert!(dev.nr_open().unwrap().unwrap() <= 4);
+        }
+    }
+    let mode = rwfs.destroy().unwrap();
+    assert!(dev.nr_open().unwrap().unwrap() <= 4);
+    drop(rwfs);
+
+    let rwfs = mount_rw(&image, mode);
+    for i in 0..20u8 {
+        let iid = rwfs.lookup(ROOT_INODE_ID, &format!("f{}", i)).unwrap().unwrap();
+        let mut buf = vec![0u8; BLK_SZ * 2];
+        assert_eq!(rwfs.iread(iid, 0, &mut buf).unwrap(), buf.len());
+        assert!(buf.iter().all(|b| *b == i + 1));
+    }
+    drop(rwfs);
+
+    assert!(matches!(FileDevice::with_max_open(&image, BLK_SZ, 0), Err(FsError::InvalidParameter)));
+    fs::remove_dir_all(&base).unwrap();
+}
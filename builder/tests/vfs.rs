@@ -0,0 +1,676 @@
+//! tests of the FileSystem interface on both fs types
+mod common;
+
+use common::*;
+use eccfs::*;
+use std::fs;
+use std::path::Path;
+
+#[test]
+fn export_key_material_decrypts_blocks() {
+    use eccfs::htree::{mht, HTREE_ROOT_BLK_PHY_POS};
+    use eccfs::crypto::crypto_in;
+
+    let base = temp_dir("export-keys");
+    let src = base.join("src");
+    fs::create_dir(&src).unwrap();
+    let content: Vec<u8> = (0..2 * BLK_SZ).map(|i| i as u8).collect();
+    fs::write(src.join("f"), &content).unwrap();
+    let key = [7u8; 16];
+
+    // rw: decrypt the first data block of f by its exported root key
+    let image = base.join("rw.image");
+    let mode = build_rw(&src, &image, Some(key));
+    let rwfs = mount_rw(&image, mode);
+    let f = rwfs.lookup(ROOT_INODE_ID, "f").unwrap().unwrap();
+    let km = rwfs.export_key_material().unwrap();
+    assert!(km.contains_key(&KeyOwner::InodeTable));
+    assert!(km.contains_key(&KeyOwner::Inode(ROOT_INODE_ID)));
+    drop(rwfs);
+
+    let data = FileDevice::new(&image, BLK_SZ).unwrap()
+        .open_rw_storage(&hex::encode_upper(eccfs::rw::inode::iid_hash(f).unwrap())).unwrap();
+    let mut root = data.read_blk(HTREE_ROOT_BLK_PHY_POS).unwrap();
+    crypto_in(&mut root, CryptoHint::from_key_entry(
+        km[&KeyOwner::Inode(f)], true, HTREE_ROOT_BLK_PHY_POS,
+    )).unwrap();
+    let phy = mht::Shape::full(BLK_SZ).logi2phy(0);
+    let mut blk = data.read_blk(phy).unwrap();
+    crypto_in(&mut blk, CryptoHint::from_key_entry(
+        mht::Shape::full(BLK_SZ).get_ke(&root, mht::Data(0)), true, phy,
+    )).unwrap();
+    assert_eq!(&blk[..], &content[..BLK_SZ]);
+
+    // ro: decrypt the root block of inode table
+    let mode = build_ro(&src, &base, "ro.image", Some(key));
+    let storage = FileStorage::new(&base.join("ro.image"), false, BLK_SZ).unwrap();
+    let rofs = mount_ro(&base.join("ro.image"), mode.clone());
+    let km = rofs.export_key_material().unwrap();
+    assert_eq!(km[&KeyOwner::SuperBlock], mode.clone().into_key_entry());
+    assert_eq!(km.keys().filter(|k| matches!(k, KeyOwner::Inode(_))).count(), 1);
+    let sb_pos = eccfs::ro::superblock::SUPERBLOCK_POS;
+    let mut sb = storage.read_blk(sb_pos).unwrap();
+    crypto_in(&mut sb, CryptoHint::from_fsmode(mode, sb_pos)).unwrap();
+    let off = std::mem::offset_of!(eccfs::ro::superblock::DSuperBlock, inode_tbl_start);
+    let itbl_start = u64::from_ne_bytes(sb[off..off + 8].try_into().unwrap());
+    let mut blk = storage.read_blk(itbl_start + HTREE_ROOT_BLK_PHY_POS).unwrap();
+    crypto_in(&mut blk, CryptoHint::from_key_entry(
+        km[&KeyOwner::InodeTable], true, HTREE_ROOT_BLK_PHY_POS,
+    )).unwrap();
+    drop(rofs);
+
+    // nothing to export without encryption
+    let plain = build_rw(&src, &base.join("plain"), None);
+    let rwfs = mount_rw(&base.join("plain"), plain);
+    assert!(matches!(rwfs.export_key_material(), Err(FsError::NotSupported)));
+    drop(rwfs);
+
+    fs::remove_dir_all(&base).unwrap();
+}
+
+#[test]
+fn walk_skip_and_stop() {
+    let base = temp_dir("walk");
+    let src = base.join("src");
+    fs::create_dir_all(src.join("a/deep")).unwrap();
+    fs::create_dir(src.join("b")).unwrap();
+    for p in ["a/x", "a/deep/y", "b/z", "c"] {
+        fs::write(src.join(p), b"").unwrap();
+    }
+    let mode = build_rw(&src, &base.join("rw.image"), None);
+    let rwfs = mount_rw(&base.join("rw.image"), mode);
+    // a hard link is visited only once
+    let c = rwfs.lookup(ROOT_INODE_ID, "c").unwrap().unwrap();
+    let b = rwfs.lookup(ROOT_INODE_ID, "b").unwrap().unwrap();
+    rwfs.link(b, "c_link", c).unwrap();
+
+    let mut paths = Vec::new();
+    rwfs.walk(ROOT_INODE_ID, &mut |e| {
+        paths.push(e.path);
+        Ok(WalkControl::Continue)
+    }).unwrap();
+    paths.sort();
+    assert_eq!(paths.len(), 8);
+    assert_eq!(paths[0], "");
+    assert!(paths.contains(&"a/deep/y".to_string()));
+
+    let mut paths = Vec::new();
+    rwfs.walk(ROOT_INODE_ID, &mut |e| {
+        let skip = e.path == "a";
+        paths.push(e.path);
+        Ok(if skip { WalkControl::SkipSubtree } else { WalkControl::Continue })
+    }).unwrap();
+    assert_eq!(paths.len(), 5);
+    assert!(paths.iter().all(|p| !p.starts_with("a/")));
+
+    let mut nr = 0;
+    rwfs.walk(ROOT_INODE_ID, &mut |_| {
+        nr += 1;
+        Ok(if nr == 3 { WalkControl::Stop } else { WalkControl::Continue })
+    }).unwrap();
+    assert_eq!(nr, 3);
+    drop(rwfs);
+
+    fs::remove_dir_all(&base).unwrap();
+}
+
+#[test]
+fn ke_sz_round_trip() {
+    use eccfs::ro::superblock::{DSuperBlock, SUPERBLOCK_POS};
+    use eccfs::htree::mht::Shape;
+    use eccfs::crypto::{KEY_ENTRY_SZ, SHORT_KEY_ENTRY_SZ};
+    use std::os::unix::fs::FileExt;
+
+    assert_eq!(Shape::full(BLK_SZ).entry_per_blk() as usize, BLK_SZ / KEY_ENTRY_SZ);
+    assert_eq!(Shape::short(BLK_SZ).data_per_blk(), 2 * Shape::full(BLK_SZ).data_per_blk());
+    let base = temp_dir("ke-sz");
+    let src = base.join("src");
+    fs::create_dir(&src).unwrap();
+    // spans more than one index block of either size
+    let content: Vec<u8> = (0..(2 * Shape::short(BLK_SZ).data_per_blk() as usize + 3) * BLK_SZ)
+        .map(|i| (i / BLK_SZ + i) as u8).collect();
+    fs::write(src.join("f"), &content).unwrap();
+
+    // ro: entry size is chosen per image and recorded in superblock
+    let full = build_ro(&src, &base, "full.image", None);
+    let short = eccfs_builder::ro::build_from_dir_with_short_ke(
+        &src, &base, Path::new("short.image"), &base,
+    ).unwrap();
+    let len = |name: &str| fs::metadata(base.join(name)).unwrap().len();
+    assert!(len("short.image") < len("full.image"));
+    for (name, mode, ke_sz, hash) in [
+        ("full.image", full, KEY_ENTRY_SZ, HashAlgo::Sha3_256),
+        ("short.image", short, SHORT_KEY_ENTRY_SZ, HashAlgo::Sha3_256Trunc128),
+    ] {
+        let image = base.join(name);
+        let mut blk = [0u8; BLK_SZ];
+        fs::File::open(&image).unwrap()
+            .read_exact_at(&mut blk, SUPERBLOCK_POS * BLK_SZ as u64).unwrap();
+        let off = std::mem::offset_of!(DSuperBlock, ke_sz);
+        assert_eq!(u64::from_ne_bytes(blk[off..off+8].try_into().unwrap()), ke_sz as u64);
+        let rofs = mount_ro(&image, mode).verified().unwrap();
+        assert_eq!(rofs.capabilities().unwrap().hash, Some(hash));
+        let f = rofs.lookup(ROOT_INODE_ID, "f").unwrap().unwrap();
+        let mut buf = vec![0u8; content.len()];
+        assert_eq!(rofs.iread(f, 0, &mut buf).unwrap(), content.len());
+        assert_eq!(buf, content);
+        drop(rofs);
+    }
+
+    // rw: rewrite the tail and read it all back after remount
+    let image = base.join("rw.image");
+    let mode = build_rw(&src, &image, None);
+    let mount = |mode| mount_rw(&image, mode);
+    let rwfs = mount(mode);
+    let f = rwfs.lookup(ROOT_INODE_ID, "f").unwrap().unwrap();
+    let tail = vec![0x5au8; 2 * BLK_SZ];
+    rwfs.iwrite(f, content.len(), &tail).unwrap();
+    let mode = rwfs.fsync().unwrap();
+    drop(rwfs);
+    let rwfs = mount(mode);
+    let mut buf = vec![0u8; content.len() + tail.len()];
+    assert_eq!(rwfs.iread(f, 0, &mut buf).unwrap(), buf.len());
+    assert_eq!(&buf[..content.len()], &content[..]);
+    assert_eq!(&buf[content.len()..], &tail[..]);
+    drop(rwfs);
+
+    fs::remove_dir_all(&base).unwrap();
+}
+
+#[test]
+fn is_inline_by_size() {
+    use eccfs::rw::disk::REG_INLINE_DATA_MAX;
+    use eccfs::ro::disk::DI_REG_INLINE_DATA_MAX;
+
+    let base = temp_dir("inline");
+    let src = base.join("src");
+    fs::create_dir(&src).unwrap();
+    fs::write(src.join("tiny"), b"tiny").unwrap();
+    fs::write(src.join("large"), vec![1u8; 2 * BLK_SZ]).unwrap();
+    fs::write(src.join("edge"), vec![2u8; DI_REG_INLINE_DATA_MAX as usize]).unwrap();
+
+    let mode = build_ro(&src, &base, "ro.image", None);
+    let rofs = mount_ro(&base.join("ro.image"), mode);
+    let tiny = rofs.lookup(ROOT_INODE_ID, "tiny").unwrap().unwrap();
+    let large = rofs.lookup(ROOT_INODE_ID, "large").unwrap().unwrap();
+    let edge = rofs.lookup(ROOT_INODE_ID, "edge").unwrap().unwrap();
+    assert!(rofs.is_inline(tiny).unwrap());
+    assert!(rofs.is_inline(edge).unwrap());
+    assert!(!rofs.is_inline(large).unwrap());
+    drop(rofs);
+
+    let image = base.join("rw.image");
+    let mode = build_rw(&src, &image, None);
+    let rwfs = mount_rw(&image, mode);
+    let tiny = rwfs.lookup(ROOT_INODE_ID, "tiny").unwrap().unwrap();
+    let large = rwfs.lookup(ROOT_INODE_ID, "large").unwrap().unwrap();
+    assert!(rwfs.is_inline(tiny).unwrap());
+    assert!(!rwfs.is_inline(large).unwrap());
+
+    // grow tiny just over the threshold
+    rwfs.iwrite(tiny, REG_INLINE_DATA_MAX, b"x").unwrap();
+    assert!(!rwfs.is_inline(tiny).unwrap());
+    let mode = rwfs.fsync().unwrap();
+    drop(rwfs);
+    let rwfs = mount_rw(&image, mode);
+    assert!(!rwfs.is_inline(tiny).unwrap());
+    drop(rwfs);
+
+    fs::remove_dir_all(&base).unwrap();
+}
+
+#[test]
+fn inode_reader_read_seek() {
+    use std::io::{BufRead, Read, Seek, SeekFrom};
+
+    let base = temp_dir("inode-reader");
+    let src = base.join("src");
+    fs::create_dir(&src).unwrap();
+    let content: Vec<u8> = (0..3 * BLK_SZ + 100).map(|i| (i % 251) as u8).collect();
+    fs::write(src.join("file"), &content).unwrap();
+    let lines: String = (0..1000).map(|i| format!("line {}\n", i)).collect();
+    fs::write(src.join("lines"), &lines).unwrap();
+    let mode = build_ro(&src, &base, "ro.image", None);
+    let rofs: std::sync::Arc<dyn FileSystem> = std::sync::Arc::new(mount_ro(&base.join("ro.image"), mode));
+
+    let iid = rofs.lookup(ROOT_INODE_ID, "file").unwrap().unwrap();
+    let mut r = InodeReader::new(rofs.clone(), iid);
+    let mut all = Vec::new();
+    assert_eq!(r.read_to_end(&mut all).unwrap(), content.len());
+    assert_eq!(all, content);
+    assert_eq!(r.read(&mut [0u8; 8]).unwrap(), 0);
+
+    let mut buf = [0u8; 10];
+    assert_eq!(r.seek(SeekFrom::End(-10)).unwrap(), content.len() as u64 - 10);
+    r.read_exact(&mut buf).unwrap();
+    assert_eq!(buf, content[content.len() - 10..]);
+    assert_eq!(r.seek(SeekFrom::Current(-(BLK_SZ as i64))).unwrap(), 2 * BLK_SZ as u64 + 100);
+    r.read_exact(&mut buf).unwrap();
+    assert_eq!(buf, content[2 * BLK_SZ + 100..][..10]);
+    assert!(r.seek(SeekFrom::Current(-(4 * BLK_SZ as i64))).is_err());
+    assert_eq!(r.seek(SeekFrom::Start(10 * BLK_SZ as u64)).unwrap(), 10 * BLK_SZ as u64);
+    assert_eq!(r.read(&mut buf).unwrap(), 0);
+
+    let iid = rofs.lookup(ROOT_INODE_ID, "lines").unwrap().unwrap();
+    let r = InodeReader::new(rofs.clone(), iid);
+    let read: Vec<String> = r.lines().map(|l| l.unwrap()).collect();
+    assert_eq!(read.len(), 1000);
+    assert_eq!(read[999], "line 999");
+    drop(rofs);
+
+    fs::remove_dir_all(&base).unwrap();
+}
+
+#[test]
+fn special_perm_bits_round_trip() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let base = temp_dir("special-perm");
+    let src = base.join("src");
+    fs::create_dir(&src).unwrap();
+    fs::write(src.join("suid"), b"#!/bin/sh\n").unwrap();
+    fs::set_permissions(src.join("suid"), fs::Permissions::from_mode(0o4755)).unwrap();
+    fs::write(src.join("sgid"), b"").unwrap();
+    fs::set_permissions(src.join("sgid"), fs::Permissions::from_mode(0o2711)).unwrap();
+    fs::create_dir(src.join("tmp")).unwrap();
+    fs::set_permissions(src.join("tmp"), fs::Permissions::from_mode(0o1777)).unwrap();
+    let expected = [("suid", 0o4755), ("sgid", 0o2711), ("tmp", 0o1777)];
+
+    let ro_mode = build_ro(&src, &base, "ro.image", None);
+    let rofs = mount_ro(&base.join("ro.image"), ro_mode);
+    for (name, perm) in expected {
+        let iid = rofs.lookup(ROOT_INODE_ID, name).unwrap().unwrap();
+        assert_eq!(rofs.get_meta(iid).unwrap().perm.bits(), perm);
+    }
+    drop(rofs);
+
+    let mode = build_rw(&src, &base.join("rw.image"), None);
+    let mount = |mode: FSMode| mount_rw(&base.join("rw.image"), mode);
+    let rwfs = mount(mode);
+    for (name, perm) in expected {
+        let iid = rwfs.lookup(ROOT_INODE_ID, name).unwrap().unwrap();
+        assert_eq!(rwfs.get_meta(iid).unwrap().perm.bits(), perm);
+    }
+    let suid = rwfs.lookup(ROOT_INODE_ID, "suid").unwrap().unwrap();
+    let perm = FilePerm::from_bits(0o6750).unwrap();
+    assert!(perm.contains(FilePerm::S_ISUID | FilePerm::S_ISGID));
+    rwfs.set_meta(suid, SetMetadata::Permission(perm)).unwrap();
+    let mode = rwfs.destroy().unwrap();
+    drop(rwfs);
+
+    let rwfs = mount(mode);
+    assert_eq!(rwfs.get_meta(suid).unwrap().perm, perm);
+    drop(rwfs);
+
+    fs::remove_dir_all(&base).unwrap();
+}
+
+#[test]
+fn lookup_many_matches_lookup() {
+    let base = temp_dir("lookup-many");
+    let src = base.join("src");
+    fs::create_dir(&src).unwrap();
+    for i in 0..100 {
+        fs::write(src.join(format!("lib_{}.so", i)), b"").unwrap();
+    }
+    let mut names: Vec<_> = (0..100).step_by(7).map(|i| format!("lib_{}.so", i)).collect();
+    names.push("lib_missing.so".into());
+    names.push("lib_3.so".into());
+    let names: Vec<&str> = names.iter().map(|n| n.as_str()).collect();
+
+    let check = |fs: &dyn FileSystem| {
+        let each: Vec<_> = names.iter()
+            .map(|name| fs.lookup(ROOT_INODE_ID, name).unwrap()).collect();
+        assert_eq!(fs.lookup_many(ROOT_INODE_ID, &names).unwrap(), each);
+        assert!(each[..each.len() - 2].iter().all(|r| r.is_some()));
+        assert_eq!(each[each.len() - 2], None);
+    };
+
+    let mode = build_ro(&src, &base, "ro.image", None);
+    let rofs = mount_ro(&base.join("ro.image"), mode);
+    check(&rofs);
+
+    let image = base.join("rw.image");
+    let mode = build_rw(&src, &image, None);
+    let rwfs = eccfs::rw::RWFS::new(
+        false, mode, None, 16,
+        std::sync::Arc::new(FileDevice::new(&image, BLK_SZ).unwrap()),
+        &SYSTEM_TIME,
+    ).unwrap();
+    check(&rwfs);
+    // again with some names in de cache
+    check(&rwfs);
+    drop(rwfs);
+
+    fs::remove_dir_all(&base).unwrap();
+}
+
+#[test]
+fn inode_tbl_offset_matches_image() {
+    use eccfs::htree::mht;
+    use eccfs::ro::disk::INODE_ALIGN;
+
+    // bytes at logi offset of htree data, for images in integrity only mode
+    fn htree_data_at(file: &Path, start_blk: u64, logi: u64, to: &mut [u8]) {
+        let blk = start_blk + mht::Shape::full(BLK_SZ).logi2phy(logi / BLK_SZ as u64);
+        let raw = fs::read(file).unwrap();
+        let start = (blk2byte!(blk, BLK_SZ) + logi % BLK_SZ as u64) as usize;
+        to.copy_from_slice(&raw[start..start + to.len()]);
+    }
+
+    fn check_base(meta: &Metadata, raw: &[u8], mode: u16, size: u64, mtime: u32) {
+        assert_eq!(raw.len() % INODE_ALIGN, 0);
+        assert_eq!(mode, get_mode(meta.ftype, &meta.perm));
+        assert_eq!(mtime, meta.mtime);
+        if meta.ftype == FileType::Reg {
+            assert_eq!(size, meta.size);
+        }
+    }
+
+    let base = temp_dir("inode-disk-offset");
+    let src = base.join("src");
+    fs::create_dir(&src).unwrap();
+    fs::write(src.join("small"), b"data").unwrap();
+    fs::write(src.join("big"), vec![3u8; BLK_SZ * 3]).unwrap();
+    fs::create_dir(src.join("d")).unwrap();
+    std::os::unix::fs::symlink("small", src.join("lnk")).unwrap();
+    let names = ["small", "big", "d", "lnk"];
+
+    let mode = build_ro(&src, &base, "ro.image", None);
+    let ro_image = base.join("ro.image");
+    let rofs = mount_ro(&ro_image, mode);
+    let sb_blk = FileStorage::new(&ro_image, false, BLK_SZ).unwrap()
+        .read_blk(eccfs::ro::superblock::SUPERBLOCK_POS).unwrap();
+    let dsb = unsafe {
+        &*(sb_blk.as_ptr() as *const eccfs::ro::superblock::DSuperBlock)
+    };
+    for name in names {
+        let iid = rofs.lookup(ROOT_INODE_ID, name).unwrap().unwrap();
+        let (offset, size) = rofs.inode_tbl_offset(iid).unwrap();
+        let mut raw = vec![0u8; size];
+        htree_data_at(&ro_image, dsb.inode_tbl_start, offset, &mut raw);
+        let di = unsafe { &*(raw.as_ptr() as *const eccfs::ro::disk::DInodeBase) };
+        check_base(&rofs.get_meta(iid).unwrap(), &raw, di.mode, di.size, di.mtime);
+    }
+    drop(rofs);
+
+    let rw_image = base.join("rw.image");
+    let mode = build_rw(&src, &rw_image, None);
+    let rwfs = mount_rw(&rw_image, mode);
+    let sb_blk = FileStorage::new(&rw_image.join(eccfs::rw::SB_FILE_NAME), false, BLK_SZ)
+        .unwrap().read_blk(0).unwrap();
+    let sb = eccfs::rw::superblock::SuperBlock::new(&sb_blk).unwrap();
+    let itbl = rw_image.join(hex::encode_upper(sb.itbl_name));
+    for name in names {
+        let iid = rwfs.lookup(ROOT_INODE_ID, name).unwrap().unwrap();
+        let (offset, size) = rwfs.inode_tbl_offset(iid).unwrap();
+        assert_eq!(size, sb.inode_sz);
+        let mut raw = vec![0u8; size];
+        htree_data_at(&itbl, 0, offset, &mut raw);
+        let di = unsafe { &*(raw.as_ptr() as *const eccfs::rw::disk::DInodeBase) };
+        check_base(&rwfs.get_meta(iid).unwrap(), &raw, di.mode, di.size, di.mtime);
+    }
+    assert!(matches!(rwfs.inode_tbl_offset(1 << 20), Err(FsError::NotFound)));
+    drop(rwfs);
+
+    fs::remove_dir_all(&base).unwrap();
+}
+
+#[test]
+fn resolve_path_normalizes() {
+    fn check(fs: &dyn FileSystem) {
+        let a = fs.lookup(ROOT_INODE_ID, "a").unwrap().unwrap();
+        let b = fs.lookup(a, "b").unwrap().unwrap();
+        let c = fs.lookup(a, "c").unwrap().unwrap();
+        let f = fs.lookup(c, "f").unwrap().unwrap();
+
+        assert_eq!(fs.resolve_path(ROOT_INODE_ID, "a/./b/../c/").unwrap(), c);
+        assert_eq!(fs.resolve_path(ROOT_INODE_ID, "a//c/f").unwrap(), f);
+        assert_eq!(fs.resolve_path(b, "/a/c").unwrap(), c);
+        assert_eq!(fs.resolve_path(b, "../c/./f").unwrap(), f);
+        assert_eq!(fs.resolve_path(b, "").unwrap(), b);
+        assert_eq!(fs.resolve_path(b, ".").unwrap(), b);
+        assert_eq!(fs.resolve_path(b, "../..").unwrap(), ROOT_INODE_ID);
+        // root is its own parent
+        assert_eq!(fs.resolve_path(ROOT_INODE_ID, "../a/..").unwrap(), ROOT_INODE_ID);
+        assert_eq!(fs.resolve_path(ROOT_INODE_ID, "/").unwrap(), ROOT_INODE_ID);
+
+        assert!(matches!(fs.resolve_path(c, "f/"), Err(FsError::NotADirectory)));
+        assert!(matches!(fs.resolve_path(c, "f/."), Err(FsError::NotADirectory)));
+        assert!(matches!(fs.resolve_path(c, "f/.."), Err(FsError::NotADirectory)));
+        assert!(matches!(fs.resolve_path(a, "missing/.."), Err(FsError::NotFound)));
+    }
+
+    let base = temp_dir("resolve-path");
+    let src = base.join("src");
+    fs::create_dir_all(src.join("a/b")).unwrap();
+    fs::create_dir_all(src.join("a/c")).unwrap();
+    fs::write(src.join("a/c/f"), b"data").unwrap();
+
+    let mode = build_ro(&src, &base, "ro.image", None);
+    let rofs = mount_ro(&base.join("ro.image"), mode);
+    check(&rofs);
+    drop(rofs);
+
+    let image = base.join("rw.image");
+    let mode = build_rw(&src, &image, None);
+    let rwfs = mount_rw(&image, mode);
+    check(&rwfs);
+    drop(rwfs);
+
+    fs::remove_dir_all(&base).unwrap();
+}
+
+#[test]
+fn attr_cache_invalidate() {
+    let base = temp_dir("attr_cache");
+    let src = base.join("src");
+    fs::create_dir(&src).unwrap();
+    fs::write(src.join("f"), b"f").unwrap();
+    let image = base.join("rw.image");
+    let mode = build_rw(&src, &image, None);
+    let rwfs = mount_rw(&image, mode);
+    let f = rwfs.lookup(ROOT_INODE_ID, "f").unwrap().unwrap();
+
+    let attrs = AttrCache::new(16).unwrap();
+    assert_eq!(attrs.getattr(&rwfs, f).unwrap().size, 1);
+    rwfs.iwrite(f, 0, b"longer").unwrap();
+    // still served from cache until invalidated
+    assert_eq!(attrs.getattr(&rwfs, f).unwrap().size, 1);
+    attrs.invalidate(f);
+    assert_eq!(attrs.getattr(&rwfs, f).unwrap().size, 6);
+
+    rwfs.set_meta(f, SetMetadata::Size(2)).unwrap();
+    attrs.invalidate(f);
+    assert_eq!(attrs.getattr(&rwfs, f).unwrap().size, 2);
+
+    rwfs.link(ROOT_INODE_ID, "g", f).unwrap();
+    attrs.invalidate(f);
+    assert_eq!(attrs.getattr(&rwfs, f).unwrap().nlinks, 2);
+    rwfs.unlink(ROOT_INODE_ID, "f").unwrap();
+    attrs.invalidate(f);
+    assert_eq!(attrs.getattr(&rwfs, f).unwrap(), rwfs.get_meta(f).unwrap());
+    assert_eq!(attrs.getattr(&rwfs, f).unwrap().nlinks, 1);
+    assert_eq!(attrs.generation(), 4);
+
+    // reads change atime as well
+    rwfs.set_meta(f, SetMetadata::Atime(0)).unwrap();
+    attrs.invalidate(f);
+    assert_eq!(attrs.getattr(&rwfs, f).unwrap().atime, 0);
+    rwfs.iread(f, 0, &mut [0u8; 2]).unwrap();
+    attrs.invalidate(f);
+    assert_ne!(attrs.getattr(&rwfs, f).unwrap().atime, 0);
+    drop(rwfs);
+
+    fs::remove_dir_all(&base).unwrap();
+}
+
+#[test]
+fn read_dirents_ro_rw_agree() {
+    let base = temp_dir("read_dirents");
+    let src = base.join("src");
+    // a small dir with inline entries in ro, and a big one with external entries
+    fs::create_dir_all(src.join("small")).unwrap();
+    fs::create_dir_all(src.join("big")).unwrap();
+    fs::write(src.join("small/f"), b"").unwrap();
+    fs::create_dir(src.join("small/d")).unwrap();
+    std::os::unix::fs::symlink("f", src.join("small/l")).unwrap();
+    for i in 0..100 {
+        fs::write(src.join("big").join(format!("entry_{}", i)), b"").unwrap();
+    }
+
+    let mode = build_ro(&src, &base, "ro.image", None);
+    let rofs = mount_ro(&base.join("ro.image"), mode);
+    let image = base.join("rw.image");
+    let mode = build_rw(&src, &image, None);
+    let rwfs = mount_rw(&image, mode);
+
+    // inode ids and order differ between the two, names and types do not
+    let entries = |fs: &dyn FileSystem, dir: &str| {
+        let iid = fs.lookup(ROOT_INODE_ID, dir).unwrap().unwrap();
+        let all = fs.read_dirents(iid, 0, 0).unwrap();
+        assert_eq!(all.len(), fs.listdir(iid, 0, 0).unwrap().len());
+        let mut dots: Vec<_> = all[..2].iter().map(|de| (de.name.as_str(), de.ipos)).collect();
+        dots.sort();
+        assert_eq!(dots, [(".", iid), ("..", ROOT_INODE_ID)]);
+        // read in pieces gives the same
+        let pieces: Vec<_> = (0..all.len()).step_by(7)
+            .flat_map(|off| fs.read_dirents(iid, off, 7).unwrap()).collect();
+        assert_eq!(pieces, all);
+        let mut names: Vec<_> = all.into_iter().map(|de| (de.name, de.tp)).collect();
+        names.sort_by(|a, b| a.0.cmp(&b.0));
+        names
+    };
+    for dir in ["small", "big"] {
+        assert_eq!(entries(&rofs, dir), entries(&rwfs, dir));
+    }
+    assert_eq!(entries(&rofs, "big").len(), 102);
+    drop(rofs);
+    drop(rwfs);
+
+    fs::remove_dir_all(&base).unwrap();
+}
+
+#[test]
+fn image_uuid_and_generation() {
+    let base = temp_dir("image_id");
+    let src = base.join("src");
+    fs::create_dir(&src).unwrap();
+    fs::write(src.join("f"), b"v1").unwrap();
+
+    // rw: a destroy with changes bumps the generation, the uuid stays
+    let image = base.join("rw.image");
+    let mode = build_rw(&src, &image, None);
+    let mount = |mode: FSMode| mount_rw(&image, mode);
+    let mut rwfs = mount(mode);
+    let uuid = rwfs.image_uuid();
+    assert_ne!(uuid, ImageUuid::default());
+    assert_eq!(rwfs.image_generation(), 0);
+    for gen in 1..=2 {
+        let f = rwfs.lookup(ROOT_INODE_ID, "f").unwrap().unwrap();
+        rwfs.iwrite(f, 0, format!("v{}", gen + 1).as_bytes()).unwrap();
+        let mode = rwfs.destroy().unwrap();
+        drop(rwfs);
+        rwfs = mount(mode);
+        assert_eq!(rwfs.image_uuid(), uuid);
+        assert_eq!(rwfs.image_generation(), gen);
+    }
+    // nothing changed
+    let mode = rwfs.destroy().unwrap();
+    drop(rwfs);
+    assert_eq!(mount(mode).image_generation(), 2);
+
+    // ro: a new build gets a new uuid, unless it's given
+    let ro_image = |name: &str, id: Option<(ImageUuid, u64)>| {
+        let mode = match id {
+            Some((uuid, gen)) => eccfs_builder::ro::build_from_dir_with_image_id(
+                &src, &base, Path::new(name), &base, None, uuid, gen,
+            ).unwrap(),
+            None => build_ro(&src, &base, name, None),
+        };
+        let rofs = mount_ro(&base.join(name), mode);
+        (rofs.image_uuid(), rofs.image_generation())
+    };
+    let (a, gen_a) = ro_image("a.image", None);
+    let (b, _) = ro_image("b.image", None);
+    assert_ne!(a, b);
+    assert_eq!(gen_a, 0);
+    assert_eq!(ro_image("c.image", Some((a, gen_a + 1))), (a, 1));
+
+    fs::remove_dir_all(&base).unwrap();
+}
+
+#[test]
+fn listdir_plus_matches_get_meta() {
+    let base = temp_dir("listdir_plus");
+    let src = base.join("src");
+    fs::create_dir_all(src.join("sub")).unwrap();
+    for i in 0..40 {
+        fs::write(src.join(format!("f{}", i)), vec![i as u8; i * 50]).unwrap();
+    }
+    std::os::unix::fs::symlink("f1", src.join("lnk")).unwrap();
+    let check = |fs: &dyn FileSystem| {
+        for (offset, num) in [(0, 0), (5, 10)] {
+            let plain = fs.listdir(ROOT_INODE_ID, offset, num).unwrap();
+            let plus = fs.listdir_plus(ROOT_INODE_ID, offset, num).unwrap();
+            assert_eq!(plus.len(), plain.len());
+            for ((iid, name, tp, meta), exp) in plus.into_iter().zip(plain) {
+                assert_eq!((iid, name, tp), exp.clone());
+                assert_eq!(meta, fs.get_meta(exp.0).unwrap());
+            }
+        }
+    };
+
+    let mode = build_ro(&src, &base, "ro.image", None);
+    let rofs = mount_ro(&base.join("ro.image"), mode);
+    check(&rofs);
+    drop(rofs);
+
+    let image = base.join("rw.image");
+    let mode = build_rw(&src, &image, None);
+    let rwfs = mount_rw(&image, mode);
+    check(&rwfs);
+    drop(rwfs);
+
+    fs::remove_dir_all(&base).unwrap();
+}
+
+#[test]
+fn dir_stream_matches_listdir() {
+    let base = temp_dir("dir_stream");
+    let src = base.join("src");
+    fs::create_dir_all(src.join("sub")).unwrap();
+    for i in 0..3 * eccfs::rw::disk::dirent_per_blk(BLK_SZ) {
+        fs::write(src.join(format!("file_with_a_long_name_{}", i)), b"x").unwrap();
+    }
+    let check = |fs: &dyn FileSystem| {
+        let all = fs.listdir(ROOT_INODE_ID, 0, 0).unwrap();
+        let streamed: Vec<_> = fs.dir_stream(ROOT_INODE_ID).unwrap()
+            .collect::<FsResult<_>>().unwrap();
+        assert_eq!(streamed, all);
+
+        // dropped halfway, the dir is still there as it was
+        let mut stream = fs.dir_stream(ROOT_INODE_ID).unwrap();
+        for exp in all.iter().take(5) {
+            assert_eq!(&stream.next().unwrap().unwrap(), exp);
+        }
+        drop(stream);
+        assert_eq!(fs.listdir(ROOT_INODE_ID, 0, 0).unwrap(), all);
+
+        let file = fs.lookup(ROOT_INODE_ID, "file_with_a_long_name_0").unwrap().unwrap();
+        assert!(matches!(fs.dir_stream(file).err(), Some(FsError::NotADirectory)));
+    };
+
+    let mode = build_ro(&src, &base, "ro.image", None);
+    let rofs = mount_ro(&base.join("ro.image"), mode);
+    check(&rofs);
+    drop(rofs);
+
+    let image = base.join("rw.image");
+    let mode = build_rw(&src, &image, None);
+    let rwfs = mount_rw(&image, mode);
+    check(&rwfs);
+    rwfs.destroy().unwrap();
+
+    fs::remove_dir_all(&base).unwrap();
+}
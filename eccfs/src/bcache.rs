@@ -1,7 +1,10 @@
 use alloc::{
     sync::Arc,
     vec::Vec,
+    boxed::Box,
 };
+#[cfg(not(feature = "ro_cache_server"))]
+use alloc::vec;
 
 use crate::storage::ROStorage;
 use crate::*;
@@ -12,7 +15,7 @@ use crate::crypto::*;
 #[cfg(feature = "ro_cache_server")]
 use std::sync::mpsc::{self, Sender, Receiver};
 #[cfg(feature = "ro_cache_server")]
-use std::thread;
+use std::thread::{self, JoinHandle};
 
 #[cfg(feature = "ro_cache_server")]
 enum ROCacheReq {
@@ -20,7 +23,7 @@ enum ROCacheReq {
         pos: u64,
         cachable: bool,
         miss_hint: Option<CryptoHint>,
-        reply: Sender<FsResult<Option<Arc<Block>>>>,
+        reply: Sender<FsResult<Option<Arc<Box<Block>>>>>,
     },
     Flush,
     Abort,
@@ -31,15 +34,18 @@ enum ROCacheReq {
 #[derive(Clone)]
 pub struct ROCache {
     tx_to_server: Sender<ROCacheReq>,
-    // server_handle: Option<JoinHandle<()>>,
+    // taken by the first close, clones share it
+    server_handle: Arc<std::sync::Mutex<Option<JoinHandle<()>>>>,
 }
 
 pub const DEFAULT_CACHE_CAP: usize = 256;
+/// max number of adjacent missing blocks read from backend in one go
+pub const DEFAULT_READ_BATCH: usize = 16;
 
 #[cfg(feature = "ro_cache_server")]
 struct ROCacheServer {
     rx: Receiver<ROCacheReq>,
-    lru: Lru<u64, Block>,
+    lru: Lru<u64, Box<Block>>,
     _capacity: usize,
     backend: Box<dyn ROStorage>,
 }
@@ -56,7 +62,7 @@ impl ROCache {
 
         let mut server = ROCacheServer::new(backend, capacity, rx);
 
-        let _handle = thread::spawn(move || {
+        let handle = thread::spawn(move || {
             loop {
                 match server.rx.recv() {
                     Ok(req) => {
@@ -72,25 +78,40 @@ impl ROCache {
 
         Self {
             tx_to_server: tx,
-            // server_handle: Some(handle),
+            server_handle: Arc::new(std::sync::Mutex::new(Some(handle))),
         }
     }
 
-    pub fn get_blk_try(&mut self, pos: u64, cachable: bool) -> FsResult<Option<Arc<Block>>> {
+    pub fn get_blk_try(&mut self, pos: u64, cachable: bool) -> FsResult<Option<Arc<Box<Block>>>> {
         self.get_blk_impl(pos, cachable, None)
     }
 
     pub fn get_blk_hint(
         &mut self, pos: u64, cachable: bool, hint: CryptoHint
-    ) -> FsResult<Arc<Block>> {
+    ) -> FsResult<Arc<Box<Block>>> {
         self.get_blk_impl(pos, cachable, Some(hint))?.ok_or_else(
             || new_error!(FsError::NotFound)
         )
     }
 
+    // the server gets blocks one by one, no batching
+    pub fn set_max_batch(&mut self, _max_batch: usize) {}
+
+    pub fn get_blks_hint(
+        &mut self, pos: u64, cachable: bool, hints: &[CryptoHint],
+        mut f: impl FnMut(u64, &Block),
+    ) -> FsResult<()> {
+        for (i, hint) in hints.iter().enumerate() {
+            let bpos = pos + i as u64;
+            let ablk = self.get_blk_hint(bpos, cachable, hint.clone())?;
+            f(bpos, &ablk);
+        }
+        Ok(())
+    }
+
     fn get_blk_impl(
         &mut self, pos: u64, cachable: bool, hint: Option<CryptoHint>
-    ) -> FsResult<Option<Arc<Block>>> {
+    ) -> FsResult<Option<Arc<Box<Block>>>> {
         let (tx, rx) = mpsc::channel();
         self.tx_to_server.send(ROCacheReq::Get {
             pos,
@@ -111,6 +132,18 @@ impl ROCache {
     pub fn abort(&mut self) -> FsResult<()> {
         self.tx_to_server.send(ROCacheReq::Abort).map_err(|_| new_error!(FsError::ChannelSendError))
     }
+
+    // stop the server and wait for it, a panic in it is an error here
+    pub fn close(&mut self) -> FsResult<()> {
+        let handle = self.server_handle.lock().map_err(
+            |_| new_error!(FsError::MutexError)
+        )?.take();
+        if let Some(handle) = handle {
+            self.abort()?;
+            handle.join().map_err(|_| new_error!(FsError::ChannelRecvError))?;
+        }
+        Ok(())
+    }
 }
 
 #[cfg(feature = "ro_cache_server")]
@@ -171,13 +204,17 @@ impl ROCacheServer {
         }
     }
 
-    fn fetch_from_backend(&mut self, pos: u64, hint: CryptoHint) -> FsResult<Block> {
+    fn fetch_from_backend(&mut self, pos: u64, hint: CryptoHint) -> FsResult<Box<Block>> {
         let mut blk = self.backend.read_blk(pos)?;
-        crypto_in(&mut blk, hint)?;
+        let checked = hint.is_checked();
+        trace_err!(crypto_in(&mut blk, hint))?;
+        if checked {
+            self.backend.confirm_blk(pos)?;
+        }
         Ok(blk)
     }
 
-    fn cache_miss(&mut self, pos: u64, hint: CryptoHint) -> FsResult<Option<Arc<Block>>> {
+    fn cache_miss(&mut self, pos: u64, hint: CryptoHint) -> FsResult<Option<Arc<Box<Block>>>> {
         let blk = self.fetch_from_backend(pos, hint)?;
         let ablk = Arc::new(blk);
         // read only cache, no write back
@@ -189,11 +226,13 @@ impl ROCacheServer {
 
 #[cfg(not(feature = "ro_cache_server"))]
 pub struct ROCache {
-    lru: Lru<u64, Block>,
+    lru: Lru<u64, Box<Block>>,
     _capacity: usize,
     backend: Arc<dyn ROStorage>,
+    max_batch: usize,
 }
 
+#[cfg(not(feature = "ro_cache_server"))]
 impl ROCache {
     pub fn new(
         backend: Arc<dyn ROStorage>,
@@ -203,16 +242,66 @@ impl ROCache {
             lru: Lru::new(capacity),
             _capacity: capacity,
             backend,
+            max_batch: DEFAULT_READ_BATCH,
         }
     }
 
-    fn fetch_from_backend(&mut self, pos: u64, hint: CryptoHint) -> FsResult<Block> {
+    pub fn set_max_batch(&mut self, max_batch: usize) {
+        self.max_batch = max_batch.max(1);
+    }
+
+    // get blocks from pos on, one for each hint, and pass them to f in order.
+    // runs of adjacent missing blocks are read from backend in one call,
+    // at most max_batch blocks each
+    pub fn get_blks_hint(
+        &mut self, pos: u64, cachable: bool, hints: &[CryptoHint],
+        mut f: impl FnMut(u64, &Block),
+    ) -> FsResult<()> {
+        let is_cached = |lru: &Lru<u64, Box<Block>>, pos: u64| cachable && lru.contains(&pos);
+        let mut i = 0;
+        while i < hints.len() {
+            let bpos = pos + i as u64;
+            if is_cached(&self.lru, bpos) {
+                f(bpos, &self.lru.get(&bpos)?.unwrap());
+                i += 1;
+                continue;
+            }
+
+            let mut j = i + 1;
+            while j < hints.len() && j - i < self.max_batch && !is_cached(&self.lru, pos + j as u64) {
+                j += 1;
+            }
+            let bsize = self.backend.blk_sz();
+            let mut buf = vec![0u8; bsize * (j - i)];
+            self.backend.read_blks_to(bpos, &mut buf)?;
+            for (k, raw) in buf.chunks_exact(bsize).enumerate() {
+                let mut blk = Box::<Block>::from(raw);
+                trace_err!(crypto_in(&mut blk, hints[i + k].clone()))?;
+                if hints[i + k].is_checked() {
+                    self.backend.confirm_blk(bpos + k as u64)?;
+                }
+                f(bpos + k as u64, &blk);
+                if cachable {
+                    // read only cache, no write back
+                    let _ = self.lru.insert_and_get(bpos + k as u64, &Arc::new(blk))?;
+                }
+            }
+            i = j;
+        }
+        Ok(())
+    }
+
+    fn fetch_from_backend(&mut self, pos: u64, hint: CryptoHint) -> FsResult<Box<Block>> {
         let mut blk = self.backend.read_blk(pos)?;
-        crypto_in(&mut blk, hint)?;
+        let checked = hint.is_checked();
+        trace_err!(crypto_in(&mut blk, hint))?;
+        if checked {
+            self.backend.confirm_blk(pos)?;
+        }
         Ok(blk)
     }
 
-    fn cache_miss(&mut self, pos: u64, hint: CryptoHint) -> FsResult<Arc<Block>> {
+    fn cache_miss(&mut self, pos: u64, hint: CryptoHint) -> FsResult<Arc<Box<Block>>> {
         let blk = self.fetch_from_backend(pos, hint)?;
         let ablk = Arc::new(blk);
         // read only cache, no write back
@@ -220,7 +309,7 @@ impl ROCache {
         Ok(ablk)
     }
 
-    pub fn get_blk_try(&mut self, pos: u64, cachable: bool) -> FsResult<Option<Arc<Block>>> {
+    pub fn get_blk_try(&mut self, pos: u64, cachable: bool) -> FsResult<Option<Arc<Box<Block>>>> {
         if cachable {
             self.lru.get(&pos)
         } else {
@@ -230,7 +319,7 @@ impl ROCache {
 
     pub fn get_blk_hint(
         &mut self, pos: u64, cachable: bool, hint: CryptoHint
-    ) -> FsResult<Arc<Block>> {
+    ) -> FsResult<Arc<Box<Block>>> {
         if cachable {
             match self.lru.get(&pos) {
                 Ok(Some(ablk)) => Ok(ablk),
@@ -250,6 +339,11 @@ impl ROCache {
     pub fn flush(&mut self) -> FsResult<()> {
         self.lru.flush_no_wb()
     }
+
+    // nothing runs in background
+    pub fn close(&mut self) -> FsResult<()> {
+        self.flush()
+    }
 }
 
 pub fn rw_cache_cap_defaults(htree_len: usize) -> usize {
@@ -262,7 +356,7 @@ pub fn rw_cache_cap_defaults(htree_len: usize) -> usize {
     cap
 }
 
-pub type RWPayLoad = RwLock<Block>;
+pub type RWPayLoad = RwLock<Box<Block>>;
 pub struct RWCache {
     lru: Lru<u64, RWPayLoad>,
     capacity: usize,
@@ -287,8 +381,8 @@ impl RWCache {
     }
 
     pub fn insert_and_get(
-        &mut self, pos: u64, blk: Block
-    ) -> FsResult<(Arc<RWPayLoad>, Option<(u64, Block)>)> {
+        &mut self, pos: u64, blk: Box<Block>
+    ) -> FsResult<(Arc<RWPayLoad>, Option<(u64, Box<Block>)>)> {
         let apay = Arc::new(RwLock::new(blk));
         self.lru.insert_and_get(pos, &apay).map(
             |wb| (apay, wb.map(
@@ -301,8 +395,13 @@ impl RWCache {
         self.lru.mark_dirty(&pos)
     }
 
+    // put back a dirty block whose write back failed
+    pub fn put_back(&mut self, pos: u64, blk: Box<Block>) -> FsResult<()> {
+        self.lru.put_back(pos, RwLock::new(blk))
+    }
+
     #[allow(unused)]
-    pub fn flush(&mut self) -> FsResult<Vec<(u64, Block)>> {
+    pub fn flush(&mut self) -> FsResult<Vec<(u64, Box<Block>)>> {
         self.lru.flush_wb().map(
             |l| {
                 l.into_iter().map(
@@ -312,7 +411,7 @@ impl RWCache {
         )
     }
 
-    pub fn flush_key(&mut self, pos: u64) -> FsResult<Option<Block>> {
+    pub fn flush_key(&mut self, pos: u64) -> FsResult<Option<Box<Block>>> {
         Ok(self.lru.try_pop_key(&pos, false)?.map(
             |payload| payload.into_inner()
         ))
@@ -321,4 +420,9 @@ impl RWCache {
     pub fn flush_keys(&mut self) -> FsResult<Vec<u64>> {
         self.lru.flush_keys()
     }
+
+    // positions of all cached blocks, in use or not
+    pub fn keys(&self) -> Vec<u64> {
+        self.lru.keys()
+    }
 }
@@ -12,16 +12,32 @@ pub type MAC128 = [u8; 16];
 pub type Hash256 = [u8; 32];
 pub type KeyEntry = [u8; 32];
 
+/// bytes of a key entry packed in htree index blocks
 pub const KEY_ENTRY_SZ: usize = 32;
 
+/// bytes of a short key entry, which an integrity only ro image may be built with,
+/// it doubles the fanout of index blocks, block hashes in them are sha3-256
+/// truncated to 128 bits. that gives 64-bit collision resistance, which is fine
+/// against accidental corruption, but NOT against an adversary who can craft
+/// colliding blocks offline, use the full entry for untrusted storage
+pub const SHORT_KEY_ENTRY_SZ: usize = 16;
+
 pub fn crypto_in(blk: &mut Block, hint: CryptoHint) -> FsResult<()> {
     match hint {
         CryptoHint::Encrypted(key, mac, pos) => {
             aes_gcm_128_blk_dec(blk, &key, &mac, pos)?;
         }
-        CryptoHint::IntegrityOnly(hash) => {
-            sha3_256_blk_check(blk, &hash)?;
+        CryptoHint::IntegrityOnly(hash, pos) => {
+            sha3_256_blk_check(blk, &hash, pos)?;
+        }
+        CryptoHint::IntegrityOnlyShort(hash, pos) => {
+            let actual = sha3_256_blk_short(blk)?;
+            if actual != hash {
+                let sz = SHORT_KEY_ENTRY_SZ;
+                return Err(blk_check_failed(pos, &hash[..sz], Some(&actual[..sz])));
+            }
         }
+        CryptoHint::Unchecked => {}
     }
     Ok(())
 }
@@ -37,10 +53,23 @@ pub fn crypto_out(blk: &mut Block, encrypted: Option<Key128>, pos: u64) -> FsRes
     Ok(mode)
 }
 
+/// same as [`crypto_out`] in integrity only mode, for a short key entry,
+/// see [`SHORT_KEY_ENTRY_SZ`]
+pub fn crypto_out_short(blk: &Block) -> FsResult<FSMode> {
+    Ok(FSMode::IntegrityOnly(sha3_256_blk_short(blk)?))
+}
+
 pub fn sha3_256_blk(input: &Block) -> FsResult<Hash256> {
     sha3_256_any(input)
 }
 
+// block hash as kept in a short key entry, bytes beyond it are zero
+fn sha3_256_blk_short(input: &Block) -> FsResult<Hash256> {
+    let mut hash = sha3_256_blk(input)?;
+    hash[SHORT_KEY_ENTRY_SZ..].fill(0);
+    Ok(hash)
+}
+
 pub fn sha3_256_any(input: &[u8]) -> FsResult<Hash256> {
     let mut hasher = Sha3_256::new();
 
@@ -53,8 +82,53 @@ pub fn sha3_256_any(input: &[u8]) -> FsResult<Hash256> {
     Ok(hash)
 }
 
-pub fn sha3_256_blk_check(input: &Block, hash: &Hash256) -> FsResult<()> {
-    sha3_256_any_check(input, hash)
+/// hash of a byte stream fed in pieces
+pub struct StreamHasher {
+    hasher: Sha3_256,
+    algo: HashAlgo,
+}
+
+impl StreamHasher {
+    pub fn new(algo: HashAlgo) -> Self {
+        Self {
+            hasher: Sha3_256::new(),
+            algo,
+        }
+    }
+
+    pub fn update(&mut self, input: &[u8]) {
+        self.hasher.update(input);
+    }
+
+    pub fn finalize(self) -> Hash256 {
+        let mut hash: Hash256 = self.hasher.finalize().into();
+        if self.algo == HashAlgo::Sha3_256Trunc128 {
+            hash[16..].fill(0);
+        }
+        hash
+    }
+}
+
+pub fn sha3_256_blk_check(input: &Block, hash: &Hash256, pos: u64) -> FsResult<()> {
+    let actual = sha3_256_blk(input)?;
+    if actual != *hash {
+        Err(blk_check_failed(pos, hash, Some(&actual)))
+    } else {
+        Ok(())
+    }
+}
+
+// what was expected of a block and what it is go to the log only, to keep FsError small,
+// the actual mac of a block is not known without decrypting it
+fn blk_check_failed(pos: u64, expected: &[u8], actual: Option<&[u8]>) -> FsError {
+    match actual {
+        Some(actual) => warn!(
+            "block {} failed its check, expected hash {}, actual {}",
+            pos, hex::encode(expected), hex::encode(actual),
+        ),
+        None => warn!("block {} failed its check, expected mac {}", pos, hex::encode(expected)),
+    }
+    new_error!(FsError::IntegrityCheckFailed { pos })
 }
 
 pub fn sha3_256_any_check(input: &[u8], hash: &Hash256) -> FsResult<()> {
@@ -110,12 +184,32 @@ pub fn aes_gcm_128_blk_dec(
     cipher.decrypt_in_place_detached(
         &nonce, b"", input, Tag::<Aes128Gcm>::from_slice(mac)
     ).map_err(
-        |_| new_error!(FsError::IntegrityCheckError)
+        |_| blk_check_failed(pos_as_nonce, mac, None)
     )?;
 
     Ok(())
 }
 
+/// whether a block as on disk matches the hint, the block is left as it is,
+/// unlike [`crypto_in`] a mismatch is not an error
+pub fn crypto_check(input: &Block, hint: CryptoHint) -> FsResult<bool> {
+    let ok = match hint {
+        CryptoHint::Encrypted(key, mac, pos) => {
+            let k = Key::<Aes128Gcm>::from_slice(&key);
+            let cipher = Aes128Gcm::new(k);
+            let nonce = pos_to_nonce(pos);
+            let mut buf = input.to_vec();
+            cipher.decrypt_in_place_detached(
+                Nonce::from_slice(&nonce), b"", &mut buf, Tag::<Aes128Gcm>::from_slice(&mac)
+            ).is_ok()
+        }
+        CryptoHint::IntegrityOnly(hash, _) => sha3_256_blk(input)? == hash,
+        CryptoHint::IntegrityOnlyShort(hash, _) => sha3_256_blk_short(input)? == hash,
+        CryptoHint::Unchecked => true,
+    };
+    Ok(ok)
+}
+
 mod key_gen {
     use aes::Aes128;
     use cmac::{Cmac, Mac};
@@ -129,6 +223,8 @@ mod key_gen {
     use rand::rngs::SmallRng;
 
     use crate::alloc::borrow::ToOwned;
+    #[cfg(debug_assertions)]
+    use alloc::collections::BTreeSet;
 
     #[repr(C)]
     struct KdfInput {
@@ -177,8 +273,14 @@ mod key_gen {
         kdk: Key128,
         used_time: u32,
         key_gen_counter: u32,
+        // (key, pos) given out under the current kdk, reusing one breaks aes-gcm,
+        // kept for the whole kdk lifetime, which is KDK_MAX_USE keys
+        #[cfg(debug_assertions)]
+        issued: BTreeSet<(Key128, u64)>,
     }
 
+    const KDK_MAX_USE: u32 = 16;
+
     impl KeyGen {
         #[cfg(feature = "std")]
         pub fn new() -> Self {
@@ -187,6 +289,8 @@ mod key_gen {
                 kdk,
                 used_time: 0,
                 key_gen_counter: 0,
+                #[cfg(debug_assertions)]
+                issued: BTreeSet::new(),
             }
         }
 
@@ -197,28 +301,103 @@ mod key_gen {
                 kdk,
                 used_time: 0,
                 key_gen_counter: 0,
+                #[cfg(debug_assertions)]
+                issued: BTreeSet::new(),
             }
         }
 
-        pub fn gen_key(&mut self, pos_as_nonce: u64) -> FsResult<Key128> {
+        // seed is only used without std
+        fn renew_kdk(&mut self, _seed: u64) {
             #[cfg(not(feature = "std"))]
-            if self.used_time >= 16 {
-                self.kdk = random_16b(pos_as_nonce);
-                self.used_time = 0;
+            {
+                self.kdk = random_16b(_seed);
             }
-
             #[cfg(feature = "std")]
-            if self.used_time >= 16 {
+            {
                 self.kdk = random_16b();
-                self.used_time = 0;
             }
+            self.used_time = 0;
+            // keys under a new kdk never meet the old ones
+            #[cfg(debug_assertions)]
+            self.issued.clear();
+        }
+
+        // a reuse is a bug of ours
+        #[cfg(debug_assertions)]
+        fn check_issued(&mut self, key: Key128, pos: u64) -> FsResult<()> {
+            if !self.issued.insert((key, pos)) {
+                return Err(new_error!(FsError::CryptoError));
+            }
+            Ok(())
+        }
+
+        #[cfg(all(test, debug_assertions))]
+        pub(crate) fn nr_issued(&self) -> usize {
+            self.issued.len()
+        }
+
+        pub fn gen_key(&mut self, pos_as_nonce: u64) -> FsResult<Key128> {
+            if self.used_time >= KDK_MAX_USE {
+                self.renew_kdk(pos_as_nonce);
+            }
+            self.used_time += 1;
 
             let key = generate_random_key(&self.kdk, self.key_gen_counter, pos_as_nonce)?;
-            self.key_gen_counter += 1;
+            // counter must not repeat under the same kdk
+            self.key_gen_counter = self.key_gen_counter.checked_add(1).unwrap_or_else(|| {
+                self.renew_kdk(pos_as_nonce);
+                0
+            });
+
+            #[cfg(debug_assertions)]
+            self.check_issued(key, pos_as_nonce)?;
 
             Ok(key)
         }
     }
+
+    #[cfg(feature = "std")]
+    #[cfg(test)]
+    mod test {
+        use super::*;
+
+        #[test]
+        fn counter_wrap_renews_kdk() -> FsResult<()> {
+            let mut kg = KeyGen::new();
+            kg.key_gen_counter = u32::MAX;
+            let kdk = kg.kdk;
+            let key = kg.gen_key(1)?;
+            assert_eq!(kg.key_gen_counter, 0);
+            assert_ne!(kg.kdk, kdk);
+            assert_ne!(kg.gen_key(1)?, key);
+            Ok(())
+        }
+
+        #[test]
+        #[cfg(debug_assertions)]
+        fn issued_keys_live_with_kdk() -> FsResult<()> {
+            let mut kg = KeyGen::new();
+            let kdk = kg.kdk;
+            for pos in 0..KDK_MAX_USE as u64 {
+                kg.gen_key(pos)?;
+                assert_eq!(kg.issued.len(), pos as usize + 1);
+            }
+            assert_eq!(kg.kdk, kdk);
+            kg.gen_key(0)?;
+            assert_ne!(kg.kdk, kdk);
+            assert_eq!(kg.issued.len(), 1);
+            Ok(())
+        }
+
+        #[test]
+        #[cfg(debug_assertions)]
+        #[should_panic(expected = "CryptoError")]
+        fn reused_key_is_caught() {
+            let mut kg = KeyGen::new();
+            let key = kg.gen_key(3).unwrap();
+            let _ = kg.check_issued(key, 3);
+        }
+    }
 }
 pub use key_gen::*;
 
@@ -233,3 +412,95 @@ pub fn half_md4(buf: &[u8]) -> FsResult<u64> {
 
     Ok(u64::from_le_bytes(hash[4..12].try_into().unwrap()))
 }
+
+/// hash function of names in ro dir entries, recorded in the superblock
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum NameHashAlgo {
+    /// predictable, colliding names are cheap to craft, default for compatibility
+    #[default]
+    HalfMd4 = 0,
+    /// SipHash-2-4 keyed per image, see [`NameHasher::new`]
+    SipHash = 1,
+}
+
+/// a name hash function with its key, as configured for an image
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NameHasher {
+    HalfMd4,
+    SipHash(u64, u64),
+}
+
+impl NameHasher {
+    /// salt is random per image and stored in the superblock. in encrypted mode
+    /// the sip key is derived from it and the root key, so it's as secret as the
+    /// root key, otherwise the salt is the key itself
+    pub fn new(algo: NameHashAlgo, salt: &Key128, root_key: Option<&Key128>) -> FsResult<Self> {
+        let hasher = match algo {
+            NameHashAlgo::HalfMd4 => Self::HalfMd4,
+            NameHashAlgo::SipHash => {
+                let mut key = *salt;
+                if let Some(root_key) = root_key {
+                    let mut input = [0u8; 2 * size_of::<Key128>()];
+                    input[..size_of::<Key128>()].copy_from_slice(root_key);
+                    input[size_of::<Key128>()..].copy_from_slice(salt);
+                    key.copy_from_slice(&sha3_256_any(&input)?[..size_of::<Key128>()]);
+                }
+                Self::SipHash(
+                    u64::from_le_bytes(key[..8].try_into().unwrap()),
+                    u64::from_le_bytes(key[8..].try_into().unwrap()),
+                )
+            }
+        };
+        Ok(hasher)
+    }
+
+    pub fn hash(&self, name: &[u8]) -> FsResult<u64> {
+        match self {
+            Self::HalfMd4 => half_md4(name),
+            Self::SipHash(k0, k1) => Ok(siphash24(*k0, *k1, name)),
+        }
+    }
+}
+
+fn siphash24(k0: u64, k1: u64, msg: &[u8]) -> u64 {
+    fn sip_round(v: &mut [u64; 4]) {
+        v[0] = v[0].wrapping_add(v[1]);
+        v[1] = v[1].rotate_left(13) ^ v[0];
+        v[0] = v[0].rotate_left(32);
+        v[2] = v[2].wrapping_add(v[3]);
+        v[3] = v[3].rotate_left(16) ^ v[2];
+        v[0] = v[0].wrapping_add(v[3]);
+        v[3] = v[3].rotate_left(21) ^ v[0];
+        v[2] = v[2].wrapping_add(v[1]);
+        v[1] = v[1].rotate_left(17) ^ v[2];
+        v[2] = v[2].rotate_left(32);
+    }
+    let mut v = [
+        k0 ^ 0x736f6d6570736575,
+        k1 ^ 0x646f72616e646f6d,
+        k0 ^ 0x6c7967656e657261,
+        k1 ^ 0x7465646279746573,
+    ];
+
+    let compress = |v: &mut [u64; 4], m: u64| {
+        v[3] ^= m;
+        sip_round(v);
+        sip_round(v);
+        v[0] ^= m;
+    };
+    let chunks = msg.chunks_exact(8);
+    let rest = chunks.remainder();
+    for c in chunks {
+        compress(&mut v, u64::from_le_bytes(c.try_into().unwrap()));
+    }
+    // last word holds the remaining bytes and length in the top byte
+    let mut last = [0u8; 8];
+    last[..rest.len()].copy_from_slice(rest);
+    compress(&mut v, u64::from_le_bytes(last) | (msg.len() as u64) << 56);
+
+    v[2] ^= 0xff;
+    for _ in 0..4 {
+        sip_round(&mut v);
+    }
+    v[0] ^ v[1] ^ v[2] ^ v[3]
+}
@@ -52,6 +52,11 @@ pub enum FsError {
     #[error("failed to check integrity of data by sha3-256")]
     IntegrityCheckError,
 
+    /// a block failed its mac or hash, pos is its physical position in its file,
+    /// see the log for what was expected
+    #[error("failed to check integrity of block {pos}")]
+    IntegrityCheckFailed { pos: u64 },
+
     #[error("cache is full")]
     CacheIsFull,
 
@@ -78,71 +83,62 @@ pub enum FsError {
     #[error("failed to check metadata in superblock")]
     SuperBlockCheckFailed,
 
+    #[error("on-disk structures of this fs are corrupted")]
+    Corrupted,
+
+    #[error("file name is longer than namemax of this fs")]
+    NameTooLong,
+
+    #[error("file is larger than the fs can address")]
+    FileTooLarge,
+
+    #[error("this fs has already been destroyed")]
+    AlreadyDestroyed,
+
+    #[error("link across file systems or layers")]
+    CrossDevice,
+
+    #[error("inode is still in use by others")]
+    Busy,
+
     #[error("unknown error")]
     UnknownError,
 }
 
 pub type FsResult<T> = Result<T, FsError>;
 
-use libc::c_int;
-impl Into<c_int> for FsError {
-    fn into(self) -> c_int {
-        match self {
-            #[cfg(feature = "std")]
-            FsError::IOError(io_err) => {
-                match io_err.kind() {
-                    ErrorKind::NotFound => libc::ENOENT,
-                    ErrorKind::PermissionDenied => libc::EACCES,
-                    ErrorKind::ConnectionRefused => libc::ECONNREFUSED,
-                    ErrorKind::ConnectionReset => libc::ECONNRESET,
-                    ErrorKind::ConnectionAborted => libc::ECONNABORTED,
-                    ErrorKind::NotConnected => libc::ENOTCONN,
-                    ErrorKind::AddrInUse => libc::EADDRINUSE,
-                    ErrorKind::AddrNotAvailable => libc::EADDRNOTAVAIL,
-                    ErrorKind::BrokenPipe => libc::EPIPE,
-                    ErrorKind::AlreadyExists => libc::EEXIST,
-                    ErrorKind::WouldBlock => libc::EWOULDBLOCK,
-                    ErrorKind::InvalidInput => libc::EINVAL,
-                    ErrorKind::InvalidData => libc::EINVAL,
-                    ErrorKind::TimedOut => libc::ETIMEDOUT,
-                    ErrorKind::WriteZero => 256 as c_int,
-                    ErrorKind::Interrupted => 257 as c_int,
-                    ErrorKind::Unsupported => libc::ENOSYS,
-                    ErrorKind::UnexpectedEof => 258 as c_int,
-                    ErrorKind::OutOfMemory => 259 as c_int,
-                    _ => 511 as c_int,
-                }
-            },
-            #[cfg(not(feature = "std"))]
-            FsError::IOError => libc::EIO,
-            FsError::DirectoryNotEmpty => libc::ENOTEMPTY,
-            FsError::InvalidData => libc::EINVAL,
-            FsError::InvalidParameter => libc::EINVAL,
-            FsError::NotFound => libc::ENOENT,
-            FsError::NotADirectory => libc::ENOTDIR,
-            FsError::IsADirectory => libc::EISDIR,
-            FsError::AlreadyExists => libc::EEXIST,
-            FsError::PermissionDenied => libc::EACCES,
-            FsError::UnexpectedEof => 258 as c_int,
-            FsError::NotSupported => libc::ENOSYS,
-            FsError::CryptoError => 260 as c_int,
-            FsError::IntegrityCheckError => 261 as c_int,
-            FsError::CacheIsFull => 262 as c_int,
-            #[cfg(any(feature = "channel_lru", feature = "ro_cache_server"))]
-            FsError::ChannelSendError => 263 as c_int,
-            #[cfg(any(feature = "channel_lru", feature = "ro_cache_server"))]
-            FsError::ChannelRecvError => 264 as c_int,
-            FsError::RwLockError => 265 as c_int,
-            FsError::MutexError => 266 as c_int,
-            FsError::CacheNeedHint => 267 as c_int,
-            FsError::IncompatibleMetadata => 268 as c_int,
-            FsError::SuperBlockCheckFailed => 269 as c_int,
-
-            FsError::UnknownError => 511 as c_int,
-        }
+#[cfg(feature = "std")]
+impl From<FsError> for std::io::Error {
+    fn from(e: FsError) -> Self {
+        let kind = match e {
+            FsError::IOError(io_err) => return io_err,
+            FsError::DirectoryNotEmpty => ErrorKind::DirectoryNotEmpty,
+            FsError::InvalidData => ErrorKind::InvalidData,
+            FsError::InvalidParameter => ErrorKind::InvalidInput,
+            FsError::NotFound => ErrorKind::NotFound,
+            FsError::NotADirectory => ErrorKind::NotADirectory,
+            FsError::IsADirectory => ErrorKind::IsADirectory,
+            FsError::AlreadyExists => ErrorKind::AlreadyExists,
+            FsError::PermissionDenied => ErrorKind::PermissionDenied,
+            FsError::UnexpectedEof => ErrorKind::UnexpectedEof,
+            FsError::NotSupported => ErrorKind::Unsupported,
+            FsError::NameTooLong => ErrorKind::InvalidInput,
+            FsError::FileTooLarge => ErrorKind::FileTooLarge,
+            FsError::CrossDevice => ErrorKind::CrossesDevices,
+            FsError::Busy => ErrorKind::ResourceBusy,
+            FsError::CryptoError
+            | FsError::IntegrityCheckError
+            | FsError::IntegrityCheckFailed { .. }
+            | FsError::IncompatibleMetadata
+            | FsError::SuperBlockCheckFailed
+            | FsError::Corrupted => ErrorKind::InvalidData,
+            _ => ErrorKind::Other,
+        };
+        std::io::Error::new(kind, e)
     }
 }
 
+#[cfg(not(feature = "err_trace"))]
 #[macro_export]
 macro_rules! new_error{
     ($e: expr) => {
@@ -154,6 +150,108 @@ macro_rules! new_error{
     }
 }
 
+// with err_trace, errors are recorded where they are made, and still panic in debug builds
+#[cfg(feature = "err_trace")]
+#[macro_export]
+macro_rules! new_error{
+    ($e: expr) => {
+        {
+            let e = $e;
+            $crate::error::trace::push(file!(), line!(), &e);
+            if cfg!(debug_assertions) {
+                panic!("Error: {:?}", e);
+            }
+            e
+        }
+    }
+}
+
+// record where an error passes through on its way up, nothing without err_trace
+#[cfg(not(feature = "err_trace"))]
+#[macro_export]
+macro_rules! trace_err{
+    ($e: expr) => {
+        $e
+    }
+}
+
+#[cfg(feature = "err_trace")]
+#[macro_export]
+macro_rules! trace_err{
+    ($e: expr) => {
+        $e.map_err(|e| {
+            $crate::error::trace::push(file!(), line!(), &e);
+            e
+        })
+    }
+}
+
+/// a ring of the latest places errors were made or passed through,
+/// one per thread with std, so that frames of errors on other threads
+/// don't get in, without std there's only one for all
+#[cfg(feature = "err_trace")]
+pub mod trace {
+    use super::FsError;
+    use core::mem::Discriminant;
+    use alloc::vec::Vec;
+
+    pub const TRACE_LEN: usize = 32;
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct Frame {
+        pub file: &'static str,
+        pub line: u32,
+        pub err: Discriminant<FsError>,
+    }
+
+    // next slot to write, frames
+    type Ring = (usize, [Option<Frame>; TRACE_LEN]);
+
+    #[cfg(feature = "std")]
+    std::thread_local! {
+        static RING: core::cell::RefCell<Ring> = const {
+            core::cell::RefCell::new((0, [None; TRACE_LEN]))
+        };
+    }
+
+    #[cfg(feature = "std")]
+    fn with_ring<T>(f: impl FnOnce(&mut Ring) -> T) -> T {
+        RING.with(|ring| f(&mut ring.borrow_mut()))
+    }
+
+    #[cfg(not(feature = "std"))]
+    static RING: spin::Mutex<Ring> = spin::Mutex::new((0, [None; TRACE_LEN]));
+
+    #[cfg(not(feature = "std"))]
+    fn with_ring<T>(f: impl FnOnce(&mut Ring) -> T) -> T {
+        f(&mut RING.lock())
+    }
+
+    pub fn push(file: &'static str, line: u32, e: &FsError) {
+        with_ring(|ring| {
+            let next = ring.0;
+            ring.1[next] = Some(Frame {
+                file,
+                line,
+                err: core::mem::discriminant(e),
+            });
+            ring.0 = (next + 1) % TRACE_LEN;
+        })
+    }
+
+    /// take all frames recorded on this thread, oldest first, and clear them
+    pub fn take() -> Vec<Frame> {
+        with_ring(|ring| {
+            let next = ring.0;
+            let (newer, older) = ring.1.split_at_mut(next);
+            let frames = older.iter_mut().chain(newer.iter_mut())
+                .filter_map(|f| f.take()).collect();
+            ring.0 = 0;
+            frames
+        })
+    }
+}
+
 #[allow(unused)]
 #[cfg(feature = "std")]
 #[macro_export]
@@ -171,3 +269,27 @@ macro_rules! io_try {
         $e.map_err(|_| FsError::IOError)?
     };
 }
+
+#[cfg(feature = "std")]
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn fserror_into_io_error() {
+        let kind = |e: FsError| std::io::Error::from(e).kind();
+        assert_eq!(kind(FsError::NotFound), ErrorKind::NotFound);
+        assert_eq!(kind(FsError::PermissionDenied), ErrorKind::PermissionDenied);
+        assert_eq!(kind(FsError::AlreadyExists), ErrorKind::AlreadyExists);
+        assert_eq!(kind(FsError::InvalidParameter), ErrorKind::InvalidInput);
+        assert_eq!(kind(FsError::NotSupported), ErrorKind::Unsupported);
+        assert_eq!(kind(FsError::IntegrityCheckError), ErrorKind::InvalidData);
+        assert_eq!(kind(FsError::IntegrityCheckFailed { pos: 3 }), ErrorKind::InvalidData);
+        assert_eq!(kind(FsError::CacheIsFull), ErrorKind::Other);
+        // wrapped io errors come back untouched
+        assert_eq!(kind(FsError::IOError(ErrorKind::TimedOut.into())), ErrorKind::TimedOut);
+
+        let res: std::io::Result<()> = (|| Err(FsError::IsADirectory)?)();
+        assert_eq!(res.unwrap_err().kind(), ErrorKind::IsADirectory);
+    }
+}
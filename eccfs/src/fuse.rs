@@ -14,9 +14,89 @@ use std::fs;
 struct EccFs {
     fs: Box<dyn vfs::FileSystem>,
     mode: Arc<Mutex<FSMode>>,
+    attrs: AttrCache,
+}
+
+// errno for errors that have no better mapping
+const ERRNO_UNKNOWN: c_int = 511;
+
+// the only place where FsError is turned into an errno, every reply goes through here,
+// it lists every error so that a new one can't go without its errno
+fn fserror_to_errno(e: &FsError) -> c_int {
+    match e {
+        #[cfg(feature = "std")]
+        FsError::IOError(io_err) => {
+            use std::io::ErrorKind;
+            if let Some(errno) = io_err.raw_os_error() {
+                return errno;
+            }
+            match io_err.kind() {
+                ErrorKind::NotFound => libc::ENOENT,
+                ErrorKind::PermissionDenied => libc::EACCES,
+                ErrorKind::ConnectionRefused => libc::ECONNREFUSED,
+                ErrorKind::ConnectionReset => libc::ECONNRESET,
+                ErrorKind::ConnectionAborted => libc::ECONNABORTED,
+                ErrorKind::NotConnected => libc::ENOTCONN,
+                ErrorKind::AddrInUse => libc::EADDRINUSE,
+                ErrorKind::AddrNotAvailable => libc::EADDRNOTAVAIL,
+                ErrorKind::BrokenPipe => libc::EPIPE,
+                ErrorKind::AlreadyExists => libc::EEXIST,
+                ErrorKind::WouldBlock => libc::EWOULDBLOCK,
+                ErrorKind::InvalidInput => libc::EINVAL,
+                ErrorKind::InvalidData => libc::EINVAL,
+                ErrorKind::TimedOut => libc::ETIMEDOUT,
+                ErrorKind::WriteZero => 256 as c_int,
+                ErrorKind::Interrupted => 257 as c_int,
+                ErrorKind::Unsupported => libc::ENOSYS,
+                ErrorKind::UnexpectedEof => 258 as c_int,
+                ErrorKind::OutOfMemory => 259 as c_int,
+                _ => libc::EIO,
+            }
+        },
+        #[cfg(not(feature = "std"))]
+        FsError::IOError => libc::EIO,
+        FsError::DirectoryNotEmpty => libc::ENOTEMPTY,
+        FsError::InvalidData => libc::EINVAL,
+        FsError::InvalidParameter => libc::EINVAL,
+        FsError::NotFound => libc::ENOENT,
+        FsError::NotADirectory => libc::ENOTDIR,
+        FsError::IsADirectory => libc::EISDIR,
+        FsError::AlreadyExists => libc::EEXIST,
+        FsError::PermissionDenied => libc::EACCES,
+        FsError::UnexpectedEof => 258 as c_int,
+        FsError::NotSupported => libc::ENOSYS,
+        FsError::CryptoError => 260 as c_int,
+        FsError::IntegrityCheckError
+        | FsError::IntegrityCheckFailed { .. } => 261 as c_int,
+        FsError::CacheIsFull => 262 as c_int,
+        #[cfg(any(feature = "channel_lru", feature = "ro_cache_server"))]
+        FsError::ChannelSendError => 263 as c_int,
+        #[cfg(any(feature = "channel_lru", feature = "ro_cache_server"))]
+        FsError::ChannelRecvError => 264 as c_int,
+        FsError::RwLockError => 265 as c_int,
+        FsError::MutexError => 266 as c_int,
+        FsError::CacheNeedHint => 267 as c_int,
+        FsError::IncompatibleMetadata => 268 as c_int,
+        FsError::SuperBlockCheckFailed => 269 as c_int,
+        FsError::Corrupted => 270 as c_int,
+        FsError::NameTooLong => libc::ENAMETOOLONG,
+        FsError::FileTooLarge => libc::EFBIG,
+        FsError::AlreadyDestroyed => libc::ESHUTDOWN,
+        FsError::CrossDevice => libc::EXDEV,
+        FsError::Busy => libc::EBUSY,
+
+        FsError::UnknownError => ERRNO_UNKNOWN,
+    }
+}
+
+impl Into<c_int> for FsError {
+    fn into(self) -> c_int {
+        fserror_to_errno(&self)
+    }
 }
 
 const DEFAULT_TTL: Duration = Duration::new(1, 0);
+const ATTR_CACHE_CAP: usize = 1024;
 
 macro_rules! fuse_try {
     ($res:expr, $reply:expr) => {
@@ -26,7 +106,7 @@ macro_rules! fuse_try {
                 if cfg!(debug_assertions) {
                     panic!("reply error: {}", e);
                 }
-                $reply.error(e.into());
+                $reply.error(fserror_to_errno(&e));
                 return;
             }
         }
@@ -47,10 +127,18 @@ fn get_perm_from_libc_mode(mode: u32) -> FilePerm {
     FilePerm::from_bits(mode as u16 & PERM_MASK).unwrap()
 }
 
+impl EccFs {
+    fn invalidate_all(&self, iids: &[Option<u64>]) {
+        for iid in iids.iter().flatten() {
+            self.attrs.invalidate(*iid);
+        }
+    }
+}
+
 impl Filesystem for EccFs {
     fn init(&mut self, _req: &Request<'_>, _config: &mut KernelConfig) -> Result<(), c_int> {
         self.fs.init().map_err(
-            |e| e.into()
+            |e| fserror_to_errno(&e)
         )
     }
 
@@ -60,17 +148,20 @@ impl Filesystem for EccFs {
     }
 
     fn lookup(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEntry) {
-        if let Some(iid) = fuse_try!(self.fs.lookup(parent, name), reply) {
-            let meta = fuse_try!(self.fs.get_meta(iid), reply);
+        let found = self.fs.lookup(parent, name);
+        // lookup updates atime of parent
+        self.attrs.invalidate(parent);
+        if let Some(iid) = fuse_try!(found, reply) {
+            let meta = fuse_try!(self.attrs.getattr(self.fs.as_ref(), iid), reply);
             reply.entry(&DEFAULT_TTL, &meta.into(), 0);
         } else {
             // debug!("lookup not found");
-            reply.error(FsError::NotFound.into());
+            reply.error(fserror_to_errno(&FsError::NotFound));
         }
     }
 
     fn getattr(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyAttr) {
-        let meta = fuse_try!(self.fs.get_meta(ino), reply);
+        let meta = fuse_try!(self.attrs.getattr(self.fs.as_ref(), ino), reply);
         reply.attr(&DEFAULT_TTL, &meta.into());
     }
 
@@ -123,15 +214,17 @@ impl Filesystem for EccFs {
         if let Some(ctime) = ctime {
             set_list.push(SetMetadata::Ctime(ctime));
         }
-        for set_md in set_list {
-            fuse_try!(self.fs.set_meta(ino, set_md), reply);
-        }
+        // some may be set before one fails
+        let res = set_list.into_iter().try_for_each(|set_md| self.fs.set_meta(ino, set_md));
+        self.attrs.invalidate(ino);
+        fuse_try!(res, reply);
         let meta = fuse_try!(self.fs.get_meta(ino), reply);
         reply.attr(&DEFAULT_TTL, &meta.into());
     }
 
     fn readlink(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyData) {
         let link_path = fuse_try!(self.fs.iread_link(ino), reply);
+        self.attrs.invalidate(ino);
         reply.data(link_path.as_os_str().as_encoded_bytes());
     }
 
@@ -151,17 +244,22 @@ impl Filesystem for EccFs {
             parent, name, vfs::FileType::Dir,
             uid, gid, perm,
         ), reply);
+        self.attrs.invalidate(parent);
         let meta = fuse_try!(self.fs.get_meta(iid), reply);
         reply.entry(&DEFAULT_TTL, &meta.into(), 0);
     }
 
     fn unlink(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        let child = fuse_try!(self.fs.lookup(parent, name), reply);
         fuse_try!(self.fs.unlink(parent, name), reply);
+        self.invalidate_all(&[Some(parent), child]);
         reply.ok();
     }
 
     fn rmdir(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        let child = fuse_try!(self.fs.lookup(parent, name), reply);
         fuse_try!(self.fs.unlink(parent, name), reply);
+        self.invalidate_all(&[Some(parent), child]);
         reply.ok();
     }
 
@@ -179,6 +277,7 @@ impl Filesystem for EccFs {
             parent, link_name, target,
             uid, gid,
         ), reply);
+        self.attrs.invalidate(parent);
         let meta = fuse_try!(self.fs.get_meta(iid), reply);
         reply.entry(&DEFAULT_TTL, &meta.into(), 0);
     }
@@ -193,7 +292,10 @@ impl Filesystem for EccFs {
         _flags: u32,
         reply: ReplyEmpty,
     ) {
+        let moved = fuse_try!(self.fs.lookup(parent, name), reply);
+        let replaced = fuse_try!(self.fs.lookup(newparent, newname), reply);
         fuse_try!(self.fs.rename(parent, name, newparent, newname), reply);
+        self.invalidate_all(&[Some(parent), Some(newparent), moved, replaced]);
         reply.ok();
     }
 
@@ -206,7 +308,8 @@ impl Filesystem for EccFs {
         reply: ReplyEntry,
     ) {
         fuse_try!(self.fs.link(newparent, newname, ino), reply);
-        let meta = fuse_try!(self.fs.get_meta(ino), reply);
+        self.invalidate_all(&[Some(ino), Some(newparent)]);
+        let meta = fuse_try!(self.attrs.getattr(self.fs.as_ref(), ino), reply);
         reply.entry(&DEFAULT_TTL, &meta.into(), 0);
     }
 
@@ -225,6 +328,7 @@ impl Filesystem for EccFs {
         buf.resize(size as usize, 0);
         assert!(offset >= 0);
         let read = fuse_try!(self.fs.iread(ino, offset as usize, buf.as_mut_slice()), reply);
+        self.attrs.invalidate(ino);
         buf.resize(read, 0);
         reply.data(buf.as_slice());
     }
@@ -243,6 +347,7 @@ impl Filesystem for EccFs {
     ) {
         assert!(offset >= 0);
         let written = fuse_try!(self.fs.iwrite(ino, offset as usize, data), reply);
+        self.attrs.invalidate(ino);
         reply.written(written as u32);
     }
 
@@ -282,6 +387,8 @@ impl Filesystem for EccFs {
     ) {
         assert!(offset >= 0);
 
+        // reading entries updates atime
+        self.attrs.invalidate(ino);
         loop {
             if let Some((iid, name, ft)) = fuse_try!(self.fs.next_entry(
                 ino, offset as usize
@@ -347,6 +454,7 @@ impl Filesystem for EccFs {
             parent, name, tp,
             uid, gid, FilePerm::from_bits(perm).unwrap(),
         ), reply);
+        self.attrs.invalidate(parent);
         let meta = fuse_try!(self.fs.get_meta(iid), reply);
         reply.created(&DEFAULT_TTL, &meta.into(), 0, 0, 0);
     }
@@ -365,10 +473,15 @@ impl Filesystem for EccFs {
         assert!(length >= 0);
 
         // const LIBC_ZERO_KEEP_SZ: i32 = libc::FALLOC_FL_ZERO_RANGE | libc::FALLOC_FL_KEEP_SIZE;
+        // linux takes punch hole only along with keep size
+        const LIBC_PUNCH_KEEP_SZ: i32 = libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE;
         let mode = match mode {
             0 => FallocateMode::Alloc,
             // libc::FALLOC_FL_KEEP_SIZE => FallocateMode::AllocKeepSize,
             libc::FALLOC_FL_ZERO_RANGE => FallocateMode::ZeroRange,
+            libc::FALLOC_FL_COLLAPSE_RANGE => FallocateMode::CollapseRange,
+            libc::FALLOC_FL_INSERT_RANGE => FallocateMode::InsertRange,
+            LIBC_PUNCH_KEEP_SZ => FallocateMode::PunchHole,
             // LIBC_ZERO_KEEP_SZ =>
             //     FallocateMode::ZeroRangeKeepSize,
             _ => {
@@ -377,6 +490,7 @@ impl Filesystem for EccFs {
             }
         };
         fuse_try!(self.fs.fallocate(ino, mode, offset as usize, length as usize), reply);
+        self.attrs.invalidate(ino);
         reply.ok();
     }
 }
@@ -456,6 +570,7 @@ fn mount_ro(mode: FSMode, target: String) -> FsResult<FSMode> {
         EccFs {
             fs: Box::new(rofs),
             mode: amode.clone(),
+            attrs: AttrCache::new(ATTR_CACHE_CAP)?,
         },
         mount,
         &vec![
@@ -488,6 +603,7 @@ fn mount_rw(mode: FSMode, target: String) -> FsResult<FSMode> {
         EccFs {
             fs: Box::new(rwfs),
             mode: amode.clone(),
+            attrs: AttrCache::new(ATTR_CACHE_CAP)?,
         },
         mount,
         &vec![
@@ -538,6 +654,7 @@ fn mount_ovl(mode: Vec<FSMode>, target: Vec<String>) -> FsResult<FSMode> {
         EccFs {
             fs: Box::new(ovl),
             mode: amode.clone(),
+            attrs: AttrCache::new(ATTR_CACHE_CAP)?,
         },
         mount,
         &vec![
@@ -7,85 +7,216 @@ pub use rw::*;
 
 pub const HTREE_ROOT_BLK_PHY_POS: u64 = 0;
 
+// log whether a block of a tree failing its check is an index or a data block,
+// the error is passed on as it is
+pub(crate) fn note_failed_blk(shape: mht::Shape, e: crate::FsError) -> crate::FsError {
+    if let crate::FsError::IntegrityCheckFailed { pos } = e {
+        let kind = if shape.is_idx(pos) { "index" } else { "data" };
+        crate::warn!("{} block {} of a htree failed its check", kind, pos);
+    }
+    e
+}
+
 pub mod mht {
     use crate::*;
     use crate::crypto::*;
     use super::*;
     use core::mem;
 
-    pub const ENTRY_PER_BLK: u64 = BLK_SZ as u64 / KEY_ENTRY_SZ as u64;
-    pub const CHILD_PER_BLK: u64 = ENTRY_PER_BLK * 1 / 4;
-    pub const DATA_PER_BLK: u64 = ENTRY_PER_BLK * 3 / 4;
-
-    pub fn logi2phy(logi: u64) -> u64 {
-        let nr_idx = (logi + 1).div_ceil(DATA_PER_BLK);
-        logi + nr_idx
+    /// how blocks of a tree are laid out, by block size and bytes of a key entry
+    /// in its index blocks, all trees of an image share one,
+    /// see [`KEY_ENTRY_SZ`] and [`SHORT_KEY_ENTRY_SZ`]
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct Shape {
+        ke_sz: usize,
+        bsize: usize,
     }
 
-    pub fn logi2dataidx(logi: u64) -> u64 {
-        logi % DATA_PER_BLK
+    impl Default for Shape {
+        fn default() -> Self {
+            Self::full(BLK_SZ)
+        }
     }
 
-    pub fn phy2idxphy(phy: u64) -> u64 {
-        phy - phy % (DATA_PER_BLK + 1)
-    }
+    /// Largest logical length of a tree, in blocks.
+    /// Position math below is unchecked, it stays in range for any length up to this,
+    /// so lengths from disk or from users must be checked against it first.
+    pub const MAX_LOGI_NR_BLK: u64 = 1 << 48;
 
-    pub fn phy2dataidx(phy: u64) -> u64 {
-        phy - phy2idxphy(phy) - 1
+    pub fn check_logi_nr_blk(logi_nr_blk: u64) -> FsResult<()> {
+        if logi_nr_blk > MAX_LOGI_NR_BLK {
+            return Err(FsError::FileTooLarge);
+        }
+        Ok(())
     }
 
-    // get idxblk's father's phypos and child_idx in father blk
-    pub fn idxphy2father(idxphy: u64) -> (u64, u64) {
-        if idxphy == HTREE_ROOT_BLK_PHY_POS {
-            return (HTREE_ROOT_BLK_PHY_POS, 0)
+    impl Shape {
+        /// full key entries in blocks of bsize, which must have passed [`check_blk_sz`]
+        pub const fn full(bsize: usize) -> Self {
+            Self { ke_sz: KEY_ENTRY_SZ, bsize }
         }
-        let idx = idxphy / (DATA_PER_BLK + 1);
-        let father = (idx - 1) / CHILD_PER_BLK;
-        let fatherphy = father * (DATA_PER_BLK + 1);
-        let child_idx = (idx - 1) % CHILD_PER_BLK;
-        (fatherphy, child_idx)
-    }
 
-    pub fn get_first_idx_child_phy(idxphy: u64) -> u64 {
-        let idxnum = idxphy2number(idxphy);
-        (idxnum * CHILD_PER_BLK + 1) * (DATA_PER_BLK + 1)
-    }
+        /// integrity only, see [`SHORT_KEY_ENTRY_SZ`]
+        pub const fn short(bsize: usize) -> Self {
+            Self { ke_sz: SHORT_KEY_ENTRY_SZ, bsize }
+        }
 
-    pub fn next_idx_sibling_phy(child_phy: u64) -> u64 {
-        child_phy + DATA_PER_BLK + 1
-    }
+        /// shape of an image by ke_sz and bsize in its superblock
+        pub fn from_ke_sz(ke_sz: u64, bsize: u64) -> FsResult<Self> {
+            let bsize = usize::try_from(bsize).map_err(|_| FsError::NotSupported)?;
+            check_blk_sz(bsize)?;
+            match ke_sz as usize {
+                KEY_ENTRY_SZ => Ok(Self::full(bsize)),
+                SHORT_KEY_ENTRY_SZ => Ok(Self::short(bsize)),
+                _ => Err(FsError::NotSupported),
+            }
+        }
 
-    pub fn get_first_data_child_phy(idxphy: u64) -> u64 {
-        idxphy + 1
-    }
+        pub const fn ke_sz(&self) -> usize {
+            self.ke_sz
+        }
 
-    pub fn next_data_sibling_phy(child_phy: u64) -> u64 {
-        child_phy + 1
-    }
+        pub const fn blk_sz(&self) -> usize {
+            self.bsize
+        }
 
-    pub fn idxphy2number(idxphy: u64) -> u64 {
-        assert_eq!(idxphy % (DATA_PER_BLK + 1), 0);
-        idxphy / (DATA_PER_BLK + 1)
-    }
+        pub const fn is_short(&self) -> bool {
+            self.ke_sz < KEY_ENTRY_SZ
+        }
 
-    pub fn get_phy_nr_blk(logi_nr_blk: u64) -> u64 {
-        logi_nr_blk + logi_nr_blk.div_ceil(DATA_PER_BLK)
-    }
+        pub const fn entry_per_blk(&self) -> u64 {
+            self.bsize as u64 / self.ke_sz as u64
+        }
 
-    pub fn get_logi_nr_blk(phy_nr_blk: u64) -> u64 {
-        phy_nr_blk - phy_nr_blk.div_ceil(DATA_PER_BLK + 1)
-    }
+        pub const fn child_per_blk(&self) -> u64 {
+            self.entry_per_blk() / 4
+        }
 
-    pub fn is_idx(phy: u64) -> bool {
-        phy % (DATA_PER_BLK + 1) == 0
-    }
+        pub const fn data_per_blk(&self) -> u64 {
+            self.entry_per_blk() * 3 / 4
+        }
+
+        pub const fn max_phy_nr_blk(&self) -> u64 {
+            MAX_LOGI_NR_BLK + MAX_LOGI_NR_BLK.div_ceil(self.data_per_blk())
+        }
+
+        pub fn check_phy_nr_blk(&self, phy_nr_blk: u64) -> FsResult<()> {
+            if phy_nr_blk > self.max_phy_nr_blk() {
+                return Err(FsError::FileTooLarge);
+            }
+            Ok(())
+        }
+
+        pub fn logi2phy(&self, logi: u64) -> u64 {
+            let nr_idx = (logi + 1).div_ceil(self.data_per_blk());
+            logi + nr_idx
+        }
+
+        pub fn logi2dataidx(&self, logi: u64) -> u64 {
+            logi % self.data_per_blk()
+        }
 
-    pub fn get_father_idx(phy: u64) -> (u64, EntryType) {
-        if is_idx(phy) {
-            let (f, idx) = idxphy2father(phy);
-            (f, Index(idx))
-        } else {
-            (phy2idxphy(phy), Data(phy2dataidx(phy)))
+        pub fn phy2idxphy(&self, phy: u64) -> u64 {
+            phy - phy % (self.data_per_blk() + 1)
+        }
+
+        pub fn phy2dataidx(&self, phy: u64) -> u64 {
+            phy - self.phy2idxphy(phy) - 1
+        }
+
+        // get idxblk's father's phypos and child_idx in father blk
+        pub fn idxphy2father(&self, idxphy: u64) -> (u64, u64) {
+            if idxphy == HTREE_ROOT_BLK_PHY_POS {
+                return (HTREE_ROOT_BLK_PHY_POS, 0)
+            }
+            let idx = idxphy / (self.data_per_blk() + 1);
+            let father = (idx - 1) / self.child_per_blk();
+            let fatherphy = father * (self.data_per_blk() + 1);
+            let child_idx = (idx - 1) % self.child_per_blk();
+            (fatherphy, child_idx)
+        }
+
+        pub fn get_first_idx_child_phy(&self, idxphy: u64) -> u64 {
+            let idxnum = self.idxphy2number(idxphy);
+            (idxnum * self.child_per_blk() + 1) * (self.data_per_blk() + 1)
+        }
+
+        pub fn next_idx_sibling_phy(&self, child_phy: u64) -> u64 {
+            child_phy + self.data_per_blk() + 1
+        }
+
+        pub fn get_first_data_child_phy(&self, idxphy: u64) -> u64 {
+            idxphy + 1
+        }
+
+        pub fn next_data_sibling_phy(&self, child_phy: u64) -> u64 {
+            child_phy + 1
+        }
+
+        pub fn idxphy2number(&self, idxphy: u64) -> u64 {
+            assert_eq!(idxphy % (self.data_per_blk() + 1), 0);
+            idxphy / (self.data_per_blk() + 1)
+        }
+
+        pub fn get_phy_nr_blk(&self, logi_nr_blk: u64) -> u64 {
+            logi_nr_blk + logi_nr_blk.div_ceil(self.data_per_blk())
+        }
+
+        pub fn get_logi_nr_blk(&self, phy_nr_blk: u64) -> u64 {
+            phy_nr_blk - phy_nr_blk.div_ceil(self.data_per_blk() + 1)
+        }
+
+        pub fn is_idx(&self, phy: u64) -> bool {
+            phy % (self.data_per_blk() + 1) == 0
+        }
+
+        pub fn get_father_idx(&self, phy: u64) -> (u64, EntryType) {
+            if self.is_idx(phy) {
+                let (f, idx) = self.idxphy2father(phy);
+                (f, Index(idx))
+            } else {
+                (self.phy2idxphy(phy), Data(self.phy2dataidx(phy)))
+            }
+        }
+
+        // bytes of a short entry beyond ke_sz are zero
+        pub fn get_ke(&self, blk: &Block, tp: EntryType) -> KeyEntry {
+            let pos = match tp {
+                Index(idx) => idx,
+                Data(idx) => self.child_per_blk() + idx,
+            };
+            let mut ret: KeyEntry = [0u8; mem::size_of::<KeyEntry>()];
+            let from = pos as usize * self.ke_sz;
+            ret[..self.ke_sz].copy_from_slice(&blk[from .. from + self.ke_sz]);
+            ret
+        }
+
+        pub fn set_ke(
+            &self, blk: &mut Block, tp: EntryType, ke: &KeyEntry
+        ) -> FsResult<()> {
+            let pos = match tp {
+                Index(idx) => {
+                    assert!(idx < self.child_per_blk());
+                    idx
+                },
+                Data(idx) => {
+                    assert!(idx < self.data_per_blk());
+                    self.child_per_blk() + idx
+                },
+            };
+            let start = pos as usize * self.ke_sz;
+            blk[start..start + self.ke_sz].copy_from_slice(&ke[..self.ke_sz]);
+            Ok(())
+        }
+
+        /// how to check a block under an entry of this shape
+        pub fn hint(&self, ke: KeyEntry, encrypted: bool, pos: u64) -> CryptoHint {
+            match CryptoHint::from_key_entry(ke, encrypted, pos) {
+                CryptoHint::IntegrityOnly(hash, pos) if self.is_short() => {
+                    CryptoHint::IntegrityOnlyShort(hash, pos)
+                }
+                hint => hint,
+            }
         }
     }
 
@@ -95,33 +226,4 @@ pub mod mht {
         Data(u64)
     }
     pub use EntryType::*;
-
-    pub fn get_ke(blk: &Block, tp: EntryType) -> KeyEntry {
-        let pos = match tp {
-            Index(idx) => idx,
-            Data(idx) => CHILD_PER_BLK + idx,
-        };
-        let mut ret: KeyEntry = [0u8; mem::size_of::<KeyEntry>()];
-        let from = pos as usize * KEY_ENTRY_SZ;
-        ret.copy_from_slice(&blk[from .. from + KEY_ENTRY_SZ]);
-        ret
-    }
-
-    pub fn set_ke(
-        blk: &mut Block, tp: EntryType, ke: &KeyEntry
-    ) -> FsResult<()> {
-        let pos = match tp {
-            Index(idx) => {
-                assert!(idx < CHILD_PER_BLK);
-                idx
-            },
-            Data(idx) => {
-                assert!(idx < DATA_PER_BLK);
-                CHILD_PER_BLK + idx
-            },
-        };
-        let start = pos as usize * KEY_ENTRY_SZ;
-        blk[start..start + KEY_ENTRY_SZ].copy_from_slice(ke.as_slice());
-        Ok(())
-    }
 }
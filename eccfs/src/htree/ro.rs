@@ -1,13 +1,32 @@
 use alloc::{
     sync::Arc,
     vec::Vec,
+    boxed::Box,
 };
 use spin::Mutex;
+use rand::{Rng, SeedableRng, rngs::SmallRng};
 use crate::bcache::*;
 use crate::*;
 use super::*;
 
 
+/// which data blocks are hash checked on read in integrity only mode,
+/// index blocks are always checked, and so is everything in encrypted mode,
+/// where decryption checks blocks anyway.
+///
+/// this trades safety for speed: a data block taken unchecked may have been
+/// tampered with, and it may stay in cache and be served later as it is
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum VerifyPolicy {
+    /// check every block
+    #[default]
+    Full,
+    /// check index blocks only, the tree structure is still intact
+    IndexOnly,
+    /// check index blocks, and data blocks at random at this percentage, up to 100
+    Sampled(u8),
+}
+
 // members are all readonly except backend, so no need to lock this whole struct
 pub struct ROHashTree {
     backend: Arc<Mutex<ROCache>>,
@@ -16,6 +35,10 @@ pub struct ROHashTree {
     encrypted: bool,
     cache_data: bool,
     root_hint: CryptoHint,
+    policy: VerifyPolicy,
+    // only with VerifyPolicy::Sampled
+    sampler: Option<Mutex<SmallRng>>,
+    shape: mht::Shape,
 }
 
 impl ROHashTree {
@@ -25,6 +48,7 @@ impl ROHashTree {
         length: u64,
         root_hint: FSMode,
         cache_data: bool,
+        shape: mht::Shape,
     ) -> Self {
         let encrypted = root_hint.is_encrypted();
 
@@ -34,19 +58,50 @@ impl ROHashTree {
             length,
             encrypted,
             cache_data,
-            root_hint: CryptoHint::from_fsmode(root_hint, HTREE_ROOT_BLK_PHY_POS),
+            root_hint: shape.hint(root_hint.into_key_entry(), encrypted, HTREE_ROOT_BLK_PHY_POS),
+            policy: VerifyPolicy::Full,
+            sampler: None,
+            shape,
+        }
+    }
+
+    /// `seed` is for the sampler of `VerifyPolicy::Sampled`,
+    /// trees of different start draw differently from the same seed
+    pub fn with_verify_policy(mut self, policy: VerifyPolicy, seed: u64) -> Self {
+        self.sampler = match policy {
+            VerifyPolicy::Sampled(_) => Some(Mutex::new(SmallRng::seed_from_u64(
+                seed ^ self.start.wrapping_mul(0x9e3779b97f4a7c15)
+            ))),
+            _ => None,
+        };
+        self.policy = policy;
+        self
+    }
+
+    fn data_hint(&self, ke: KeyEntry, data_phy: u64, check_all: bool) -> CryptoHint {
+        let check = check_all || self.encrypted || match self.policy {
+            VerifyPolicy::Full => true,
+            VerifyPolicy::IndexOnly => false,
+            VerifyPolicy::Sampled(pct) => {
+                self.sampler.as_ref().unwrap().lock().gen_range(0..100) < pct
+            }
+        };
+        if check {
+            self.shape.hint(ke, self.encrypted, data_phy)
+        } else {
+            CryptoHint::Unchecked
         }
     }
 
     // pos is by block
-    pub fn get_blk(&self, pos: u64) -> FsResult<Arc<Block>> {
+    pub fn get_blk(&self, pos: u64) -> FsResult<Arc<Box<Block>>> {
         if pos >= self.length {
             return Err(new_error!(FsError::UnexpectedEof))
         }
 
         let mut backend = self.backend.lock();
 
-        let data_phy = mht::logi2phy(pos);
+        let data_phy = self.shape.logi2phy(pos);
         if self.cache_data {
             if let Some(ablk) = backend.get_blk_try(
                 self.start + data_phy, self.cache_data
@@ -56,9 +111,16 @@ impl ROHashTree {
         }
 
         // data blk not cached
+        let idx_ablk = self.get_idx_blk(&mut backend, self.shape.phy2idxphy(data_phy))
+            .map_err(|e| note_failed_blk(self.shape, e))?;
+        let ke = self.shape.get_ke(&idx_ablk, mht::Data(self.shape.logi2dataidx(pos)));
+        let hint = self.data_hint(ke, data_phy, false);
+        trace_err!(backend.get_blk_hint(self.start + data_phy, true, hint))
+            .map_err(|e| note_failed_blk(self.shape, e))
+    }
+
+    fn get_idx_blk(&self, backend: &mut ROCache, mut idxphy: u64) -> FsResult<Arc<Box<Block>>> {
         let mut idx_stack = Vec::new();
-        let mut idxphy = mht::phy2idxphy(data_phy);
-        idx_stack.push((mht::logi2dataidx(pos), data_phy));
 
         let first_cached_idx = {
             // find backward through the tree to the first cached idx blk
@@ -76,7 +138,7 @@ impl ROHashTree {
                         self.start + idxphy, true, self.root_hint.clone()
                     )?;
                 } else {
-                    let (father, child_idx) = mht::idxphy2father(idxphy);
+                    let (father, child_idx) = self.shape.idxphy2father(idxphy);
                     idx_stack.push((child_idx, idxphy));
                     idxphy = father;
                 }
@@ -84,44 +146,103 @@ impl ROHashTree {
             }
         };
 
-        // down the tree, use child_idx to get next idx blk, then final data blk
+        // down the tree, use child_idx to get next idx blk
         let mut this_idx_ablk = first_cached_idx;
-        while !idx_stack.is_empty() {
-            let (child_idx, child_phy) = idx_stack.pop().unwrap();
-            let ke = mht::get_ke(
-                &this_idx_ablk,
-                // if this is the last index, it's an data block
-                if idx_stack.is_empty() {
-                    mht::Data(child_idx)
-                } else {
-                    mht::Index(child_idx)
-                }
-            );
-            let hint = CryptoHint::from_key_entry(ke, self.encrypted, child_phy);
+        while let Some((child_idx, child_phy)) = idx_stack.pop() {
+            let ke = self.shape.get_ke(&this_idx_ablk, mht::Index(child_idx));
+            let hint = self.shape.hint(ke, self.encrypted, child_phy);
             this_idx_ablk = backend.get_blk_hint(
                 self.start + child_phy, true, hint
             )?;
         }
-        let data_ablk = this_idx_ablk;
-        Ok(data_ablk)
+        Ok(this_idx_ablk)
+    }
+
+    // get nr logical blocks from pos, data blks under the same idx blk
+    // are physically adjacent, so they are fetched from backend in batches
+    pub fn get_blks(
+        &self, pos: u64, nr: u64, f: impl FnMut(u64, &Block),
+    ) -> FsResult<()> {
+        self.get_blks_with(pos, nr, false, f)
+    }
+
+    // check_all overrides the verify policy
+    fn get_blks_with(
+        &self, pos: u64, nr: u64, check_all: bool, mut f: impl FnMut(u64, &Block),
+    ) -> FsResult<()> {
+        if pos + nr > self.logi_nr_blk() {
+            return Err(new_error!(FsError::UnexpectedEof))
+        }
+
+        let mut backend = self.backend.lock();
+        let mut logi = pos;
+        while logi < pos + nr {
+            let data_phy = self.shape.logi2phy(logi);
+            let idxphy = self.shape.phy2idxphy(data_phy);
+            let first_idx = self.shape.logi2dataidx(logi);
+            let round = (pos + nr - logi).min(self.shape.data_per_blk() - first_idx);
+
+            let idx_ablk = self.get_idx_blk(&mut backend, idxphy)
+                .map_err(|e| note_failed_blk(self.shape, e))?;
+            let hints: Vec<_> = (0..round).map(|i| {
+                let ke = self.shape.get_ke(&idx_ablk, mht::Data(first_idx + i));
+                self.data_hint(ke, data_phy + i, check_all)
+            }).collect();
+            drop(idx_ablk);
+
+            trace_err!(backend.get_blks_hint(
+                self.start + data_phy, true, &hints,
+                |p, blk| f(logi + (p - self.start - data_phy), blk),
+            )).map_err(|e| note_failed_blk(self.shape, e))?;
+            logi += round;
+        }
+        Ok(())
     }
 
     pub fn read_exact(&self, mut offset: usize, to: &mut [u8]) -> FsResult<usize> {
-        assert!(offset + to.len() <= blk2byte!(self.length) as usize);
+        let bsize = self.shape.blk_sz();
+        assert!(offset + to.len() <= blk2byte!(self.length, bsize) as usize);
 
         let total = to.len();
+        if total == 0 {
+            return Ok(0);
+        }
+        let first = (offset / bsize) as u64;
+        let last = ((offset + total - 1) / bsize) as u64;
         let mut done = 0;
-        while done < total {
-            let ablk = self.get_blk(( offset / BLK_SZ ) as u64)?;
-            let round = (total - done).min(BLK_SZ - offset % BLK_SZ);
-            let start = offset % BLK_SZ;
-            to[done..done+round].copy_from_slice(&ablk[start..start+round]);
+        self.get_blks(first, last - first + 1, |_, blk| {
+            let round = (total - done).min(bsize - offset % bsize);
+            let start = offset % bsize;
+            to[done..done+round].copy_from_slice(&blk[start..start+round]);
             done += round;
             offset += round;
-        }
+        })?;
         Ok(done)
     }
 
+    // pull nr data blocks from start into cache, index blocks are resolved once
+    // for all data blocks under them. blocks beyond the tree are left out,
+    // and nothing is done without data cache
+    pub fn prefetch(&self, start_blk: u64, nr_blk: u64) -> FsResult<()> {
+        if !self.cache_data {
+            return Ok(());
+        }
+        let end = start_blk.saturating_add(nr_blk).min(self.logi_nr_blk());
+        if start_blk >= end {
+            return Ok(());
+        }
+        self.get_blks_with(start_blk, end - start_blk, false, |_, _| {})
+    }
+
+    pub fn logi_nr_blk(&self) -> u64 {
+        self.shape.get_logi_nr_blk(self.length)
+    }
+
+    // fetch every block once, so that the whole tree is checked
+    pub fn verify_all(&self) -> FsResult<()> {
+        self.get_blks_with(0, self.logi_nr_blk(), true, |_, _| {})
+    }
+
     // flush all blocks including root
     // pub fn flush(&self) -> FsResult<()> {
     //     self.backend.lock().flush()
@@ -1,7 +1,8 @@
 use alloc::{
     sync::Arc,
     vec::Vec,
-    collections::BTreeMap,
+    boxed::Box,
+    collections::{BTreeMap, BTreeSet},
 };
 use crate::bcache::*;
 use crate::*;
@@ -24,6 +25,14 @@ pub struct RWHashTree {
     root_mode: FSMode,
     ke_buf: BTreeMap<u64, KeyEntry>,
     key_gen: KeyGen,
+    // data blocks never written, which are not cached and have empty kes,
+    // they read as zero without a backend read
+    nr_hole: u64,
+    // plain blocks written since last flush, checked again after it
+    #[cfg(feature = "debug_verify")]
+    written: BTreeMap<u64, Box<Block>>,
+    // block size comes from backend
+    shape: mht::Shape,
 }
 
 impl RWHashTree {
@@ -42,6 +51,7 @@ impl RWHashTree {
             cache: RWCache::new(
                 cache_cap_hint.unwrap_or(rw_cache_cap_defaults(length as usize))
             ),
+            shape: mht::Shape::full(backend.blk_sz()),
             backend,
             logi_len: length,
             encrypted,
@@ -51,17 +61,142 @@ impl RWHashTree {
             key_gen: KeyGen::new(length),
             #[cfg(feature = "std")]
             key_gen: KeyGen::new(),
+            nr_hole: 0,
+            #[cfg(feature = "debug_verify")]
+            written: BTreeMap::new(),
         }
     }
 
+    /// layout of the tree, decided by the block size of its backend
+    pub fn shape(&self) -> mht::Shape {
+        self.shape
+    }
+
+    /// data blocks never written, kept along with the tree by its owner
+    pub fn nr_hole(&self) -> u64 {
+        self.nr_hole
+    }
+
+    /// set the count kept for a tree just opened
+    pub fn set_nr_hole(&mut self, nr_hole: u64) {
+        self.nr_hole = nr_hole;
+    }
+
+    // a data blk is a hole if it's not cached and its ke is empty,
+    // since a hole is never cached, even when it's read
+    fn is_hole(&mut self, pos: u64) -> FsResult<bool> {
+        let data_phy = self.shape.logi2phy(pos);
+        if self.cache.get_blk_try(data_phy)?.is_some() {
+            return Ok(false);
+        }
+        let ke = match self.ke_buf.get(&data_phy) {
+            Some(ke) => *ke,
+            None => {
+                let father = self.get_idx_blk(self.shape.phy2idxphy(data_phy))?;
+                let ke = self.shape.get_ke(&father.read(), mht::Data(self.shape.logi2dataidx(pos)));
+                ke
+            }
+        };
+        Ok(FSMode::from_key_entry(ke, self.encrypted).is_empty())
+    }
+
+    // holes among data blks in [lo, hi), idx blks are read once each, and those
+    // never written are not read at all, nor are idx blks below them
+    fn count_holes(&mut self, lo: u64, hi: u64) -> FsResult<u64> {
+        let cached: BTreeSet<u64> = self.cache.keys().into_iter().collect();
+        self.count_holes_under(0, false, lo, hi, &cached)
+    }
+
+    // under the idx blk numbered n, whose content is all zero if `zero`
+    fn count_holes_under(
+        &mut self, n: u64, zero: bool, lo: u64, hi: u64, cached: &BTreeSet<u64>,
+    ) -> FsResult<u64> {
+        let shape = self.shape;
+        let (d, c) = (shape.data_per_blk(), shape.child_per_blk());
+        // idx blks of a subtree on each level are numbered in a row,
+        // the whole subtree is holes if it's all zero, with nothing cached or buffered
+        let (mut first, mut last) = (n, n);
+        let (mut in_range, mut touched) = (0, false);
+        while first.saturating_mul(d) < hi {
+            let (start, end) = (first * d, last.saturating_add(1).saturating_mul(d));
+            in_range += end.min(hi).saturating_sub(start.max(lo));
+            let phys = first * (d + 1)..last.saturating_add(1).saturating_mul(d + 1);
+            touched |= cached.range(phys.clone()).next().is_some()
+                || self.ke_buf.range(phys).next().is_some();
+            first = first.saturating_mul(c).saturating_add(1);
+            last = last.saturating_mul(c).saturating_add(c);
+        }
+        if in_range == 0 || (zero && !touched) {
+            return Ok(in_range);
+        }
+
+        let idxphy = n * (d + 1);
+        let blk = if zero {
+            None
+        } else {
+            Some(self.get_idx_blk(idxphy)?.read().clone())
+        };
+        let encrypted = self.encrypted;
+        let is_empty = |ke: KeyEntry| FSMode::from_key_entry(ke, encrypted).is_empty();
+        let ke_in = |blk: &Option<Box<Block>>, tp| blk.as_ref().map_or(KeyEntry::default(), |b| shape.get_ke(b, tp));
+
+        let mut nr = 0;
+        for logi in (n * d).max(lo)..((n + 1) * d).min(hi) {
+            let phy = shape.logi2phy(logi);
+            if cached.contains(&phy) {
+                continue;
+            }
+            let ke = match self.ke_buf.get(&phy) {
+                Some(ke) => *ke,
+                None => ke_in(&blk, mht::Data(shape.logi2dataidx(logi))),
+            };
+            if is_empty(ke) {
+                nr += 1;
+            }
+        }
+        for i in 0..c {
+            let child = n * c + 1 + i;
+            let child_phy = child * (d + 1);
+            let child_zero = !cached.contains(&child_phy) && is_empty(
+                match self.ke_buf.get(&child_phy) {
+                    Some(ke) => *ke,
+                    None => ke_in(&blk, mht::Index(i)),
+                }
+            );
+            nr += self.count_holes_under(child, child_zero, lo, hi, cached)?;
+        }
+        Ok(nr)
+    }
+
+    // a hole is about to be written
+    fn fill_hole(&mut self, pos: u64) -> FsResult<()> {
+        if self.is_hole(pos)? {
+            self.nr_hole = self.nr_hole.saturating_sub(1);
+        }
+        Ok(())
+    }
+
     pub fn get_cur_mode(&self) -> FSMode {
         self.root_mode.clone()
     }
 
     pub fn resize(&mut self, nr_blk: u64) -> FsResult<()> {
         // debug!("resize to {}", nr_blk);
+        mht::check_logi_nr_blk(nr_blk)?;
+
+        // holes cut off are counted while their idx blks are still there
+        if nr_blk == 0 {
+            self.nr_hole = 0;
+        } else if self.nr_hole != 0 && nr_blk < self.logi_len {
+            let cut = self.count_holes(nr_blk, self.logi_len)?;
+            self.nr_hole = self.nr_hole.saturating_sub(cut);
+        }
 
-        let new_phy_nr_blk = mht::get_phy_nr_blk(nr_blk);
+        let new_phy_nr_blk = self.shape.get_phy_nr_blk(nr_blk);
+        let org_phy_nr_blk = self.shape.get_phy_nr_blk(self.logi_len);
+        if new_phy_nr_blk < org_phy_nr_blk {
+            self.backend.discard(new_phy_nr_blk, org_phy_nr_blk - new_phy_nr_blk)?;
+        }
         // if the htree is cut, there should be invalid ke that points to somewhere over length
         // but it's ok, since we don't check anything over length
         self.backend.set_len(new_phy_nr_blk)?;
@@ -78,36 +213,19 @@ impl RWHashTree {
             return Ok(());
         }
 
-        let mut idx_pos = 0;
-        let mut idx_blk = None;
-        let mut idx_blk_next_idx = 0;
-        for pos in mht::get_phy_nr_blk(self.logi_len)..new_phy_nr_blk {
-            if mht::is_idx(pos) {
-                if let Some(blk) = idx_blk {
-                    let ke = self.backend_write(idx_pos, blk)?.into_key_entry();
-                    self.buffer_ke(idx_pos, ke)?;
-                }
-                idx_blk = Some([0u8; BLK_SZ]);
-                idx_pos = pos;
-                idx_blk_next_idx = 0;
-            } else {
-                let ke = self.backend_write(pos, [0u8; BLK_SZ])?.into_key_entry();
-                if let Some(idx) = &mut idx_blk {
-                    assert!(idx_blk_next_idx < mht::DATA_PER_BLK);
-                    mht::set_ke(idx, mht::Data(idx_blk_next_idx), &ke)?;
-                    idx_blk_next_idx += 1;
-                } else {
-                    // idx block already exists
-                    self.buffer_ke(pos, ke)?;
-                }
+        // grow without writing any block, a block never written has an empty ke and
+        // reads as zero, only stale kes left in existing idx blocks by a cut are cleared
+        self.ke_buf.split_off(&org_phy_nr_blk);
+        for pos in org_phy_nr_blk..new_phy_nr_blk {
+            if pos != HTREE_ROOT_BLK_PHY_POS && self.shape.get_father_idx(pos).0 < org_phy_nr_blk {
+                self.buffer_ke(pos, KeyEntry::default())?;
             }
         }
-        if let Some(blk) = idx_blk {
-            let ke = self.backend_write(idx_pos, blk)?.into_key_entry();
-            self.buffer_ke(idx_pos, ke)?;
-        }
 
         // reset htree length
+        if nr_blk > self.logi_len {
+            self.nr_hole += nr_blk - self.logi_len;
+        }
         self.logi_len = nr_blk;
 
         self.possible_flush_ke_buf()?;
@@ -116,47 +234,97 @@ impl RWHashTree {
     }
 
     pub fn zero_range(&mut self, offset: usize, len: usize) -> FsResult<()> {
-        let org_len = blk2byte!(self.logi_len) as usize;
+        let bsize = self.shape.blk_sz();
+        let org_len = blk2byte!(self.logi_len, bsize) as usize;
+        let end = offset.checked_add(len).ok_or(FsError::FileTooLarge)?;
 
-        let end = (offset + len).div_ceil(BLK_SZ);
-        self.resize(end.div_ceil(BLK_SZ) as u64)?;
-
-        if offset >= org_len {
-            return Ok(())
+        // only grow, blocks padded by resize are already zero
+        if end > org_len {
+            self.resize(end.div_ceil(bsize) as u64)?;
         }
 
+        // zero the part inside original length, edge blocks are partially written,
+        // interior blocks are overwritten without reading
         let end = end.min(org_len);
+        let zero = new_blk(bsize);
+        let mut cur = offset;
+        while cur < end {
+            let round = (end - cur).min(bsize - cur % bsize);
+            assert_eq!(self.write_exact(cur, &zero[..round])?, round);
+            cur += round;
+        }
 
-        let start = { // in blocks
-            if offset % BLK_SZ != 0 {
-                let len = BLK_SZ - offset % BLK_SZ;
-                let mut b = Vec::new();
-                b.resize(len, 0u8);
-                assert_eq!(self.write_exact(offset, &b)?, len);
+        self.possible_flush_ke_buf()?;
+
+        Ok(())
+    }
+
+    /// zero a range within length, which is kept, whole blocks of it become holes
+    /// and are discarded on storage, partial ones at both ends are written as zero
+    pub fn punch_hole(&mut self, offset: usize, len: usize) -> FsResult<()> {
+        let bsize = self.shape.blk_sz();
+        let end = offset.saturating_add(len).min(blk2byte!(self.logi_len, bsize) as usize);
+        if offset >= end {
+            return Ok(());
+        }
+        let first = offset.div_ceil(bsize);
+        let last = end / bsize;
+        if first >= last {
+            return self.zero_range(offset, end - offset);
+        }
+        self.zero_range(offset, first * bsize - offset)?;
+        self.zero_range(last * bsize, end - last * bsize)?;
+
+        let (first, last) = (first as u64, last as u64);
+        let mut pos = first;
+        while pos < last {
+            // data blocks under the same idx blk are adjacent on storage
+            let nr = (self.shape.data_per_blk() - self.shape.logi2dataidx(pos)).min(last - pos);
+            for logi in pos..pos + nr {
+                self.make_hole(logi)?;
             }
-            mht::get_phy_nr_blk(offset.div_ceil(BLK_SZ) as u64)
-        };
-        let end = { // in blocks
-            if end % BLK_SZ != 0 {
-                let len = end % BLK_SZ;
-                let mut b = Vec::new();
-                b.resize(len, 0u8);
-                assert_eq!(self.write_exact(end - len, &b)?, len);
-            }
-            mht::get_phy_nr_blk((end / BLK_SZ) as u64)
-        };
+            self.backend.discard(self.shape.logi2phy(pos), nr)?;
+            pos += nr;
+        }
+        self.possible_flush_ke_buf()?;
 
+        Ok(())
+    }
 
-        // now zero blocks in (start..end) which is not newly padded
-        for pos in start..end {
-            if !mht::is_idx(pos) {
-                if let Some(apay) = self.cache.get_blk_try(pos)? {
-                    apay.write().fill(0);
-                    self.cache.mark_dirty(pos)?;
-                } else {
-                    self.write_back(pos, [0u8; BLK_SZ])?;
-                }
+    // the block becomes a hole, left for the caller to discard on storage
+    fn make_hole(&mut self, logi: u64) -> FsResult<()> {
+        if !self.is_hole(logi)? {
+            self.nr_hole += 1;
+        }
+        let data_phy = self.shape.logi2phy(logi);
+        // dirty or not, the cached block is dropped without write back
+        self.cache.flush_key(data_phy)?;
+        #[cfg(feature = "debug_verify")]
+        self.written.remove(&data_phy);
+        self.buffer_ke(data_phy, KeyEntry::default())
+    }
+
+    // move nr blocks from `from` to `to`, ranges may overlap,
+    // moved blocks are written anew so they get keys of their new positions,
+    // and holes stay holes
+    pub fn move_blks(&mut self, from: u64, to: u64, nr: u64) -> FsResult<()> {
+        assert!(from.max(to) + nr <= self.logi_len);
+        if from == to {
+            return Ok(());
+        }
+
+        let mut blk = new_blk(self.shape.blk_sz());
+        for i in 0..nr {
+            // ascending when moving left, descending when moving right,
+            // so no source block is overwritten before it is read
+            let i = if to < from { i } else { nr - 1 - i };
+            if self.is_hole(from + i)? {
+                self.make_hole(to + i)?;
+                self.backend.discard(self.shape.logi2phy(to + i), 1)?;
+                continue;
             }
+            self.read_exact(blk2byte!(from + i, self.shape.blk_sz()) as usize, &mut blk)?;
+            self.overwrite_blk(to + i, &blk)?;
         }
 
         self.possible_flush_ke_buf()?;
@@ -171,11 +339,11 @@ impl RWHashTree {
             if !write {
                 return Ok(None);
             }
-            // pad file length to pos + 1
-            self.resize(pos + 1)?;
+            // pad file length to pos + 1, resize rejects a pos too large
+            self.resize(pos.saturating_add(1))?;
         }
 
-        let data_phy = mht::logi2phy(pos);
+        let data_phy = self.shape.logi2phy(pos);
         if let Some(apay) = self.cache.get_blk_try(data_phy)? {
             if write {
                 self.cache.mark_dirty(data_phy)?;
@@ -185,8 +353,8 @@ impl RWHashTree {
 
         // data blk not cached
         let mut idx_stack = Vec::new();
-        let mut idxphy = mht::phy2idxphy(data_phy);
-        idx_stack.push((mht::logi2dataidx(pos), data_phy));
+        let mut idxphy = self.shape.phy2idxphy(data_phy);
+        idx_stack.push((self.shape.logi2dataidx(pos), data_phy));
 
         let first_cached_idx = {
             // find backward through the tree to the first cached idx blk
@@ -200,7 +368,7 @@ impl RWHashTree {
                     // root blk is not cached
                     break self.cache_miss(idxphy, self.root_mode.clone())?;
                 } else {
-                    let (father, child_idx) = mht::idxphy2father(idxphy);
+                    let (father, child_idx) = self.shape.idxphy2father(idxphy);
                     idx_stack.push((child_idx, idxphy));
                     idxphy = father;
                 }
@@ -212,15 +380,17 @@ impl RWHashTree {
         let mut cur_apay = first_cached_idx;
         while !idx_stack.is_empty() {
             let (child_idx, child_phy) = idx_stack.pop().unwrap();
+            // if this is the last index, it's an data block
+            let is_data = idx_stack.is_empty();
             // try get ke from ke_buf
-            let ke = if let Some(ke) = self.ke_buf.remove(&child_phy) {
+            let buffered = self.ke_buf.get(&child_phy).copied();
+            let ke = if let Some(ke) = buffered {
                 ke
             } else {
                 let lock = cur_apay.read();
-                mht::get_ke(
+                self.shape.get_ke(
                     &lock,
-                    // if this is the last index, it's an data block
-                    if idx_stack.is_empty() {
+                    if is_data {
                         mht::Data(child_idx)
                     } else {
                         mht::Index(child_idx)
@@ -228,17 +398,44 @@ impl RWHashTree {
                 )
             };
             let mode = FSMode::from_key_entry(ke, self.encrypted);
+            if is_data && mode.is_empty() {
+                if !write {
+                    // a zero block of no one's, so it's never cached or written back
+                    return Ok(Some(Arc::new(RWPayLoad::new(new_blk(self.shape.blk_sz())))));
+                }
+                self.nr_hole = self.nr_hole.saturating_sub(1);
+            }
+            if buffered.is_some() {
+                self.ke_buf.remove(&child_phy);
+            }
             cur_apay = self.cache_miss(child_phy, mode)?;
         }
 
         // mark dirty
         if write {
-            self.cache.mark_dirty(mht::logi2phy(pos))?;
+            self.cache.mark_dirty(self.shape.logi2phy(pos))?;
         }
 
         Ok(Some(cur_apay))
     }
 
+    // bring an idx blk into cache, down the tree from the first cached one
+    fn get_idx_blk(&mut self, idxphy: u64) -> FsResult<Arc<RWPayLoad>> {
+        if let Some(apay) = self.cache.get_blk_try(idxphy)? {
+            return Ok(apay);
+        }
+        if idxphy == HTREE_ROOT_BLK_PHY_POS {
+            return self.cache_miss(idxphy, self.root_mode.clone());
+        }
+        let (father, child_idx) = self.shape.idxphy2father(idxphy);
+        let father = self.get_idx_blk(father)?;
+        let ke = match self.ke_buf.remove(&idxphy) {
+            Some(ke) => ke,
+            None => self.shape.get_ke(&father.read(), mht::Index(child_idx)),
+        };
+        self.cache_miss(idxphy, FSMode::from_key_entry(ke, self.encrypted))
+    }
+
     fn cache_miss(
         &mut self, pos: u64, mode: FSMode
     ) -> FsResult<Arc<RWPayLoad>> {
@@ -258,27 +455,40 @@ impl RWHashTree {
         Ok(apay)
     }
 
-    fn write_back(&mut self, pos: u64, mut blk: Block) -> FsResult<()> {
+    fn write_back(&mut self, pos: u64, mut blk: Box<Block>) -> FsResult<()> {
         // debug!("write back {pos}");
         // debug!("ke_buf before wb: {:?}", self.ke_buf.keys().collect::<Vec<_>>());
         assert_eq!(self.possible_ke_wb(pos, &mut blk)?, false);
 
-        let mode = self.backend_write(pos, blk)?;
+        let mode = match self.backend_write(pos, blk.clone()) {
+            Ok(mode) => mode,
+            Err(e) => {
+                // keep it dirty in cache for a later flush
+                self.cache.put_back(pos, blk)?;
+                return Err(e);
+            }
+        };
 
         // ke changes, try to write back into father
         self.buffer_ke(pos, mode.into_key_entry())?;
         Ok(())
     }
 
-    fn backend_read(&mut self, pos: u64, mode: FSMode) -> FsResult<Block> {
+    fn backend_read(&mut self, pos: u64, mode: FSMode) -> FsResult<Box<Block>> {
+        if mode.is_empty() {
+            return Ok(new_blk(self.shape.blk_sz()));
+        }
         let mut blk = self.backend.read_blk(pos)?;
-        crypto_in(&mut blk, CryptoHint::from_fsmode(mode, pos))?;
+        trace_err!(crypto_in(&mut blk, CryptoHint::from_fsmode(mode, pos)))
+            .map_err(|e| note_failed_blk(self.shape, e))?;
         Ok(blk)
     }
 
     fn backend_write(
-        &mut self, pos: u64, mut blk: Block,
+        &mut self, pos: u64, mut blk: Box<Block>,
     ) -> FsResult<FSMode> {
+        #[cfg(feature = "debug_verify")]
+        self.written.insert(pos, blk.clone());
         let mode = crypto_out(
             &mut blk,
             if self.encrypted {
@@ -294,16 +504,17 @@ impl RWHashTree {
     }
 
     pub fn read_exact(&mut self, mut offset: usize, to: &mut [u8]) -> FsResult<usize> {
-        assert!(offset + to.len() <= blk2byte!(self.logi_len) as usize);
+        let bsize = self.shape.blk_sz();
+        assert!(offset + to.len() <= blk2byte!(self.logi_len, bsize) as usize);
 
         let total = to.len();
         let mut done = 0;
         while done < total {
             let apay = self.get_blk(
-                ( offset / BLK_SZ ) as u64, false
+                ( offset / bsize ) as u64, false
             )?.ok_or_else(|| new_error!(FsError::IncompatibleMetadata))?;
-            let round = (total - done).min(BLK_SZ - offset % BLK_SZ);
-            let start = offset % BLK_SZ;
+            let round = (total - done).min(bsize - offset % bsize);
+            let start = offset % bsize;
             to[done..done+round].copy_from_slice(
                 &apay.read()[start..start+round]
             );
@@ -313,23 +524,118 @@ impl RWHashTree {
         Ok(done)
     }
 
-    pub fn write_exact(&mut self, mut offset: usize, from: &[u8]) -> FsResult<usize> {
+    pub fn write_exact(&mut self, offset: usize, from: &[u8]) -> FsResult<usize> {
+        let bsize = self.shape.blk_sz();
         let total = from.len();
+        // partial head and tail blocks are read first, whole blocks in between are not
+        let head = ((bsize - offset % bsize) % bsize).min(total);
+        let nr_mid = (total - head) / bsize;
+        let tail = head + nr_mid * bsize;
+
+        self.write_partial(offset, &from[..head])?;
+        if nr_mid != 0 {
+            self.write_blocks(((offset + head) / bsize) as u64, &from[head..tail])?;
+        }
+        self.write_partial(offset + tail, &from[tail..])?;
+
+        Ok(total)
+    }
+
+    // from is within one block
+    fn write_partial(&mut self, offset: usize, from: &[u8]) -> FsResult<()> {
+        if from.is_empty() {
+            return Ok(());
+        }
+        let bsize = self.shape.blk_sz();
+        let start = offset % bsize;
+        let apay = self.get_blk((offset / bsize) as u64, true)?.unwrap();
+        apay.write()[start..start + from.len()].copy_from_slice(from);
+        Ok(())
+    }
+
+    /// overwrite a contiguous run of whole data blocks, the htree is resized once,
+    /// a run that fits in cache goes there, a longer one would only push itself out,
+    /// so it's written to storage right away, with kes set in idx blks held in cache
+    pub fn write_blocks(&mut self, start_blk: u64, from: &[u8]) -> FsResult<()> {
+        let bsize = self.shape.blk_sz();
+        assert_eq!(from.len() % bsize, 0);
+        let blocks: Vec<&Block> = from.chunks_exact(bsize).collect();
+        if blocks.is_empty() {
+            return Ok(());
+        }
+        let end = start_blk.checked_add(blocks.len() as u64).ok_or(FsError::FileTooLarge)?;
+        if end > self.logi_len {
+            self.resize(end)?;
+        }
+
+        if blocks.len() < self.cache.get_cap() {
+            for (pos, blk) in (start_blk..end).zip(blocks) {
+                self.overwrite_blk(pos, blk)?;
+            }
+            return Ok(());
+        }
+
+        let mut pos = start_blk;
         let mut done = 0;
-        while done < total {
-            let apay = self.get_blk(
-                ( offset / BLK_SZ ) as u64, true
-            )?.unwrap();
-            let round = (total - done).min(BLK_SZ - offset % BLK_SZ);
-            let start = offset % BLK_SZ;
-            apay.write()[start..start+round].copy_from_slice(
-                &from[done..done+round]
-            );
-            done += round;
-            offset += round;
+        while done < blocks.len() {
+            // data blocks under the same idx blk share its path from root
+            let nr = ((self.shape.data_per_blk() - self.shape.logi2dataidx(pos)) as usize).min(blocks.len() - done);
+            let idxphy = self.shape.phy2idxphy(self.shape.logi2phy(pos));
+            let father = self.get_idx_blk(idxphy)?;
+            for (i, blk) in blocks[done..done + nr].iter().enumerate() {
+                let data_phy = self.shape.logi2phy(pos + i as u64);
+                if let Some(apay) = self.cache.get_blk_try(data_phy)? {
+                    apay.write().copy_from_slice(blk);
+                    self.cache.mark_dirty(data_phy)?;
+                    continue;
+                }
+                self.fill_hole(pos + i as u64)?;
+                let ke = match self.backend_write(data_phy, Box::from(*blk)) {
+                    Ok(mode) => mode.into_key_entry(),
+                    Err(e) => {
+                        // keep it dirty in cache for a later flush
+                        self.cache.mark_dirty(idxphy)?;
+                        self.cache.put_back(data_phy, Box::from(*blk))?;
+                        return Err(e);
+                    }
+                };
+                self.shape.set_ke(&mut father.write(), mht::Data(self.shape.phy2dataidx(data_phy)), &ke)?;
+            }
+            self.cache.mark_dirty(idxphy)?;
+            done += nr;
+            pos += nr as u64;
         }
 
-        Ok(done)
+        self.possible_flush_ke_buf()?;
+
+        Ok(())
+    }
+
+    // replace a whole data block, pos is by block
+    fn overwrite_blk(&mut self, pos: u64, from: &[u8]) -> FsResult<()> {
+        assert_eq!(from.len(), self.shape.blk_sz());
+        if pos >= self.logi_len {
+            self.resize(pos.saturating_add(1))?;
+        }
+
+        let data_phy = self.shape.logi2phy(pos);
+        if let Some(apay) = self.cache.get_blk_try(data_phy)? {
+            apay.write().copy_from_slice(from);
+            self.cache.mark_dirty(data_phy)?;
+            return Ok(());
+        }
+        self.fill_hole(pos)?;
+
+        // old ke of this block is useless now, new one comes with write back
+        self.ke_buf.remove(&data_phy);
+
+        let (_, wb) = self.cache.insert_and_get(data_phy, Box::from(from))?;
+        self.cache.mark_dirty(data_phy)?;
+
+        if let Some((pos, blk)) = wb {
+            self.write_back(pos, blk)?;
+        }
+        Ok(())
     }
 
     // flush all blocks including root
@@ -348,25 +654,181 @@ impl RWHashTree {
 
         self.flush_ke_buf()?;
 
+        #[cfg(feature = "debug_verify")]
+        self.verify_written()?;
+
+        Ok(self.root_mode.clone())
+    }
+
+    // read every block written since last flush down from root again, so that
+    // a wrong ke fails here rather than on next mount, children of written idx
+    // blocks are read as well, since their kes are what is written there
+    #[cfg(feature = "debug_verify")]
+    fn verify_written(&mut self) -> FsResult<()> {
+        let phy_len = self.shape.get_phy_nr_blk(self.logi_len);
+        let mut to_check = BTreeSet::new();
+        for &pos in self.written.keys().filter(|pos| **pos < phy_len) {
+            to_check.insert(pos);
+            if self.shape.is_idx(pos) {
+                let data = (1..=self.shape.data_per_blk()).map(|i| pos + i);
+                let first_idx = self.shape.get_first_idx_child_phy(pos);
+                let idx = (0..self.shape.child_per_blk()).map(|i| first_idx + i * (self.shape.data_per_blk() + 1));
+                to_check.extend(data.chain(idx).filter(|child| *child < phy_len));
+            }
+        }
+
+        for pos in to_check {
+            let mut path = Vec::new();
+            let mut cur = pos;
+            while cur != HTREE_ROOT_BLK_PHY_POS {
+                let (father, tp) = self.shape.get_father_idx(cur);
+                path.push((tp, cur));
+                cur = father;
+            }
+            let mut blk = self.backend_read(HTREE_ROOT_BLK_PHY_POS, self.root_mode.clone())?;
+            for (tp, child) in path.into_iter().rev() {
+                let ke = self.shape.get_ke(&blk, tp);
+                blk = self.backend_read(child, FSMode::from_key_entry(ke, self.encrypted))?;
+            }
+            if self.written.get(&pos).is_some_and(|w| *w != blk) {
+                return Err(new_error!(FsError::IntegrityCheckError));
+            }
+        }
+        self.written.clear();
+        Ok(())
+    }
+
+    // write back data blocks in [pos, pos + nr) and idx blocks on their way
+    // to root, other dirty data blocks stay in cache
+    pub fn flush_range(&mut self, pos: u64, nr: u64) -> FsResult<FSMode> {
+        let end = (pos + nr).min(self.logi_len);
+        if pos >= end {
+            return Ok(self.root_mode.clone());
+        }
+
+        let mut idx_set = BTreeSet::new();
+        for logi in pos..end {
+            let data_phy = self.shape.logi2phy(logi);
+            if let Some(blk) = self.cache.flush_key(data_phy)? {
+                self.write_back(data_phy, blk)?;
+            }
+            let mut idxphy = self.shape.phy2idxphy(data_phy);
+            while idx_set.insert(idxphy) && idxphy != HTREE_ROOT_BLK_PHY_POS {
+                idxphy = self.shape.idxphy2father(idxphy).0;
+            }
+        }
+
+        // ke of uncached fathers go to cached idx blks or directly to root
+        self.flush_ke_buf()?;
+
+        // children have bigger pos than fathers, so a ke always reaches
+        // its father before the father is written back
+        for idxphy in idx_set.into_iter().rev() {
+            if let Some(blk) = self.cache.flush_key(idxphy)? {
+                self.write_back(idxphy, blk)?;
+            }
+        }
+        self.flush_ke_buf()?;
+
         Ok(self.root_mode.clone())
     }
 
-    // this function does not modify cache (but maybe cached blocks)
+    /// re-encrypt every block under fresh keys into `to` at the same positions,
+    /// nothing is written to this tree, so flush it first, returns the new root mode
+    pub fn rekey_into(&mut self, to: &Arc<dyn RWStorage>) -> FsResult<FSMode> {
+        let phy_len = self.shape.get_phy_nr_blk(self.logi_len);
+        to.set_len(phy_len)?;
+        if phy_len == 0 {
+            return Ok(self.root_mode.clone());
+        }
+        self.rekey_idx_blk(to, HTREE_ROOT_BLK_PHY_POS, self.root_mode.clone(), phy_len)
+    }
+
+    // an idx blk and all below it, blocks never written have empty kes and stay so
+    fn rekey_idx_blk(
+        &mut self, to: &Arc<dyn RWStorage>, idxphy: u64, mode: FSMode, phy_len: u64,
+    ) -> FsResult<FSMode> {
+        let mut blk = self.backend_read(idxphy, mode)?;
+
+        let mut child_phy = self.shape.get_first_data_child_phy(idxphy);
+        for i in 0..self.shape.data_per_blk() {
+            if child_phy >= phy_len {
+                break;
+            }
+            let mode = FSMode::from_key_entry(self.shape.get_ke(&blk, mht::Data(i)), self.encrypted);
+            if !mode.is_empty() {
+                let data = self.backend_read(child_phy, mode)?;
+                let ke = self.rekey_write(to, child_phy, data)?.into_key_entry();
+                self.shape.set_ke(&mut blk, mht::Data(i), &ke)?;
+            }
+            child_phy = self.shape.next_data_sibling_phy(child_phy);
+        }
+
+        let mut child_phy = self.shape.get_first_idx_child_phy(idxphy);
+        for i in 0..self.shape.child_per_blk() {
+            if child_phy >= phy_len {
+                break;
+            }
+            let mode = FSMode::from_key_entry(self.shape.get_ke(&blk, mht::Index(i)), self.encrypted);
+            if !mode.is_empty() {
+                let ke = self.rekey_idx_blk(to, child_phy, mode, phy_len)?.into_key_entry();
+                self.shape.set_ke(&mut blk, mht::Index(i), &ke)?;
+            }
+            child_phy = self.shape.next_idx_sibling_phy(child_phy);
+        }
+
+        self.rekey_write(to, idxphy, blk)
+    }
+
+    fn rekey_write(&mut self, to: &Arc<dyn RWStorage>, pos: u64, mut blk: Box<Block>) -> FsResult<FSMode> {
+        let key = if self.encrypted {
+            Some(self.key_gen.gen_key(pos)?)
+        } else {
+            None
+        };
+        let mode = crypto_out(&mut blk, key, pos)?;
+        to.write_blk(pos, &blk)?;
+        Ok(mode)
+    }
+
+    // this function does not modify cache (but maybe cached blocks),
+    // unless it fails, then blocks changed but not written go to cache as dirty
+    // and kes are kept, so that a later flush retries from there
     fn flush_ke_buf(&mut self) -> FsResult<()> {
         if self.ke_buf.len() == 0 {
             return Ok(());
         }
 
-        // let mut cache_keys = self.cache.flush_keys()?;
-        // cache_keys.sort();
-        // debug!("cache keys: {:?}", cache_keys);
-        // let mut keys = self.ke_buf.keys().collect::<Vec<_>>();
-        // keys.sort();
-        // debug!("Flush ke buf");
-        // debug!("ke_buf: {:?}", keys);
+        let ke_buf = mem::take(&mut self.ke_buf);
+        let mut pending = BTreeMap::new();
+        let ret = self.write_ke_buf(&ke_buf, &mut pending);
+        if ret.is_err() {
+            for (pos, blk) in pending {
+                self.cache.put_back(pos, blk)?;
+            }
+            // same rule as buffer_ke, kes of cached fathers never stay in ke_buf
+            for (pos, ke) in ke_buf {
+                let (father, child_idx) = self.shape.get_father_idx(pos);
+                if let Some(apay) = self.cache.get_blk_try(father)? {
+                    self.shape.set_ke(&mut apay.write(), child_idx, &ke)?;
+                    self.cache.mark_dirty(father)?;
+                } else {
+                    self.ke_buf.insert(pos, ke);
+                }
+            }
+        }
+        ret
+    }
+
+    // blocks read and changed here are kept in pending until written
+    fn write_ke_buf(
+        &mut self,
+        ke_buf: &BTreeMap<u64, KeyEntry>,
+        pending: &mut BTreeMap<u64, Box<Block>>,
+    ) -> FsResult<()> {
         let mut buf: BTreeMap<_, Vec<_>> = BTreeMap::new();
-        for (pos, ke) in mem::take(&mut self.ke_buf) {
-            let (f, idx) = mht::get_father_idx(pos);
+        for (&pos, &ke) in ke_buf {
+            let (f, idx) = self.shape.get_father_idx(pos);
             if let Some(v) = buf.get_mut(&f) {
                 v.push((idx, ke));
             } else {
@@ -375,25 +837,20 @@ impl RWHashTree {
                 assert!(buf.insert(f, v).is_none());
             }
         }
-        // debug!("buf: {:?}", buf.iter().map(
-        //     |(k, v)| (k, v.iter().map(|x|x.0.clone()).collect::<Vec<_>>())
-        // ).collect::<Vec<_>>());
 
         macro_rules! write_ke_list {
             ($blk: expr, $ke_list: expr) => {
                 for (idx, ke) in $ke_list {
-                    mht::set_ke(&mut $blk, idx.clone(), &ke)?;
+                    self.shape.set_ke($blk, idx.clone(), &ke)?;
                 }
             };
         }
 
         // pin root block
-        let mut root_blk = if self.cache.get_blk_try(HTREE_ROOT_BLK_PHY_POS)?.is_some() {
-            // root already cached
-            None
-        } else {
-            Some(self.backend_read(HTREE_ROOT_BLK_PHY_POS, self.root_mode.clone())?)
-        };
+        if self.cache.get_blk_try(HTREE_ROOT_BLK_PHY_POS)?.is_none() {
+            let blk = self.backend_read(HTREE_ROOT_BLK_PHY_POS, self.root_mode.clone())?;
+            pending.insert(HTREE_ROOT_BLK_PHY_POS, blk);
+        }
 
         let mut keys: Vec<_> = buf.keys().map(
             |k| *k
@@ -425,12 +882,6 @@ impl RWHashTree {
             let (first_not_cached, mode) = loop {
                 if let Some(apay) = self.cache.get_blk_try(idxphy)? {
                     // a cache block should not have any pending ke in ke_buf
-                    // let ret = buf.remove(&idxphy);
-                    // if ret.is_some() {
-                    //     panic!("phy {} have childs ke {:?} not wb",
-                    //         idxphy,
-                    //     ret.as_ref().unwrap().iter().map(|x| x.0.clone()).collect::<Vec<_>>());
-                    // }
                     assert!(buf.remove(&idxphy).is_none());
 
                     let (child_idx, child_phy) = idx_stack.pop().unwrap();
@@ -438,7 +889,7 @@ impl RWHashTree {
 
                     let ke = {
                         let lock = apay.read();
-                        mht::get_ke(
+                        self.shape.get_ke(
                             &lock,
                             // must be index
                             mht::Index(child_idx)
@@ -449,7 +900,7 @@ impl RWHashTree {
                     // root blk is not cached
                     break (idxphy, self.root_mode.clone());
                 } else {
-                    let (father, child_idx) = mht::idxphy2father(idxphy);
+                    let (father, child_idx) = self.shape.idxphy2father(idxphy);
                     idx_stack.push((child_idx, idxphy));
                     idxphy = father;
                 }
@@ -459,20 +910,19 @@ impl RWHashTree {
             // down the tree, use child_idx to get next idx blk
             let mut blk_stack = Vec::new();
             while !idx_stack.is_empty() {
-                let mut cur_blk = if cur_phy == HTREE_ROOT_BLK_PHY_POS {
-                    root_blk.clone().unwrap()
-                } else {
-                    self.backend_read(cur_phy, cur_mode)?
-                };
+                if cur_phy != HTREE_ROOT_BLK_PHY_POS {
+                    let blk = self.backend_read(cur_phy, cur_mode)?;
+                    pending.insert(cur_phy, blk);
+                }
+                let cur_blk = pending.get_mut(&cur_phy).unwrap();
                 if let Some(ke_list) = buf.remove(&cur_phy) {
                     write_ke_list!(cur_blk, ke_list);
                 }
                 let (child_idx, child_phy) = idx_stack.pop().unwrap();
-                blk_stack.push((cur_phy, cur_blk, child_idx));
-
+                blk_stack.push((cur_phy, child_idx));
                 // try get ke from ke_buf
-                let ke = mht::get_ke(
-                    &cur_blk,
+                let ke = self.shape.get_ke(
+                    cur_blk,
                     // must be index
                     mht::Index(child_idx)
                 );
@@ -484,31 +934,34 @@ impl RWHashTree {
 
             // get "pos" and write ke
             if cur_phy == HTREE_ROOT_BLK_PHY_POS {
-                write_ke_list!(root_blk.as_mut().unwrap(), ke_list);
+                write_ke_list!(pending.get_mut(&cur_phy).unwrap(), ke_list);
                 continue;
             }
             let mut cur_blk = self.backend_read(cur_phy, cur_mode)?;
-            write_ke_list!(cur_blk, ke_list);
+            write_ke_list!(&mut cur_blk, ke_list);
+            pending.insert(cur_phy, cur_blk.clone());
 
             // write back "pos"
             let mut ke = self.backend_write(cur_phy, cur_blk)?.into_key_entry();
+            pending.remove(&cur_phy);
 
             // write back blk_stack
-            for (pos, mut blk, child_idx) in blk_stack.into_iter().rev() {
-                mht::set_ke(&mut blk, mht::Index(child_idx), &ke)?;
+            for (pos, child_idx) in blk_stack.into_iter().rev() {
+                let blk = pending.get_mut(&pos).unwrap();
+                self.shape.set_ke(blk, mht::Index(child_idx), &ke)?;
                 if pos == HTREE_ROOT_BLK_PHY_POS {
-                    assert!(root_blk.is_some());
-                    root_blk = Some(blk);
                     break;
                 } else {
+                    let blk = blk.clone();
                     ke = self.backend_write(pos, blk)?.into_key_entry();
+                    pending.remove(&pos);
                 }
             }
 
             // write last ke to first_cached_idx or root
             if let Some((pos, apay, idx)) = last_ke_dest {
                 let mut lock = apay.write();
-                mht::set_ke(&mut lock, mht::Index(idx), &ke)?;
+                self.shape.set_ke(&mut lock, mht::Index(idx), &ke)?;
                 self.cache.mark_dirty(pos)?;
             } else {
                 // last ke goes to root
@@ -517,19 +970,20 @@ impl RWHashTree {
         }
 
         // unpin root block and write back
-        if let Some(blk) = root_blk {
+        if let Some(blk) = pending.get(&HTREE_ROOT_BLK_PHY_POS).cloned() {
             self.root_mode = self.backend_write(HTREE_ROOT_BLK_PHY_POS, blk)?;
+            pending.remove(&HTREE_ROOT_BLK_PHY_POS);
         }
 
         Ok(())
     }
 
     fn buffer_ke(&mut self, pos: u64, ke: KeyEntry) -> FsResult<()> {
-        let (father, child_idx) = mht::get_father_idx(pos);
+        let (father, child_idx) = self.shape.get_father_idx(pos);
         if let Some(apay) = self.cache.get_blk_try(father)? {
             // debug!("ke of {} goes to cached father {}", pos, father);
             let mut lock = apay.write();
-            mht::set_ke(
+            self.shape.set_ke(
                 &mut lock,
                 child_idx,
                 &ke,
@@ -555,38 +1009,38 @@ impl RWHashTree {
     }
 
     fn possible_ke_wb(&mut self, pos: u64, blk: &mut Block) -> FsResult<bool> {
-        if !mht::is_idx(pos) {
+        if !self.shape.is_idx(pos) {
             return Ok(false);
         }
 
         let mut dirty = false;
 
         // idx ke
-        let mut child_phy = mht::get_first_idx_child_phy(pos);
-        for i in 0..mht::CHILD_PER_BLK {
+        let mut child_phy = self.shape.get_first_idx_child_phy(pos);
+        for i in 0..self.shape.child_per_blk() {
             if let Some(ke) = self.ke_buf.remove(&child_phy) {
-                mht::set_ke(
+                self.shape.set_ke(
                     blk,
                     mht::Index(i),
                     &ke,
                 )?;
                 dirty = true;
             }
-            child_phy = mht::next_idx_sibling_phy(child_phy);
+            child_phy = self.shape.next_idx_sibling_phy(child_phy);
         }
 
         // data ke
-        let mut child_phy = mht::get_first_data_child_phy(pos);
-        for i in 0..mht::DATA_PER_BLK {
+        let mut child_phy = self.shape.get_first_data_child_phy(pos);
+        for i in 0..self.shape.data_per_blk() {
             if let Some(ke) = self.ke_buf.remove(&child_phy) {
-                mht::set_ke(
+                self.shape.set_ke(
                     blk,
                     mht::Data(i),
                     &ke,
                 )?;
                 dirty = true;
             }
-            child_phy = mht::next_data_sibling_phy(child_phy);
+            child_phy = self.shape.next_data_sibling_phy(child_phy);
         }
 
         let mut keys = self.ke_buf.keys().collect::<Vec<_>>();
@@ -603,6 +1057,7 @@ mod test {
     use crate::*;
     use super::*;
 
+    const FULL: mht::Shape = mht::Shape::full(BLK_SZ);
     const MODE_PATH: &str = "test/mode";
     fn open_htree(htree_path: &str) -> FsResult<RWHashTree> {
         use crate::*;
@@ -647,6 +1102,7 @@ mod test {
         let back = FileStorage::new(
             Path::new(htree_path),
             true,
+            BLK_SZ,
         )?;
         Ok(RWHashTree::new(
             Some(10),
@@ -735,4 +1191,437 @@ mod test {
 
         Ok(())
     }
+
+    // blocks never written are not kept
+    struct CountStorage {
+        blks: std::sync::Mutex<(u64, BTreeMap<u64, Box<Block>>)>,
+        data_reads: std::sync::atomic::AtomicUsize,
+        idx_reads: std::sync::atomic::AtomicUsize,
+        discarded: std::sync::Mutex<Vec<(u64, u64)>>,
+        writes: std::sync::Mutex<Vec<u64>>,
+    }
+
+    impl CountStorage {
+        fn new() -> Self {
+            Self {
+                blks: std::sync::Mutex::new((0, BTreeMap::new())),
+                data_reads: std::sync::atomic::AtomicUsize::new(0),
+                idx_reads: std::sync::atomic::AtomicUsize::new(0),
+                discarded: std::sync::Mutex::new(Vec::new()),
+                writes: std::sync::Mutex::new(Vec::new()),
+            }
+        }
+    }
+
+    impl crate::storage::ROStorage for CountStorage {
+        fn blk_sz(&self) -> usize {
+            BLK_SZ
+        }
+
+        fn read_blk_to(&self, pos: u64, to: &mut Block) -> FsResult<()> {
+            if FULL.is_idx(pos) {
+                self.idx_reads.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            } else {
+                self.data_reads.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            }
+            let blks = self.blks.lock().unwrap();
+            assert!(pos < blks.0);
+            match blks.1.get(&pos) {
+                Some(blk) => to.copy_from_slice(blk),
+                None => to.fill(0),
+            }
+            Ok(())
+        }
+    }
+
+    impl RWStorage for CountStorage {
+        fn write_blk(&self, pos: u64, from: &Block) -> FsResult<()> {
+            let mut blks = self.blks.lock().unwrap();
+            assert!(pos < blks.0);
+            blks.1.insert(pos, from.into());
+            self.writes.lock().unwrap().push(pos);
+            Ok(())
+        }
+
+        fn get_len(&self) -> FsResult<u64> {
+            Ok(blk2byte!(self.blks.lock().unwrap().0, BLK_SZ))
+        }
+
+        fn set_len(&self, nr_blk: u64) -> FsResult<()> {
+            let mut blks = self.blks.lock().unwrap();
+            blks.0 = nr_blk;
+            blks.1.split_off(&nr_blk);
+            Ok(())
+        }
+
+        fn discard(&self, pos: u64, nr_blk: u64) -> FsResult<()> {
+            self.discarded.lock().unwrap().push((pos, nr_blk));
+            Ok(())
+        }
+    }
+
+    // debug_verify reads back what flush writes
+    #[test]
+    #[cfg(not(feature = "debug_verify"))]
+    fn full_blk_overwrite_no_read() -> FsResult<()> {
+        let back = Arc::new(CountStorage::new());
+        let nr_blk = 2 * FULL.data_per_blk() as usize;
+
+        let mut htree = RWHashTree::new(Some(4), back.clone(), 0, None, true);
+        htree.write_exact(0, &vec![1u8; nr_blk * BLK_SZ])?;
+        let mode = htree.flush()?;
+
+        let reads = back.data_reads.load(std::sync::atomic::Ordering::SeqCst);
+        let mut htree = RWHashTree::new(Some(4), back.clone(), nr_blk as u64, Some(mode), true);
+        htree.write_exact(0, &vec![2u8; nr_blk * BLK_SZ])?;
+        let mode = htree.flush()?;
+        assert_eq!(back.data_reads.load(std::sync::atomic::Ordering::SeqCst), reads);
+
+        let mut htree = RWHashTree::new(Some(4), back.clone(), nr_blk as u64, Some(mode), true);
+        let mut buf = vec![0u8; nr_blk * BLK_SZ];
+        assert_eq!(htree.read_exact(0, &mut buf)?, buf.len());
+        assert!(buf.iter().all(|b| *b == 2));
+
+        Ok(())
+    }
+
+    #[test]
+    fn holes_kept_by_count() -> FsResult<()> {
+        use std::sync::atomic::Ordering::SeqCst;
+
+        let back = Arc::new(CountStorage::new());
+        let far = 4 * FULL.data_per_blk();
+        let mut htree = RWHashTree::new(Some(4), back.clone(), 0, None, true);
+        htree.write_exact(0, &[1u8; 10])?;
+        htree.write_exact(blk2byte!(far, BLK_SZ) as usize, &[1u8; 10])?;
+        assert_eq!(htree.nr_hole(), far - 1);
+        let mode = htree.flush()?;
+
+        // opening the tree reads nothing, neither does a hole
+        let (idx_reads, data_reads) = (back.idx_reads.load(SeqCst), back.data_reads.load(SeqCst));
+        let mut htree = RWHashTree::new(Some(4), back.clone(), far + 1, Some(mode), true);
+        htree.set_nr_hole(far - 1);
+        assert_eq!(back.idx_reads.load(SeqCst), idx_reads);
+        let mut buf = [1u8; BLK_SZ];
+        htree.read_exact(blk2byte!(far / 2, BLK_SZ) as usize, &mut buf)?;
+        assert!(buf.iter().all(|b| *b == 0));
+        assert_eq!(back.data_reads.load(SeqCst), data_reads);
+
+        // holes are counted as they are filled, punched and cut off
+        htree.write_exact(blk2byte!(far / 2, BLK_SZ) as usize, &[2u8; 3])?;
+        htree.write_blocks(1, &[3u8; 2 * BLK_SZ])?;
+        assert_eq!(htree.nr_hole(), far - 4);
+        htree.punch_hole(0, blk2byte!(2, BLK_SZ) as usize)?;
+        assert_eq!(htree.nr_hole(), far - 2);
+        htree.resize(far / 2)?;
+        assert_eq!(htree.nr_hole(), far / 2 - 1);
+        htree.resize(0)?;
+        assert_eq!(htree.nr_hole(), 0);
+
+        // cutting a huge sparse tree reads only idx blks that are written
+        let huge = FULL.data_per_blk() * FULL.child_per_blk() * FULL.child_per_blk();
+        let back = Arc::new(CountStorage::new());
+        let mut htree = RWHashTree::new(Some(4), back.clone(), 0, None, true);
+        htree.write_exact(0, &[1u8; 10])?;
+        htree.write_exact(blk2byte!(huge, BLK_SZ) as usize, &[1u8; 10])?;
+        assert_eq!(htree.nr_hole(), huge - 1);
+        let mode = htree.flush()?;
+        let idx_reads = back.idx_reads.load(SeqCst);
+        let mut htree = RWHashTree::new(Some(4), back.clone(), huge + 1, Some(mode), true);
+        htree.set_nr_hole(huge - 1);
+        htree.resize(huge / 2)?;
+        assert_eq!(htree.nr_hole(), huge / 2 - 1);
+        htree.resize(1)?;
+        assert_eq!(htree.nr_hole(), 0);
+        assert!(back.idx_reads.load(SeqCst) - idx_reads <= 3);
+
+        Ok(())
+    }
+
+    // key gen fails on a reused (key, pos) in debug builds
+    #[test]
+    fn rewrites_never_reuse_key() -> FsResult<()> {
+        let back = Arc::new(CountStorage::new());
+        let nr_blk = 40;
+
+        let mut htree = RWHashTree::new(Some(4), back.clone(), 0, None, true);
+        for round in 0..8u8 {
+            htree.write_exact(0, &vec![round; nr_blk * BLK_SZ])?;
+            // partial rewrites and ones that stay in cache
+            htree.write_exact(BLK_SZ / 2, &[round; 7])?;
+            htree.write_exact(0, &[round; 7])?;
+            htree.flush()?;
+            // keys written back went through the check
+            #[cfg(debug_assertions)]
+            assert!(htree.key_gen.nr_issued() > 0);
+        }
+        let mode = htree.flush()?;
+
+        let mut htree = RWHashTree::new(Some(4), back.clone(), nr_blk as u64, Some(mode), true);
+        let mut buf = vec![0u8; nr_blk * BLK_SZ];
+        assert_eq!(htree.read_exact(0, &mut buf)?, buf.len());
+        assert!(buf.iter().all(|b| *b == 7));
+
+        Ok(())
+    }
+
+    #[test]
+    fn write_blocks_same_as_blk_by_blk() -> FsResult<()> {
+        // crosses idx blks, starts and ends in the middle of them
+        let start = FULL.data_per_blk() - 3;
+        let nr_blk = 3 * FULL.data_per_blk() as usize + 5;
+        let blocks: Vec<u8> = (0..nr_blk).flat_map(|i| [(i % 251) as u8; BLK_SZ]).collect();
+
+        for encrypted in [false, true] {
+            let batch_back = Arc::new(CountStorage::new());
+            let mut batch = RWHashTree::new(Some(16), batch_back.clone(), 0, None, encrypted);
+            batch.write_blocks(start, &blocks)?;
+            let batch_mode = batch.flush()?;
+
+            let back = Arc::new(CountStorage::new());
+            let mut htree = RWHashTree::new(Some(16), back.clone(), 0, None, encrypted);
+            for (i, blk) in blocks.chunks(BLK_SZ).enumerate() {
+                htree.write_exact(blk2byte!(start + i as u64, BLK_SZ) as usize, blk)?;
+            }
+            let mode = htree.flush()?;
+
+            // every data blk is written once
+            let writes = batch_back.writes.lock().unwrap().clone();
+            let data_writes: Vec<_> = writes.iter().filter(|pos| !FULL.is_idx(**pos)).collect();
+            assert_eq!(data_writes.len(), nr_blk);
+            if !encrypted {
+                // kes are hashes of blks, so the whole tree is the same
+                assert_eq!(batch_mode, mode);
+                assert!(*batch_back.blks.lock().unwrap() == *back.blks.lock().unwrap());
+            }
+
+            let logi_len = start + nr_blk as u64;
+            let mut htree = RWHashTree::new(Some(16), batch_back, logi_len, Some(batch_mode), encrypted);
+            let mut buf = vec![0u8; nr_blk * BLK_SZ];
+            assert_eq!(htree.read_exact(blk2byte!(start, BLK_SZ) as usize, &mut buf)?, buf.len());
+            assert!(buf == blocks);
+        }
+
+        Ok(())
+    }
+
+    // the error panics where it is made in debug builds
+    #[test]
+    #[cfg(feature = "debug_verify")]
+    #[cfg_attr(debug_assertions, should_panic(expected = "IntegrityCheckFailed"))]
+    fn verify_after_flush_catches_bad_ke() {
+        let back = Arc::new(CountStorage::new());
+        let mut htree = RWHashTree::new(Some(4), back.clone(), 0, None, false);
+        htree.write_exact(0, &vec![1u8; 3 * BLK_SZ]).unwrap();
+        htree.flush().unwrap();
+
+        // a wrong ke of data block 1 goes into root on next flush
+        htree.ke_buf.insert(FULL.logi2phy(1), [0xffu8; KEY_ENTRY_SZ]);
+        assert!(matches!(
+            htree.flush(),
+            Err(FsError::IntegrityCheckFailed { pos }) if pos == FULL.logi2phy(1)
+        ));
+    }
+
+    // a tree of two index blocks with one block of its backend broken,
+    // reading all of it reports the broken block
+    fn read_with_broken_blk(broken: u64) -> FsResult<()> {
+        let back = Arc::new(CountStorage::new());
+        let nr_blk = FULL.data_per_blk() + 2;
+        let mut htree = RWHashTree::new(Some(4), back.clone(), 0, None, false);
+        htree.write_exact(0, &vec![5u8; nr_blk as usize * BLK_SZ])?;
+        let mode = htree.flush()?;
+        drop(htree);
+
+        back.blks.lock().unwrap().1.get_mut(&broken).unwrap()[7] ^= 1;
+        let mut htree = RWHashTree::new(Some(4), back, nr_blk, Some(mode), false);
+        let mut buf = vec![0u8; nr_blk as usize * BLK_SZ];
+        htree.read_exact(0, &mut buf).map(|_| ())
+    }
+
+    // the error panics where it is made in debug builds
+    #[test]
+    #[cfg_attr(debug_assertions, should_panic(expected = "IntegrityCheckFailed"))]
+    fn broken_idx_blk_is_reported() {
+        let idx = FULL.phy2idxphy(FULL.logi2phy(FULL.data_per_blk()));
+        assert_ne!(idx, HTREE_ROOT_BLK_PHY_POS);
+        assert!(matches!(
+            read_with_broken_blk(idx),
+            Err(FsError::IntegrityCheckFailed { pos }) if pos == idx && FULL.is_idx(pos)
+        ));
+    }
+
+    #[test]
+    #[cfg_attr(debug_assertions, should_panic(expected = "IntegrityCheckFailed"))]
+    fn broken_data_blk_is_reported() {
+        let data = FULL.logi2phy(FULL.data_per_blk() + 1);
+        assert!(matches!(
+            read_with_broken_blk(data),
+            Err(FsError::IntegrityCheckFailed { pos }) if pos == data && !FULL.is_idx(pos)
+        ));
+    }
+
+    #[test]
+    fn flush_range_only_writes_range() -> FsResult<()> {
+        let back = Arc::new(CountStorage::new());
+        // two regions under different idx blks
+        let a = 1..4u64;
+        let b = 2 * FULL.data_per_blk()..2 * FULL.data_per_blk() + 3;
+        let nr_blk = b.end;
+
+        let mut htree = RWHashTree::new(Some(64), back.clone(), 0, None, false);
+        for pos in a.clone() {
+            htree.write_exact(pos as usize * BLK_SZ, &[1u8; BLK_SZ])?;
+        }
+        for pos in b.clone() {
+            htree.write_exact(pos as usize * BLK_SZ, &[2u8; BLK_SZ])?;
+        }
+        back.writes.lock().unwrap().clear();
+
+        let mode = htree.flush_range(a.start, a.end - a.start)?;
+        let writes = back.writes.lock().unwrap().clone();
+        for pos in a.clone() {
+            assert!(writes.contains(&FULL.logi2phy(pos)));
+        }
+        for pos in b.clone() {
+            assert!(!writes.contains(&FULL.logi2phy(pos)));
+        }
+        assert!(writes.contains(&HTREE_ROOT_BLK_PHY_POS));
+
+        // range is readable with the new root
+        let mut check = RWHashTree::new(Some(64), back.clone(), nr_blk, Some(mode), false);
+        let mut buf = vec![0u8; BLK_SZ];
+        for pos in a.clone() {
+            check.read_exact(pos as usize * BLK_SZ, &mut buf)?;
+            assert!(buf.iter().all(|x| *x == 1));
+        }
+
+        // the rest goes with a full flush
+        let mode = htree.flush()?;
+        let mut check = RWHashTree::new(Some(64), back.clone(), nr_blk, Some(mode), false);
+        for (range, v) in [(a, 1), (b, 2)] {
+            for pos in range {
+                check.read_exact(pos as usize * BLK_SZ, &mut buf)?;
+                assert!(buf.iter().all(|x| *x == v));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn zero_range_check(
+        htree: &mut RWHashTree, offset: usize, len: usize, expect: &mut Vec<u8>,
+    ) -> FsResult<()> {
+        htree.zero_range(offset, len)?;
+        if offset + len > expect.len() {
+            expect.resize((offset + len).next_multiple_of(BLK_SZ), 0);
+        }
+        expect[offset..offset+len].fill(0);
+
+        assert_eq!(blk2byte!(htree.logi_len, BLK_SZ) as usize, expect.len());
+        let mut buf = vec![0xffu8; expect.len()];
+        assert_eq!(htree.read_exact(0, &mut buf)?, buf.len());
+        assert!(buf == *expect);
+        Ok(())
+    }
+
+    #[test]
+    fn zero_range() -> FsResult<()> {
+        let back = Arc::new(CountStorage::new());
+        let mut expect = vec![3u8; 8 * BLK_SZ];
+        let mut htree = RWHashTree::new(Some(4), back.clone(), 0, None, false);
+        htree.write_exact(0, &expect)?;
+
+        // inside one block
+        zero_range_check(&mut htree, BLK_SZ + 100, 200, &mut expect)?;
+        // across blocks, with whole interior blocks
+        zero_range_check(&mut htree, 2 * BLK_SZ + 300, 3 * BLK_SZ, &mut expect)?;
+        // crossing eof
+        zero_range_check(&mut htree, 7 * BLK_SZ + 10, 2 * BLK_SZ, &mut expect)?;
+        // totally beyond eof
+        zero_range_check(&mut htree, 12 * BLK_SZ + 5, 10, &mut expect)?;
+
+        let mode = htree.flush()?;
+        let mut htree = RWHashTree::new(Some(4), back.clone(), htree.logi_len, Some(mode), false);
+        let mut buf = vec![0xffu8; expect.len()];
+        assert_eq!(htree.read_exact(0, &mut buf)?, buf.len());
+        assert!(buf == expect);
+
+        Ok(())
+    }
+
+    #[test]
+    fn shrink_discards_cut_blocks() -> FsResult<()> {
+        let back = Arc::new(CountStorage::new());
+        let mut htree = RWHashTree::new(Some(4), back.clone(), 0, None, false);
+        htree.write_exact(0, &vec![1u8; 10 * BLK_SZ])?;
+        assert!(back.discarded.lock().unwrap().is_empty());
+
+        htree.resize(4)?;
+        let phy_4 = FULL.get_phy_nr_blk(4);
+        let phy_10 = FULL.get_phy_nr_blk(10);
+        assert_eq!(*back.discarded.lock().unwrap(), vec![(phy_4, phy_10 - phy_4)]);
+        Ok(())
+    }
+
+    #[test]
+    fn huge_positions_are_rejected() -> FsResult<()> {
+        let back = Arc::new(CountStorage::new());
+        let mut htree = RWHashTree::new(Some(4), back.clone(), 0, None, false);
+        htree.write_exact(0, &[1u8; 8])?;
+
+        let max = mht::MAX_LOGI_NR_BLK;
+        assert!(matches!(htree.resize(u64::MAX), Err(FsError::FileTooLarge)));
+        assert!(matches!(htree.resize(max + 1), Err(FsError::FileTooLarge)));
+        for pos in [u64::MAX, u64::MAX - 1, max] {
+            assert!(matches!(htree.get_blk(pos, true), Err(FsError::FileTooLarge)));
+        }
+        assert!(matches!(
+            htree.write_exact(usize::MAX - 3, &[1u8; 8]),
+            Err(FsError::FileTooLarge)
+        ));
+        assert!(matches!(htree.zero_range(usize::MAX, 2), Err(FsError::FileTooLarge)));
+        assert!(FULL.check_phy_nr_blk(FULL.get_phy_nr_blk(max)).is_ok());
+        assert!(FULL.check_phy_nr_blk(u64::MAX).is_err());
+
+        // nothing was padded by the failed calls
+        assert_eq!(htree.logi_len, 1);
+        assert!(back.discarded.lock().unwrap().is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn grow_is_sparse() -> FsResult<()> {
+        use std::sync::atomic::Ordering;
+
+        let back = Arc::new(CountStorage::new());
+        let mut htree = RWHashTree::new(Some(4), back.clone(), 0, None, false);
+        htree.write_exact(0, &vec![0x11u8; 2 * BLK_SZ])?;
+        htree.flush()?;
+        back.writes.lock().unwrap().clear();
+
+        let nr_blk = 50 * FULL.data_per_blk();
+        htree.resize(nr_blk)?;
+        htree.flush()?;
+        assert!(back.writes.lock().unwrap().iter().all(|pos| FULL.is_idx(*pos)));
+        let reads = back.data_reads.load(Ordering::SeqCst);
+        let mut blk = [0xffu8; BLK_SZ];
+        for pos in (2..nr_blk).step_by(7) {
+            htree.read_exact(blk2byte!(pos, BLK_SZ) as usize, &mut blk)?;
+            assert!(blk.iter().all(|b| *b == 0));
+        }
+        assert_eq!(back.data_reads.load(Ordering::SeqCst), reads);
+
+        // blocks get written on first write, and a cut leaves no stale data behind
+        htree.write_exact(blk2byte!(nr_blk - 1, BLK_SZ) as usize, &[0x22u8; 8])?;
+        htree.resize(1)?;
+        htree.resize(3)?;
+        let mode = htree.flush()?;
+        let mut htree = RWHashTree::new(Some(4), back.clone(), htree.logi_len, Some(mode), false);
+        let mut buf = vec![0xffu8; 3 * BLK_SZ];
+        htree.read_exact(0, &mut buf)?;
+        assert!(buf[..BLK_SZ].iter().all(|b| *b == 0x11));
+        assert!(buf[BLK_SZ..].iter().all(|b| *b == 0));
+        Ok(())
+    }
 }
@@ -12,7 +12,13 @@ pub mod rw;
 pub(crate) mod bcache;
 pub mod htree;
 pub(crate) mod storage;
-pub use storage::{ROStorage, RWStorage, Device};
+pub use storage::{ROStorage, RWStorage, Device, RetryStorage, MemStorage, MemDevice};
+#[cfg(feature = "std")]
+pub use storage::{FileStorage, FileDevice, LazyStorage, FetchFn, MmapStorage, PrefetchStorage};
+#[cfg(feature = "std")]
+pub(crate) mod packed;
+#[cfg(feature = "std")]
+pub use packed::PackedDevice;
 pub mod crypto;
 pub(crate) mod lru;
 pub mod error;
@@ -25,13 +31,38 @@ pub use log::{warn, info, debug};
 #[cfg(feature = "fuse")]
 mod fuse;
 
+#[cfg(test)]
+pub(crate) mod test_util;
+
 pub const MAX_LOOP_CNT: u64 = 10000;
 
+/// default block size, an image may be built with any power of two
+/// in [`MIN_BLK_SZ`, `MAX_BLK_SZ`], the size is kept in its superblock
 pub const BLK_SZ: usize = 4096;
-pub type Block = [u8; 4096];
+pub const MIN_BLK_SZ: usize = 4096;
+pub const MAX_BLK_SZ: usize = 65536;
+/// a block is as long as the block size of the storage it comes from
+pub type Block = [u8];
+
+/// a zeroed block of `bsize` bytes
+pub fn new_blk(bsize: usize) -> alloc::boxed::Box<Block> {
+    alloc::vec![0u8; bsize].into_boxed_slice()
+}
+
+pub fn check_blk_sz(bsize: usize) -> FsResult<()> {
+    if bsize.is_power_of_two() && (MIN_BLK_SZ..=MAX_BLK_SZ).contains(&bsize) {
+        Ok(())
+    } else {
+        Err(FsError::NotSupported)
+    }
+}
 
 pub const ROOT_INODE_ID: u64 = 1;
 
+/// random identity of an image, kept in its superblock, with a generation beside it
+/// that changes on every seal, caches of an image can be keyed on both
+pub type ImageUuid = [u8; 16];
+
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub enum FSMode {
     Encrypted(Key128, MAC128),
@@ -99,16 +130,102 @@ impl FSMode {
     }
 }
 
+/// what a root key entry returned by `export_key_material` protects
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum KeyOwner {
+    /// the superblock itself, i.e. the mode to mount with
+    SuperBlock,
+    InodeTable,
+    /// ro only
+    DirEntryTable,
+    /// ro only
+    PathTable,
+    /// rw only, by block index in inode bitmap
+    InodeBitmap(u64),
+    /// rw only, by block index in block bitmap
+    BlockBitmap(u64),
+    /// rw only, if it's created
+    LinkTable,
+    /// rw only, by block index in link slot bitmap
+    LinkBitmap(u64),
+    /// rw only, if it's created
+    XattrTable,
+    /// rw only, xattr file of an inode
+    Xattr(u64),
+    /// data htree of a file, dir or link
+    Inode(u64),
+}
+
+pub type KeyMaterial = alloc::collections::BTreeMap<KeyOwner, KeyEntry>;
+
+/// block size of a rwfs image dir, the superblock takes a whole block
+/// and only one of the right size passes its check.
+/// a rekey cut short is finished or undone first, as on mount
+#[cfg(feature = "std")]
+pub fn rw_image_blk_sz(path: &std::path::Path, mode: &FSMode) -> FsResult<usize> {
+    // recovery only renames and removes, any block size does
+    rw::rekey::recover_rekey(&FileDevice::new(path, MIN_BLK_SZ)?, mode)?;
+    let sb_path = path.join(rw::SB_FILE_NAME);
+    let sb_len = io_try!(std::fs::metadata(&sb_path)).len();
+    let pos = rw::superblock::SUPERBLOCK_POS;
+    let mut bsize = MIN_BLK_SZ;
+    while bsize <= MAX_BLK_SZ && sb_len >= bsize as u64 {
+        if sb_len % bsize as u64 == 0 {
+            let sb_blk = FileStorage::new(&sb_path, false, bsize)?.read_blk(pos)?;
+            if crypto_check(&sb_blk, CryptoHint::from_fsmode(mode.clone(), pos))? {
+                return Ok(bsize);
+            }
+        }
+        bsize *= 2;
+    }
+    Err(FsError::IntegrityCheckFailed { pos })
+}
+
+/// open a rofs image file or a rwfs image dir, type is decided by magic
+#[cfg(feature = "std")]
+pub fn open_fs(
+    path: &std::path::Path,
+    mode: FSMode,
+) -> FsResult<alloc::boxed::Box<dyn FileSystem>> {
+    use alloc::boxed::Box;
+    use alloc::sync::Arc;
+
+    if !io_try!(std::fs::metadata(path)).is_dir() {
+        // rofs image tells its type and block size in the public header
+        let hdr = ro::ROFS::read_public_header(&FileStorage::new(path, false, MIN_BLK_SZ)?)?;
+        let storage = Arc::new(FileStorage::new(path, false, hdr.bsize)?);
+        return Ok(Box::new(ro::ROFS::new(mode, DEFAULT_CACHE_CAP, None, 0, storage)?));
+    }
+
+    let bsize = rw_image_blk_sz(path, &mode)?;
+    let sb_path = path.join(rw::SB_FILE_NAME);
+    let pos = rw::superblock::SUPERBLOCK_POS;
+    let mut sb_blk = FileStorage::new(&sb_path, false, bsize)?.read_blk(pos)?;
+    crypto_in(&mut sb_blk, CryptoHint::from_fsmode(mode.clone(), pos))?;
+    let rw_magic = u64::from_le_bytes(sb_blk[8..16].try_into().unwrap());
+
+    if rw_magic == rw::RWFS_MAGIC {
+        let device = Arc::new(FileDevice::new(path, bsize)?);
+        Ok(Box::new(rw::RWFS::new(false, mode, None, 0, device, &SYSTEM_TIME)?))
+    } else {
+        Err(new_error!(FsError::SuperBlockCheckFailed))
+    }
+}
+
 #[derive(Clone)]
 pub enum CryptoHint {
     Encrypted(Key128, MAC128, u64), // key, mac, nonce
-    IntegrityOnly(Hash256),
+    IntegrityOnly(Hash256, u64), // hash, pos
+    /// hash truncated to a short key entry, see mht::Shape
+    IntegrityOnlyShort(Hash256, u64),
+    /// integrity only block taken as it is, see htree::VerifyPolicy
+    Unchecked,
 }
 
 impl CryptoHint {
     pub fn from_fsmode(fsmode: FSMode, nonce: u64) -> Self {
         match fsmode {
-            FSMode::IntegrityOnly(hash) => CryptoHint::IntegrityOnly(hash),
+            FSMode::IntegrityOnly(hash) => CryptoHint::IntegrityOnly(hash, nonce),
             FSMode::Encrypted(key, mac) => CryptoHint::Encrypted(key, mac, nonce),
         }
     }
@@ -121,6 +238,11 @@ impl CryptoHint {
         }
     }
 
+    /// whether crypto_in checks the block, only checked ones are confirmed to backends
+    pub fn is_checked(&self) -> bool {
+        !matches!(self, Self::Unchecked)
+    }
+
     pub fn from_key_entry(ke: KeyEntry, encrypted: bool, nonce: u64) -> Self {
         Self::from_fsmode(FSMode::from_key_entry(ke, encrypted), nonce)
     }
@@ -183,7 +305,7 @@ macro_rules! rwlock_write {
 
 #[macro_export]
 macro_rules! blk2byte {
-    ($e: expr) => {
-        (BLK_SZ * $e as usize) as u64
+    ($e: expr, $bsize: expr) => {
+        ($bsize * $e as usize) as u64
     };
 }
@@ -13,11 +13,41 @@ use std::thread;
 
 use core::hash::Hash;
 
-pub struct Lru<K: Hash + Eq + Clone, V>(lru::LruCache<K, (Arc<V>, bool)>);
+// the second field is the capacity asked for, the cache only goes above it
+// to hold entries put back, and comes back once they are gone
+pub struct Lru<K: Hash + Eq + Clone, V>(lru::LruCache<K, (Arc<V>, bool)>, usize);
 
 impl<K: Hash + Eq + Clone, V> Lru<K, V> {
     pub fn new(capacity: usize) -> Self {
-        Self(lru::LruCache::new(NonZeroUsize::new(capacity).unwrap()))
+        Self(lru::LruCache::new(NonZeroUsize::new(capacity).unwrap()), capacity)
+    }
+
+    // entries are only popped on demand
+    pub fn unbounded() -> Self {
+        Self(lru::LruCache::unbounded(), usize::MAX)
+    }
+
+    pub fn cap(&self) -> usize {
+        self.1
+    }
+
+    // give back room taken by put back entries that are gone,
+    // never below len so that nothing is evicted by resizing
+    fn shrink(&mut self) {
+        let cap = self.0.len().max(self.1);
+        if cap < self.0.cap().into() {
+            self.0.resize(NonZeroUsize::new(cap).unwrap());
+        }
+    }
+
+    // no change to LRU order
+    pub fn contains(&self, key: &K) -> bool {
+        self.0.contains(key)
+    }
+
+    // all keys, no change to LRU order
+    pub fn keys(&self) -> Vec<K> {
+        self.0.iter().map(|(k, _)| k.clone()).collect()
     }
 
     pub fn get(&mut self, key: &K) -> FsResult<Option<Arc<V>>> {
@@ -48,9 +78,19 @@ impl<K: Hash + Eq + Clone, V> Lru<K, V> {
         &mut self, key: K, val: &Arc<V>
     ) -> FsResult<Option<(K, V)>> {
         let mut ret = None;
-        if self.0.len() >= self.0.cap().into() {
+        let full = self.0.len() >= self.0.cap().into();
+        let over = self.0.len() >= self.1 && self.0.iter().any(
+            |(_, v)| Arc::<V>::strong_count(&v.0) == 1
+        );
+        if full || over {
             // pop tail item
             ret = self.pop_lru()?;
+            // over capacity by put back entries, drop clean ones until it's not
+            while self.0.len() >= self.1 && self.pop_clean() {}
+            self.shrink();
+        }
+        if self.0.len() >= self.0.cap().into() {
+            self.0.resize(NonZeroUsize::new(self.0.len() + 1).unwrap());
         }
 
         // push new entry into cache
@@ -61,6 +101,34 @@ impl<K: Hash + Eq + Clone, V> Lru<K, V> {
         }
     }
 
+    // put back a dirty payload whose write back failed, grow cache if full,
+    // so that no other entry has to be evicted for it, the cache shrinks back
+    // on later inserts and flushes
+    pub fn put_back(&mut self, key: K, val: V) -> FsResult<()> {
+        if self.0.contains(&key) {
+            return Err(FsError::AlreadyExists);
+        }
+        if self.0.len() >= self.0.cap().into() {
+            self.0.resize(NonZeroUsize::new(self.0.len() + 1).unwrap());
+        }
+        self.0.put(key, (Arc::new(val), true));
+        Ok(())
+    }
+
+    // pop the least recently used entry that is neither used nor dirty
+    fn pop_clean(&mut self) -> bool {
+        let k = self.0.iter().rev().find(
+            |&(_, v)| !v.1 && Arc::<V>::strong_count(&v.0) == 1
+        ).map(|(k, _)| k.clone());
+        match k {
+            Some(k) => {
+                self.0.pop(&k);
+                true
+            }
+            None => false,
+        }
+    }
+
     // pop first entry by LRU rules, return it for write back if it's dirty
     fn pop_lru(&mut self) -> FsResult<Option<(K, V)>> {
         let res = self.0.iter().rev().find(
@@ -91,6 +159,7 @@ impl<K: Hash + Eq + Clone, V> Lru<K, V> {
             let arc_cnt = Arc::<V>::strong_count(alock);
             if arc_cnt == 1 {
                 let (alock, dirty) = self.0.pop(&k).unwrap();
+                self.shrink();
                 if force || dirty {
                     // return payload for write back
                     Ok(Some(Arc::<V>::try_unwrap(alock).map_err(
@@ -131,12 +200,13 @@ impl<K: Hash + Eq + Clone, V> Lru<K, V> {
                 self.0.pop(k).unwrap();
             }
         );
+        self.shrink();
         Ok(())
     }
 
     // flush all entries that is not referenced, return dirty ones
     pub fn flush_wb(&mut self) -> FsResult<Vec<(K, V)>> {
-        Ok(self.get_all_unused().into_iter().filter_map(
+        let dirty = self.get_all_unused().into_iter().filter_map(
             |k| {
                 let (arc, dirty) = self.0.pop(&k).unwrap();
                 if dirty {
@@ -149,7 +219,9 @@ impl<K: Hash + Eq + Clone, V> Lru<K, V> {
                     None
                 }
             }
-        ).collect())
+        ).collect();
+        self.shrink();
+        Ok(dirty)
     }
 
     // return all keys that can be flushed, no matter dirty
@@ -379,3 +451,26 @@ where
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn put_back_grows_then_shrinks() {
+        let mut lru = Lru::<u64, u64>::new(2);
+        assert!(lru.insert_and_get(1, &Arc::new(1)).unwrap().is_none());
+        assert!(lru.insert_and_get(2, &Arc::new(2)).unwrap().is_none());
+        // a failed write back is put back without evicting anything
+        lru.put_back(3, 3).unwrap();
+        assert!(lru.contains(&1) && lru.contains(&2) && lru.contains(&3));
+        assert!(matches!(lru.put_back(3, 3), Err(FsError::AlreadyExists)));
+
+        // next insert drops clean entries until the asked capacity holds again
+        assert!(lru.insert_and_get(4, &Arc::new(4)).unwrap().is_none());
+        assert_eq!(lru.0.len(), 2);
+        assert_eq!(usize::from(lru.0.cap()), 2);
+        assert!(lru.contains(&3) && lru.contains(&4));
+        assert_eq!(lru.cap(), 2);
+    }
+}
@@ -1,11 +1,12 @@
 use crate::*;
-use spin::{RwLock, RwLockReadGuard, RwLockWriteGuard};
-use alloc::collections::{BTreeMap, BTreeSet};
+use spin::{RwLock, RwLockWriteGuard};
+use alloc::collections::BTreeMap;
 
 extern crate alloc;
 use alloc::vec::Vec;
 use alloc::sync::Arc;
 use alloc::string::{String, ToString};
+use core::sync::atomic::{AtomicBool, Ordering};
 
 
 #[derive(Clone, Debug)]
@@ -20,18 +21,66 @@ pub struct Inode {
     rw_fiid: InodeID,
     // last valid ancestor's idx in full_path
     rw_fidx: isize,
-    // full path from root dir, with perm, uid, gid
-    full_path: Vec<(String, FilePerm, u32, u32)>,
+    // full path from root dir, with perm, uid, gid and flags
+    full_path: Vec<(String, FilePerm, u32, u32, InodeFlags)>,
     // existing inodes in the lower layers
     // for reg and sym, len is only 1
     ipos: Vec<InodePos>,
-    // if true, same names in lower layers are blacked out
-    // only useful when ipos[0] is RW layer
+    // same names in layers below this one are blacked out, usize::MAX if none
+    // only set by black out files in writable layers
     // actually only useful for dirs, but set for reg and sym, too
-    black_out_ro: bool,
+    black_out_below: usize,
     // cached dir entries: all children here are allocated an iid and sotred in icac
     // useful only for dirs
     children: Option<BTreeMap<String, (FileType, InodeID)>>,
+    // for reg files copied up lazily, content still lives in this lower inode
+    // until the first data modification, see METACOPY_XATTR
+    lazy_data: Option<InodePos>,
+    // content of this reg file is being copied up, others wait here until it's done
+    copying: Option<Arc<CopyWait>>,
+}
+
+#[derive(Debug, Default)]
+struct CopyWait {
+    #[cfg(feature = "std")]
+    done: std::sync::Mutex<bool>,
+    #[cfg(feature = "std")]
+    cv: std::sync::Condvar,
+    #[cfg(not(feature = "std"))]
+    done: AtomicBool,
+}
+
+impl CopyWait {
+    #[cfg(feature = "std")]
+    fn wait(&self) -> FsResult<()> {
+        let mut done = mutex_lock!(self.done);
+        while !*done {
+            done = self.cv.wait(done).map_err(|_| FsError::MutexError)?;
+        }
+        Ok(())
+    }
+
+    #[cfg(feature = "std")]
+    fn wake(&self) -> FsResult<()> {
+        *mutex_lock!(self.done) = true;
+        self.cv.notify_all();
+        Ok(())
+    }
+
+    // no threads to park without std, but at least icac is left alone
+    #[cfg(not(feature = "std"))]
+    fn wait(&self) -> FsResult<()> {
+        while !self.done.load(Ordering::Acquire) {
+            core::hint::spin_loop();
+        }
+        Ok(())
+    }
+
+    #[cfg(not(feature = "std"))]
+    fn wake(&self) -> FsResult<()> {
+        self.done.store(true, Ordering::Release);
+        Ok(())
+    }
 }
 
 // impl Inode {
@@ -71,13 +120,20 @@ pub struct Inode {
 //     }
 // }
 
+// topmost writable layer, all modifications go here
 const RW_LAYER_IDX: usize = 0;
 
 pub struct OverlayFS {
     /// filesystem layers, 0 is RW layer
     layers: Vec<RwLock<Arc<dyn FileSystem>>>,
+    /// layers before this are writable, those below RW layer are only read through,
+    /// but black out files in them are honored
+    nr_rw: usize,
     /// inode cache, all found inodes are here, second number is next_iid
     icac: RwLock<(BTreeMap<InodeID, Inode>, InodeID)>,
+    /// min namemax of all layers, minus room for black out prefix
+    namemax: usize,
+    destroyed: AtomicBool,
 }
 
 const BLACK_OUT_PREFIX: &str = ".blacked.";
@@ -94,23 +150,59 @@ fn rm_black_out_prefix(name: &str) -> String {
     name[BLACK_OUT_PREFIX.len()..].to_string()
 }
 
+// kept on a reg file of a writable layer whose content is not copied up yet,
+// value is how many layers below it the content is, and its inode id there
+const METACOPY_XATTR: &str = "overlay.metacopy";
+
+fn metacopy_of(depth: usize, innd: InodeID) -> [u8; 16] {
+    let mut v = [0u8; 16];
+    v[..8].copy_from_slice(&(depth as u64).to_le_bytes());
+    v[8..].copy_from_slice(&innd.to_le_bytes());
+    v
+}
+
+fn parse_metacopy(v: &[u8]) -> FsResult<(usize, InodeID)> {
+    if v.len() != 16 {
+        return Err(FsError::InvalidData);
+    }
+    let depth = u64::from_le_bytes(v[..8].try_into().unwrap());
+    let innd = u64::from_le_bytes(v[8..].try_into().unwrap());
+    Ok((depth as usize, innd))
+}
+
 impl OverlayFS {
     pub fn new(
         upper: Arc<dyn FileSystem>,
         mut lower: Vec<Arc<dyn FileSystem>>,
     ) -> FsResult<Self> {
-        // prepare root dir
         lower.insert(RW_LAYER_IDX, upper);
-        let layers = lower;
+        Self::new_with_rw_layers(lower, 1)
+    }
 
+    /// layers are given from top to bottom, the first `nr_rw` of them are writable,
+    /// copy up goes to the topmost one, while lower writable layers keep their
+    /// own black out files, e.g. a scratch layer on top of a cache layer
+    pub fn new_with_rw_layers(
+        layers: Vec<Arc<dyn FileSystem>>,
+        nr_rw: usize,
+    ) -> FsResult<Self> {
+        if nr_rw == 0 || nr_rw > layers.len() {
+            return Err(FsError::InvalidParameter);
+        }
+
+        // prepare root dir
         let mut ipos = Vec::new();
+        let mut namemax = usize::MAX;
         for (i, layer) in layers.iter().enumerate() {
             let meta = layer.get_meta(ROOT_INODE_ID)?;
             if meta.ftype != FileType::Dir {
-                return Err(new_error!(FsError::NotADirectory));
+                return Err(FsError::NotADirectory);
             }
             ipos.push(InodePos(i, ROOT_INODE_ID));
+            namemax = namemax.min(layer.finfo()?.namemax);
         }
+        // any name may need a black out file in RW layer
+        let namemax = namemax.saturating_sub(BLACK_OUT_PREFIX.len());
 
         let root_inode = Inode {
             tp: FileType::Dir,
@@ -118,8 +210,10 @@ impl OverlayFS {
             rw_fidx: -1,
             full_path: Vec::new(),
             ipos,
-            black_out_ro: false, // root inode of lower layers must not be blacked
+            black_out_below: usize::MAX, // root inode of lower layers must not be blacked
             children: None,
+            lazy_data: None,
+            copying: None,
         };
 
         let mut map = BTreeMap::new();
@@ -130,10 +224,21 @@ impl OverlayFS {
             layers: layers.into_iter().map(
                 |fs| RwLock::new(fs)
             ).collect(),
+            nr_rw,
             icac: RwLock::new((map, 2)),
+            namemax,
+            destroyed: AtomicBool::new(false),
         })
     }
 
+    fn check_name_len(&self, name: &str) -> FsResult<()> {
+        if name.len() > self.namemax {
+            Err(FsError::NameTooLong)
+        } else {
+            Ok(())
+        }
+    }
+
     #[allow(unused)]
     fn insert_inode(&self, inode: Inode) -> FsResult<InodeID> {
         let mut lock = self.icac.write();
@@ -155,85 +260,186 @@ impl OverlayFS {
     // for reg and sym, copy file content
     // for dir, create new dir in RW only
     fn ensure_copy_up(&self, iid: InodeID) -> FsResult<()> {
-        let mut lock = self.icac.write();
-        let ino = lock.0.get_mut(&iid).unwrap();
+        self.copy_up(iid, false)
+    }
 
-        if ino.rw_fidx == ino.full_path.len() as isize - 1 {
-            return Ok(())
-        }
+    // same as ensure_copy_up, but content of reg files is not copied
+    // until it is modified, used when only metadata is about to change,
+    // the RW layer keeps where the content is, so it stays lazy across mounts
+    fn ensure_copy_up_lazy(&self, iid: InodeID) -> FsResult<()> {
+        self.copy_up(iid, true)
+    }
+
+    fn copy_up(&self, iid: InodeID, lazy: bool) -> FsResult<()> {
+        // metadata is copied up with icac locked,
+        // while file content is copied after icac is unlocked,
+        // readers are served by lazy_data in the meantime
+        let (from, to, flags) = loop {
+            let mut lock = self.icac.write();
+            let ino = lock.0.get_mut(&iid).unwrap();
+
+            if let Some(wait) = ino.copying.clone() {
+                // another thread is copying content of this inode
+                drop(lock);
+                wait.wait()?;
+                continue;
+            }
+
+            if ino.rw_fidx == ino.full_path.len() as isize - 1 {
+                if !lazy {
+                    if let Some(from) = ino.lazy_data.clone() {
+                        let InodePos(lidx, innd) = ino.ipos[0];
+                        assert_eq!(lidx, RW_LAYER_IDX);
+                        ino.copying = Some(Arc::new(CopyWait::default()));
+                        break (from, innd, ino.full_path.last().unwrap().4);
+                    }
+                }
+                return Ok(())
+            }
+
+            // a flagged file is copied up in full, its flags are set once content is there
+            let mut lazy = lazy && ino.full_path.last().unwrap().4.is_empty();
+            // flags are set once all entries are in place, an immutable dir takes no child
+            let mut flagged = Vec::new();
+
+            // crate all intermediate dirs
+            let mut idx = ino.rw_fidx + 1;
+            let mut father = ino.rw_fiid;
+            let rwfs_lock = self.layers[RW_LAYER_IDX].read();
+            while idx < ino.full_path.len() as isize - 1 {
+                let path = &ino.full_path[idx as usize];
+                match rwfs_lock.create(
+                    father,
+                    &path.0,
+                    FileType::Dir,
+                    path.2,
+                    path.3,
+                    path.1,
+                ) {
+                    Ok(new_iid) => {
+                        father = new_iid;
+                        flagged.push((new_iid, path.4));
+                    }
+                    // Err(FsError::AlreadyExists) => {},
+                    Err(e) => return Err(e),
+                }
+                idx += 1;
+            }
 
-        // crate all intermediate dirs
-        let mut idx = ino.rw_fidx + 1;
-        let mut father = ino.rw_fiid;
-        let rwfs_lock = self.layers[RW_LAYER_IDX].read();
-        while idx < ino.full_path.len() as isize - 1 {
             let path = &ino.full_path[idx as usize];
-            match rwfs_lock.create(
+            let flags = path.4;
+            let new_iid = rwfs_lock.create(
                 father,
                 &path.0,
-                FileType::Dir,
+                ino.tp,
                 path.2,
                 path.3,
                 path.1,
-            ) {
-                Ok(new_iid) => father = new_iid,
-                // Err(FsError::AlreadyExists) => {},
-                Err(e) => return Err(e),
-            }
-            idx += 1;
-        }
-
-        let path = &ino.full_path[idx as usize];
-        let new_iid = rwfs_lock.create(
-            father,
-            &path.0,
-            ino.tp,
-            path.2,
-            path.3,
-            path.1,
-        )?;
+            )?;
 
-        match ino.tp {
-            FileType::Reg => {
-                assert_eq!(ino.ipos.len(), 1);
-                let InodePos(lidx, innd) = ino.ipos[0];
-                let mut buf = [0u8; BLK_SZ];
-                let mut done = 0;
-                loop {
-                    let read = self.layers[lidx].read().iread(innd, done, &mut buf)?;
-                    let write = rwfs_lock.iwrite(new_iid, done, &buf[..read])?;
-                    assert_eq!(read, write);
-                    if read != BLK_SZ {
-                        break;
+            let mut pending = None;
+            match ino.tp {
+                FileType::Reg => {
+                    assert_eq!(ino.ipos.len(), 1);
+                    // a lower layer may only keep metadata of it, too
+                    let from = ino.lazy_data.clone().unwrap_or(ino.ipos[0].clone());
+                    if lazy {
+                        let InodePos(lidx, innd) = from;
+                        match rwfs_lock.iset_xattr(
+                            new_iid, METACOPY_XATTR, &metacopy_of(lidx - RW_LAYER_IDX, innd),
+                        ) {
+                            Ok(()) => {}
+                            // content is copied at once where it can't be kept lazy
+                            Err(FsError::NotSupported) => lazy = false,
+                            Err(e) => return Err(e),
+                        }
                     }
-                    done += read;
+                    if !lazy {
+                        ino.copying = Some(Arc::new(CopyWait::default()));
+                        pending = Some((from.clone(), new_iid, flags));
+                    }
+                    ino.lazy_data = Some(from);
+                    ino.ipos[0] = InodePos(RW_LAYER_IDX, new_iid);
+                }
+                FileType::Dir => {
+                    ino.ipos.insert(0, InodePos(RW_LAYER_IDX, new_iid));
+                    flagged.push((new_iid, flags));
+                }
+                FileType::Lnk => {
+                    assert_eq!(ino.ipos.len(), 1);
+                    let InodePos(lidx, innd) = ino.ipos[0];
+                    let lname = self.layers[lidx].read().iread_link(innd)?;
+                    rwfs_lock.iset_link(new_iid, &lname)?;
+                    ino.ipos[0] = InodePos(RW_LAYER_IDX, new_iid);
+                    flagged.push((new_iid, flags));
                 }
-                ino.ipos[0] = InodePos(RW_LAYER_IDX, new_iid);
             }
-            FileType::Dir => {
-                ino.ipos.insert(0, InodePos(RW_LAYER_IDX, new_iid));
+            for (iid, flags) in flagged.into_iter().rev() {
+                if !flags.is_empty() {
+                    rwfs_lock.set_flags(iid, flags)?;
+                }
             }
-            FileType::Lnk => {
-                assert_eq!(ino.ipos.len(), 1);
-                let InodePos(lidx, innd) = ino.ipos[0];
-                let lname = self.layers[lidx].read().iread_link(innd)?;
-                rwfs_lock.iset_link(new_iid, &lname)?;
-                ino.ipos[0] = InodePos(RW_LAYER_IDX, new_iid);
+
+            ino.rw_fidx = ino.full_path.len() as isize - 1;
+            ino.rw_fiid = new_iid;
+
+            match pending {
+                Some(p) => break p,
+                None => return Ok(()),
             }
-        }
+        };
 
-        ino.rw_fidx = ino.full_path.len() as isize - 1;
-        ino.rw_fiid = new_iid;
+        let res = self.copy_data(&from, to).and_then(|_| {
+            let rwfs_lock = self.layers[RW_LAYER_IDX].read();
+            // content is all there now
+            match rwfs_lock.iremove_xattr(to, METACOPY_XATTR) {
+                Ok(()) | Err(FsError::NotFound) | Err(FsError::NotSupported) => {}
+                Err(e) => return Err(e),
+            }
+            if flags.is_empty() {
+                return Ok(());
+            }
+            rwfs_lock.set_flags(to, flags)
+        });
 
+        let mut lock = self.icac.write();
+        let ino = lock.0.get_mut(&iid).unwrap();
+        let wait = ino.copying.take().unwrap();
+        if res.is_ok() {
+            ino.lazy_data = None;
+        }
+        drop(lock);
+        wait.wake()?;
+        res
+    }
+
+    // copy content of a reg file into RW layer inode `to`
+    fn copy_data(&self, from: &InodePos, to: InodeID) -> FsResult<()> {
+        let InodePos(lidx, innd) = *from;
+        let rwfs_lock = self.layers[RW_LAYER_IDX].read();
+        let mut buf = [0u8; BLK_SZ];
+        let mut done = 0;
+        loop {
+            let read = self.layers[lidx].read().iread(innd, done, &mut buf)?;
+            let write = rwfs_lock.iwrite(to, done, &buf[..read])?;
+            assert_eq!(read, write);
+            if read != BLK_SZ {
+                break;
+            }
+            done += read;
+        }
         Ok(())
     }
 
+    // black out name in layers below lidx, parent is the dir inode in that layer
     fn ensure_black_out_file(
         &self,
-        fs: &RwLockReadGuard<'_, Arc<dyn FileSystem>>,
+        lidx: usize,
         parent: InodeID,
         name: &str,
     ) -> FsResult<()> {
+        assert!(lidx < self.nr_rw);
+        let fs = self.layers[lidx].read();
         let blk_name = black_out_file_of(name);
         if fs.lookup(parent, &blk_name)?.is_none() {
             let Metadata { uid, gid, .. } = fs.get_meta(parent)?;
@@ -245,6 +451,16 @@ impl OverlayFS {
         Ok(())
     }
 
+    // entries of an immutable or append-only dir are neither removed nor renamed,
+    // nor are such inodes themselves, whichever layer they are in
+    fn check_unlink(&self, parent: InodeID, child: InodeID) -> FsResult<()> {
+        let flags = self.get_flags(parent)? | self.get_flags(child)?;
+        if flags.intersects(InodeFlags::IMMUTABLE | InodeFlags::APPEND_ONLY) {
+            return Err(FsError::PermissionDenied);
+        }
+        Ok(())
+    }
+
     fn dir_has_ro_layer(&self, ino: &Inode) -> bool {
         assert_eq!(ino.tp, FileType::Dir);
         ino.ipos.len() > 1 || ino.ipos[0].0 != RW_LAYER_IDX
@@ -269,43 +485,65 @@ impl OverlayFS {
 
         // debug!("caching children of parent: {:?}", parent_ino);
 
-        let mut blk_out_files = BTreeSet::new();
+        // black out file name -> the topmost writable layer it's in
+        let mut blk_out_files = BTreeMap::new();
         let mut map = BTreeMap::new();
         for InodePos(lidx, innd) in parent_ino.ipos.iter().filter(
-            |InodePos(lidx, _)| *lidx == RW_LAYER_IDX || !parent_ino.black_out_ro
+            |InodePos(lidx, _)| *lidx <= parent_ino.black_out_below
         ) {
             let fs = self.layers[*lidx].read();
             // debug!("processing layer {} innd {}", lidx, innd);
 
-            let mut offset = 0;
-            while let Some((child_innd, name, tp)) = fs.next_entry(*innd, offset)? {
-                // debug!("child {} innd {} tp {:?}", name.display(), child_innd, tp);
-                if *lidx == RW_LAYER_IDX && is_black_out_file(name.as_str()) {
+            let mut dirents = fs.read_dirents(*innd, 0, 0)?;
+            if *lidx < self.nr_rw {
+                // black out files count for the whole layer, whatever the order,
+                // they also cut off lower layers of children found in upper layers
+                dirents.retain(|Dirent { name, .. }| {
+                    if !is_black_out_file(name.as_str()) {
+                        return true;
+                    }
                     // debug!("is black out file, remember it");
-                    blk_out_files.insert(rm_black_out_prefix(&name));
-                } else if let Some((upper_tp, iid)) = map.get(&name) {
+                    let name = rm_black_out_prefix(name);
+                    if let Some((_, iid)) = map.get(&name) {
+                        let ino = lock.0.get_mut(iid).unwrap();
+                        ino.black_out_below = ino.black_out_below.min(*lidx);
+                    }
+                    blk_out_files.entry(name).or_insert(*lidx);
+                    false
+                });
+            }
+
+            for Dirent { ipos: child_innd, tp, name } in dirents {
+                // debug!("child {} innd {} tp {:?}", name.display(), child_innd, tp);
+                let blk_out_at = blk_out_files.get(&name).copied().unwrap_or(usize::MAX);
+                if blk_out_at < *lidx {
+                    // debug!("blacked out by an upper layer");
+                    continue;
+                }
+                if let Some((upper_tp, iid)) = map.get(&name) {
                     // if a child already found in upper layers and it's a dir
                     // we need to add this layer to ipos list
                     // debug!("already exist in upper");
-                    if tp == FileType::Dir && *upper_tp == FileType::Dir {
+                    let ino = lock.0.get_mut(iid).unwrap();
+                    if tp == FileType::Dir && *upper_tp == FileType::Dir
+                        && *lidx <= ino.black_out_below {
                         // debug!("is dir, update ipos");
-                        let ino = lock.0.get_mut(iid).unwrap();
                         ino.ipos.push(InodePos(*lidx, child_innd));
                     }
                 } else {
                     // create inode in icac
                     // debug!("first found, creating new ovl inode");
                     let Metadata { uid, gid, perm, .. } = fs.get_meta(child_innd)?;
-
-                    let black_out_ro = if *lidx == RW_LAYER_IDX {
-                        parent_ino.black_out_ro | blk_out_files.contains(&name)
-                    } else {
-                        false
+                    let flags = match fs.get_flags(child_innd) {
+                        Err(FsError::NotSupported) => InodeFlags::empty(),
+                        r => r?,
                     };
-                    // debug!("black_out_ro = {}", black_out_ro);
+
+                    let black_out_below = parent_ino.black_out_below.min(blk_out_at);
+                    // debug!("black_out_below = {}", black_out_below);
 
                     let mut full_path = parent_ino.full_path.clone();
-                    full_path.push((name.clone().into(), perm, uid, gid));
+                    full_path.push((name.clone().into(), perm, uid, gid, flags));
 
                     let (rw_fiid, rw_fidx) = {
                         if *lidx == RW_LAYER_IDX {
@@ -320,6 +558,22 @@ impl OverlayFS {
                         }
                     };
 
+                    // only metadata of it may be copied up to a writable layer
+                    let mut lazy_data = None;
+                    if tp == FileType::Reg && *lidx < self.nr_rw {
+                        match fs.iget_xattr(child_innd, METACOPY_XATTR) {
+                            Ok(Some(v)) => {
+                                let (depth, innd) = parse_metacopy(&v)?;
+                                if lidx + depth >= self.layers.len() {
+                                    return Err(FsError::InvalidData);
+                                }
+                                lazy_data = Some(InodePos(lidx + depth, innd));
+                            }
+                            Ok(None) | Err(FsError::NotSupported) => {}
+                            Err(e) => return Err(e),
+                        }
+                    }
+
                     let mut ipos = Vec::new();
                     ipos.push(InodePos(*lidx, child_innd));
                     let new_ino = Inode {
@@ -328,13 +582,14 @@ impl OverlayFS {
                         rw_fidx,
                         full_path,
                         ipos,
-                        black_out_ro,
+                        black_out_below,
                         children: None,
+                        lazy_data,
+                        copying: None,
                     };
                     let new_iid = self.insert_inode_with_lock(&mut lock, new_ino)?;
                     map.insert(name.clone(), (tp, new_iid));
                 }
-                offset += 1;
             }
         }
 
@@ -356,30 +611,62 @@ macro_rules! allow_nosys {
 
 impl FileSystem for OverlayFS {
     fn init(&self) -> FsResult<()> {
+        if self.destroyed.load(Ordering::Acquire) {
+            return Err(FsError::AlreadyDestroyed);
+        }
         for fs in self.layers.iter() {
             fs.read().init()?;
         }
         Ok(())
     }
 
+    // layers are destroyed from the lowest RO layer up,
+    // the RW layer goes last and gives the new mode
+    fn destroy(&self) -> FsResult<FSMode> {
+        if self.destroyed.swap(true, Ordering::AcqRel) {
+            return Err(FsError::AlreadyDestroyed);
+        }
+        for fs in self.layers[1..].iter().rev() {
+            // may be done in a former failed try
+            match fs.read().destroy() {
+                Ok(_) | Err(FsError::AlreadyDestroyed) => {},
+                Err(e) => {
+                    self.destroyed.store(false, Ordering::Release);
+                    return Err(e);
+                }
+            }
+        }
+        self.layers[RW_LAYER_IDX].read().destroy()
+            .inspect_err(|_| self.destroyed.store(false, Ordering::Release))
+    }
+
     fn finfo(&self) -> FsResult<FsInfo> {
         let mut info = self.layers[RW_LAYER_IDX].read().finfo()?;
-        for fs in self.layers[1..].iter() {
+        for (lidx, fs) in self.layers.iter().enumerate().skip(1) {
             let FsInfo {
                 blocks,
                 bfree,
                 files,
-                namemax,
+                ffree,
                 ..
             } = fs.read().finfo()?;
             info.blocks += blocks;
-            info.bfree += bfree;
             info.files += files;
-            info.namemax = info.namemax.min(namemax);
+            // nothing is ever written to lower writable layers
+            if lidx >= self.nr_rw {
+                info.bfree += bfree;
+                info.ffree += ffree;
+            }
         }
+        info.namemax = self.namemax;
         Ok(info)
     }
 
+    fn capabilities(&self) -> FsResult<FsCapabilities> {
+        // all modifications go to RW layer
+        self.layers[RW_LAYER_IDX].read().capabilities()
+    }
+
     fn fsync(&self) -> FsResult<FSMode> {
         // debug!("ovl fsync");
         for fs in self.layers[1..].iter().rev() {
@@ -393,10 +680,17 @@ impl FileSystem for OverlayFS {
         let lock = self.icac.read();
         let ino = lock.0.get(&iid).unwrap();
         assert_eq!(ino.tp, FileType::Reg);
-        let InodePos(lidx, innd) = ino.ipos[0];
+        let InodePos(lidx, innd) = ino.lazy_data.clone().unwrap_or(ino.ipos[0].clone());
         self.layers[lidx].read().iread(innd, offset, to)
     }
 
+    fn is_inline(&self, iid: InodeID) -> FsResult<bool> {
+        let lock = self.icac.read();
+        let ino = lock.0.get(&iid).unwrap();
+        let InodePos(lidx, innd) = ino.lazy_data.clone().unwrap_or(ino.ipos[0].clone());
+        self.layers[lidx].read().is_inline(innd)
+    }
+
     fn iwrite(&self, iid: InodeID, offset: usize, from: &[u8]) -> FsResult<usize> {
         self.ensure_copy_up(iid)?;
         let lock = self.icac.read();
@@ -407,6 +701,16 @@ impl FileSystem for OverlayFS {
         self.layers[lidx].read().iwrite(innd, offset, from)
     }
 
+    fn iappend(&self, iid: InodeID, from: &[u8]) -> FsResult<(u64, usize)> {
+        self.ensure_copy_up(iid)?;
+        let lock = self.icac.read();
+        let ino = lock.0.get(&iid).unwrap();
+        assert_eq!(ino.tp, FileType::Reg);
+        let InodePos(lidx, innd) = ino.ipos[0];
+        assert_eq!(lidx, RW_LAYER_IDX);
+        self.layers[lidx].read().iappend(innd, from)
+    }
+
     fn get_meta(&self, iid: InodeID) -> FsResult<Metadata> {
         let lock = self.icac.read();
         let ino = lock.0.get(&iid).unwrap();
@@ -415,6 +719,12 @@ impl FileSystem for OverlayFS {
             FileType::Reg | FileType::Lnk => {
                 let mut meta = self.layers[lidx].read().get_meta(innd)?;
                 meta.iid = iid;
+                if let Some(InodePos(data_lidx, data_innd)) = ino.lazy_data {
+                    // content is not copied up yet
+                    let mt = self.layers[data_lidx].read().get_meta(data_innd)?;
+                    meta.size = mt.size;
+                    meta.blocks = mt.blocks;
+                }
                 Ok(meta)
             }
             FileType::Dir => {
@@ -432,12 +742,37 @@ impl FileSystem for OverlayFS {
         }
     }
 
-    fn set_meta(&self, iid: InodeID, set_meta: SetMetadata) -> FsResult<()> {
+    fn get_flags(&self, iid: InodeID) -> FsResult<InodeFlags> {
+        let lock = self.icac.read();
+        let ino = lock.0.get(&iid).unwrap();
+        let InodePos(lidx, innd) = ino.ipos[0];
+        match self.layers[lidx].read().get_flags(innd) {
+            // a layer without flags has none set
+            Err(FsError::NotSupported) => Ok(InodeFlags::empty()),
+            r => r,
+        }
+    }
+
+    fn set_flags(&self, iid: InodeID, flags: InodeFlags) -> FsResult<()> {
+        // a flagged file is never left lazily copied up
         self.ensure_copy_up(iid)?;
         let lock = self.icac.read();
         let ino = lock.0.get(&iid).unwrap();
         let InodePos(lidx, innd) = ino.ipos[0];
         assert_eq!(lidx, RW_LAYER_IDX);
+        self.layers[lidx].read().set_flags(innd, flags)
+    }
+
+    fn set_meta(&self, iid: InodeID, set_meta: SetMetadata) -> FsResult<()> {
+        if let SetMetadata::Size(_) = set_meta {
+            self.ensure_copy_up(iid)?;
+        } else {
+            self.ensure_copy_up_lazy(iid)?;
+        }
+        let lock = self.icac.read();
+        let ino = lock.0.get(&iid).unwrap();
+        let InodePos(lidx, innd) = ino.ipos[0];
+        assert_eq!(lidx, RW_LAYER_IDX);
         self.layers[lidx].read().set_meta(innd, set_meta)?;
         Ok(())
     }
@@ -495,6 +830,18 @@ impl FileSystem for OverlayFS {
         Ok(())
     }
 
+    fn fsync_range(&self, iid: InodeID, offset: usize, len: usize) -> FsResult<()> {
+        let lock = self.icac.read();
+        let ino = lock.0.get(&iid).unwrap();
+        if ino.tp != FileType::Reg {
+            drop(lock);
+            return self.isync_data(iid);
+        }
+        let InodePos(lidx, innd) = ino.ipos[0];
+        allow_nosys!(self.layers[lidx].read().fsync_range(innd, offset, len));
+        Ok(())
+    }
+
     fn create(
         &self,
         parent: InodeID,
@@ -507,6 +854,7 @@ impl FileSystem for OverlayFS {
         if is_black_out_file(name) {
             return Err(new_error!(FsError::PermissionDenied));
         }
+        self.check_name_len(name)?;
         if self.lookup(parent, name)?.is_some() {
             return Err(new_error!(FsError::AlreadyExists));
         }
@@ -528,7 +876,7 @@ impl FileSystem for OverlayFS {
         };
 
         let mut full_path = ino.full_path.clone();
-        full_path.push((name.into(), perm, uid, gid));
+        full_path.push((name.into(), perm, uid, gid, InodeFlags::empty()));
         let mut ipos = Vec::new();
         ipos.push(InodePos(RW_LAYER_IDX, new_innd));
         let new_ino = Inode {
@@ -539,8 +887,14 @@ impl FileSystem for OverlayFS {
             // create sth means this name does not exist in any lower layers,
             // or it is blacked out, so the new overlay inode has no lower layers
             ipos,
-            black_out_ro: ino.black_out_ro | blk_out_file_exist,
+            black_out_below: if blk_out_file_exist {
+                RW_LAYER_IDX
+            } else {
+                ino.black_out_below
+            },
             children: None,
+            lazy_data: None,
+            copying: None,
         };
 
         let new_iid = self.insert_inode_with_lock(&mut lock, new_ino)?;
@@ -555,12 +909,20 @@ impl FileSystem for OverlayFS {
         if is_black_out_file(name) {
             return Err(new_error!(FsError::PermissionDenied));
         }
+        self.check_name_len(name)?;
         if self.lookup(parent, name)?.is_some() {
             return Err(new_error!(FsError::AlreadyExists));
         }
 
-        self.ensure_copy_up(parent)?;
+        // a lower file cannot be linked in rw layer, copy-up makes another file
+        // rather than another name of it, callers copy it on their own
+        if self.icac.read().0.get(&linkto).unwrap().ipos[0].0 != RW_LAYER_IDX {
+            return Err(FsError::CrossDevice);
+        }
+        // names of a file share its content, which is then never left in a lower layer
         self.ensure_copy_up(linkto)?;
+
+        self.ensure_copy_up(parent)?;
         self.ensure_children_cached(parent)?;
 
         let mut lock = self.icac.write();
@@ -594,6 +956,7 @@ impl FileSystem for OverlayFS {
         let child_iid = self.lookup(parent, name)?.ok_or_else(
             || new_error!(FsError::NotFound)
         )?;
+        self.check_unlink(parent, child_iid)?;
 
         let mut lock = self.icac.write();
         let fino = lock.0.get(&parent).unwrap();
@@ -601,13 +964,23 @@ impl FileSystem for OverlayFS {
         assert_eq!(lidx, RW_LAYER_IDX);
 
         let fs = self.layers[lidx].read();
+
+        // the last link of a lazily copied-up file is gone, no need to copy its content
+        let child = lock.0.get(&child_iid).unwrap();
+        if child.lazy_data.is_some() && child.copying.is_none() {
+            let InodePos(_, child_innd) = child.ipos[0];
+            if fs.get_meta(child_innd)?.nlinks <= 1 {
+                lock.0.get_mut(&child_iid).unwrap().lazy_data = None;
+            }
+        }
+
         match fs.unlink(innd, name) {
             Ok(_) | Err(FsError::NotFound) => {
             // Ok(_) => {
-                self.ensure_black_out_file(&fs, innd, name)?;
+                self.ensure_black_out_file(lidx, innd, name)?;
                 // set black out ro
                 let ino = lock.0.get_mut(&child_iid).unwrap();
-                ino.black_out_ro = true;
+                ino.black_out_below = RW_LAYER_IDX;
             }
             Err(e) => return Err(e),
         }
@@ -629,6 +1002,7 @@ impl FileSystem for OverlayFS {
         if is_black_out_file(name) {
             return Err(new_error!(FsError::PermissionDenied));
         }
+        self.check_name_len(name)?;
         if self.lookup(parent, name)?.is_some() {
             return Err(new_error!(FsError::AlreadyExists));
         }
@@ -650,7 +1024,9 @@ impl FileSystem for OverlayFS {
         };
 
         let mut full_path = ino.full_path.clone();
-        full_path.push((name.into(), FilePerm::from_bits(0o777).unwrap(), uid, gid));
+        full_path.push((
+            name.into(), FilePerm::from_bits(0o777).unwrap(), uid, gid, InodeFlags::empty(),
+        ));
         let mut ipos = Vec::new();
         ipos.push(InodePos(RW_LAYER_IDX, new_innd));
         let new_ino = Inode {
@@ -659,8 +1035,14 @@ impl FileSystem for OverlayFS {
             rw_fidx: full_path.len() as isize - 1,
             full_path,
             ipos,
-            black_out_ro: ino.black_out_ro | blk_out_file_exist,
+            black_out_below: if blk_out_file_exist {
+                RW_LAYER_IDX
+            } else {
+                ino.black_out_below
+            },
             children: None,
+            lazy_data: None,
+            copying: None,
         };
 
         let new_iid = self.insert_inode_with_lock(&mut lock, new_ino)?;
@@ -682,6 +1064,7 @@ impl FileSystem for OverlayFS {
         if is_black_out_file(newname) {
             return Err(new_error!(FsError::PermissionDenied));
         }
+        self.check_name_len(newname)?;
 
         let old_iid = if let Some(old_iid) = self.lookup(from, name)? {
             let lock = self.icac.read();
@@ -694,13 +1077,17 @@ impl FileSystem for OverlayFS {
         } else {
             return Err(new_error!(FsError::NotFound));
         };
+        self.check_unlink(from, old_iid)?;
+        if let Some(target) = self.lookup(to, newname)? {
+            self.check_unlink(to, target)?;
+        }
 
         self.ensure_copy_up(from)?;
         self.ensure_copy_up(to)?;
         self.ensure_children_cached(from)?;
         self.ensure_children_cached(to)?;
 
-        self.ensure_copy_up(old_iid)?;
+        self.ensure_copy_up_lazy(old_iid)?;
 
         let mut lock = self.icac.write();
         let from_ino = lock.0.get_mut(&from).unwrap();
@@ -731,10 +1118,10 @@ impl FileSystem for OverlayFS {
         to_ino.children.as_mut().unwrap().insert(String::from(newname), entry);
 
         // create black out file for oldname
-        self.ensure_black_out_file(&fs, from_innd, name)?;
+        self.ensure_black_out_file(from_lidx, from_innd, name)?;
         // set black out ro
         let ino = lock.0.get_mut(&old_iid).unwrap();
-        ino.black_out_ro = true;
+        ino.black_out_below = RW_LAYER_IDX;
 
         Ok(())
     }
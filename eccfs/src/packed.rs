@@ -0,0 +1,465 @@
+use crate::*;
+use alloc::{
+    sync::Arc,
+    vec::Vec,
+    string::{String, ToString},
+    collections::BTreeMap,
+};
+use std::sync::Mutex;
+
+pub const PACKED_MAGIC: u64 = 0x004543435041434B; // ECCPACK
+
+const PACKED_HEADER_POS: u64 = 0;
+const PACKED_NAME_MAX: usize = 96;
+// start(8) cap(8) len(8) name_len(8) name(96)
+const PACKED_ENTRY_SZ: usize = 128;
+
+// a device whose storages are all packed into one backend storage.
+// every storage owns an extent of the backend, extents are recorded in a catalog,
+// the header at block 0 points to the catalog, blocks are as large as backend ones.
+// space not covered by any extent is free, so the catalog is also the allocator.
+// like file names in a FileDevice dir, the catalog itself is not protected,
+// data in storages is still checked by their own htrees.
+// the catalog lock is held across backend I/O, so it is a blocking one.
+pub struct PackedDevice {
+    cat: Arc<Mutex<PackedCatalog>>,
+    bsize: usize,
+}
+
+#[derive(Clone)]
+struct PackedExtent {
+    slot: usize,
+    start: u64, // in blocks
+    cap: u64, // in blocks
+    len: u64, // in blocks
+}
+
+struct PackedCatalog {
+    backend: Arc<dyn RWStorage>,
+    extents: BTreeMap<String, PackedExtent>,
+    // storage names in catalog order
+    slots: Vec<String>,
+    cat_start: u64,
+    cat_cap: u64, // in blocks
+    // ranges being filled by growing storages, not in any extent yet
+    reserved: Vec<(u64, u64)>,
+}
+
+fn get_u64(b: &[u8], off: usize) -> u64 {
+    u64::from_le_bytes(b[off..off+8].try_into().unwrap())
+}
+
+fn put_u64(b: &mut [u8], off: usize, v: u64) {
+    b[off..off+8].copy_from_slice(&v.to_le_bytes());
+}
+
+impl PackedCatalog {
+    fn get(&self, name: &str) -> FsResult<&PackedExtent> {
+        self.extents.get(name).ok_or(FsError::NotFound)
+    }
+
+    fn entry_per_blk(&self) -> usize {
+        self.backend.blk_sz() / PACKED_ENTRY_SZ
+    }
+
+    fn write_header(&self) -> FsResult<()> {
+        let mut blk = new_blk(self.backend.blk_sz());
+        put_u64(&mut blk, 0, PACKED_MAGIC);
+        put_u64(&mut blk, 8, self.cat_start);
+        put_u64(&mut blk, 16, self.cat_cap);
+        put_u64(&mut blk, 24, self.slots.len() as u64);
+        self.backend.write_blk(PACKED_HEADER_POS, &blk)
+    }
+
+    // write back the catalog block that holds this slot
+    fn write_slot(&self, slot: usize) -> FsResult<()> {
+        let per_blk = self.entry_per_blk();
+        let first = slot / per_blk * per_blk;
+        let mut blk = new_blk(self.backend.blk_sz());
+        for (i, name) in self.slots.iter().enumerate().skip(first).take(per_blk) {
+            let ext = &self.extents[name];
+            let e = &mut blk[(i - first) * PACKED_ENTRY_SZ..][..PACKED_ENTRY_SZ];
+            put_u64(e, 0, ext.start);
+            put_u64(e, 8, ext.cap);
+            put_u64(e, 16, ext.len);
+            put_u64(e, 24, name.len() as u64);
+            e[32..32+name.len()].copy_from_slice(name.as_bytes());
+        }
+        self.backend.write_blk(self.cat_start + (slot / per_blk) as u64, &blk)
+    }
+
+    // first fit among gaps between extents, or append to the end of backend
+    fn alloc(&mut self, nr_blk: u64) -> FsResult<u64> {
+        let mut used: Vec<_> = self.extents.values()
+            .filter(|ext| ext.cap != 0).map(|ext| (ext.start, ext.cap)).collect();
+        used.push((PACKED_HEADER_POS, 1));
+        used.push((self.cat_start, self.cat_cap));
+        used.extend_from_slice(&self.reserved);
+        used.sort();
+
+        let mut cur = 0;
+        for (start, cap) in used {
+            if start >= cur + nr_blk {
+                return Ok(cur);
+            }
+            cur = cur.max(start + cap);
+        }
+        if self.backend.get_len()? < blk2byte!(cur + nr_blk, self.backend.blk_sz()) {
+            self.backend.set_len(cur + nr_blk)?;
+        }
+        Ok(cur)
+    }
+
+    fn grow_catalog(&mut self) -> FsResult<()> {
+        let new_cap = self.cat_cap * 2;
+        // old catalog is still in use during alloc, so they never overlap
+        let new_start = self.alloc(new_cap)?;
+        copy_blks(&self.backend, self.cat_start, new_start, self.cat_cap)?;
+        self.cat_start = new_start;
+        self.cat_cap = new_cap;
+        self.write_header()
+    }
+
+    fn add(&mut self, name: &str) -> FsResult<()> {
+        if name.len() > PACKED_NAME_MAX {
+            return Err(FsError::InvalidParameter);
+        }
+        if self.extents.contains_key(name) {
+            return Err(FsError::AlreadyExists);
+        }
+        if self.slots.len() == self.cat_cap as usize * self.entry_per_blk() {
+            self.grow_catalog()?;
+        }
+
+        let slot = self.slots.len();
+        self.slots.push(name.to_string());
+        self.extents.insert(name.to_string(), PackedExtent {
+            slot,
+            start: 0,
+            cap: 0,
+            len: 0,
+        });
+        self.write_slot(slot)?;
+        self.write_header()
+    }
+
+    fn remove(&mut self, name: &str) -> FsResult<()> {
+        let ext = self.extents.remove(name).ok_or(FsError::NotFound)?;
+
+        // move the last slot into the hole, until the header is written
+        // the last slot is stale, it is dropped on open
+        let last = self.slots.pop().unwrap();
+        if ext.slot != self.slots.len() {
+            self.extents.get_mut(&last).unwrap().slot = ext.slot;
+            self.slots[ext.slot] = last;
+            self.write_slot(ext.slot)?;
+        }
+        self.write_header()?;
+        self.write_slot(self.slots.len())?;
+        // the catalog no longer has the extent, its space can go
+        self.backend.discard(ext.start, ext.cap)
+    }
+
+    // the replaced storage is dropped before the renamed slot is written,
+    // a crash in between loses `to` but never leaves it half written
+    fn rename(&mut self, from: &str, to: &str) -> FsResult<()> {
+        if to.len() > PACKED_NAME_MAX {
+            return Err(FsError::InvalidParameter);
+        }
+        self.get(from)?;
+        if from == to {
+            return Ok(());
+        }
+        if self.extents.contains_key(to) {
+            self.remove(to)?;
+        }
+
+        let ext = self.extents.remove(from).unwrap();
+        let slot = ext.slot;
+        self.slots[slot] = to.to_string();
+        self.extents.insert(to.to_string(), ext);
+        self.write_slot(slot)
+    }
+
+    // set len of a storage within its capacity
+    fn set_len(&mut self, name: &str, nr_blk: u64) -> FsResult<()> {
+        let mut ext = self.get(name)?.clone();
+        assert!(nr_blk <= ext.cap);
+        zero_blks(&self.backend, ext.start + ext.len, ext.start + nr_blk)?;
+        ext.len = nr_blk;
+
+        let slot = ext.slot;
+        self.extents.insert(name.to_string(), ext);
+        self.write_slot(slot)
+    }
+
+    // take the reserved range as the new extent of a grown storage,
+    // the old range is discarded only after its slot is written
+    fn move_to(&mut self, name: &str, old: &PackedExtent, start: u64, cap: u64, nr_blk: u64)
+        -> FsResult<()>
+    {
+        self.reserved.retain(|r| *r != (start, cap));
+        let ext = self.extents.get_mut(name).ok_or(FsError::NotFound)?;
+        if (ext.start, ext.cap) != (old.start, old.cap) {
+            // resized by someone else during copy
+            return Err(FsError::Busy);
+        }
+        ext.start = start;
+        ext.cap = cap;
+        ext.len = nr_blk;
+        let slot = ext.slot;
+        self.write_slot(slot)?;
+        self.backend.discard(old.start, old.cap)
+    }
+}
+
+// blocks exposed by growing read as zero, like a file
+fn zero_blks(backend: &Arc<dyn RWStorage>, from: u64, to: u64) -> FsResult<()> {
+    let blk = new_blk(backend.blk_sz());
+    for pos in from..to {
+        backend.write_blk(pos, &blk)?;
+    }
+    Ok(())
+}
+
+fn copy_blks(backend: &Arc<dyn RWStorage>, from: u64, to: u64, nr_blk: u64) -> FsResult<()> {
+    let mut blk = new_blk(backend.blk_sz());
+    for i in 0..nr_blk {
+        backend.read_blk_to(from + i, &mut blk)?;
+        backend.write_blk(to + i, &blk)?;
+    }
+    Ok(())
+}
+
+impl PackedDevice {
+    // open a packed device from an already formatted backend
+    pub fn new(backend: Arc<dyn RWStorage>) -> FsResult<Self> {
+        let header = backend.read_blk(PACKED_HEADER_POS)?;
+        if get_u64(&header, 0) != PACKED_MAGIC {
+            return Err(new_error!(FsError::InvalidData));
+        }
+        let cat_start = get_u64(&header, 8);
+        let cat_cap = get_u64(&header, 16);
+        let nr_slot = get_u64(&header, 24) as usize;
+        let (bsize, per_blk) = (backend.blk_sz(), backend.blk_sz() / PACKED_ENTRY_SZ);
+        if nr_slot > cat_cap as usize * per_blk
+            || backend.get_len()? < blk2byte!(cat_start + cat_cap, bsize) {
+            return Err(new_error!(FsError::InvalidData));
+        }
+
+        let mut extents = BTreeMap::new();
+        let mut slots = Vec::with_capacity(nr_slot);
+        let mut blk = new_blk(bsize);
+        for slot in 0..nr_slot {
+            if slot % per_blk == 0 {
+                backend.read_blk_to(cat_start + (slot / per_blk) as u64, &mut blk)?;
+            }
+            let e = &blk[slot % per_blk * PACKED_ENTRY_SZ..][..PACKED_ENTRY_SZ];
+            let name_len = get_u64(e, 24) as usize;
+            if name_len > PACKED_NAME_MAX {
+                return Err(new_error!(FsError::InvalidData));
+            }
+            let name = core::str::from_utf8(&e[32..32+name_len])
+                .map_err(|_| new_error!(FsError::InvalidData))?.to_string();
+            if name.is_empty() || extents.contains_key(&name) {
+                // the last slot left stale by an unfinished remove
+                if slot + 1 == nr_slot {
+                    break;
+                }
+                return Err(new_error!(FsError::InvalidData));
+            }
+            extents.insert(name.clone(), PackedExtent {
+                slot,
+                start: get_u64(e, 0),
+                cap: get_u64(e, 8),
+                len: get_u64(e, 16),
+            });
+            slots.push(name);
+        }
+
+        Ok(Self {
+            bsize,
+            cat: Arc::new(Mutex::new(PackedCatalog {
+                backend,
+                extents,
+                slots,
+                cat_start,
+                cat_cap,
+                reserved: Vec::new(),
+            })),
+        })
+    }
+
+    // write an empty catalog into backend, anything in it is dropped
+    pub fn format(backend: Arc<dyn RWStorage>) -> FsResult<Self> {
+        backend.set_len(2)?;
+        let cat = PackedCatalog {
+            backend,
+            extents: BTreeMap::new(),
+            slots: Vec::new(),
+            cat_start: 1,
+            cat_cap: 1,
+            reserved: Vec::new(),
+        };
+        cat.write_slot(0)?;
+        cat.write_header()?;
+
+        Ok(Self {
+            bsize: cat.backend.blk_sz(),
+            cat: Arc::new(Mutex::new(cat)),
+        })
+    }
+}
+
+impl Device for PackedDevice {
+    fn blk_sz(&self) -> usize {
+        self.bsize
+    }
+
+    fn open_rw_storage(&self, path: &str) -> FsResult<Arc<dyn RWStorage>> {
+        mutex_lock!(self.cat).get(path)?;
+        Ok(Arc::new(PackedStorage {
+            cat: self.cat.clone(),
+            name: path.to_string(),
+            bsize: self.bsize,
+        }))
+    }
+
+    fn create_rw_storage(&self, path: &str) -> FsResult<Arc<dyn RWStorage>> {
+        mutex_lock!(self.cat).add(path)?;
+        self.open_rw_storage(path)
+    }
+
+    fn remove_storage(&self, path: &str) -> FsResult<()> {
+        mutex_lock!(self.cat).remove(path)
+    }
+
+    fn rename_storage(&self, from: &str, to: &str) -> FsResult<()> {
+        mutex_lock!(self.cat).rename(from, to)
+    }
+
+    fn get_storage_len(&self, path: &str) -> FsResult<u64> {
+        Ok(blk2byte!(mutex_lock!(self.cat).get(path)?.len, self.bsize))
+    }
+
+    fn nr_storage(&self) -> FsResult<usize> {
+        Ok(mutex_lock!(self.cat).slots.len())
+    }
+}
+
+// a storage inside a packed device, its extent may move when growing,
+// so look it up on every access
+struct PackedStorage {
+    cat: Arc<Mutex<PackedCatalog>>,
+    name: String,
+    bsize: usize,
+}
+
+impl ROStorage for PackedStorage {
+    fn blk_sz(&self) -> usize {
+        self.bsize
+    }
+
+    fn read_blk_to(&self, pos: u64, to: &mut Block) -> FsResult<()> {
+        let cat = mutex_lock!(self.cat);
+        let ext = cat.get(&self.name)?;
+        if pos >= ext.len {
+            return Err(new_error!(FsError::UnexpectedEof));
+        }
+        cat.backend.read_blk_to(ext.start + pos, to)
+    }
+}
+
+impl RWStorage for PackedStorage {
+    fn write_blk(&self, pos: u64, from: &Block) -> FsResult<()> {
+        let cat = mutex_lock!(self.cat);
+        let ext = cat.get(&self.name)?;
+        if pos >= ext.len {
+            return Err(new_error!(FsError::UnexpectedEof));
+        }
+        cat.backend.write_blk(ext.start + pos, from)
+    }
+
+    fn get_len(&self) -> FsResult<u64> {
+        Ok(blk2byte!(mutex_lock!(self.cat).get(&self.name)?.len, self.bsize))
+    }
+
+    fn set_len(&self, nr_blk: u64) -> FsResult<()> {
+        let (backend, old, start, cap) = {
+            let mut cat = mutex_lock!(self.cat);
+            let old = cat.get(&self.name)?.clone();
+            if nr_blk <= old.cap {
+                return cat.set_len(&self.name, nr_blk);
+            }
+            // double capacity, so that growing block by block is not copying all the time
+            let cap = nr_blk.next_power_of_two();
+            let start = cat.alloc(cap)?;
+            cat.reserved.push((start, cap));
+            (cat.backend.clone(), old, start, cap)
+        };
+
+        // the new range is reserved, fill it without blocking other storages
+        let filled = copy_blks(&backend, old.start, start, old.len)
+            .and_then(|_| zero_blks(&backend, start + old.len, start + nr_blk));
+        let mut cat = mutex_lock!(self.cat);
+        if let Err(e) = filled {
+            cat.reserved.retain(|r| *r != (start, cap));
+            return Err(e);
+        }
+        cat.move_to(&self.name, &old, start, cap, nr_blk)
+    }
+
+    fn discard(&self, pos: u64, nr_blk: u64) -> FsResult<()> {
+        let cat = mutex_lock!(self.cat);
+        let ext = cat.get(&self.name)?;
+        if pos + nr_blk > ext.len {
+            return Err(new_error!(FsError::UnexpectedEof));
+        }
+        cat.backend.discard(ext.start + pos, nr_blk)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::*;
+    use crate::test_util::*;
+
+    #[test]
+    fn packed_remove_crash() {
+        use std::sync::Arc;
+
+        let mut nr_write = 0;
+        loop {
+            let mem = Arc::new(MemStorage::new(BLK_SZ));
+            let state = FailState::new();
+            let backend = Arc::new(FailStorage(mem.clone(), state.clone()));
+            let dev = PackedDevice::format(backend).unwrap();
+            for (i, name) in ["a", "b", "c"].iter().enumerate() {
+                let s = dev.create_rw_storage(name).unwrap();
+                s.set_len(i as u64 + 1).unwrap();
+                s.write_blk(i as u64, &[i as u8 + 1; BLK_SZ]).unwrap();
+            }
+
+            // lose everything after nr_write during remove, as if it crashed
+            state.stop_after(nr_write);
+            dev.remove_storage("a").unwrap();
+            let finished = state.left() > 0;
+            drop(dev);
+
+            let dev = PackedDevice::new(mem).unwrap();
+            let has_a = dev.open_rw_storage("a").is_ok();
+            assert!(!(finished && has_a));
+            assert_eq!(dev.nr_storage().unwrap(), if has_a { 3 } else { 2 });
+            for (i, name) in ["b", "c"].iter().enumerate() {
+                let pos = i as u64 + 1;
+                let s = dev.open_rw_storage(name).unwrap();
+                assert_eq!(s.get_len().unwrap(), blk2byte!(pos + 1, BLK_SZ));
+                assert_eq!(*s.read_blk(pos).unwrap(), [pos as u8 + 1; BLK_SZ]);
+            }
+            if finished {
+                break;
+            }
+            nr_write += 1;
+        }
+    }
+}
@@ -2,6 +2,7 @@ use crate::rw_as_blob;
 use core::mem::size_of;
 
 pub const INODE_ALIGN: usize = 16;
+pub const NR_ENCODING: usize = 2;
 
 #[repr(C)]
 #[derive(Default)]
@@ -29,17 +30,28 @@ pub struct DInodeBase {
     /// modiied time
     pub mtime: u32,
 
+    /// birth time, set once when the file is created
+    pub btime: u32,
+
+    /// bits of InodeFlags
+    pub flags: u32,
+
     /// file size(regular file), if inline, actual size is size.next_multiple_of(INODE_ALIGN)
     /// dir-entry num(dir), without . and ..
     /// name length(symbolic link)
     pub size: u64,
+
+    /// bytes of precompressed representations of a regular file, by Encoding,
+    /// 0 if one is not stored. they follow file data in its hash tree,
+    /// each starting at a block boundary. an inline file has none
+    pub precompressed: [u32; NR_ENCODING],
 }
 rw_as_blob!(DInodeBase);
 
-// di_base(32)
-// data 480Bytes
+// di_base(48)
+// data 464Bytes
 // = 512Bytes
-pub const DI_REG_INLINE_DATA_MAX: u64 = 480;
+pub const DI_REG_INLINE_DATA_MAX: u64 = 464;
 
 #[repr(C)]
 #[derive(Default)]
@@ -59,6 +71,13 @@ pub struct DInodeReg {
 }
 rw_as_blob!(DInodeReg);
 
+/// with compression in superblock, file data at the start of the hash tree of an
+/// external regular file is a table of chunk ends, one for each block of file data,
+/// followed by the chunks. a chunk runs from the end of the one before it to its own,
+/// in bytes after the table. a chunk of a whole block is the block as it is, others
+/// are compressed. precompressed representations follow from the next block boundary
+pub type ChunkEnd = u64;
+
 #[repr(C)]
 #[derive(Clone, Debug)]
 pub struct EntryIndex {
@@ -84,13 +103,20 @@ pub struct DirEntry {
 }
 rw_as_blob!(DirEntry);
 
+// dirent tables are read block by block, so an entry must never cross a block,
+// block sizes are powers of two from MIN_BLK_SZ on
+const _: () = assert!(crate::MIN_BLK_SZ.is_multiple_of(size_of::<DirEntry>()));
+
 pub const DE_MAX_INLINE_NAME: usize = 12;
 
-// di_base(32)
+// di_base(48)
 // dot&dotdot: 2*dir_entry(32)
-// 13*dir_entry(32)
-// = 512Bytes
-pub const DE_INLINE_MAX: u64 = 13;
+// 12*dir_entry(32)
+// = 496Bytes
+pub const DE_INLINE_MAX: u64 = 12;
+
+// a dir of n external entries has at most n / MAX_ENTRY_GROUP_LEN (rounded up) indexes
+pub const MAX_ENTRY_GROUP_LEN: usize = 16;
 
 #[repr(C)]
 pub struct DInodeDirBaseNoInline {
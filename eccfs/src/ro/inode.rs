@@ -3,9 +3,9 @@ use super::disk::*;
 use core::mem::size_of;
 use crate::htree::*;
 use crate::bcache::*;
-use crate::crypto::half_md4;
 use super::*;
 use alloc::string::{String, ToString};
+use alloc::boxed::Box;
 
 pub enum DirEntryInfo<'a> {
     Inline(&'a [DirEntry]),
@@ -29,6 +29,9 @@ enum InodeExt {
         _data_start: u64,
         _data_len: u64,
         data: ROHashTree,
+        precompressed: [u32; NR_ENCODING],
+        // file data is in chunks, see ChunkEnd
+        compressed: bool,
     },
     RegInline {
         data: Vec<u8>,
@@ -53,8 +56,11 @@ pub struct Inode {
     atime: u32,
     ctime: u32,
     mtime: u32,
+    btime: u32,
+    flags: InodeFlags,
     size: usize, // with . and ..
     ext: InodeExt,
+    bsize: usize,
 }
 
 impl Inode {
@@ -67,6 +73,9 @@ impl Inode {
         file_sec_len: u64,
         encrypted: bool,
         cache_data: bool,
+        verify: (VerifyPolicy, u64), // with seed of its sampler
+        compression: Compression,
+        shape: mht::Shape,
     ) -> FsResult<Self> {
 
         match tp {
@@ -97,18 +106,24 @@ impl Inode {
                     let dinode = unsafe {
                         &*(raw.as_ptr() as *const DInodeReg)
                     };
-                    assert!(dinode.data_start + dinode.data_len <= file_sec_len);
+                    if dinode.data_start.checked_add(dinode.data_len)
+                        .is_none_or(|end| end > file_sec_len) {
+                        return Err(new_error!(FsError::Corrupted));
+                    }
                     InodeExt::Reg {
                         _data_start: file_sec_start + dinode.data_start,
                         _data_len: dinode.data_len,
                         data: ROHashTree::new(
                             backend, file_sec_start + dinode.data_start, dinode.data_len,
-                            FSMode::from_key_entry(dinode.key_entry, encrypted), cache_data,
-                        )
+                            FSMode::from_key_entry(dinode.key_entry, encrypted), cache_data, shape,
+                        ).with_verify_policy(verify.0, verify.1),
+                        precompressed: dinode_base.precompressed,
+                        compressed: compression != Compression::None,
                     }
                 };
                 Ok(Self {
                     iid,
+                    bsize: shape.blk_sz(),
                     tp: FileType::Reg,
                     perm: get_perm_from_mode(dinode_base.mode),
                     nlinks: dinode_base.nlinks,
@@ -117,6 +132,8 @@ impl Inode {
                     atime: dinode_base.atime,
                     ctime: dinode_base.ctime,
                     mtime: dinode_base.mtime,
+                    btime: dinode_base.btime,
+                    flags: InodeFlags::from_bits_truncate(dinode_base.flags),
                     size: dinode_base.size as usize,
                     ext,
                 })
@@ -163,13 +180,14 @@ impl Inode {
                     let (pos, off) = pos64_split(di_dir_base.de_list_start);
                     assert_eq!(off as usize % INODE_ALIGN, 0);
                     InodeExt::Dir {
-                        de_list_start: pos64_to_byte(pos, off),
+                        de_list_start: pos64_to_byte(pos, off, shape.blk_sz()),
                         idx_list,
                     }
                 };
 
                 Ok(Self {
                     iid,
+                    bsize: shape.blk_sz(),
                     tp: FileType::Dir,
                     perm: get_perm_from_mode(dinode_base.mode),
                     nlinks: dinode_base.nlinks,
@@ -178,6 +196,8 @@ impl Inode {
                     atime: dinode_base.atime,
                     ctime: dinode_base.ctime,
                     mtime: dinode_base.mtime,
+                    btime: dinode_base.btime,
+                    flags: InodeFlags::from_bits_truncate(dinode_base.flags),
                     size: dinode_base.size as usize + 2,
                     ext,
                 })
@@ -190,6 +210,7 @@ impl Inode {
                 let ibase = &dinode.base;
                 Ok(Self {
                     iid,
+                    bsize: shape.blk_sz(),
                     tp: FileType::Lnk,
                     perm: get_perm_from_mode(ibase.mode),
                     nlinks: ibase.nlinks,
@@ -198,6 +219,8 @@ impl Inode {
                     atime: ibase.atime,
                     ctime: ibase.ctime,
                     mtime: ibase.mtime,
+                    btime: ibase.btime,
+                    flags: InodeFlags::from_bits_truncate(ibase.flags),
                     size: ibase.size as usize,
                     ext: InodeExt::Lnk(
                         if ibase.size > 32 {
@@ -224,8 +247,11 @@ impl Inode {
         } else {
             let readable = (self.size - offset).min(to.len());
             match &self.ext {
+                InodeExt::Reg { data, compressed: true, .. } => {
+                    trace_err!(self.read_chunks(data, offset, &mut to[..readable]))
+                }
                 InodeExt::Reg { data, .. } => {
-                    let read = data.read_exact(offset, &mut to[..readable])?;
+                    let read = trace_err!(data.read_exact(offset, &mut to[..readable]))?;
                     Ok(read)
                 }
                 InodeExt::RegInline { data } => {
@@ -238,6 +264,108 @@ impl Inode {
         }
     }
 
+    // byte range of the chunk of a block in the hash tree
+    fn chunk_range(&self, data: &ROHashTree, blk: usize) -> FsResult<(usize, usize)> {
+        let tbl_sz = self.size.div_ceil(self.bsize) * size_of::<ChunkEnd>();
+        let mut ends = [0u8; 2 * size_of::<ChunkEnd>()];
+        let (pos, ends) = if blk == 0 {
+            (0, &mut ends[size_of::<ChunkEnd>()..])
+        } else {
+            ((blk - 1) * size_of::<ChunkEnd>(), &mut ends[..])
+        };
+        if tbl_sz > blk2byte!(data.logi_nr_blk(), self.bsize) as usize {
+            return Err(new_error!(FsError::Corrupted));
+        }
+        data.read_exact(pos, ends)?;
+        let mut ends = ends.chunks(size_of::<ChunkEnd>())
+            .map(|e| ChunkEnd::from_le_bytes(e.try_into().unwrap()) as usize);
+        let start = if blk == 0 { 0 } else { ends.next().unwrap() };
+        let end = ends.next().unwrap();
+        if start > end || end - start > self.bsize
+            || tbl_sz + end > blk2byte!(data.logi_nr_blk(), self.bsize) as usize {
+            return Err(new_error!(FsError::Corrupted));
+        }
+        Ok((tbl_sz + start, tbl_sz + end))
+    }
+
+    // file data of a compressed file, block by block
+    fn read_chunks(&self, data: &ROHashTree, mut offset: usize, to: &mut [u8]) -> FsResult<usize> {
+        let mut done = 0;
+        let bsize = self.bsize;
+        let mut chunk = Vec::with_capacity(bsize);
+        while done < to.len() {
+            let (start, end) = self.chunk_range(data, offset / bsize)?;
+            chunk.resize(end - start, 0);
+            data.read_exact(start, &mut chunk)?;
+            let blk = decompress_chunk(&chunk, bsize)?;
+            let round = (to.len() - done).min(bsize - offset % bsize);
+            to[done..done+round].copy_from_slice(&blk[offset % bsize..][..round]);
+            done += round;
+            offset += round;
+        }
+        Ok(done)
+    }
+
+    // bytes of file data in the hash tree, padded to blocks
+    fn stored_data_len(&self, data: &ROHashTree, compressed: bool) -> FsResult<usize> {
+        if !compressed || self.size == 0 {
+            return Ok(self.size.next_multiple_of(self.bsize));
+        }
+        let (_, end) = self.chunk_range(data, self.size.div_ceil(self.bsize) - 1)?;
+        Ok(end.next_multiple_of(self.bsize))
+    }
+
+    pub fn read_precompressed(&self, enc: Encoding) -> FsResult<Option<Vec<u8>>> {
+        match &self.ext {
+            InodeExt::Reg { data, precompressed, compressed, .. } => {
+                let len = precompressed[enc as usize] as usize;
+                if len == 0 {
+                    return Ok(None);
+                }
+                // skip file data and those stored before
+                let offset = precompressed[..enc as usize].iter().fold(
+                    self.stored_data_len(data, *compressed)?,
+                    |off, l| off + (*l as usize).next_multiple_of(self.bsize),
+                );
+                let mut buf = vec![0u8; len];
+                if trace_err!(data.read_exact(offset, &mut buf))? != len {
+                    return Err(new_error!(FsError::UnexpectedEof));
+                }
+                Ok(Some(buf))
+            }
+            InodeExt::RegInline { .. } => Ok(None),
+            _ => Err(new_error!(FsError::PermissionDenied)),
+        }
+    }
+
+    // pull up to len bytes of file data from offset into cache, block aligned
+    pub fn readahead(&self, offset: usize, len: usize) -> FsResult<()> {
+        // positions of compressed blocks are not known without the chunk table
+        if let InodeExt::Reg { data, compressed: false, .. } = &self.ext {
+            let start = offset.div_ceil(self.bsize);
+            let end = (start + len.div_ceil(self.bsize)).min(self.size.div_ceil(self.bsize));
+            if start < end {
+                data.prefetch(start as u64, (end - start) as u64)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn is_inline(&self) -> bool {
+        matches!(
+            self.ext,
+            InodeExt::RegInline { .. } | InodeExt::DirInline { .. } | InodeExt::Lnk(LnkName::Short(_))
+        )
+    }
+
+    // check all external data blocks of this inode
+    pub fn verify_data(&self) -> FsResult<()> {
+        if let InodeExt::Reg { data, .. } = &self.ext {
+            data.verify_all()?;
+        }
+        Ok(())
+    }
+
     pub fn get_meta(&self) -> FsResult<Metadata> {
         Ok(Metadata {
             iid: self.iid,
@@ -247,13 +375,15 @@ impl Inode {
                 FileType::Lnk => 0,
             } as u64,
             blocks: if self.tp == FileType::Reg {
-                self.size.div_ceil(BLK_SZ) as u64
+                self.size.div_ceil(self.bsize) as u64
             } else {
                 0
             },
+            blksize: self.bsize as u32,
             atime: self.atime,
             ctime: self.ctime,
             mtime: self.mtime,
+            btime: self.btime,
             ftype: self.tp,
             perm: self.perm,
             nlinks: self.nlinks,
@@ -262,6 +392,10 @@ impl Inode {
         })
     }
 
+    pub fn get_flags(&self) -> InodeFlags {
+        self.flags
+    }
+
     pub fn get_link(&self) -> FsResult<LnkName> {
         if let InodeExt::Lnk(ref lnk) = self.ext {
             Ok(lnk.clone())
@@ -307,7 +441,7 @@ impl Inode {
     }
 
     // return de_list_start(pos64), group_start(num of entry), group length
-    pub fn lookup_index<'a>(&'a self, name: &str) -> FsResult<LookUpInfo<'a>> {
+    pub fn lookup_index<'a>(&'a self, hash: u64) -> FsResult<LookUpInfo<'a>> {
         match &self.ext {
             InodeExt::Dir{ref idx_list, de_list_start} => {
                 if idx_list.len() == 0 {
@@ -318,7 +452,6 @@ impl Inode {
                         self.size
                     ));
                 }
-                let hash = half_md4(name.as_bytes())?;
                 if let Some(EntryIndex {
                     position, group_len, ..
                 }) = idx_list.iter().rev().find(
@@ -338,3 +471,23 @@ impl Inode {
         }
     }
 }
+
+// a chunk of a whole block is stored as it is
+fn decompress_chunk(chunk: &[u8], bsize: usize) -> FsResult<Box<Block>> {
+    let mut blk = new_blk(bsize);
+    if chunk.len() == bsize {
+        blk.copy_from_slice(chunk);
+        return Ok(blk);
+    }
+    #[cfg(feature = "zstd")]
+    {
+        let mut decoder = ruzstd::decoding::FrameDecoder::new();
+        match decoder.decode_all(chunk, &mut blk) {
+            Ok(n) if n == bsize => Ok(blk),
+            _ => Err(new_error!(FsError::Corrupted)),
+        }
+    }
+    // ROFS::new does not open such an image
+    #[cfg(not(feature = "zstd"))]
+    Err(new_error!(FsError::NotSupported))
+}
@@ -13,45 +13,105 @@ use crate::storage::*;
 use crate::lru::*;
 use disk::*;
 use core::mem::size_of;
+use core::sync::atomic::{AtomicBool, Ordering};
 use core::slice;
-use crate::crypto::half_md4;
+use crate::crypto::{Hash256, NameHasher, StreamHasher};
 use alloc::vec::Vec;
+use alloc::vec;
 use alloc::string::String;
 use alloc::sync::Arc;
 use alloc::string::ToString;
+use alloc::collections::{BTreeSet, BTreeMap};
 
 
 pub const ROFS_MAGIC: u64 = 0x00454343524F4653; // ECCROFS
 pub const NAME_MAX: u64 = u16::MAX as u64;
 
+/// content encoding of a precompressed representation of a file, as in http
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Gzip,
+    Zstd,
+}
+
+impl Encoding {
+    pub const ALL: [Encoding; NR_ENCODING] = [Encoding::Gzip, Encoding::Zstd];
+
+    /// extension of source files the builder takes in this encoding
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Encoding::Gzip => "gz",
+            Encoding::Zstd => "zst",
+        }
+    }
+}
+
+// reads of more blocks than this trigger readahead
+const READAHEAD_MIN_BLK: usize = 4;
+
+/// compression of regular file data in an image, recorded in the superblock,
+/// each block is compressed on its own, see [`disk::ChunkEnd`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Compression {
+    #[default]
+    None = 0,
+    /// needs feature `zstd` to read
+    Zstd = 1,
+}
+
+/// a dir entry as it is stored in the image
+#[derive(Clone, Debug)]
+pub struct RawDirEntry {
+    /// hash of name by the name hash function of the image, 0 for . and ..
+    pub hash: u64,
+    pub ipos: InodeID,
+    pub tp: FileType,
+    pub name: String,
+    /// byte position in path table if name is too long to be inline
+    pub name_pos: Option<u64>,
+}
+
 pub struct ROFS {
     mode: FSMode,
     cache_data: bool,
     backend: Arc<Mutex<ROCache>>,
     sb: RwLock<SuperBlock>,
     inode_tbl: ROHashTree,
+    // whole inode table verified and kept in memory, see load_inode_table
+    inode_tbl_mem: RwLock<Option<Vec<u8>>>,
     dirent_tbl: Option<ROHashTree>,
     path_tbl: Option<ROHashTree>,
     icac: Option<Mutex<Lru<InodeID, Inode>>>,
     de_cac: Option<Mutex<Lru<String, InodeID>>>,
+    verify_policy: RwLock<(VerifyPolicy, u64)>, // with seed of its sampler
+    name_hasher: NameHasher,
+    destroyed: AtomicBool,
 }
 
 #[cfg(feature = "channel_lru")]
 impl Drop for ROFS {
+    // errors are dropped here, see them by close
     fn drop(&mut self) {
-        self.backend.abort().unwrap();
+        let _ = self.backend.lock().close();
         if let Some(mu_icac) = &self.icac {
-            mu_icac.lock().unwrap().abort().unwrap();
+            let _ = mu_icac.lock().abort();
         }
         if let Some(mu_decac) = &self.de_cac {
-            mu_decac.lock().unwrap().abort().unwrap();
+            let _ = mu_decac.lock().abort();
         }
     }
 }
 
 pub const DEFAULT_ICAC_CAP: usize = 32;
+// max span of inode table blocks read at once when prefetching inodes
+pub const RO_PREFETCH_MAX_BLK: u64 = 8;
 
 impl ROFS {
+    /// read the public header of an image, no key is needed
+    pub fn read_public_header(storage: &dyn ROStorage) -> FsResult<PublicHeader> {
+        PublicHeader::new(&storage.read_blk(PUBLIC_HEADER_POS)?)
+    }
+
     pub fn new(
         mode: FSMode,
         cache_data: usize,
@@ -59,11 +119,46 @@ impl ROFS {
         cache_de: usize,
         storage: Arc<dyn ROStorage>
     ) -> FsResult<Self> {
+        // plain header tells the block size of an image before any crypto,
+        // storage must be opened with it
+        let hdr = Self::read_public_header(storage.as_ref())?;
+        if !(RO_LAYOUT_VERSION_MIN..=FS_LAYOUT_VERSION).contains(&hdr.version) {
+            return Err(FsError::NotSupported);
+        }
+        if hdr.bsize != storage.blk_sz() {
+            return Err(FsError::InvalidParameter);
+        }
         // read superblock
         let mut sb_blk = storage.read_blk(SUPERBLOCK_POS)?;
         // check crypto
         crypto_in(&mut sb_blk, CryptoHint::from_fsmode(mode.clone(), SUPERBLOCK_POS))?;
-        let sb = SuperBlock::new(sb_blk)?;
+        let mut sb = SuperBlock::new(&sb_blk)?;
+        // older images keep their version in the public header only
+        if sb.version == 0 && hdr.version < RO_SB_VERSION_SINCE {
+            sb.version = hdr.version;
+        }
+        // plain header must agree with the superblock
+        if hdr != sb.public_header() {
+            return Err(new_error!(FsError::SuperBlockCheckFailed));
+        }
+        storage.confirm_blk(PUBLIC_HEADER_POS)?;
+        storage.confirm_blk(SUPERBLOCK_POS)?;
+        if sb.compression != Compression::None && !cfg!(feature = "zstd") {
+            return Err(FsError::NotSupported);
+        }
+        // table lengths are used in htree position math unchecked
+        if [sb.inode_tbl_len, sb.dirent_tbl_len, sb.path_tbl_len, sb.file_sec_len]
+            .into_iter().any(|len| sb.shape.check_phy_nr_blk(len).is_err()) {
+            return Err(new_error!(FsError::SuperBlockCheckFailed));
+        }
+        let name_hasher = NameHasher::new(
+            sb.name_hash,
+            &sb.name_hash_salt,
+            match &mode {
+                FSMode::Encrypted(key, _) => Some(key),
+                FSMode::IntegrityOnly(_) => None,
+            },
+        )?;
 
         // start cache channel server
         let cac = ROCache::new(
@@ -84,6 +179,7 @@ impl ROFS {
             sb.inode_tbl_len,
             FSMode::from_key_entry(sb.inode_tbl_key, mode.is_encrypted()),
             cache_data != 0,
+            sb.shape,
         );
         let dirent_tbl = if sb.dirent_tbl_len != 0 {
             Some(ROHashTree::new(
@@ -92,6 +188,7 @@ impl ROFS {
                 sb.dirent_tbl_len,
                 FSMode::from_key_entry(sb.dirent_tbl_key, mode.is_encrypted()),
                 cache_data != 0,
+                sb.shape,
             ))
         } else {
             None
@@ -103,6 +200,7 @@ impl ROFS {
                 sb.path_tbl_len,
                 FSMode::from_key_entry(sb.path_tbl_key, mode.is_encrypted()),
                 cache_data != 0,
+                sb.shape,
             ))
         } else {
             None
@@ -114,12 +212,13 @@ impl ROFS {
             }
         );
 
-        Ok(ROFS {
+        let rofs = ROFS {
             mode,
             sb: RwLock::new(sb),
             backend: alock_cac.clone(),
             cache_data: cache_data != 0,
             inode_tbl,
+            inode_tbl_mem: RwLock::new(None),
             dirent_tbl,
             path_tbl,
             icac,
@@ -128,18 +227,301 @@ impl ROFS {
             } else {
                 None
             },
-        })
+            verify_policy: RwLock::new((VerifyPolicy::Full, 0)),
+            name_hasher,
+            destroyed: AtomicBool::new(false),
+        };
+
+        Ok(rofs)
+    }
+
+    /// check the whole image right after mount, e.g. `ROFS::new(..)?.verified()?`,
+    /// a broken image fails here rather than on some later read
+    pub fn verified(self) -> FsResult<Self> {
+        self.verify_all()?;
+        Ok(self)
+    }
+
+    /// shut down for good and return the mode, like destroy, but also stops
+    /// background cache servers and reports errors of that, which drop can not
+    pub fn close(self) -> FsResult<FSMode> {
+        self.destroyed.store(true, Ordering::Release);
+        let mode = self.fsync()?;
+        self.backend.lock().close()?;
+        Ok(mode)
+    }
+
+    /// uuid of the image, zero if it's built before uuids are recorded
+    pub fn image_uuid(&self) -> ImageUuid {
+        self.sb.read().uuid
+    }
+
+    /// generation of the image under its uuid, given by the builder
+    pub fn image_generation(&self) -> u64 {
+        self.sb.read().generation
+    }
+
+    /// stored bytes of a regular file in the first of `accept` it's precompressed in,
+    /// None if it's in none of them, then callers compress it on their own
+    pub fn read_compressed(
+        &self, iid: InodeID, accept: &[Encoding],
+    ) -> FsResult<Option<(Vec<u8>, Encoding)>> {
+        let inode = self.get_inode(iid)?;
+        for enc in accept {
+            if let Some(data) = inode.read_precompressed(*enc)? {
+                return Ok(Some((data, *enc)));
+            }
+        }
+        Ok(None)
+    }
+
+    /// choose which blocks of file data are checked on read in integrity only mode,
+    /// all tables are always fully checked. cached inodes and blocks are dropped to take it,
+    /// while inodes in use keep the old policy till they are dropped.
+    ///
+    /// `seed` is for `VerifyPolicy::Sampled`, a random one is taken if it's None with std,
+    /// without std it must be given
+    pub fn set_verify_policy(&self, policy: VerifyPolicy, seed: Option<u64>) -> FsResult<()> {
+        let seed = match (policy, seed) {
+            (VerifyPolicy::Sampled(pct), _) if pct > 100 => return Err(FsError::InvalidParameter),
+            (_, Some(seed)) => seed,
+            #[cfg(feature = "std")]
+            (_, None) => rand::random(),
+            #[cfg(not(feature = "std"))]
+            (VerifyPolicy::Sampled(_), None) => return Err(FsError::InvalidParameter),
+            #[cfg(not(feature = "std"))]
+            (_, None) => 0,
+        };
+        *self.verify_policy.write() = (policy, seed);
+        if let Some(ref icac) = self.icac {
+            icac.lock().flush_no_wb()?;
+        }
+        // blocks taken unchecked under a weaker policy must not be served from cache
+        self.backend.lock().flush()
+    }
+
+    /// check every block of the image, including all tables and file data
+    pub fn verify_all(&self) -> FsResult<()> {
+        self.inode_tbl.verify_all()?;
+        if let Some(ref dtbl) = self.dirent_tbl {
+            dtbl.verify_all()?;
+        }
+        if let Some(ref ptbl) = self.path_tbl {
+            ptbl.verify_all()?;
+        }
+
+        // walk the whole tree from root, parse every inode and check file data
+        let mut visited = BTreeSet::new();
+        let mut stack = Vec::new();
+        stack.push(ROOT_INODE_ID);
+        visited.insert(ROOT_INODE_ID);
+        while let Some(iid) = stack.pop() {
+            let inode = self.get_inode(iid)?;
+            match inode.get_meta()?.ftype {
+                FileType::Reg => inode.verify_data()?,
+                FileType::Lnk => {
+                    self.iread_link(iid)?;
+                }
+                FileType::Dir => {
+                    for (child, name, _) in self.listdir(iid, 0, 0)? {
+                        if name != "." && name != ".." && visited.insert(child) {
+                            stack.push(child);
+                        }
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// set max nr of adjacent blocks fetched from storage in one read,
+    /// 1 means no batching
+    pub fn set_read_batch(&self, max_blk: usize) {
+        self.backend.lock().set_max_batch(max_blk);
+    }
+
+    /// read and verify the whole inode table into memory, so that inodes are fetched
+    /// without going to the cache or storage, if the table is within `max_bytes`.
+    /// return whether it's loaded, if not, inodes are read through the cache as before
+    pub fn load_inode_table(&self, max_bytes: usize) -> FsResult<bool> {
+        let len = blk2byte!(self.inode_tbl.logi_nr_blk(), self.sb.read().bsize) as usize;
+        if len > max_bytes {
+            return Ok(false);
+        }
+        let mut itbl = vec![0u8; len];
+        if self.inode_tbl.read_exact(0, &mut itbl)? != len {
+            return Err(new_error!(FsError::UnexpectedEof));
+        }
+        *self.inode_tbl_mem.write() = Some(itbl);
+        Ok(true)
+    }
+
+    // read inode table bytes from memory if it's loaded,
+    // reads past the end of it are short as from the hash tree
+    fn read_itbl(&self, start: usize, to: &mut [u8]) -> FsResult<usize> {
+        if let Some(ref itbl) = *self.inode_tbl_mem.read() {
+            let n = itbl.len().saturating_sub(start).min(to.len());
+            to[..n].copy_from_slice(&itbl[start..][..n]);
+            return Ok(n);
+        }
+        self.inode_tbl.read_exact(start, to)
+    }
+
+    /// digest of the logical content of a regular file, computed from data
+    /// read out, so it can be checked against a manifest made outside this fs
+    pub fn file_digest(&self, iid: InodeID, algo: HashAlgo) -> FsResult<Hash256> {
+        let meta = self.get_meta(iid)?;
+        match meta.ftype {
+            FileType::Reg => {},
+            FileType::Dir => return Err(FsError::IsADirectory),
+            FileType::Lnk => return Err(FsError::InvalidParameter),
+        }
+
+        let mut hasher = StreamHasher::new(algo);
+        let mut buf = vec![0u8; self.sb.read().bsize * DEFAULT_READ_BATCH];
+        let mut offset = 0;
+        while offset < meta.size as usize {
+            let read = self.iread(iid, offset, &mut buf)?;
+            if read == 0 {
+                return Err(FsError::UnexpectedEof);
+            }
+            hasher.update(&buf[..read]);
+            offset += read;
+        }
+        Ok(hasher.finalize())
+    }
+
+    /// check whether a dir has a child with this name, without fetching the child inode
+    pub fn dir_contains(&self, iid: InodeID, name: &str) -> FsResult<bool> {
+        // lookup already jumps to the group by entry index and only compares
+        // names with matching hash, the child iid comes for free from the entry
+        Ok(self.lookup(iid, name)?.is_some())
+    }
+
+    /// list all dir entries of a dir with their stored hash, including . and ..
+    pub fn raw_entries(&self, iid: InodeID) -> FsResult<Vec<RawDirEntry>> {
+        let mut ret = Vec::new();
+        for de in self.read_de_list(iid, 0, 0)? {
+            ret.push(RawDirEntry {
+                hash: de.hash,
+                ipos: de.ipos,
+                tp: FileType::from(de.tp),
+                name: self.get_dir_ent_name(&de)?,
+                name_pos: if de.len as usize > de.name.len() {
+                    Some(u64::from_le_bytes(de.name[..8].try_into().unwrap()))
+                } else {
+                    None
+                },
+            });
+        }
+        Ok(ret)
+    }
+
+    /// export root key entries of every htree in this image, only in encrypted mode.
+    ///
+    /// whoever holds the result can decrypt the whole image without the root key,
+    /// so treat it as the root key itself: never log it or keep it next to the image.
+    pub fn export_key_material(&self) -> FsResult<KeyMaterial> {
+        if !self.mode.is_encrypted() {
+            return Err(FsError::NotSupported);
+        }
+
+        let mut km = KeyMaterial::new();
+        km.insert(KeyOwner::SuperBlock, self.mode.clone().into_key_entry());
+        {
+            let sb = self.sb.read();
+            km.insert(KeyOwner::InodeTable, sb.inode_tbl_key);
+            if sb.dirent_tbl_len != 0 {
+                km.insert(KeyOwner::DirEntryTable, sb.dirent_tbl_key);
+            }
+            if sb.path_tbl_len != 0 {
+                km.insert(KeyOwner::PathTable, sb.path_tbl_key);
+            }
+        }
+
+        // only regular files with data out of inode have their own htree
+        self.walk(ROOT_INODE_ID, &mut |e| {
+            if e.meta.ftype == FileType::Reg {
+                if let Some(ke) = self.reg_key_entry(e.iid)? {
+                    km.insert(KeyOwner::Inode(e.iid), ke);
+                }
+            }
+            Ok(WalkControl::Continue)
+        })?;
+        Ok(km)
+    }
+
+    fn reg_key_entry(&self, iid: InodeID) -> FsResult<Option<KeyEntry>> {
+        let (bpos, offset) = pos64_split(iid);
+        let start = pos64_to_byte(bpos, offset, self.sb.read().bsize) as usize;
+        let mut raw = vec![0u8; size_of::<DInodeBase>()];
+        self.read_itbl(start, &mut raw)?;
+        let di_base = unsafe {
+            core::ptr::read_unaligned(raw.as_ptr() as *const DInodeBase)
+        };
+        if di_base.size <= DI_REG_INLINE_DATA_MAX {
+            return Ok(None);
+        }
+        raw.resize(size_of::<DInodeReg>(), 0);
+        self.read_itbl(start, &mut raw)?;
+        let di = unsafe {
+            core::ptr::read_unaligned(raw.as_ptr() as *const DInodeReg)
+        };
+        Ok(Some(di.key_entry))
+    }
+
+    fn read_de_list(
+        &self, iid: InodeID, offset: usize, num: usize,
+    ) -> FsResult<Vec<DirEntry>> {
+        let inode = self.get_inode(iid)?;
+        self.read_de_list_of(&inode, offset, num)
+    }
+
+    fn read_de_list_of(
+        &self, inode: &Inode, offset: usize, num: usize,
+    ) -> FsResult<Vec<DirEntry>> {
+        match inode.get_entry_list_info(offset, num)? {
+            Some(DirEntryInfo::External(de_start, num)) => {
+                let mut de_list = Vec::new();
+                de_list.resize(num, DirEntry::default());
+                let to = unsafe {
+                    slice::from_raw_parts_mut(
+                        de_list.as_mut_ptr() as *mut u8,
+                        num * size_of::<DirEntry>(),
+                    )
+                };
+                let read = self.get_dirent_tbl()?.read_exact(de_start as usize, to)?;
+
+                if read != num * size_of::<DirEntry>() {
+                    Err(new_error!(FsError::InvalidData))
+                } else {
+                    Ok(de_list)
+                }
+            }
+            Some(DirEntryInfo::Inline(de_list)) => Ok(de_list.to_vec()),
+            None => Ok(Vec::new())
+        }
     }
 
     fn fetch_inode(&self, iid: InodeID) -> FsResult<Inode> {
+        self.fetch_inode_with(iid, |start, to| self.read_itbl(start, to))
+    }
+
+    // byte start in inode table, size and type of an inode,
+    // reading inode table bytes by `read`
+    fn locate_inode_with(
+        &self, iid: InodeID,
+        read: &impl Fn(usize, &mut [u8]) -> FsResult<usize>,
+    ) -> FsResult<(usize, usize, FileType)> {
         let (bpos, offset) = pos64_split(iid);
         assert!(offset as usize % INODE_ALIGN == 0);
 
         // try read dinode_base to get inode type
         let mut raw = Vec::new();
         raw.resize(size_of::<DInodeBase>(), 0u8);
-        let start = pos64_to_byte(bpos, offset) as usize;
-        if self.inode_tbl.read_exact(start, &mut raw)? != raw.len() {
+        let start = pos64_to_byte(bpos, offset, self.sb.read().bsize) as usize;
+        if read(start, &mut raw)? != raw.len() {
             return Err(new_error!(FsError::UnexpectedEof));
         }
         let di_base = unsafe {
@@ -164,7 +546,7 @@ impl ROFS {
                         + (di_base.size as usize + 2) * size_of::<DirEntry>()
                 } else {
                     raw.resize(size_of::<DInodeDirBaseNoInline>(), 0);
-                    if self.inode_tbl.read_exact(start, &mut raw)? != raw.len() {
+                    if read(start, &mut raw)? != raw.len() {
                         return Err(new_error!(FsError::UnexpectedEof));
                     }
                     let di_dir_base = unsafe {
@@ -178,9 +560,19 @@ impl ROFS {
         };
         assert!(inode_size % INODE_ALIGN == 0);
 
+        Ok((start, inode_size, itp))
+    }
+
+    // parse an inode, reading inode table bytes by `read`
+    fn fetch_inode_with(
+        &self, iid: InodeID,
+        read: impl Fn(usize, &mut [u8]) -> FsResult<usize>,
+    ) -> FsResult<Inode> {
+        let (start, inode_size, itp) = self.locate_inode_with(iid, &read)?;
+
         // read whole inode
-        raw.resize(inode_size, 0);
-        if self.inode_tbl.read_exact(start, &mut raw)? != raw.len() {
+        let mut raw = vec![0u8; inode_size];
+        if read(start, &mut raw)? != raw.len() {
             return Err(new_error!(FsError::UnexpectedEof));
         }
 
@@ -193,6 +585,9 @@ impl ROFS {
             self.sb.read().file_sec_len,
             self.mode.is_encrypted(),
             self.cache_data,
+            *self.verify_policy.read(),
+            self.sb.read().compression,
+            self.sb.read().shape,
         )
     }
 
@@ -214,6 +609,90 @@ impl ROFS {
         }
     }
 
+    // load inodes of listed entries into icac, so that a following stat loop
+    // does not read the inode table again
+    fn prefetch_inodes(&self, de_list: &[DirEntry]) -> FsResult<()> {
+        let mu_icac = match &self.icac {
+            Some(mu_icac) => mu_icac,
+            None => return Ok(()),
+        };
+
+        let mut iids: Vec<InodeID> = {
+            let mut icac = mu_icac.lock();
+            let cap = icac.cap();
+            let mut v = Vec::new();
+            for de in de_list {
+                if icac.get(&de.ipos)?.is_none() {
+                    v.push(de.ipos);
+                }
+            }
+            // more than cap would evict the ones just fetched
+            v.truncate(cap);
+            v
+        };
+        iids.sort_by_key(|iid| pos64_split(*iid));
+        iids.dedup();
+
+        let fetched = self.fetch_inodes(&iids);
+        let mut icac = mu_icac.lock();
+        for (iid, inode) in fetched {
+            if icac.get(&iid)?.is_none() {
+                icac.insert_and_get(iid, &Arc::new(inode?))?;
+            }
+        }
+        Ok(())
+    }
+
+    // fetch inodes sorted by position, inode table blocks covering close
+    // ipos are read in one go, instead of once per inode
+    fn fetch_inodes(&self, iids: &[InodeID]) -> Vec<(InodeID, FsResult<Inode>)> {
+        let bsize = self.sb.read().bsize;
+        let itbl_end = blk2byte!(self.inode_tbl.logi_nr_blk(), bsize) as usize;
+        let mut ret = Vec::with_capacity(iids.len());
+        let mut i = 0;
+        while i < iids.len() {
+            // gather a run of inodes within a window of blocks
+            let first_blk = pos64_split(iids[i]).0;
+            let mut j = i + 1;
+            while j < iids.len()
+                && pos64_split(iids[j]).0 < first_blk + RO_PREFETCH_MAX_BLK {
+                j += 1;
+            }
+            let last_blk = pos64_split(iids[j - 1]).0;
+
+            // one more block for the tail of the last inode
+            let buf_start = blk2byte!(first_blk, bsize) as usize;
+            let buf_end = (blk2byte!(last_blk + 2, bsize) as usize).min(itbl_end);
+            let mut buf = vec![0u8; buf_end.saturating_sub(buf_start)];
+            let buf_ok = matches!(self.read_itbl(buf_start, &mut buf), Ok(n) if n == buf.len());
+
+            for &iid in &iids[i..j] {
+                // inodes that are longer than the buffer fall back to inode table,
+                // so does every inode if the buffer failed to read
+                ret.push((iid, self.fetch_inode_with(iid, |start, to| {
+                    if buf_ok && start >= buf_start && start + to.len() <= buf_end {
+                        to.copy_from_slice(&buf[start - buf_start..][..to.len()]);
+                        Ok(to.len())
+                    } else {
+                        self.read_itbl(start, to)
+                    }
+                })));
+            }
+            i = j;
+        }
+        ret
+    }
+
+    fn list_de(&self, de_list: Vec<DirEntry>) -> FsResult<Vec<(InodeID, String, FileType)>> {
+        self.prefetch_inodes(&de_list)?;
+        let mut ret = Vec::with_capacity(de_list.len());
+        for de in de_list {
+            let name = self.get_dir_ent_name(&de)?;
+            ret.push((de.ipos, name, FileType::from(de.tp)));
+        }
+        Ok(ret)
+    }
+
     fn get_dir_ent_name(&self, de: &DirEntry) -> FsResult<String> {
         let DirEntry {len, name, ..} = de;
         let name = if *len as usize > name.len() {
@@ -221,8 +700,7 @@ impl ROFS {
             let mut buf = Vec::new();
             buf.resize((*len) as usize, 0u8);
 
-            let read = self.path_tbl.as_ref().unwrap()
-                .read_exact(pos as usize, buf.as_mut_slice())?;
+            let read = self.get_path_tbl()?.read_exact(pos as usize, buf.as_mut_slice())?;
             if read != *len as usize {
                 return Err(new_error!(FsError::InvalidData))
             }
@@ -230,11 +708,71 @@ impl ROFS {
         } else {
             core::str::from_utf8(
                 name.split_at(*len as usize).0
-            ).unwrap().to_string()
+            ).map_err(|_| new_error!(FsError::InvalidData))?.to_string()
         };
         Ok(name.into())
     }
 
+    fn get_path_tbl(&self) -> FsResult<&ROHashTree> {
+        // a long name without path table comes from a broken image, not a bug of ours
+        self.path_tbl.as_ref().ok_or(FsError::Corrupted)
+    }
+
+    // a dir with external entries must come with a dirent table
+    fn get_dirent_tbl(&self) -> FsResult<&ROHashTree> {
+        self.dirent_tbl.as_ref().ok_or(FsError::Corrupted)
+    }
+
+    fn lookup_in(&self, inode: &Inode, name: &str) -> FsResult<Option<InodeID>> {
+        Ok(self.lookup_all_in(inode, &[name])?.pop().unwrap())
+    }
+
+    // names falling in the same entry group are looked up in one scan of it
+    fn lookup_all_in(&self, inode: &Inode, names: &[&str]) -> FsResult<Vec<Option<InodeID>>> {
+        let mut ret = vec![None; names.len()];
+        let mut groups: BTreeMap<(u64, usize), Vec<(usize, u64)>> = BTreeMap::new();
+        for (i, name) in names.iter().enumerate() {
+            let hash = self.name_hasher.hash(name.as_bytes())?;
+            match inode.lookup_index(hash)? {
+                LookUpInfo::External(gstart, glen) => {
+                    groups.entry((gstart, glen)).or_default().push((i, hash));
+                }
+                LookUpInfo::Inline(de_list) => {
+                    ret[i] = self.find_de_in_list(de_list, hash, name)?;
+                }
+                LookUpInfo::NonExistent => {}
+            }
+        }
+
+        let bsize = self.sb.read().bsize;
+        for ((gstart, glen), mut wants) in groups {
+            let step = size_of::<DirEntry>();
+            let mut pos = gstart / bsize as u64;
+            let mut off = (gstart % bsize as u64) as u16;
+
+            let mut done = 0;
+            while done < glen && !wants.is_empty() {
+                let ablk = self.get_dirent_tbl()?.get_blk(pos)?;
+                let round = (glen - done).min((bsize - off as usize) / step);
+                let de_list = unsafe {
+                    slice::from_raw_parts(
+                        ablk[off as usize..].as_ptr() as *const DirEntry, round)
+                };
+                let mut rest = Vec::with_capacity(wants.len());
+                for (i, hash) in wants {
+                    match self.find_de_in_list(de_list, hash, names[i])? {
+                        Some(iid) => ret[i] = Some(iid),
+                        None => rest.push((i, hash)),
+                    }
+                }
+                wants = rest;
+                done += round;
+                (pos, off) = pos64_add((pos, off), (step * round) as u64, bsize);
+            }
+        }
+        Ok(ret)
+    }
+
     fn find_de_in_list(
         &self,
         de_list: &[DirEntry],
@@ -254,10 +792,35 @@ impl ROFS {
 }
 
 impl FileSystem for ROFS {
+    fn init(&self) -> FsResult<()> {
+        if self.destroyed.load(Ordering::Acquire) {
+            return Err(FsError::AlreadyDestroyed);
+        }
+        Ok(())
+    }
+
+    fn destroy(&self) -> FsResult<FSMode> {
+        if self.destroyed.swap(true, Ordering::AcqRel) {
+            return Err(FsError::AlreadyDestroyed);
+        }
+        self.fsync().inspect_err(|_| self.destroyed.store(false, Ordering::Release))
+    }
+
     fn finfo(&self) -> FsResult<FsInfo> {
         self.sb.read().get_fsinfo()
     }
 
+    fn capabilities(&self) -> FsResult<FsCapabilities> {
+        let sb = self.sb.read();
+        let mut caps = FsCapabilities::new(
+            sb.magic, sb.version, sb.encrypted, sb.bsize, sb.compression != Compression::None, false,
+        );
+        if sb.shape.is_short() {
+            caps.hash = Some(HashAlgo::Sha3_256Trunc128);
+        }
+        Ok(caps)
+    }
+
     fn fsync(&self) -> FsResult<FSMode> {
         if let Some(ref icac) = self.icac {
             assert_eq!(icac.lock().flush_wb()?.len(), 0);
@@ -273,21 +836,83 @@ impl FileSystem for ROFS {
     }
 
     fn iread(&self, iid: InodeID, offset: usize, to: &mut [u8]) -> FsResult<usize> {
-        self.get_inode(iid)?.read_data(offset, to)
+        let inode = self.get_inode(iid)?;
+        let read = trace_err!(inode.read_data(offset, to))?;
+        // a large read is likely to go on sequentially, the next as many blocks
+        // are pulled in. it's only a hint, errors are seen when they are read
+        if read > READAHEAD_MIN_BLK * self.sb.read().bsize {
+            let _ = inode.readahead(offset + read, read);
+        }
+        Ok(read)
+    }
+
+    fn is_inline(&self, iid: InodeID) -> FsResult<bool> {
+        Ok(self.get_inode(iid)?.is_inline())
     }
 
     fn get_meta(&self, iid: InodeID) -> FsResult<Metadata> {
         self.get_inode(iid)?.get_meta()
     }
 
+    // the inode table is a hash tree from block inode_tbl_start of the image,
+    // data block n of it is at block inode_tbl_start + logi2phy(n) in shape of the image
+    fn inode_tbl_offset(&self, iid: InodeID) -> FsResult<(u64, usize)> {
+        let (start, inode_size, _) = self.locate_inode_with(
+            iid, &|start, to| self.read_itbl(start, to),
+        )?;
+        Ok((start as u64, inode_size))
+    }
+
+    fn get_flags(&self, iid: InodeID) -> FsResult<InodeFlags> {
+        Ok(self.get_inode(iid)?.get_flags())
+    }
+
+    fn get_meta_many(&self, iids: &[InodeID]) -> FsResult<Vec<(InodeID, FsResult<Metadata>)>> {
+        let mut metas = BTreeMap::new();
+        let mut missing = Vec::new();
+        if let Some(ref mu_icac) = self.icac {
+            let mut icac = mu_icac.lock();
+            for &iid in iids {
+                if let Some(ainode) = icac.get(&iid)? {
+                    metas.insert(iid, ainode.get_meta());
+                } else {
+                    missing.push(iid);
+                }
+            }
+        } else {
+            missing.extend_from_slice(iids);
+        }
+        missing.sort_by_key(|iid| pos64_split(*iid));
+        missing.dedup();
+
+        let fetched = self.fetch_inodes(&missing);
+        let mut icac = self.icac.as_ref().map(|mu_icac| mu_icac.lock());
+        for (iid, inode) in fetched {
+            metas.insert(iid, inode.and_then(|inode| {
+                let meta = inode.get_meta();
+                if let Some(ref mut icac) = icac {
+                    if icac.get(&iid)?.is_none() {
+                        icac.insert_and_get(iid, &Arc::new(inode))?;
+                    }
+                }
+                meta
+            }));
+        }
+        drop(icac);
+
+        // errors can not be cloned, so a repeated iid is looked up again
+        Ok(iids.iter().map(|&iid| {
+            (iid, metas.remove(&iid).unwrap_or_else(|| self.get_meta(iid)))
+        }).collect())
+    }
+
     fn iread_link(&self, iid: InodeID) -> FsResult<String> {
         match self.get_inode(iid)?.get_link()? {
             LnkName::Short(s) => Ok(s),
             LnkName::Long(pos, len) => {
                 let mut buf = Vec::new();
                 buf.resize(len, 0u8);
-                let read = self.path_tbl.as_ref().unwrap()
-                            .read_exact(pos as usize, buf.as_mut_slice())?;
+                let read = self.get_path_tbl()?.read_exact(pos as usize, buf.as_mut_slice())?;
                 if read != len {
                     Err(new_error!(FsError::IncompatibleMetadata))
                 } else {
@@ -316,74 +941,57 @@ impl FileSystem for ROFS {
         // This only influences SGX deployments, not FUSE,
         // because FUSE leverages kernel's dir entry cache.
 
-        let hash = half_md4(name.as_bytes())?;
-        match self.get_inode(iid)?.lookup_index(name)? {
-            LookUpInfo::External(gstart, glen) => {
-                let step = size_of::<DirEntry>();
-                let mut pos = gstart / BLK_SZ as u64;
-                let mut off = (gstart % BLK_SZ as u64) as u16;
-
-                let mut done = 0;
-                while done < glen {
-                    let ablk = self.dirent_tbl.as_ref().unwrap().get_blk(pos)?;
-                    let round = (glen - done).min((BLK_SZ - off as usize) / step);
-                    let de_list = unsafe {
-                        slice::from_raw_parts(
-                            ablk[off as usize..].as_ptr() as *const DirEntry, round)
-                    };
-                    if let Some(iid) = self.find_de_in_list(de_list, hash, name)? {
-                        return Ok(Some(iid));
-                    }
-                    done += round;
-                    (pos, off) = pos64_add((pos, off), (step * round) as u64);
-                }
-                Ok(None)
-            }
-            LookUpInfo::Inline(de_list) => {
-                Ok(self.find_de_in_list(de_list, hash, name)?)
-            }
-            LookUpInfo::NonExistent => Ok(None),
+        let inode = self.get_inode(iid)?;
+        self.lookup_in(&inode, name)
+    }
+
+    // the dir inode is fetched once for all names, and each entry group scanned once
+    fn lookup_many(&self, iid: InodeID, names: &[&str]) -> FsResult<Vec<Option<InodeID>>> {
+        let inode = self.get_inode(iid)?;
+        self.lookup_all_in(&inode, names)
+    }
+
+    // ".." has a zero hash so that lookup can not find it,
+    // but it is always the second entry
+    fn get_parent(&self, iid: InodeID) -> FsResult<InodeID> {
+        if self.get_meta(iid)?.ftype != FileType::Dir {
+            return Err(FsError::NotADirectory);
         }
+        let de_list = self.read_de_list(iid, 1, 1)?;
+        de_list.first().map(|de| de.ipos).ok_or_else(|| new_error!(FsError::InvalidData))
+    }
+
+    fn read_dirents(
+        &self, iid: InodeID, offset: usize, num: usize,
+    ) -> FsResult<Vec<Dirent>> {
+        self.read_de_list(iid, offset, num)?.iter().map(|de| Ok(Dirent {
+            ipos: de.ipos,
+            tp: FileType::from(de.tp),
+            name: self.get_dir_ent_name(de)?,
+        })).collect()
     }
 
     fn listdir(
         &self, iid: InodeID, offset: usize, num: usize,
     ) -> FsResult<Vec<(InodeID, String, FileType)>> {
-        match self.get_inode(iid)?.get_entry_list_info(offset, num)? {
-            Some(DirEntryInfo::External(de_start, num)) => {
-                let mut de_list = Vec::new();
-                de_list.resize(num, DirEntry::default());
-                let to = unsafe {
-                    slice::from_raw_parts_mut(
-                        de_list.as_mut_ptr() as *mut u8,
-                        num * size_of::<DirEntry>(),
-                    )
-                };
-                let read = self.dirent_tbl.as_ref().unwrap()
-                            .read_exact(de_start as usize, to)?;
-
-                if read != num * size_of::<DirEntry>() {
-                    Err(new_error!(FsError::InvalidData))
-                } else {
-                    let mut ret = Vec::with_capacity(num);
-                    for de in de_list.into_iter() {
-                        let name = self.get_dir_ent_name(&de)?;
+        let de_list = self.read_de_list(iid, offset, num)?;
+        self.list_de(de_list)
+    }
 
-                        ret.push((de.ipos, name, FileType::from(de.tp)));
-                    }
-                    Ok(ret)
-                }
-            }
-            Some(DirEntryInfo::Inline(de_list)) => {
-                let mut ret = Vec::with_capacity(de_list.len());
-                for de in de_list {
-                    let name = self.get_dir_ent_name(de)?;
-                    ret.push((de.ipos, name, FileType::from(de.tp)));
-                }
-                Ok(ret)
-            }
-            None => Ok(Vec::new())
+    #[cfg(feature = "std")]
+    fn dir_stream(
+        &self, iid: InodeID,
+    ) -> FsResult<DirIter<'_>> {
+        let inode = self.get_inode(iid)?;
+        if inode.get_meta()?.ftype != FileType::Dir {
+            return Err(FsError::NotADirectory);
         }
+        // entries of a dir are in a row in dirent table, a block of them at a time
+        let per_blk = self.sb.read().bsize / size_of::<DirEntry>();
+        Ok(alloc::boxed::Box::new(DirStream::new(move |offset| {
+            let de_list = self.read_de_list_of(&inode, offset, per_blk)?;
+            self.list_de(de_list)
+        })))
     }
 }
 
@@ -395,11 +1003,12 @@ pub fn pos64_join(pos: u64, off: u16) -> u64 {
     pos | ((off as u64) << 48)
 }
 
-pub fn pos64_add((pos, off): (u64, u16), add: u64) -> (u64, u16) {
+// an offset in a block of up to MAX_BLK_SZ fits in 16 bits
+pub fn pos64_add((pos, off): (u64, u16), add: u64, bsize: usize) -> (u64, u16) {
     let newoff = off as u64 + add;
-    (pos + newoff / BLK_SZ as u64, (newoff % BLK_SZ as u64) as u16)
+    (pos + newoff / bsize as u64, (newoff % bsize as u64) as u16)
 }
 
-pub fn pos64_to_byte(pos: u64, off: u16) -> u64 {
-    blk2byte!(pos) + off as u64
+pub fn pos64_to_byte(pos: u64, off: u16, bsize: usize) -> u64 {
+    blk2byte!(pos, bsize) + off as u64
 }
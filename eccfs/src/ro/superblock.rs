@@ -1,9 +1,114 @@
 use crate::*;
 use crate::crypto::*;
 use super::*;
+use alloc::boxed::Box;
 
 
-pub const SUPERBLOCK_POS: u64 = 0;
+pub const PUBLIC_HEADER_POS: u64 = 0;
+pub const SUPERBLOCK_POS: u64 = 1;
+/// first block of tables, after public header and superblock
+pub const TBL_START: u64 = SUPERBLOCK_POS + 1;
+
+/// oldest layout version of ro images still read, the first with a public header
+pub const RO_LAYOUT_VERSION_MIN: u32 = 6;
+/// superblocks keep the version since this one, before it's only in the public header
+pub const RO_SB_VERSION_SINCE: u32 = 11;
+
+/// non-secret facts of an image, stored in plain so that it can be inspected
+/// without the key. it's not protected by itself, ROFS checks it against
+/// the superblock on mount
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PublicHeader {
+    /// File system type
+    pub magic: u64,
+    /// version of on-disk layout
+    pub version: u32,
+    pub encrypted: bool,
+    /// File system block size
+    pub bsize: usize,
+    /// Total number of blocks of the image
+    pub blocks: usize,
+    pub inode_tbl_start: u64,
+    pub inode_tbl_len: u64,
+    pub dirent_tbl_start: u64,
+    pub dirent_tbl_len: u64,
+    pub path_tbl_start: u64,
+    pub path_tbl_len: u64,
+    pub file_sec_start: u64,
+    pub file_sec_len: u64,
+}
+
+#[repr(C)]
+#[derive(Clone)]
+pub struct DPublicHeader {
+    pub magic: u64,
+    pub version: u64,
+    // bool is not used, the header is read from untrusted bytes
+    pub encrypted: u64,
+    pub bsize: u64,
+    pub blocks: u64,
+    pub inode_tbl_start: u64,
+    pub inode_tbl_len: u64,
+    pub dirent_tbl_start: u64,
+    pub dirent_tbl_len: u64,
+    pub path_tbl_start: u64,
+    pub path_tbl_len: u64,
+    pub file_sec_start: u64,
+    pub file_sec_len: u64,
+}
+rw_as_blob!(DPublicHeader);
+
+impl PublicHeader {
+    pub fn new(raw_blk: &Block) -> FsResult<Self> {
+        let dh = unsafe {
+            core::ptr::read_unaligned(raw_blk.as_ptr() as *const DPublicHeader)
+        };
+
+        // version is left to callers, so that other versions can be inspected
+        if dh.magic != super::ROFS_MAGIC || dh.encrypted > 1 || dh.version > u32::MAX as u64 {
+            return Err(new_error!(FsError::SuperBlockCheckFailed));
+        }
+        // a block size this build can't read, not corrupted
+        check_blk_sz(usize::try_from(dh.bsize).map_err(|_| FsError::NotSupported)?)?;
+
+        Ok(Self {
+            magic: dh.magic,
+            version: dh.version as u32,
+            encrypted: dh.encrypted == 1,
+            bsize: dh.bsize as usize,
+            blocks: dh.blocks as usize,
+            inode_tbl_start: dh.inode_tbl_start,
+            inode_tbl_len: dh.inode_tbl_len,
+            dirent_tbl_start: dh.dirent_tbl_start,
+            dirent_tbl_len: dh.dirent_tbl_len,
+            path_tbl_start: dh.path_tbl_start,
+            path_tbl_len: dh.path_tbl_len,
+            file_sec_start: dh.file_sec_start,
+            file_sec_len: dh.file_sec_len,
+        })
+    }
+
+    pub fn to_blk(&self) -> Box<Block> {
+        let dh = DPublicHeader {
+            magic: self.magic,
+            version: self.version as u64,
+            encrypted: self.encrypted as u64,
+            bsize: self.bsize as u64,
+            blocks: self.blocks as u64,
+            inode_tbl_start: self.inode_tbl_start,
+            inode_tbl_len: self.inode_tbl_len,
+            dirent_tbl_start: self.dirent_tbl_start,
+            dirent_tbl_len: self.dirent_tbl_len,
+            path_tbl_start: self.path_tbl_start,
+            path_tbl_len: self.path_tbl_len,
+            file_sec_start: self.file_sec_start,
+            file_sec_len: self.file_sec_len,
+        };
+        let mut blk = new_blk(self.bsize);
+        blk[..size_of::<DPublicHeader>()].copy_from_slice(dh.as_ref());
+        blk
+    }
+}
 
 #[derive(Default)]
 pub struct SuperBlock {
@@ -29,6 +134,16 @@ pub struct SuperBlock {
     pub files: usize,
     /// Maximum filename length, as for dirent structure, it's 65535 (max of u16)
     pub namemax: usize,
+    pub name_hash: NameHashAlgo,
+    /// random per image, key material of a keyed name hash
+    pub name_hash_salt: Key128,
+    pub uuid: ImageUuid,
+    pub generation: u64,
+    pub compression: Compression,
+    /// version of on-disk layout the image is built with, 0 if it's not recorded
+    pub version: u32,
+    /// by bytes of a key entry, shared by all hash trees of the image
+    pub shape: mht::Shape,
 }
 
 #[repr(C)]
@@ -51,6 +166,18 @@ pub struct DSuperBlock {
     pub file_sec_len: u64,
     pub blocks: u64,
     pub encrypted: bool,
+    /// bytes of a key entry in htree index blocks, short ones are integrity only
+    pub ke_sz: u64,
+    // zero in images built before it's configurable, which is half_md4
+    pub name_hash: u64,
+    pub name_hash_salt: Key128,
+    // both zero in images built before they're recorded
+    pub uuid: ImageUuid,
+    pub generation: u64,
+    /// Compression of regular file data
+    pub compression: u8,
+    // zero in images built before it's recorded here
+    pub version: u64,
 }
 rw_as_blob!(DSuperBlock);
 
@@ -74,6 +201,13 @@ impl Into<SuperBlock> for DSuperBlock {
             file_sec_len,
             blocks,
             encrypted,
+            ke_sz,
+            name_hash,
+            name_hash_salt,
+            uuid,
+            generation,
+            compression,
+            version,
         } = self;
 
         SuperBlock {
@@ -94,22 +228,60 @@ impl Into<SuperBlock> for DSuperBlock {
             file_sec_len,
             blocks: blocks as usize,
             encrypted,
+            name_hash: if name_hash == 0 {
+                NameHashAlgo::HalfMd4
+            } else {
+                NameHashAlgo::SipHash
+            },
+            name_hash_salt,
+            uuid,
+            generation,
+            compression: if compression == 0 {
+                Compression::None
+            } else {
+                Compression::Zstd
+            },
+            version: version as u32,
+            shape: mht::Shape::from_ke_sz(ke_sz, bsize).unwrap_or_default(),
         }
     }
 }
 
 impl SuperBlock {
-    pub fn new(raw_blk: Block) -> FsResult<Self> {
+    pub fn new(raw_blk: &Block) -> FsResult<Self> {
         let dsb = unsafe {
-            &*(raw_blk.as_ptr() as *const DSuperBlock)
+            core::ptr::read_unaligned(raw_blk.as_ptr() as *const DSuperBlock)
         };
 
-        // check constants
+        // check constants, the block size is the one it is read with
         if dsb.magic != super::ROFS_MAGIC
-            || dsb.bsize != BLK_SZ as u64 || dsb.namemax != NAME_MAX {
+            || dsb.bsize != raw_blk.len() as u64 || dsb.namemax != NAME_MAX
+            || mht::Shape::from_ke_sz(dsb.ke_sz, dsb.bsize).is_err()
+            || (dsb.encrypted && dsb.ke_sz != KEY_ENTRY_SZ as u64)
+            || dsb.name_hash > 1 || dsb.compression > 1
+            || dsb.version > u32::MAX as u64 {
             Err(new_error!(FsError::SuperBlockCheckFailed))
         } else {
-            Ok(dsb.clone().into())
+            Ok(dsb.into())
+        }
+    }
+
+    /// the public header this superblock should come with
+    pub fn public_header(&self) -> PublicHeader {
+        PublicHeader {
+            magic: self.magic,
+            version: self.version,
+            encrypted: self.encrypted,
+            bsize: self.bsize,
+            blocks: self.blocks,
+            inode_tbl_start: self.inode_tbl_start,
+            inode_tbl_len: self.inode_tbl_len,
+            dirent_tbl_start: self.dirent_tbl_start,
+            dirent_tbl_len: self.dirent_tbl_len,
+            path_tbl_start: self.path_tbl_start,
+            path_tbl_len: self.path_tbl_len,
+            file_sec_start: self.file_sec_start,
+            file_sec_len: self.file_sec_len,
         }
     }
 
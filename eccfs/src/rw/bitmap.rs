@@ -1,24 +1,20 @@
 use crate::*;
 use alloc::collections::BTreeSet;
+use alloc::boxed::Box;
 use alloc::vec::Vec;
-use core::slice;
 
 pub struct BitMap {
     used: BTreeSet<u64>,
     possible_free_pos: u64,
+    bsize: usize,
 }
 
 impl BitMap {
-    pub fn new(raw_blks: Vec<Block>) -> FsResult<Self> {
-        let bytes: &[u8] = unsafe {
-            slice::from_raw_parts(
-                raw_blks.as_ptr() as *const u8,
-                blk2byte!(raw_blks.len()) as usize,
-            )
-        };
+    pub fn new(raw_blks: Vec<Box<Block>>, bsize: usize) -> FsResult<Self> {
+        let bytes = raw_blks.iter().flat_map(|b| b.iter());
         let mut used = BTreeSet::new();
-        let mut possible_free_pos = bytes.len() as u64 * 8;
-        for (i, b) in bytes.iter().enumerate() {
+        let mut possible_free_pos = blk2byte!(raw_blks.len(), bsize) * 8;
+        for (i, b) in bytes.enumerate() {
             for off in 0..8 {
                 let iid = (i * 8 + off) as u64;
                 if (*b >> off) & 0x01 == 0x01 {
@@ -33,60 +29,81 @@ impl BitMap {
         Ok(Self {
             used,
             possible_free_pos,
+            bsize,
         })
     }
 
     pub fn alloc(&mut self) -> FsResult<u64> {
-        let i = self.possible_free_pos;
-        let safe_cnt = 0;
-        loop {
-            if safe_cnt > MAX_LOOP_CNT {
-                panic!("Loop exceeds MAX count!");
-            }
-            if !self.used.contains(&i) {
-                self.used.insert(i);
-                self.possible_free_pos = i + 1;
+        // first gap in used from possible_free_pos on
+        let mut i = self.possible_free_pos;
+        for &pos in self.used.range(i..) {
+            if pos != i {
                 break;
             }
+            i += 1;
         }
+        assert!(self.used.insert(i));
+        self.possible_free_pos = i + 1;
         // debug!("bitmap alloc {}", i);
         Ok(i)
     }
 
+    pub fn nr_used(&self) -> usize {
+        self.used.len()
+    }
+
+    // bits in the blocks it takes when written, some more are added when all are used
+    pub fn capacity(&self) -> u64 {
+        let bits = (self.bsize * 8) as u64;
+        self.last_used().map_or(0, |last| (last + 1).div_ceil(bits) * bits)
+    }
+
+    pub fn count_free(&self) -> usize {
+        self.capacity() as usize - self.nr_used()
+    }
+
+    pub fn is_used(&self, pos: u64) -> bool {
+        self.used.contains(&pos)
+    }
+
+    pub fn last_used(&self) -> Option<u64> {
+        self.used.last().copied()
+    }
+
+    pub fn iter_used(&self) -> impl Iterator<Item = u64> + '_ {
+        self.used.iter().copied()
+    }
+
     pub fn free(&mut self, pos: u64) -> FsResult<()> {
         if self.used.remove(&pos) {
             self.possible_free_pos = self.possible_free_pos.min(pos);
             // debug!("bitmap free {}", pos);
             Ok(())
         } else {
-            Err(new_error!(FsError::NotFound))
+            // a caller may free what it never got, that's not a bug of ours
+            Err(FsError::NotFound)
         }
     }
 
     // after calling this function, this struct can not be used anymore
-    pub fn write(&mut self) -> FsResult<Vec<Block>> {
+    pub fn write(&mut self) -> FsResult<Vec<Box<Block>>> {
         // debug!("bitmap write {:?}", self.used);
         let pos_list: Vec<_> = self.used.clone().into_iter().collect();
 
-        Self::write_from_list(pos_list)
+        Self::write_from_list(pos_list, self.bsize)
     }
 
-    pub fn write_from_list(pos_list: Vec<u64>) -> FsResult<Vec<Block>> {
-        // pos_list can not be empty, at least we have root inode
-        let max_pos = *pos_list.iter().max().unwrap() as usize;
-        let blks_needed = (max_pos + 1).div_ceil(BLK_SZ * 8);
-
-        let mut blks = Vec::new();
-        blks.resize(blks_needed, [0u8; BLK_SZ]);
-        let bytes: &mut [u8] = unsafe {
-            slice::from_raw_parts_mut(
-                blks.as_mut_ptr() as *mut u8,
-                blk2byte!(blks_needed) as usize,
-            )
+    pub fn write_from_list(pos_list: Vec<u64>, bsize: usize) -> FsResult<Vec<Box<Block>>> {
+        // empty only for block bitmap, inode bitmap has at least root inode
+        let blks_needed = match pos_list.iter().max() {
+            Some(&max_pos) => (max_pos as usize + 1).div_ceil(bsize * 8),
+            None => 0,
         };
 
+        let mut blks: Vec<_> = (0..blks_needed).map(|_| new_blk(bsize)).collect();
         for pos in pos_list {
-            let b = &mut bytes[pos as usize/8];
+            let byte = pos as usize / 8;
+            let b = &mut blks[byte / bsize][byte % bsize];
             *b = *b | (0x01u8 << (pos % 8));
         }
 
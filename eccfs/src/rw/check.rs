@@ -0,0 +1,156 @@
+use crate::*;
+use super::*;
+use super::xattr::xattr_file_name;
+
+/// a discrepancy found by [`RWFS::check`]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CheckIssue {
+    /// marked used in inode bitmap, but zero in inode table
+    FreeInodeInUse(InodeID),
+    /// not marked in inode bitmap, but not zero in inode table
+    UsedInodeNotMarked(InodeID),
+    /// file type in mode is none of reg, dir and lnk
+    BadFileType(InodeID),
+    /// data file name kept in inode is not the hash of its iid
+    BadDataFileName(InodeID),
+    /// data file of an inode is not on device
+    MissingDataFile(InodeID),
+    /// data file of an inode is on device with a length other than that in inode, in blocks
+    DataFileLen { iid: InodeID, expected: u64, actual: u64 },
+    /// xattr file of an inode is not on device
+    MissingXattrFile(InodeID),
+    /// xattr file of an inode is on device with a length other than that in xattr table
+    XattrFileLen { iid: InodeID, expected: u64, actual: u64 },
+    /// number of data files in superblock, and that of files found and on device
+    DataFileCount { sb: usize, found: usize, device: usize },
+    /// total blocks in superblock and that of all files found
+    BlockCount { sb: usize, found: usize },
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct CheckReport {
+    pub issues: Vec<CheckIssue>,
+}
+
+impl CheckReport {
+    pub fn is_clean(&self) -> bool {
+        self.issues.is_empty()
+    }
+}
+
+#[cfg(feature = "std")]
+pub(super) fn is_not_found(e: &FsError) -> bool {
+    match e {
+        FsError::IOError(ioe) => ioe.kind() == std::io::ErrorKind::NotFound,
+        _ => matches!(e, FsError::NotFound),
+    }
+}
+
+#[cfg(not(feature = "std"))]
+pub(super) fn is_not_found(e: &FsError) -> bool {
+    matches!(e, FsError::NotFound)
+}
+
+impl RWFS {
+    /// check the image against the inode table and superblock, nothing is written,
+    /// all discrepancies are collected in the report. changes since the last sync
+    /// are not on device yet, so check right after mount or fsync
+    pub fn check(&self) -> FsResult<CheckReport> {
+        let mut issues = Vec::new();
+        let storage_blks = |name: &str| -> FsResult<Option<u64>> {
+            match self.device.get_storage_len(name) {
+                Ok(len) => Ok(Some(len.div_ceil(self.device.blk_sz() as u64))),
+                Err(e) if is_not_found(&e) => Ok(None),
+                Err(e) => Err(e),
+            }
+        };
+
+        // superblock and tables are checked on mount, but their blocks count as well
+        let (mut files, mut blocks, sb_files, sb_blocks) = {
+            let sb = self.sb.read();
+            let mut blocks = storage_blks(SB_FILE_NAME)?.unwrap_or(0) + sb.itbl_len as u64;
+            let mut files = 2;
+            for len in [sb.ltbl_len, sb.xtbl_len] {
+                if len != 0 {
+                    files += 1;
+                    blocks += len as u64;
+                }
+            }
+            (files, blocks, sb.nr_data_file, sb.blocks)
+        };
+
+        let nr_inode = blk2byte!(self.inode_tbl.lock().logi_len, self.device.blk_sz()) as usize
+            / self.inode_sz;
+        let ibitmap_used: Vec<_> = self.ibitmap.lock().iter_used().collect();
+        for iid in (ROOT_INODE_ID..nr_inode as u64).chain(
+            ibitmap_used.iter().copied().filter(|iid| *iid >= nr_inode as u64)
+        ) {
+            let used = self.ibitmap.lock().is_used(iid);
+            let ib = if iid < nr_inode as u64 {
+                self.read_itbl(iid)?
+            } else {
+                vec![0u8; self.inode_sz]
+            };
+            let zero = ib.iter().all(|b| *b == 0);
+            match (used, zero) {
+                (true, true) => issues.push(CheckIssue::FreeInodeInUse(iid)),
+                (false, false) => issues.push(CheckIssue::UsedInodeNotMarked(iid)),
+                _ => {}
+            }
+            if zero {
+                continue;
+            }
+
+            let di_base = unsafe {
+                &*(ib.as_ptr() as *const DInodeBase)
+            };
+            if di_base.mode >> 12 > 2 {
+                issues.push(CheckIssue::BadFileType(iid));
+                continue;
+            }
+            if Self::data_file_ke(&ib).is_some() {
+                // name and length of data file are at the same place for reg, dir and lnk
+                let di = unsafe {
+                    &*(ib.as_ptr() as *const DInodeReg)
+                };
+                if di.data_file != iid_hash(iid)? {
+                    issues.push(CheckIssue::BadDataFileName(iid));
+                }
+                match storage_blks(&hex::encode_upper(di.data_file))? {
+                    Some(actual) => {
+                        files += 1;
+                        blocks += actual;
+                        if actual != di.len {
+                            issues.push(CheckIssue::DataFileLen { iid, expected: di.len, actual });
+                        }
+                    }
+                    None => issues.push(CheckIssue::MissingDataFile(iid)),
+                }
+            }
+
+            let xattr = self.xtbl.lock().entry(iid)?;
+            if xattr.len != 0 {
+                match storage_blks(&xattr_file_name(iid)?)? {
+                    Some(actual) => {
+                        files += 1;
+                        blocks += actual;
+                        if actual != xattr.len {
+                            issues.push(CheckIssue::XattrFileLen { iid, expected: xattr.len, actual });
+                        }
+                    }
+                    None => issues.push(CheckIssue::MissingXattrFile(iid)),
+                }
+            }
+        }
+
+        let on_device = self.device.nr_storage()?;
+        if sb_files != files || sb_files != on_device {
+            issues.push(CheckIssue::DataFileCount { sb: sb_files, found: files, device: on_device });
+        }
+        if sb_blocks as u64 != blocks {
+            issues.push(CheckIssue::BlockCount { sb: sb_blocks, found: blocks as usize });
+        }
+
+        Ok(CheckReport { issues })
+    }
+}
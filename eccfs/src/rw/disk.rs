@@ -1,28 +1,36 @@
 use crate::*;
+use alloc::vec::Vec;
 
-pub type InodeBytes = [u8; INODE_SZ];
+// raw inode of the size of the image, see SuperBlock::inode_sz
+pub type InodeBytes = Vec<u8>;
 
-macro_rules! into_inode_bytes {
+macro_rules! to_inode_bytes {
     ($T: ty) => {
-        impl Into<InodeBytes> for $T {
-            #[inline]
-            fn into(self) -> InodeBytes {
+        impl $T {
+            // padded with zero to inode_sz
+            pub fn to_inode_bytes(&self, inode_sz: usize) -> InodeBytes {
                 assert_eq!(core::mem::size_of::<$T>(), INODE_SZ);
-                unsafe {
+                let mut ib = alloc::vec![0u8; inode_sz];
+                ib[..INODE_SZ].copy_from_slice(unsafe {
                     core::slice::from_raw_parts(
-                        &self as *const $T as *const u8,
+                        self as *const $T as *const u8,
                         core::mem::size_of::<$T>(),
-                    ).try_into().unwrap()
-                }
+                    )
+                });
+                ib
             }
         }
     };
 }
 
+/// default and smallest inode size, an image may use any power of 2 up to INODE_SZ_MAX
 pub const INODE_SZ: usize = 128;
-pub const INODE_PER_BLK: usize = BLK_SZ / INODE_SZ;
+// an inode never crosses a block of any size
+pub const INODE_SZ_MAX: usize = MIN_BLK_SZ;
 
-pub const ZERO_INODE: [u8; INODE_SZ] = [0u8; INODE_SZ];
+pub fn is_valid_inode_sz(inode_sz: usize) -> bool {
+    inode_sz.is_power_of_two() && (INODE_SZ..=INODE_SZ_MAX).contains(&inode_sz)
+}
 
 #[repr(C)]
 #[derive(Default)]
@@ -49,6 +57,12 @@ pub struct DInodeBase {
     /// modiied time
     pub mtime: u32,
 
+    /// birth time, set once when the file is created
+    pub btime: u32,
+
+    /// bits of InodeFlags
+    pub flags: u32,
+
     /// file size(regular file)
     /// dir-entry data total size (dir)
     /// name length (symbolic link)
@@ -56,10 +70,13 @@ pub struct DInodeBase {
 }
 rw_as_blob!(DInodeBase);
 
-// di_base(32)
-// data 96 Bytes
-// = 128 Bytes
-pub const REG_INLINE_DATA_MAX: usize = 96;
+// inline data or link name follows di_base(40) to the end of inode
+pub const fn inline_max(inode_sz: usize) -> usize {
+    inode_sz - size_of::<DInodeBase>()
+}
+
+// of an image with the default inode size
+pub const REG_INLINE_DATA_MAX: usize = inline_max(INODE_SZ);
 
 #[repr(C)]
 #[derive(Default)]
@@ -75,23 +92,36 @@ pub struct DInodeReg {
     /// total blocks of data file, i.e. the Hash Tree
     pub len: u64,
 
-    pub _padding: [u8; 24],
+    /// data blocks never written, zero in images before holes are kept
+    pub nr_hole: u64,
+
+    pub _padding: [u8; 8],
 }
 rw_as_blob!(DInodeReg);
-into_inode_bytes!(DInodeReg);
-
-#[repr(C)]
-pub struct DInodeRegInline {
-    pub base: DInodeBase,
+to_inode_bytes!(DInodeReg);
+
+// an inline reg or lnk inode, data right after base
+pub fn inline_inode_bytes(base: DInodeBase, data: &[u8], inode_sz: usize) -> InodeBytes {
+    assert!(data.len() <= inline_max(inode_sz));
+    let mut ib = alloc::vec![0u8; inode_sz];
+    ib[..size_of::<DInodeBase>()].copy_from_slice(unsafe {
+        core::slice::from_raw_parts(
+            &base as *const DInodeBase as *const u8,
+            size_of::<DInodeBase>(),
+        )
+    });
+    ib[size_of::<DInodeBase>()..][..data.len()].copy_from_slice(data);
+    ib
+}
 
-    /// data
-    pub data: [u8; REG_INLINE_DATA_MAX],
+pub fn inline_data(ib: &[u8]) -> &[u8] {
+    &ib[size_of::<DInodeBase>()..]
 }
-rw_as_blob!(DInodeRegInline);
-into_inode_bytes!(DInodeRegInline);
 
 pub const DIRENT_SZ: usize = 256;
-pub const DIRENT_PER_BLK: usize = BLK_SZ / DIRENT_SZ;
+pub const fn dirent_per_blk(bsize: usize) -> usize {
+    bsize / DIRENT_SZ
+}
 pub const DIRENT_NAME_MAX: usize = DIRENT_SZ - 12;
 
 #[repr(C)]
@@ -107,6 +137,32 @@ pub struct DiskDirEntry {
 }
 rw_as_blob!(DiskDirEntry);
 
+impl DiskDirEntry {
+    // parse one entry from raw dir data, the slice needs no alignment,
+    // type, name length and name encoding are all checked
+    pub fn from_bytes(b: &[u8]) -> FsResult<Self> {
+        if b.len() != DIRENT_SZ {
+            return Err(FsError::InvalidData);
+        }
+        let ipos = u64::from_ne_bytes(b[0..8].try_into().unwrap());
+        let tp = u16::from_ne_bytes(b[8..10].try_into().unwrap());
+        let len = u16::from_ne_bytes(b[10..12].try_into().unwrap());
+        if tp > 2 || len as usize > DIRENT_NAME_MAX {
+            return Err(FsError::InvalidData);
+        }
+        if core::str::from_utf8(&b[12..12 + len as usize]).is_err() {
+            return Err(FsError::InvalidData);
+        }
+
+        Ok(Self {
+            ipos,
+            tp,
+            len,
+            name: b[12..].try_into().unwrap(),
+        })
+    }
+}
+
 #[repr(C)]
 pub struct DInodeDir {
     pub base: DInodeBase,
@@ -120,22 +176,13 @@ pub struct DInodeDir {
     /// total blocks of data file, i.e. the Hash Tree
     pub len: u64,
 
-    pub _padding: [u8; 24],
+    pub _padding: [u8; 16],
 }
 rw_as_blob!(DInodeDir);
-into_inode_bytes!(DInodeDir);
-
-pub const LNK_INLINE_MAX: usize = INODE_SZ - size_of::<DInodeBase>();
+to_inode_bytes!(DInodeDir);
 
-#[repr(C)]
-pub struct DInodeLnkInline {
-    pub base: DInodeBase,
-
-    /// name
-    pub name: [u8; LNK_INLINE_MAX],
-}
-rw_as_blob!(DInodeLnkInline);
-into_inode_bytes!(DInodeLnkInline);
+// of an image with the default inode size
+pub const LNK_INLINE_MAX: usize = inline_max(INODE_SZ);
 
 #[repr(C)]
 pub struct DInodeLnk{
@@ -150,11 +197,110 @@ pub struct DInodeLnk{
     /// total blocks of data file, i.e. the Hash Tree
     pub len: u64,
 
-    pub _padding: [u8; 24],
+    pub _padding: [u8; 16],
 }
 rw_as_blob!(DInodeLnk);
-into_inode_bytes!(DInodeLnk);
+to_inode_bytes!(DInodeLnk);
 
-pub const LNK_NAME_MAX: usize = BLK_SZ;
+// a name file is a single block of the smallest size or larger
+pub const LNK_NAME_MAX: usize = MIN_BLK_SZ;
 
 pub const LNK_DATA_FILE_BLK_POS: u64 = 0;
+
+// a link name longer than inline but within a slot is kept in the link table
+pub const LNK_SLOT_SZ: usize = 256;
+
+// link table data file is named by hash of it, like itbl of the builder with InodeID::MAX
+pub const LTBL_IID: u64 = u64::MAX - 1;
+
+// a lnk inode with its name in the link table,
+// it has no data file so len, at the same place as in DInodeLnk, is 0
+#[repr(C)]
+pub struct DInodeLnkSlot {
+    pub base: DInodeBase,
+
+    /// slot index in link table
+    pub slot: u64,
+
+    pub _reserved: [u8; 56],
+
+    /// always 0
+    pub len: u64,
+
+    pub _padding: [u8; 16],
+}
+rw_as_blob!(DInodeLnkSlot);
+to_inode_bytes!(DInodeLnkSlot);
+
+// whether a lnk inode not inline has its name in the link table
+pub fn is_lnk_in_slot(ib: &[u8]) -> bool {
+    let di = unsafe {
+        &*(ib.as_ptr() as *const DInodeLnk)
+    };
+    di.len == 0
+}
+
+// extended attributes of an inode are packed in a data file of its own,
+// found by the entry of the inode in xattr table, which is indexed by iid
+pub const XTBL_IID: u64 = u64::MAX - 2;
+
+#[repr(C)]
+#[derive(Default)]
+pub struct DXattrEntry {
+    /// xattr file key entry
+    pub ke: [u8; 32],
+
+    /// total blocks of xattr file, 0 if the inode has no xattr
+    pub len: u64,
+
+    /// bytes of packed xattrs
+    pub size: u64,
+
+    pub _padding: [u8; 16],
+}
+rw_as_blob!(DXattrEntry);
+
+pub const XATTR_ENTRY_SZ: usize = size_of::<DXattrEntry>();
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::vec;
+
+    fn raw_de(ipos: u64, tp: u16, name: &[u8], len: u16) -> Vec<u8> {
+        let mut b = vec![0u8; DIRENT_SZ];
+        b[0..8].copy_from_slice(&ipos.to_ne_bytes());
+        b[8..10].copy_from_slice(&tp.to_ne_bytes());
+        b[10..12].copy_from_slice(&len.to_ne_bytes());
+        b[12..12 + name.len()].copy_from_slice(name);
+        b
+    }
+
+    #[test]
+    fn dir_entry_from_bytes() {
+        let b = raw_de(7, 1, b"sub", 3);
+        let de = DiskDirEntry::from_bytes(&b).unwrap();
+        assert_eq!((de.ipos, de.tp, de.len), (7, 1, 3));
+        assert_eq!(&de.name[..3], b"sub");
+
+        // an entry at an odd address parses the same
+        let mut shifted = vec![0u8; 1];
+        shifted.extend_from_slice(&b);
+        let de = DiskDirEntry::from_bytes(&shifted[1..]).unwrap();
+        assert_eq!((de.ipos, de.tp, de.len), (7, 1, 3));
+
+        // truncated or trailing bytes
+        assert!(matches!(DiskDirEntry::from_bytes(&b[1..]), Err(FsError::InvalidData)));
+        assert!(matches!(DiskDirEntry::from_bytes(&shifted), Err(FsError::InvalidData)));
+
+        // bad length, type and name encoding
+        let long = raw_de(7, 0, b"", DIRENT_NAME_MAX as u16 + 1);
+        assert!(matches!(DiskDirEntry::from_bytes(&long), Err(FsError::InvalidData)));
+        let max = raw_de(7, 0, &[b'a'; DIRENT_NAME_MAX], DIRENT_NAME_MAX as u16);
+        assert!(DiskDirEntry::from_bytes(&max).is_ok());
+        let tp = raw_de(7, 3, b"a", 1);
+        assert!(matches!(DiskDirEntry::from_bytes(&tp), Err(FsError::InvalidData)));
+        let utf8 = raw_de(7, 0, &[0xff, 0xfe], 2);
+        assert!(matches!(DiskDirEntry::from_bytes(&utf8), Err(FsError::InvalidData)));
+    }
+}
@@ -4,6 +4,7 @@ use core::mem::{size_of, size_of_val};
 use crate::htree::*;
 use super::*;
 use alloc::string::String;
+use alloc::vec;
 use core::slice;
 
 pub struct DirEntry {
@@ -59,10 +60,12 @@ enum InodeExt {
         name_file_ke: KeyEntry,
         backend: Arc<dyn RWStorage>,
     },
+    LnkSlot {
+        lnk_name: String,
+        slot: u64,
+    },
 }
 
-pub const REG_INLINE_EXPAND_THRESHOLD: usize = BLK_SZ;
-
 pub struct Inode {
     iid: InodeID,
     pub tp: FileType,
@@ -73,16 +76,21 @@ pub struct Inode {
     atime: u32,
     ctime: u32,
     mtime: u32,
+    btime: u32,
+    flags: InodeFlags,
     size: usize, // with . and ..
     ext: InodeExt,
     encrypted: bool,
     key_gen: KeyGen,
     sb_meta: Arc<RwLock<(usize, usize)>>,
     device: Arc<dyn Device>,
+    ltbl: Arc<Mutex<LnkTable>>,
+    inode_sz: usize,
+    bsize: usize,
 }
 
-pub fn iid_to_htree_logi_pos(iid: InodeID) -> usize {
-    iid as usize * INODE_SZ
+pub fn iid_to_htree_logi_pos(iid: InodeID, inode_sz: usize) -> usize {
+    iid as usize * inode_sz
 }
 
 pub fn iid_hash(iid: InodeID) -> FsResult<Hash256> {
@@ -114,17 +122,22 @@ fn iid_hash_check(iid: InodeID, exp_hash: &Hash256) -> FsResult<()> {
 }
 
 impl Inode {
+    // inode size of the image is the length of raw
     pub fn new_from_raw(
-        raw: &InodeBytes,
+        raw: &[u8],
         iid: InodeID,
         encrypted: bool,
         sb_meta: Arc<RwLock<(usize, usize)>>,
         device: Arc<dyn Device>,
+        ltbl: Arc<Mutex<LnkTable>>,
     ) -> FsResult<Self> {
         let di_base = unsafe {
             &*(raw.as_ptr() as *const DInodeBase)
         };
         let tp = get_ftype_from_mode(di_base.mode);
+        let inode_sz = raw.len();
+        let bsize = device.blk_sz();
+        let shape = mht::Shape::full(bsize);
         let mut ret = Self {
             iid,
             tp,
@@ -135,6 +148,8 @@ impl Inode {
             atime: di_base.atime,
             ctime: di_base.ctime,
             mtime: di_base.mtime,
+            btime: di_base.btime,
+            flags: InodeFlags::from_bits_truncate(di_base.flags),
             size: di_base.size as usize,
             // just something to hold the place
             ext: InodeExt::LnkInline(String::new()),
@@ -145,17 +160,17 @@ impl Inode {
             key_gen: KeyGen::new(),
             sb_meta,
             device: device.clone(),
+            ltbl,
+            inode_sz,
+            bsize,
         };
 
         ret.ext = match tp {
             FileType::Reg => {
-                if di_base.size <= REG_INLINE_DATA_MAX as u64 {
+                if di_base.size <= inline_max(inode_sz) as u64 {
                     // inline data
-                    let di = unsafe {
-                        &*(raw.as_ptr() as *const DInodeRegInline)
-                    };
                     let d = Vec::from(
-                        &di.data[..di_base.size as usize]
+                        &inline_data(raw)[..di_base.size as usize]
                     );
                     InodeExt::RegInline(d)
                 } else {
@@ -168,22 +183,26 @@ impl Inode {
                     let fname = hex::encode_upper(&di.data_file);
                     assert_eq!(fname.len(), DATA_FILE_NAME_LEN);
 
+                    if mht::check_logi_nr_blk(di.base.size.div_ceil(bsize as u64)).is_err() {
+                        return Err(new_error!(FsError::Corrupted));
+                    }
                     let back = device.open_rw_storage(&fname)?;
-                    assert_eq!(back.get_len()?, blk2byte!(di.len));
-                    assert_eq!(
-                        mht::get_phy_nr_blk(di.base.size.div_ceil(BLK_SZ as u64)),
-                        di.len
+                    if back.get_len()? != blk2byte!(di.len, bsize)
+                        || shape.get_phy_nr_blk(di.base.size.div_ceil(bsize as u64)) != di.len {
+                        return Err(FsError::Corrupted);
+                    }
+                    let mut data = RWHashTree::new(
+                        None,
+                        back,
+                        di.base.size.div_ceil(bsize as u64),
+                        Some(FSMode::from_key_entry(di.data_file_ke.clone(), encrypted)),
+                        encrypted,
                     );
+                    data.set_nr_hole(di.nr_hole);
                     InodeExt::Reg {
                         data_file_name: fname.into(),
                         htree_org_len: di.len,
-                        data: RWHashTree::new(
-                            None,
-                            back,
-                            di.base.size.div_ceil(BLK_SZ as u64),
-                            Some(FSMode::from_key_entry(di.data_file_ke.clone(), encrypted)),
-                            encrypted,
-                        )
+                        data,
                     }
                 }
             }
@@ -196,34 +215,42 @@ impl Inode {
                 let fname = hex::encode_upper(&di.data_file);
                 assert_eq!(fname.len(), DATA_FILE_NAME_LEN);
 
+                if mht::check_logi_nr_blk(di.base.size.div_ceil(bsize as u64)).is_err() {
+                    return Err(new_error!(FsError::Corrupted));
+                }
                 let back = device.open_rw_storage(&fname)?;
-                assert_eq!(back.get_len()?, blk2byte!(di.len));
-                assert_eq!(
-                    mht::get_phy_nr_blk(di.base.size.div_ceil(BLK_SZ as u64)),
-                    di.len
-                );
+                // e.g. a truncated data file
+                if back.get_len()? != blk2byte!(di.len, bsize)
+                    || shape.get_phy_nr_blk(di.base.size.div_ceil(bsize as u64)) != di.len {
+                    return Err(FsError::Corrupted);
+                }
                 InodeExt::Dir {
                     data_file_name: fname.into(),
                     htree_org_len: di.len,
                     data: RWHashTree::new(
                         None,
                         back,
-                        di.base.size.div_ceil(BLK_SZ as u64),
+                        di.base.size.div_ceil(bsize as u64),
                         Some(FSMode::from_key_entry(di.data_file_ke.clone(), encrypted)),
                         encrypted,
                     )
                 }
             }
             FileType::Lnk => {
-                if di_base.size <= LNK_INLINE_MAX as u64 {
+                if di_base.size <= inline_max(inode_sz) as u64 {
                     // inline link name
-                    let di = unsafe {
-                        &*(raw.as_ptr() as *const DInodeLnkInline)
-                    };
                     let lnk_name = core::str::from_utf8(
-                        &di.name[..di.base.size as usize]
-                    ).unwrap().to_string();
+                        &inline_data(raw)[..di_base.size as usize]
+                    ).map_err(|_| FsError::Corrupted)?.to_string();
                     InodeExt::LnkInline(lnk_name)
+                } else if is_lnk_in_slot(raw) {
+                    let di = unsafe {
+                        &*(raw.as_ptr() as *const DInodeLnkSlot)
+                    };
+                    InodeExt::LnkSlot {
+                        lnk_name: ret.ltbl.lock().read(di.slot, di.base.size as usize)?,
+                        slot: di.slot,
+                    }
                 } else {
                     // single block file
                     let di = unsafe {
@@ -236,8 +263,10 @@ impl Inode {
                     assert_eq!(fname.len(), DATA_FILE_NAME_LEN);
 
                     let backend = device.open_rw_storage(&fname)?;
-                    assert_eq!(backend.get_len()?, BLK_SZ as u64);
-                    assert_eq!(di.len, 1);
+                    if backend.get_len()? != bsize as u64 || di.len != 1
+                        || di.base.size as usize >= LNK_NAME_MAX {
+                        return Err(FsError::Corrupted);
+                    }
                     let mut blk = backend.read_blk(0)?;
                     crypto_in(
                         &mut blk,
@@ -250,7 +279,7 @@ impl Inode {
 
                     let lnk_name = core::str::from_utf8(
                         &blk[..di.base.size as usize]
-                    ).unwrap().to_string();
+                    ).map_err(|_| FsError::Corrupted)?.to_string();
                     InodeExt::Lnk {
                         lnk_name,
                         data_file_name: fname.into(),
@@ -273,8 +302,11 @@ impl Inode {
         encrypted: bool,
         sb_meta: Arc<RwLock<(usize, usize)>>,
         device: Arc<dyn Device>,
+        ltbl: Arc<Mutex<LnkTable>>,
         now: u32,
+        inode_sz: usize,
     ) -> FsResult<Self> {
+        let bsize = device.blk_sz();
         let mut inode = Self {
             iid,
             tp,
@@ -285,6 +317,8 @@ impl Inode {
             atime: now,
             ctime: now,
             mtime: now,
+            btime: now,
+            flags: InodeFlags::empty(),
             size: 0,
             ext: InodeExt::LnkInline(String::new()),
             encrypted,
@@ -294,6 +328,9 @@ impl Inode {
             key_gen: KeyGen::new(),
             sb_meta,
             device,
+            ltbl,
+            inode_sz,
+            bsize,
         };
         inode.ext = match tp {
             FileType::Reg => InodeExt::RegInline(Vec::new()),
@@ -334,7 +371,7 @@ impl Inode {
                 )?;
                 inode.size = 2 * DIRENT_SZ;
 
-                assert_eq!(mht::get_phy_nr_blk(data.logi_len), 2);
+                assert_eq!(data.shape().get_phy_nr_blk(data.logi_len), 2);
                 nf_nb_change(&inode.sb_meta, 1, 2)?;
 
                 InodeExt::Dir {
@@ -365,7 +402,7 @@ impl Inode {
             let readable = (self.size - offset).min(to.len());
             match &mut self.ext {
                 InodeExt::Reg { data, .. } => {
-                    let read = data.read_exact(offset, &mut to[..readable])?;
+                    let read = trace_err!(data.read_exact(offset, &mut to[..readable]))?;
                     Ok(read)
                 }
                 InodeExt::RegInline(data) => {
@@ -379,7 +416,8 @@ impl Inode {
     }
 
     pub fn write_data(&mut self, offset: usize, from: &[u8]) -> FsResult<usize> {
-        let write_end = offset + from.len();
+        self.check_write(offset)?;
+        let write_end = offset.checked_add(from.len()).ok_or(FsError::FileTooLarge)?;
         self.possible_expand_to_htree(write_end)?;
 
         let ret = match &mut self.ext {
@@ -398,9 +436,31 @@ impl Inode {
         ret
     }
 
+    // form on disk, which is decided by size on every sync, see reg_force_shape
+    pub fn is_inline(&self) -> bool {
+        match &self.ext {
+            InodeExt::Reg { .. } | InodeExt::RegInline(_)
+                | InodeExt::Lnk { .. } | InodeExt::LnkSlot { .. }
+                | InodeExt::LnkInline(_) => self.size <= self.inline_max(),
+            _ => false,
+        }
+    }
+
+    fn inline_max(&self) -> usize {
+        inline_max(self.inode_sz)
+    }
+
+    // write at current end of file, return where it lands
+    pub fn append_data(&mut self, from: &[u8]) -> FsResult<(u64, usize)> {
+        let offset = self.size;
+        let written = self.write_data(offset, from)?;
+        Ok((offset as u64, written))
+    }
+
+    // kept inline in memory until it grows over a block
     fn possible_expand_to_htree(&mut self, write_end: usize) -> FsResult<()> {
         if let InodeExt::RegInline(_) = &self.ext {
-            if write_end > REG_INLINE_EXPAND_THRESHOLD {
+            if write_end > self.bsize {
                 self.reg_expand_to_htree()?;
             }
         }
@@ -420,7 +480,7 @@ impl Inode {
                 );
                 assert_eq!(htree.write_exact(0, data)?, data.len());
 
-                nf_nb_change(&self.sb_meta, 1, mht::get_phy_nr_blk(htree.logi_len) as isize)?;
+                nf_nb_change(&self.sb_meta, 1, htree.shape().get_phy_nr_blk(htree.logi_len) as isize)?;
 
                 (data_file_name, htree)
             }
@@ -429,7 +489,7 @@ impl Inode {
 
         self.ext = InodeExt::Reg {
             data_file_name,
-            htree_org_len: mht::get_phy_nr_blk(htree.logi_len),
+            htree_org_len: htree.shape().get_phy_nr_blk(htree.logi_len),
             data: htree,
         };
 
@@ -439,7 +499,7 @@ impl Inode {
     fn reg_shrink_to_inline(&mut self) -> FsResult<()> {
         let (d, file_to_remove) = match &mut self.ext {
             InodeExt::Reg { data_file_name, data, .. } =>{
-                assert!(self.size <= REG_INLINE_DATA_MAX);
+                assert!(self.size <= inline_max(self.inode_sz));
 
                 let mut d = Vec::new();
                 d.resize(self.size, 0u8);
@@ -465,7 +525,7 @@ impl Inode {
                 data.resize(new_sz, 0);
             }
             InodeExt::Reg { data, .. } => {
-                data.resize(new_sz.div_ceil(BLK_SZ) as u64)?;
+                data.resize(new_sz.div_ceil(self.bsize) as u64)?;
             }
             _ => return Err(new_error!(FsError::PermissionDenied)),
         }
@@ -481,15 +541,17 @@ impl Inode {
                 FileType::Dir => self.size,
                 FileType::Lnk => 0,
             } as u64,
-            blocks: match self.tp {
-                FileType::Reg | FileType::Dir => {
-                    self.size.div_ceil(BLK_SZ) as u64
-                }
+            blocks: match (&self.ext, self.tp) {
+                // holes take no block
+                (InodeExt::Reg { data, .. }, _) => data.logi_len.saturating_sub(data.nr_hole()),
+                (_, FileType::Reg | FileType::Dir) => self.size.div_ceil(self.bsize) as u64,
                 _ => 0,
             },
+            blksize: self.bsize as u32,
             atime: self.atime,
             ctime: self.ctime,
             mtime: self.mtime,
+            btime: self.btime,
             ftype: self.tp,
             perm: self.perm,
             nlinks: self.nlinks,
@@ -514,18 +576,60 @@ impl Inode {
         Ok(())
     }
 
+    pub fn get_flags(&self) -> InodeFlags {
+        self.flags
+    }
+
+    pub fn set_flags(&mut self, flags: InodeFlags) {
+        self.flags = flags;
+    }
+
+    // immutable takes no change, append-only only takes writes at the end
+    fn check_write(&self, offset: usize) -> FsResult<()> {
+        if self.flags.contains(InodeFlags::IMMUTABLE) || (
+            self.flags.contains(InodeFlags::APPEND_ONLY) && offset != self.size
+        ) {
+            return Err(FsError::PermissionDenied);
+        }
+        Ok(())
+    }
+
+    // for set_meta from users, times updated by the fs itself are not checked,
+    // an append-only file can not be truncated
+    pub fn check_set_meta(&self, set_meta: &SetMetadata) -> FsResult<()> {
+        if self.flags.contains(InodeFlags::IMMUTABLE) || (
+            self.flags.contains(InodeFlags::APPEND_ONLY)
+                && matches!(set_meta, SetMetadata::Size(_))
+        ) {
+            return Err(FsError::PermissionDenied);
+        }
+        Ok(())
+    }
+
+    // neither an immutable nor an append-only inode can be linked, unlinked or renamed,
+    // neither can entries of such a dir be removed or renamed
+    pub fn check_unlink(&self) -> FsResult<()> {
+        if self.flags.intersects(InodeFlags::IMMUTABLE | InodeFlags::APPEND_ONLY) {
+            return Err(FsError::PermissionDenied);
+        }
+        Ok(())
+    }
+
     pub fn get_link(&self) -> FsResult<String> {
         match &self.ext {
             InodeExt::LnkInline(lnk) => Ok(lnk.clone()),
-            InodeExt::Lnk { lnk_name, .. } => Ok(lnk_name.clone()),
+            InodeExt::Lnk { lnk_name, .. }
+                | InodeExt::LnkSlot { lnk_name, .. } => Ok(lnk_name.clone()),
             _ => Err(new_error!(FsError::PermissionDenied)),
         }
     }
 
     pub fn set_link(&mut self, target: &str) -> FsResult<()> {
+        self.check_write(0)?;
         match &mut self.ext {
             InodeExt::LnkInline(lnk) => *lnk = target.into(),
-            InodeExt::Lnk { lnk_name, .. } => *lnk_name = target.into(),
+            InodeExt::Lnk { lnk_name, .. }
+                | InodeExt::LnkSlot { lnk_name, .. } => *lnk_name = target.into(),
             _ => return Err(new_error!(FsError::PermissionDenied)),
         }
         self.size = target.len();
@@ -538,7 +642,7 @@ impl Inode {
         match &mut self.ext {
             InodeExt::Dir { data, .. } => {
                 let num = if num == 0 {
-                    self.size / DIRENT_SZ - offset
+                    (self.size / DIRENT_SZ).saturating_sub(offset)
                 } else {
                     // assert!(self.size / DIRENT_SZ >= offset);
                     if self.size / DIRENT_SZ < offset {
@@ -546,24 +650,15 @@ impl Inode {
                     }
                     num.min(self.size / DIRENT_SZ - offset)
                 };
-                let mut de_list: Vec<DiskDirEntry> = Vec::with_capacity(num);
-                unsafe {
-                    de_list.set_len(num);
-                }
                 let len = num * DIRENT_SZ;
-                let read = data.read_exact(
-                    offset * DIRENT_SZ,
-                    unsafe {
-                        slice::from_raw_parts_mut(
-                            de_list.as_mut_ptr() as *mut u8,
-                            len,
-                        )
-                    }
-                )?;
-                assert_eq!(len, read);
-                Ok(de_list.into_iter().map(
-                    |de| de.into()
-                ).collect())
+                let mut raw = vec![0u8; len];
+                // fewer bytes than size says, e.g. the htree is shorter
+                if data.read_exact(offset * DIRENT_SZ, &mut raw)? != len {
+                    return Err(FsError::Corrupted);
+                }
+                raw.chunks_exact(DIRENT_SZ).map(
+                    |b| DiskDirEntry::from_bytes(b).map(|de| de.into())
+                ).collect()
             }
             _ => Err(new_error!(FsError::PermissionDenied)),
         }
@@ -574,7 +669,7 @@ impl Inode {
         let nr_de = self.size / DIRENT_SZ;
         while done < nr_de {
             // try read a block of de
-            let round = DIRENT_PER_BLK.min(nr_de - done);
+            let round = dirent_per_blk(self.bsize).min(nr_de - done);
             let des = self.read_child(done, round)?;
             let round = des.len();
             for de in des {
@@ -587,12 +682,36 @@ impl Inode {
         Ok(None)
     }
 
+    // one pass over all entries, stops once every name is found
+    pub fn find_children(&mut self, names: &[&str]) -> FsResult<Vec<Option<InodeID>>> {
+        let mut ret = vec![None; names.len()];
+        let mut nr_left = names.len();
+        let mut done = 0;
+        let nr_de = self.size / DIRENT_SZ;
+        while done < nr_de && nr_left > 0 {
+            // try read a block of de
+            let round = dirent_per_blk(self.bsize).min(nr_de - done);
+            let des = self.read_child(done, round)?;
+            let round = des.len();
+            for de in des {
+                for (i, name) in names.iter().enumerate() {
+                    if ret[i].is_none() && de.name.as_str() == *name {
+                        ret[i] = Some(de.ipos);
+                        nr_left -= 1;
+                    }
+                }
+            }
+            done += round;
+        }
+        Ok(ret)
+    }
+
     fn find_child_pos(&mut self, name: &str) -> FsResult<Option<(usize, DirEntry)>> {
         let mut done = 0;
         let nr_de = self.size / DIRENT_SZ;
         while done < nr_de {
             // try read a block of de
-            let round = DIRENT_PER_BLK.min(nr_de - done);
+            let round = dirent_per_blk(self.bsize).min(nr_de - done);
             for (i, de) in self.read_child(done, round)?.into_iter().enumerate() {
                 if de.name.as_str() == name {
                     return Ok(Some((done + i, de)));
@@ -604,6 +723,17 @@ impl Inode {
     }
 
     pub fn add_child(&mut self, name: &str, tp: FileType, iid: InodeID) -> FsResult<()> {
+        if name.len() > DIRENT_NAME_MAX {
+            return Err(FsError::NameTooLong);
+        }
+        // an append-only dir still takes new entries
+        if self.flags.contains(InodeFlags::IMMUTABLE) {
+            return Err(FsError::PermissionDenied);
+        }
+        // a dir without links is being removed
+        if self.tp == FileType::Dir && self.nlinks == 0 {
+            return Err(FsError::NotFound);
+        }
         if self.find_child(name)?.is_some() {
             return Err(new_error!(FsError::AlreadyExists));
         }
@@ -625,6 +755,10 @@ impl Inode {
     }
 
     pub fn rename_child(&mut self, name: &str, newname: &str) -> FsResult<()> {
+        if newname.len() > DIRENT_NAME_MAX {
+            return Err(FsError::NameTooLong);
+        }
+        self.check_unlink()?;
         if self.find_child(newname)?.is_some() {
             return Err(new_error!(FsError::AlreadyExists));
         }
@@ -645,7 +779,26 @@ impl Inode {
         }
     }
 
+    // point an existing entry to another inode, e.g. .. of a moved dir
+    pub fn set_child_ipos(&mut self, name: &str, iid: InodeID) -> FsResult<()> {
+        if let Some((pos, mut de)) = self.find_child_pos(name)? {
+            match &mut self.ext {
+                InodeExt::Dir { data, .. } => {
+                    de.ipos = iid;
+                    let dde: DiskDirEntry = de.into();
+                    let written = data.write_exact(pos * DIRENT_SZ, dde.as_ref())?;
+                    assert_eq!(written, DIRENT_SZ);
+                    Ok(())
+                }
+                _ => Err(new_error!(FsError::PermissionDenied)),
+            }
+        } else {
+            Err(new_error!(FsError::NotFound))
+        }
+    }
+
     pub fn remove_child(&mut self, name: &str) -> FsResult<(InodeID, FileType)> {
+        self.check_unlink()?;
         if let Some((pos, de)) = self.find_child_pos(name)? {
             if let InodeExt::Dir { data, .. } = &mut self.ext {
                 if pos * DIRENT_SZ != self.size - DIRENT_SZ {
@@ -660,7 +813,7 @@ impl Inode {
                 }
                 self.size -= DIRENT_SZ;
                 // resize htree
-                data.resize(self.size.div_ceil(BLK_SZ) as u64)?;
+                data.resize(self.size.div_ceil(self.bsize) as u64)?;
 
                 // debug!("iid {} remove child left size {}", self.iid, self.size / DIRENT_SZ);
                 Ok((de.ipos, de.tp))
@@ -675,13 +828,22 @@ impl Inode {
     pub fn fallocate(
         &mut self, mode: FallocateMode, offset: usize, len: usize,
     ) -> FsResult<()> {
-        let end = offset + len;
+        self.check_write(offset)?;
+        if matches!(mode, FallocateMode::CollapseRange | FallocateMode::InsertRange) {
+            return self.shift_range(
+                matches!(mode, FallocateMode::InsertRange), offset, len,
+            );
+        }
+        if let FallocateMode::PunchHole = mode {
+            return self.punch_hole(offset, len);
+        }
+        let end = offset.checked_add(len).ok_or(FsError::FileTooLarge)?;
         self.possible_expand_to_htree(end)?;
 
         if let FallocateMode::Alloc = mode {
             match &mut self.ext {
                 InodeExt::Reg { data, .. } => {
-                    data.resize(end.div_ceil(BLK_SZ) as u64)?;
+                    data.resize(end.div_ceil(self.bsize) as u64)?;
                 }
                 InodeExt::RegInline(d) => {
                     d.resize(end, 0);
@@ -695,6 +857,9 @@ impl Inode {
                     data.zero_range(offset, len)?;
                 }
                 InodeExt::RegInline(d) => {
+                    if d.len() < end {
+                        d.resize(end, 0);
+                    }
                     d[offset..end].fill(0);
                 }
                 _ => return Err(new_error!(FsError::PermissionDenied)),
@@ -704,6 +869,58 @@ impl Inode {
         Ok(())
     }
 
+    // only the part within size is zeroed, size never changes
+    fn punch_hole(&mut self, offset: usize, len: usize) -> FsResult<()> {
+        let end = offset.saturating_add(len).min(self.size);
+        if offset >= end {
+            return Ok(());
+        }
+        match &mut self.ext {
+            InodeExt::Reg { data, .. } => data.punch_hole(offset, end - offset),
+            InodeExt::RegInline(d) => {
+                d[offset..end].fill(0);
+                Ok(())
+            }
+            _ => Err(new_error!(FsError::PermissionDenied)),
+        }
+    }
+
+    // remove or insert whole blocks inside a reg file, data after the range
+    // moves left or right, inserted blocks are holes
+    fn shift_range(&mut self, insert: bool, offset: usize, len: usize) -> FsResult<()> {
+        if !offset.is_multiple_of(self.bsize) || !len.is_multiple_of(self.bsize) || len == 0 {
+            return Err(FsError::InvalidParameter);
+        }
+        // a collapsed range may not reach eof, an inserted one must start before it
+        if (insert && offset >= self.size) || (!insert && offset.saturating_add(len) >= self.size) {
+            return Err(FsError::InvalidParameter);
+        }
+        let new_size = if insert {
+            self.size.checked_add(len).ok_or(FsError::FileTooLarge)?
+        } else {
+            self.size - len
+        };
+        self.possible_expand_to_htree(new_size)?;
+
+        // a collapsible inline file is never long enough, an inserted one is expanded above
+        let data = match &mut self.ext {
+            InodeExt::Reg { data, .. } => data,
+            _ => return Err(new_error!(FsError::PermissionDenied)),
+        };
+        let (pos, nr) = ((offset / self.bsize) as u64, (len / self.bsize) as u64);
+        let total = data.logi_len;
+        if insert {
+            data.resize(total + nr)?;
+            data.move_blks(pos, pos + nr, total - pos)?;
+            data.punch_hole(offset, len)?;
+        } else {
+            data.move_blks(pos + nr, pos, total - pos - nr)?;
+            data.resize(total - nr)?;
+        }
+        self.size = new_size;
+        Ok(())
+    }
+
     fn write_lnk_file(
         store: &Arc<dyn RWStorage>,
         lnk_name: &str,
@@ -711,7 +928,7 @@ impl Inode {
     ) -> FsResult<FSMode> {
         store.set_len(1)?;
 
-        let mut blk = [0u8; BLK_SZ];
+        let mut blk = new_blk(store.blk_sz());
         blk[..lnk_name.len()].copy_from_slice(lnk_name.as_bytes());
 
         let mode = crypto_out(
@@ -725,15 +942,15 @@ impl Inode {
     }
 
     fn reg_force_shape(&mut self) ->FsResult<()> {
-        // htree to inline, inline to tree, no REG_INLINE_EXPAND_THRESHOLD
+        // htree to inline, inline to tree, not waiting for a whole block
         match &mut self.ext {
             InodeExt::Reg { .. } => {
-                if self.size <= REG_INLINE_DATA_MAX {
+                if self.size <= self.inline_max() {
                     self.reg_shrink_to_inline()?;
                 }
             }
             InodeExt::RegInline(_) => {
-                if self.size > REG_INLINE_DATA_MAX {
+                if self.size > self.inline_max() {
                     self.reg_expand_to_htree()?;
                 }
             }
@@ -742,64 +959,98 @@ impl Inode {
         Ok(())
     }
 
+    // the data file or slot of the new shape is taken before the old one is given back,
+    // so a failed sync always leaves a shape that a retry can go on from
+    fn lnk_force_shape(&mut self) -> FsResult<()> {
+        let lnk = match &self.ext {
+            InodeExt::LnkInline(lnk_name)
+                | InodeExt::Lnk { lnk_name, .. }
+                | InodeExt::LnkSlot { lnk_name, .. } => lnk_name.clone(),
+            _ => return Ok(()),
+        };
+        let ext = match (&self.ext, lnk.len()) {
+            (InodeExt::LnkInline(_), len) if len <= self.inline_max() => return Ok(()),
+            (InodeExt::LnkSlot { .. }, len) if len > self.inline_max()
+                && len <= LNK_SLOT_SZ => return Ok(()),
+            (InodeExt::Lnk { .. }, len) if len > LNK_SLOT_SZ => return Ok(()),
+            (_, len) if len <= self.inline_max() => InodeExt::LnkInline(lnk),
+            (_, len) if len <= LNK_SLOT_SZ => {
+                let slot = self.ltbl.lock().alloc()?;
+                InodeExt::LnkSlot { lnk_name: lnk, slot }
+            }
+            _ => {
+                let (data_file_name, backend) = self.new_storage()?;
+                nf_nb_change(&self.sb_meta, 1, 1)?;
+                InodeExt::Lnk {
+                    lnk_name: lnk,
+                    data_file_name,
+                    name_file_ke: KeyEntry::default(),
+                    backend,
+                }
+            }
+        };
+        match &self.ext {
+            InodeExt::Lnk { data_file_name, .. } => {
+                let fname = data_file_name.clone();
+                if let Err(e) = self.remove_fs_file(&fname) {
+                    if let InodeExt::LnkSlot { slot, .. } = ext {
+                        self.ltbl.lock().free(slot)?;
+                    }
+                    return Err(e);
+                }
+            }
+            InodeExt::LnkSlot { slot, .. } => self.ltbl.lock().free(*slot)?,
+            _ => {},
+        }
+        self.ext = ext;
+        Ok(())
+    }
+
     // return file changes,  block changes
     pub fn sync_data(&mut self) -> FsResult<()> {
         self.reg_force_shape()?;
+        self.lnk_force_shape()?;
 
-        let mut file_to_remove = None;
         match &mut self.ext {
             InodeExt::Reg { data, .. } | InodeExt::Dir { data, .. } => {
                 data.flush()?.into_key_entry();
             }
-            InodeExt::Lnk { lnk_name, data_file_name, name_file_ke, backend } => {
-                if lnk_name.len() <= LNK_INLINE_MAX {
-                    file_to_remove = Some(data_file_name.clone());
-                    self.ext = InodeExt::LnkInline(lnk_name.clone());
-                } else {
-                    *name_file_ke = Self::write_lnk_file(
-                        backend,
-                        lnk_name,
-                        if self.encrypted {
-                            Some(self.key_gen.gen_key(0)?)
-                        } else {
-                            None
-                        },
-                    )?.into_key_entry();
-                }
+            InodeExt::Lnk { lnk_name, name_file_ke, backend, .. } => {
+                *name_file_ke = Self::write_lnk_file(
+                    backend,
+                    lnk_name,
+                    if self.encrypted {
+                        Some(self.key_gen.gen_key(0)?)
+                    } else {
+                        None
+                    },
+                )?.into_key_entry();
             }
-            InodeExt::LnkInline(lnk_name) => {
-                // shape to single block storage file
-                if lnk_name.len() > LNK_INLINE_MAX {
-                    let lnk = lnk_name.clone();
-                    let (data_file_name, mut backend) = self.new_storage()?;
-                    let name_file_ke = Self::write_lnk_file(
-                        &mut backend,
-                        &lnk,
-                        if self.encrypted {
-                            Some(self.key_gen.gen_key(0)?)
-                        } else {
-                            None
-                        },
-                    )?.into_key_entry();
-
-                    self.ext = InodeExt::Lnk {
-                        lnk_name: lnk,
-                        data_file_name,
-                        name_file_ke,
-                        backend,
-                    };
-
-                    nf_nb_change(&self.sb_meta, 1, 1)?;
-                }
+            InodeExt::LnkSlot { lnk_name, slot } => {
+                self.ltbl.lock().write(*slot, lnk_name)?;
             }
             _ => {},
         };
-        if let Some(f) = file_to_remove {
-            self.remove_fs_file(&f)?;
-        }
         Ok(())
     }
 
+    // only blocks of a reg htree can be written back by range,
+    // otherwise sync the whole data
+    pub fn sync_data_range(&mut self, offset: usize, len: usize) -> FsResult<()> {
+        let keep_htree = self.size > self.inline_max();
+        match &mut self.ext {
+            InodeExt::Reg { data, .. } if keep_htree => {
+                if len != 0 {
+                    let first = (offset / self.bsize) as u64;
+                    let end = (offset + len).div_ceil(self.bsize) as u64;
+                    data.flush_range(first, end - first)?;
+                }
+                Ok(())
+            }
+            _ => self.sync_data(),
+        }
+    }
+
     pub fn sync_meta(&mut self) -> FsResult<InodeBytes> {
         self.reg_force_shape()?;
 
@@ -811,45 +1062,45 @@ impl Inode {
             atime: self.atime,
             ctime: self.ctime,
             mtime: self.mtime,
+            btime: self.btime,
+            flags: self.flags.bits(),
             size: self.size as u64,
+            ..Default::default()
         };
-        let mut ib = [0u8; INODE_SZ];
-        match &mut self.ext {
+        let ib = match &mut self.ext {
             InodeExt::Reg { data_file_name, htree_org_len, data } => {
                 let fname_ke = iid_hash(self.iid)?;
                 let fname = hex::encode_upper(fname_ke);
                 assert_eq!(fname.as_bytes(), data_file_name.as_bytes());
 
-                let inode = unsafe {
-                    &mut *(ib.as_mut_ptr() as *mut DInodeReg)
+                let inode = DInodeReg {
+                    base,
+                    data_file: fname_ke,
+                    data_file_ke: data.get_cur_mode().into_key_entry(),
+                    len: data.shape().get_phy_nr_blk(data.logi_len),
+                    nr_hole: data.nr_hole(),
+                    _padding: [0u8; 8],
                 };
-                inode.base = base;
-                inode.data_file = fname_ke;
-                inode.data_file_ke = data.get_cur_mode().into_key_entry();
-                inode.len = mht::get_phy_nr_blk(data.logi_len);
                 nf_nb_change(&self.sb_meta, 0, inode.len as isize - *htree_org_len as isize)?;
+                inode.to_inode_bytes(self.inode_sz)
             }
             InodeExt::RegInline(data) => {
-                assert!(data.len() <= REG_INLINE_DATA_MAX);
-                let inode = unsafe {
-                    &mut *(ib.as_mut_ptr() as *mut DInodeRegInline)
-                };
-                inode.base = base;
-                inode.data[..data.len()].copy_from_slice(data);
+                inline_inode_bytes(base, data, self.inode_sz)
             }
             InodeExt::Dir { data_file_name, htree_org_len, data } => {
                 let fname_ke = iid_hash(self.iid)?;
                 let fname = hex::encode_upper(fname_ke);
                 assert_eq!(fname.as_bytes(), data_file_name.as_bytes());
 
-                let inode = unsafe {
-                    &mut *(ib.as_mut_ptr() as *mut DInodeDir)
+                let inode = DInodeDir {
+                    base,
+                    data_file: fname_ke,
+                    data_file_ke: data.get_cur_mode().into_key_entry(),
+                    len: data.shape().get_phy_nr_blk(data.logi_len),
+                    _padding: [0u8; 16],
                 };
-                inode.base = base;
-                inode.data_file = fname_ke;
-                inode.data_file_ke = data.get_cur_mode().into_key_entry();
-                inode.len = mht::get_phy_nr_blk(data.logi_len);
                 nf_nb_change(&self.sb_meta, 0, inode.len as isize - *htree_org_len as isize)?;
+                inode.to_inode_bytes(self.inode_sz)
             }
             InodeExt::Lnk { lnk_name, data_file_name, name_file_ke, .. } => {
                 let fname_ke = iid_hash(self.iid)?;
@@ -859,23 +1110,27 @@ impl Inode {
                 // check link name length
                 assert!(lnk_name.len() < LNK_NAME_MAX);
 
-                let inode = unsafe {
-                    &mut *(ib.as_mut_ptr() as *mut DInodeLnk)
-                };
-                inode.base = base;
-                inode.data_file = fname_ke;
-                inode.name_file_ke = name_file_ke.clone();
-                inode.len = 1;
+                DInodeLnk {
+                    base,
+                    data_file: fname_ke,
+                    name_file_ke: name_file_ke.clone(),
+                    len: 1,
+                    _padding: [0u8; 16],
+                }.to_inode_bytes(self.inode_sz)
+            }
+            InodeExt::LnkSlot { slot, .. } => {
+                DInodeLnkSlot {
+                    base,
+                    slot: *slot,
+                    _reserved: [0u8; 56],
+                    len: 0,
+                    _padding: [0u8; 16],
+                }.to_inode_bytes(self.inode_sz)
             }
             InodeExt::LnkInline(lnk_name) => {
-                let inode = unsafe {
-                    &mut *(ib.as_mut_ptr() as *mut DInodeLnkInline)
-                };
-                inode.base = base;
-                assert!(lnk_name.len() < LNK_INLINE_MAX);
-                inode.name[..lnk_name.len()].copy_from_slice(lnk_name.as_bytes());
+                inline_inode_bytes(base, lnk_name.as_bytes(), self.inode_sz)
             }
-        }
+        };
         Ok(ib)
     }
 
@@ -886,7 +1141,7 @@ impl Inode {
     }
 
     fn remove_fs_file(&self, fname: &str) -> FsResult<()> {
-        let nr_blk = self.device.get_storage_len(&fname)?.div_ceil(BLK_SZ as u64);
+        let nr_blk = self.device.get_storage_len(&fname)?.div_ceil(self.bsize as u64);
         self.device.remove_storage(&fname)?;
 
         nf_nb_change(&self.sb_meta, -1, -(nr_blk as isize))?;
@@ -894,14 +1149,24 @@ impl Inode {
     }
 
     // called when an inode is flushed
+    // the storage on device owned by this inode, for a symlink it holds the name
+    pub fn data_file_name(&self) -> Option<&str> {
+        match &self.ext {
+            InodeExt::Reg { data_file_name, .. } => Some(data_file_name),
+            InodeExt::Dir { data_file_name, .. } => Some(data_file_name),
+            InodeExt::Lnk { data_file_name, .. } => Some(data_file_name),
+            _ => None,
+        }
+    }
+
+    // a slot in link table is given back as well
     pub fn remove_data_file(self) -> FsResult<()> {
-        let df_name = match &self.ext {
-            InodeExt::Reg { data_file_name, .. } => data_file_name,
-            InodeExt::Dir { data_file_name, .. } => data_file_name,
-            InodeExt::Lnk { data_file_name, .. } => data_file_name,
-            _ => return Ok(()),
-        };
-        self.remove_fs_file(&df_name)?;
+        if let Some(df_name) = self.data_file_name() {
+            self.remove_fs_file(df_name)?;
+        }
+        if let InodeExt::LnkSlot { slot, .. } = self.ext {
+            self.ltbl.lock().free(slot)?;
+        }
         Ok(())
     }
 }
@@ -0,0 +1,123 @@
+use crate::*;
+use crate::htree::*;
+use crate::storage::*;
+use super::*;
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec;
+
+/// link names longer than inline and within LNK_SLOT_SZ, one in each slot of a shared htree,
+/// so that a tree of many such links doesn't take a data file for each
+pub struct LnkTable {
+    // created when the first slot is taken
+    data: Option<RWHashTree>,
+    slots: BitMap,
+    htree_org_len: u64, // in blocks
+    encrypted: bool,
+    sb_meta: Arc<RwLock<(usize, usize)>>,
+    device: Arc<dyn Device>,
+}
+
+fn slot_pos(slot: u64) -> usize {
+    slot as usize * LNK_SLOT_SZ
+}
+
+impl LnkTable {
+    pub fn new(
+        len: u64,
+        ke: KeyEntry,
+        slots: BitMap,
+        encrypted: bool,
+        sb_meta: Arc<RwLock<(usize, usize)>>,
+        device: Arc<dyn Device>,
+    ) -> FsResult<Self> {
+        let data = if len == 0 {
+            if slots.nr_used() != 0 {
+                return Err(new_error!(FsError::SuperBlockCheckFailed));
+            }
+            None
+        } else {
+            let storage = device.open_rw_storage(&iid_hash_name(LTBL_IID)?)?;
+            let shape = mht::Shape::full(device.blk_sz());
+            if shape.check_phy_nr_blk(len).is_err()
+                || storage.get_len()? != blk2byte!(len, shape.blk_sz()) {
+                return Err(new_error!(FsError::SuperBlockCheckFailed));
+            }
+            Some(RWHashTree::new(
+                Some(RW_CACHE_CAP_DEFAULT_LTBL),
+                storage,
+                shape.get_logi_nr_blk(len),
+                Some(FSMode::from_key_entry(ke, encrypted)),
+                encrypted,
+            ))
+        };
+        Ok(Self {
+            data,
+            slots,
+            htree_org_len: len,
+            encrypted,
+            sb_meta,
+            device,
+        })
+    }
+
+    pub fn alloc(&mut self) -> FsResult<u64> {
+        if self.data.is_none() {
+            let storage = self.device.create_rw_storage(&iid_hash_name(LTBL_IID)?)?;
+            nf_nb_change(&self.sb_meta, 1, 0)?;
+            self.data = Some(RWHashTree::new(
+                Some(RW_CACHE_CAP_DEFAULT_LTBL),
+                storage,
+                0,
+                None,
+                self.encrypted,
+            ));
+        }
+        self.slots.alloc()
+    }
+
+    pub fn free(&mut self, slot: u64) -> FsResult<()> {
+        self.slots.free(slot)
+    }
+
+    pub fn read(&mut self, slot: u64, len: usize) -> FsResult<String> {
+        if len > LNK_SLOT_SZ || !self.slots.is_used(slot) {
+            return Err(new_error!(FsError::Corrupted));
+        }
+        let data = self.data.as_mut().ok_or(FsError::Corrupted)?;
+        let mut name = vec![0u8; len];
+        if data.read_exact(slot_pos(slot), &mut name)? != len {
+            return Err(new_error!(FsError::Corrupted));
+        }
+        String::from_utf8(name).map_err(|_| new_error!(FsError::Corrupted))
+    }
+
+    // the rest of the slot is zeroed
+    pub fn write(&mut self, slot: u64, name: &str) -> FsResult<()> {
+        assert!(name.len() <= LNK_SLOT_SZ && self.slots.is_used(slot));
+        let mut buf = [0u8; LNK_SLOT_SZ];
+        buf[..name.len()].copy_from_slice(name.as_bytes());
+        self.data.as_mut().unwrap().write_exact(slot_pos(slot), &buf)?;
+        Ok(())
+    }
+
+    // return length and key entry of the htree to keep in superblock
+    pub fn flush(&mut self) -> FsResult<(usize, KeyEntry)> {
+        let Some(data) = self.data.as_mut() else {
+            return Ok((0, KeyEntry::default()));
+        };
+        let ke = data.flush()?.into_key_entry();
+        let len = data.shape().get_phy_nr_blk(data.logi_len);
+        nf_nb_change(&self.sb_meta, 0, len as isize - self.htree_org_len as isize)?;
+        self.htree_org_len = len;
+        Ok((len as usize, ke))
+    }
+
+    pub fn slots_capacity(&self) -> u64 {
+        self.slots.capacity()
+    }
+
+    pub fn write_slots(&mut self) -> FsResult<Vec<Box<Block>>> {
+        self.slots.write()
+    }
+}
@@ -2,6 +2,10 @@ pub mod superblock;
 pub mod inode;
 pub mod disk;
 pub mod bitmap;
+pub mod lnk_table;
+pub mod xattr;
+pub mod check;
+pub mod rekey;
 
 extern crate alloc;
 use crate::vfs::*;
@@ -16,32 +20,80 @@ use crate::storage::*;
 use crate::lru::*;
 use disk::*;
 use core::mem::size_of;
+use core::sync::atomic::{AtomicBool, Ordering};
+#[cfg(feature = "std")]
+use core::sync::atomic::AtomicUsize;
 use bitmap::*;
+use lnk_table::*;
+use xattr::*;
 use alloc::vec::Vec;
+use alloc::vec;
+use alloc::boxed::Box;
 use alloc::string::{String, ToString};
+use alloc::format;
 
 
 pub const RWFS_MAGIC: u64 = 0x0045434352574653; // ECCRWFS
 pub const NAME_MAX: u64 = DIRENT_NAME_MAX as u64;
+
+pub fn check_name_len(name: &str) -> FsResult<()> {
+    if name.len() > NAME_MAX as usize {
+        Err(FsError::NameTooLong)
+    } else {
+        Ok(())
+    }
+}
+
+// only "." and ".." left
+fn is_empty_dir(ino: &mut Inode) -> FsResult<()> {
+    if ino.tp == FileType::Dir && ino.get_meta()?.size > 2 * DIRENT_SZ as u64 {
+        return Err(FsError::DirectoryNotEmpty);
+    }
+    Ok(())
+}
 pub const SB_FILE_NAME: &str = "meta";
 
 pub const RW_CACHE_CAP_DEFAULT_ITBL: usize = 4;
+pub const RW_CACHE_CAP_DEFAULT_LTBL: usize = 4;
+pub const RW_CACHE_CAP_DEFAULT_XTBL: usize = 4;
 
 pub const DATA_FILE_NAME_LEN: usize = size_of::<Hash256>() * 2;
 
+// inodes are locked with lock_api, so that a BulkWriter can own the guard of one
+type InodeLock = spin::lock_api::RwLock<Inode>;
+type InodeWriteGuard = lock_api::ArcRwLockWriteGuard<spin::RwLock<()>, Inode>;
+
 pub struct RWFS {
     regen_root_key: bool,
     mode: FSMode,
+    // mode returned by the last sync, or the mount mode
+    synced: Mutex<FSMode>,
     sb: RwLock<SuperBlock>,
     ibitmap: Mutex<BitMap>,
+    // blocks in use of the shared data region
+    bbitmap: Mutex<BitMap>,
     inode_tbl: Mutex<RWHashTree>,
-    icac: Mutex<Lru<InodeID, RwLock<Inode>>>,
+    // with slots in use of it
+    ltbl: Arc<Mutex<LnkTable>>,
+    // locked after the inode
+    xtbl: Mutex<XattrTable>,
+    icac: Mutex<Lru<InodeID, InodeLock>>,
+    // inodes are kept in icac only while in use, and written back right after
+    no_icac: bool,
     de_cac: Option<Mutex<Lru<String, InodeID>>>,
     key_gen: Mutex<KeyGen>,
     sb_meta_for_inode: Arc<RwLock<(usize, usize)>>,
     device: Arc<dyn Device>,
     sb_storage: Arc<dyn RWStorage>,
     time_source: &'static dyn TimeSource,
+    destroyed: AtomicBool,
+    // taken by renames across dirs
+    rename_lock: Mutex<()>,
+    sync_dir_on_read: AtomicBool,
+    #[cfg(feature = "std")]
+    flush_threads: AtomicUsize,
+    inode_sz: usize,
+    read_only: bool,
 }
 
 #[cfg(feature = "channel_lru")]
@@ -54,9 +106,34 @@ impl Drop for RWFS {
     }
 }
 
+/// a synced state of a RWFS
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Checkpoint {
+    /// mode to mount with
+    pub mode: FSMode,
+    /// digest over superblock and key entries of all data files
+    pub digest: Hash256,
+    /// Total number of blocks of the fs
+    pub block_count: u64,
+}
+
 pub const DEFAULT_ICAC_CAP: usize = 64;
 
+// a read-only fs keeps times as they are
+macro_rules! update_times {
+    ($self:ident, $lock: expr, $($x:expr),* ) => {
+        if !$self.read_only {
+            let now = $self.time_source.now();
+            $(
+                $lock.set_meta($x(now))?;
+            )*
+        }
+    };
+}
+
 impl RWFS {
+    /// with `icache_cap_hint` of `Some(0)` no inode is cached,
+    /// each is fetched on use and written back once no one uses it
     pub fn new(
         regen_root_key: bool,
         mode: FSMode,
@@ -65,6 +142,23 @@ impl RWFS {
         device: Arc<dyn Device>,
         time_source: &'static dyn TimeSource,
     ) -> FsResult<Self> {
+        let rwfs = Self::open_image(
+            regen_root_key, mode, icache_cap_hint, cache_de, device, time_source,
+        )?;
+        rwfs.release_pending()?;
+        Ok(rwfs)
+    }
+
+    // nothing is changed on open, see new
+    fn open_image(
+        regen_root_key: bool,
+        mode: FSMode,
+        icache_cap_hint: Option<usize>,
+        cache_de: usize,
+        device: Arc<dyn Device>,
+        time_source: &'static dyn TimeSource,
+    ) -> FsResult<Self> {
+        rekey::recover_rekey(device.as_ref(), &mode)?;
 
         let sb_storage = device.open_rw_storage(SB_FILE_NAME)?;
 
@@ -72,10 +166,13 @@ impl RWFS {
         let mut sb_blk = sb_storage.read_blk(SUPERBLOCK_POS)?;
         // check crypto
         crypto_in(&mut sb_blk, CryptoHint::from_fsmode(mode.clone(), SUPERBLOCK_POS))?;
-        let sb = SuperBlock::new(sb_blk)?;
+        let sb = SuperBlock::new(&sb_blk)?;
+        let bsize = sb.bsize;
+        let shape = mht::Shape::full(bsize);
 
         // check sb file len
-        if sb_storage.get_len()? != blk2byte!(sb.ibitmap_len + 1) {
+        if sb_storage.get_len()?
+            != blk2byte!(1 + sb.ibitmap_len + sb.bbitmap_len + sb.lbitmap_len, bsize) {
             return Err(new_error!(FsError::SuperBlockCheckFailed));
         }
         // check nr_data_file
@@ -88,19 +185,17 @@ impl RWFS {
             // no possibilty that ibitmap is empty
             return Err(new_error!(FsError::SuperBlockCheckFailed));
         }
-        let mut ibitmap_blks = Vec::new();
-        ibitmap_blks.resize(sb.ibitmap_len as usize, [0u8; BLK_SZ]);
-        for (i, (blk, ke)) in ibitmap_blks.iter_mut().zip(sb.ibitmap_ke.iter()).enumerate() {
-            let pos = i as u64 + sb.ibitmap_start;
-            sb_storage.read_blk_to(pos, blk)?;
-            crypto_in(
-                blk,
-                CryptoHint::from_key_entry(
-                    ke.clone(), mode.is_encrypted(), pos
-                )
-            )?;
-        }
-        let ibitmap = BitMap::new(ibitmap_blks)?;
+        let ibitmap = Self::read_bitmap(
+            &sb_storage, sb.ibitmap_start, &sb.ibitmap_ke, mode.is_encrypted(),
+        )?;
+        let bbitmap = Self::read_bitmap(
+            &sb_storage, sb.ibitmap_start + sb.ibitmap_len as u64,
+            &sb.bbitmap_ke, mode.is_encrypted(),
+        )?;
+        let lbitmap = Self::read_bitmap(
+            &sb_storage, sb.ibitmap_start + (sb.ibitmap_len + sb.bbitmap_len) as u64,
+            &sb.lbitmap_ke, mode.is_encrypted(),
+        )?;
 
         // read itbl
         if sb.itbl_len == 0 {
@@ -110,18 +205,28 @@ impl RWFS {
         let itbl_file_name = hex::encode_upper(&sb.itbl_name);
         assert_eq!(itbl_file_name.len(), DATA_FILE_NAME_LEN);
         let itbl_storage = device.open_rw_storage(&itbl_file_name)?;
-        if itbl_storage.get_len()? != blk2byte!(sb.itbl_len) {
+        if shape.check_phy_nr_blk(sb.itbl_len as u64).is_err()
+            || itbl_storage.get_len()? != blk2byte!(sb.itbl_len, bsize) {
             return Err(new_error!(FsError::SuperBlockCheckFailed));
         }
         let inode_tbl = RWHashTree::new(
             Some(RW_CACHE_CAP_DEFAULT_ITBL),
             itbl_storage,
-            mht::get_logi_nr_blk(sb.itbl_len as u64),
+            shape.get_logi_nr_blk(sb.itbl_len as u64),
             Some(FSMode::from_key_entry(sb.itbl_ke, mode.is_encrypted())),
             mode.is_encrypted(),
         );
 
         let sb_meta_for_inode = Arc::new(RwLock::new((sb.nr_data_file, sb.blocks)));
+        let ltbl = LnkTable::new(
+            sb.ltbl_len as u64, sb.ltbl_ke, lbitmap, mode.is_encrypted(),
+            sb_meta_for_inode.clone(), device.clone(),
+        )?;
+        let xtbl = XattrTable::new(
+            sb.xtbl_len as u64, sb.xtbl_ke, mode.is_encrypted(),
+            sb_meta_for_inode.clone(), device.clone(),
+        )?;
+        let inode_sz = sb.inode_sz;
 
         #[cfg(not(feature = "std"))]
         let seed = half_md4(unsafe {
@@ -131,15 +236,21 @@ impl RWFS {
             )
         })?;
 
-        Ok(RWFS {
+        let rwfs = RWFS {
             regen_root_key,
+            synced: Mutex::new(mode.clone()),
             mode,
             sb: RwLock::new(sb),
             ibitmap: Mutex::new(ibitmap),
+            bbitmap: Mutex::new(bbitmap),
             inode_tbl: Mutex::new(inode_tbl),
-            icac: Mutex::new(Lru::new(
-                icache_cap_hint.unwrap_or(DEFAULT_ICAC_CAP)
-            )),
+            ltbl: Arc::new(Mutex::new(ltbl)),
+            xtbl: Mutex::new(xtbl),
+            icac: Mutex::new(match icache_cap_hint {
+                Some(0) => Lru::unbounded(),
+                cap => Lru::new(cap.unwrap_or(DEFAULT_ICAC_CAP)),
+            }),
+            no_icac: icache_cap_hint == Some(0),
             de_cac: if cache_de != 0 {
                 Some(Mutex::new(Lru::new(cache_de)))
             } else {
@@ -153,110 +264,576 @@ impl RWFS {
             device,
             sb_storage,
             time_source,
+            destroyed: AtomicBool::new(false),
+            rename_lock: Mutex::new(()),
+            sync_dir_on_read: AtomicBool::new(false),
+            #[cfg(feature = "std")]
+            flush_threads: AtomicUsize::new(1),
+            inode_sz,
+            read_only: false,
+        };
+
+        let ke_digest = rwfs.sb.read().ke_digest;
+        if let Some(digest) = ke_digest {
+            if rwfs.calc_ke_digest()? != digest {
+                return Err(FsError::IntegrityCheckError);
+            }
+        }
+
+        Ok(rwfs)
+    }
+
+    fn read_bitmap(
+        sb_storage: &Arc<dyn RWStorage>,
+        start: u64,
+        ke_list: &[KeyEntry],
+        encrypted: bool,
+    ) -> FsResult<BitMap> {
+        let bsize = sb_storage.blk_sz();
+        let mut blks: Vec<_> = ke_list.iter().map(|_| new_blk(bsize)).collect();
+        for (i, (blk, ke)) in blks.iter_mut().zip(ke_list.iter()).enumerate() {
+            let pos = i as u64 + start;
+            sb_storage.read_blk_to(pos, blk)?;
+            crypto_in(
+                blk,
+                CryptoHint::from_key_entry(
+                    *ke, encrypted, pos
+                )
+            )?;
+        }
+        BitMap::new(blks, bsize)
+    }
+
+    /// allocate a block in the shared data region, recorded in the block bitmap
+    pub fn alloc_block(&self) -> FsResult<u64> {
+        self.check_writable()?;
+        let pos = self.bbitmap.lock().alloc()?;
+        if let Err(e) = self.check_bitmap_blks() {
+            self.bbitmap.lock().free(pos)?;
+            return Err(e);
+        }
+        Ok(pos)
+    }
+
+    pub fn free_block(&self, pos: u64) -> FsResult<()> {
+        self.check_writable()?;
+        self.bbitmap.lock().free(pos)
+    }
+
+    pub fn nr_used_block(&self) -> usize {
+        self.bbitmap.lock().nr_used()
+    }
+
+    pub fn image_uuid(&self) -> ImageUuid {
+        self.sb.read().uuid
+    }
+
+    /// number of times the image is sealed with changes by destroy since it's built
+    pub fn image_generation(&self) -> u64 {
+        self.sb.read().generation
+    }
+
+    /// shrink the inode table to end at the highest inode in use, cheaper than compaction
+    /// but only reclaims slots after it, returns the number of blocks reclaimed
+    pub fn trim_inode_table(&self) -> FsResult<usize> {
+        self.check_writable()?;
+        let org_itbl_len = self.sb.read().itbl_len;
+        {
+            // no inode is allocated while the table is cut
+            let ibitmap = self.ibitmap.lock();
+            let last = ibitmap.last_used().unwrap_or(ROOT_INODE_ID);
+            let logi_len = iid_to_htree_logi_pos(last + 1, self.inode_sz).div_ceil(self.device.blk_sz()) as u64;
+            let mut itbl = self.inode_tbl.lock();
+            if logi_len < itbl.logi_len {
+                itbl.resize(logi_len)?;
+            }
+        }
+        // update itbl_len and sb_meta
+        self.sync_itbl()?;
+        Ok(org_itbl_len.saturating_sub(self.sb.read().itbl_len))
+    }
+
+    /// names of the device storages an inode references, empty if all its data is inline,
+    /// storages on device not owned by any inode are orphans
+    pub fn inode_storages(&self, iid: InodeID) -> FsResult<Vec<String>> {
+        if !self.ibitmap.lock().is_used(iid) {
+            return Err(FsError::NotFound);
+        }
+        let alock = self.get_inode(iid, false)?;
+        let lock = alock.read();
+        Ok(lock.data_file_name().into_iter().map(String::from).collect())
+    }
+
+    /// write a regular file in bulk, the inode is locked until the writer is dropped,
+    /// times and metadata are updated only once by [`BulkWriter::finish`]
+    pub fn bulk_write(&self, iid: InodeID) -> FsResult<BulkWriter<'_>> {
+        self.check_writable()?;
+        let alock = self.get_inode(iid, true)?;
+        match alock.read().tp {
+            FileType::Reg => {}
+            FileType::Dir => return Err(FsError::IsADirectory),
+            FileType::Lnk => return Err(FsError::InvalidParameter),
+        }
+        // the guard owns an Arc of the inode, which keeps it in icac
+        Ok(BulkWriter {
+            fs: self,
+            iid,
+            guard: Some(alock.into_arc().write_arc()),
+            written: false,
+        })
+    }
+
+    /// flush data of a dir before lookup and listdir on it, so that its data file
+    /// is a consistent tree for those reading it from storage outside this fs
+    pub fn set_sync_dir_on_read(&self, on: bool) {
+        self.sync_dir_on_read.store(on && !self.read_only, Ordering::Relaxed);
+    }
+
+    /// flush data htrees of dirty inodes on up to `n` threads in fsync,
+    /// inode table and superblock are still written by the calling thread after that,
+    /// 1 (the default) flushes all on the calling thread
+    #[cfg(feature = "std")]
+    pub fn set_flush_threads(&self, n: usize) {
+        self.flush_threads.store(n.max(1), Ordering::Relaxed);
+    }
+
+    // data of dirty inodes with htrees, each on its own data file and cache,
+    // links are left to the serial write back, as slots of the link table are taken in order
+    #[cfg(feature = "std")]
+    fn sync_data_parallel(dirty: &mut [(InodeID, InodeLock)], threads: usize) -> FsResult<()> {
+        let chunk = dirty.len().div_ceil(threads);
+        std::thread::scope(|s| {
+            let handles: Vec<_> = dirty.chunks_mut(chunk).map(|part| s.spawn(move || {
+                part.iter_mut()
+                    .map(|(_, i)| i.get_mut())
+                    .filter(|i| i.tp != FileType::Lnk)
+                    .try_for_each(|i| i.sync_data())
+            })).collect();
+            handles.into_iter().try_for_each(|h| h.join().unwrap())
+        })
+    }
+
+    /// keep a digest over key entries of all data files in superblock,
+    /// it's checked on every later mount and kept up to date by every mount
+    pub fn enable_ke_digest(&self) -> FsResult<()> {
+        self.check_writable()?;
+        let digest = self.calc_ke_digest()?;
+        self.sb.write().ke_digest = Some(digest);
+        Ok(())
+    }
+
+    /// sync everything and report the state on disk,
+    /// it can be opened later by [`RWFS::open_checkpoint`] if nothing has changed
+    pub fn checkpoint(&self) -> FsResult<Checkpoint> {
+        let mode = self.fsync()?;
+        Ok(Checkpoint {
+            mode,
+            digest: self.state_digest()?,
+            block_count: self.sb.read().blocks as u64,
+        })
+    }
+
+    /// mount the fs for reading only, mutating ops fail with PermissionDenied,
+    /// reads leave atime and everything else on device untouched,
+    /// changes made by another writer after mount are not visible and may fail checks
+    pub fn open_readonly(
+        mode: FSMode,
+        icache_cap_hint: Option<usize>,
+        cache_de: usize,
+        device: Arc<dyn Device>,
+        time_source: &'static dyn TimeSource,
+    ) -> FsResult<Self> {
+        let mut rwfs = Self::open_image(false, mode, icache_cap_hint, cache_de, device, time_source)?;
+        rwfs.read_only = true;
+        Ok(rwfs)
+    }
+
+    // sync and bump the generation if anything changed since the last sync
+    fn seal(&self) -> FsResult<FSMode> {
+        let last = self.synced.lock().clone();
+        let mode = self.fsync()?;
+        if mode == last {
+            return Ok(mode);
+        }
+        self.sb.write().generation += 1;
+        self.fsync().inspect_err(|_| {
+            self.sb.write().generation -= 1;
+            *self.synced.lock() = last;
         })
     }
 
+    fn check_writable(&self) -> FsResult<()> {
+        if self.read_only {
+            return Err(FsError::PermissionDenied);
+        }
+        Ok(())
+    }
+
+    /// mount the fs at a checkpoint, IntegrityCheckError if it has changed since
+    pub fn open_checkpoint(
+        cp: &Checkpoint,
+        icache_cap_hint: Option<usize>,
+        cache_de: usize,
+        device: Arc<dyn Device>,
+        time_source: &'static dyn TimeSource,
+    ) -> FsResult<Self> {
+        let rwfs = Self::open_image(
+            false, cp.mode.clone(), icache_cap_hint, cache_de, device, time_source,
+        )?;
+        if rwfs.state_digest()? != cp.digest || rwfs.sb.read().blocks as u64 != cp.block_count {
+            return Err(FsError::IntegrityCheckError);
+        }
+        rwfs.release_pending()?;
+        Ok(rwfs)
+    }
+
+    /// check the state on disk against a checkpoint, false if it has changed since,
+    /// besides the digest, root block of every data file is checked against its key entry,
+    /// so that a data file rolled back to an older but valid version is caught as well,
+    /// changes not yet synced make it differ
+    pub fn verify_against(&self, expected: &Checkpoint) -> FsResult<bool> {
+        if self.state_digest()? != expected.digest
+            || self.sb.read().blocks as u64 != expected.block_count {
+            return Ok(false);
+        }
+        let encrypted = self.mode.is_encrypted();
+        let used: Vec<_> = self.ibitmap.lock().iter_used().collect();
+        for iid in used {
+            let Some(ke) = Self::data_file_ke(&self.read_itbl(iid)?) else {
+                continue;
+            };
+            let storage = self.device.open_rw_storage(&iid_hash_name(iid)?)?;
+            let hint = CryptoHint::from_key_entry(ke, encrypted, HTREE_ROOT_BLK_PHY_POS);
+            if !crypto_check(&storage.read_blk(HTREE_ROOT_BLK_PHY_POS)?, hint)? {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    // hash of the superblock as on disk, along with the digest over key entries
+    // of all data files
+    fn state_digest(&self) -> FsResult<Hash256> {
+        let mut buf = Vec::with_capacity(self.device.blk_sz() + size_of::<Hash256>());
+        buf.extend_from_slice(&self.sb_storage.read_blk(SUPERBLOCK_POS)?);
+        buf.extend_from_slice(&self.calc_ke_digest()?);
+        sha3_256_any(&buf)
+    }
+
+    // key entry of data file of an inode, None if it has none
+    fn data_file_ke(ib: &InodeBytes) -> Option<KeyEntry> {
+        let di_base = unsafe {
+            &*(ib.as_ptr() as *const DInodeBase)
+        };
+        let inline_max = inline_max(ib.len()) as u64;
+        match get_ftype_from_mode(di_base.mode) {
+            FileType::Reg if di_base.size > inline_max => {
+                Some(unsafe { &*(ib.as_ptr() as *const DInodeReg) }.data_file_ke)
+            }
+            FileType::Dir => {
+                Some(unsafe { &*(ib.as_ptr() as *const DInodeDir) }.data_file_ke)
+            }
+            FileType::Lnk if di_base.size > inline_max && !is_lnk_in_slot(ib) => {
+                Some(unsafe { &*(ib.as_ptr() as *const DInodeLnk) }.name_file_ke)
+            }
+            _ => None,
+        }
+    }
+
+    // the digest is xor of hashes of (iid, ke) for each data file,
+    // so that it can be updated inode by inode
+    fn ke_digest_leaf(iid: InodeID, ib: &InodeBytes) -> FsResult<Option<Hash256>> {
+        let ke = match Self::data_file_ke(ib) {
+            Some(ke) => ke,
+            None => return Ok(None),
+        };
+        let mut buf = [0u8; size_of::<InodeID>() + size_of::<KeyEntry>()];
+        buf[..size_of::<InodeID>()].copy_from_slice(&iid.to_le_bytes());
+        buf[size_of::<InodeID>()..].copy_from_slice(&ke);
+        Ok(Some(sha3_256_any(&buf)?))
+    }
+
+    fn xor_ke_digest(digest: &mut Hash256, leaf: Option<Hash256>) {
+        if let Some(leaf) = leaf {
+            digest.iter_mut().zip(leaf.iter()).for_each(|(d, l)| *d ^= l);
+        }
+    }
+
+    /// export root key entries of every htree in this fs, only in encrypted mode.
+    ///
+    /// whoever holds the result can decrypt the whole image without the root key,
+    /// so treat it as the root key itself: never log it or keep it next to the image.
+    /// the fs is synced first, so the keys match what is on disk,
+    /// and the superblock entry is the mode to mount with next time.
+    pub fn export_key_material(&self) -> FsResult<KeyMaterial> {
+        if !self.mode.is_encrypted() {
+            return Err(FsError::NotSupported);
+        }
+
+        let mut km = KeyMaterial::new();
+        km.insert(KeyOwner::SuperBlock, self.fsync()?.into_key_entry());
+        {
+            let sb = self.sb.read();
+            km.insert(KeyOwner::InodeTable, sb.itbl_ke);
+            for (i, ke) in sb.ibitmap_ke.iter().enumerate() {
+                km.insert(KeyOwner::InodeBitmap(i as u64), *ke);
+            }
+            for (i, ke) in sb.bbitmap_ke.iter().enumerate() {
+                km.insert(KeyOwner::BlockBitmap(i as u64), *ke);
+            }
+            if sb.ltbl_len != 0 {
+                km.insert(KeyOwner::LinkTable, sb.ltbl_ke);
+            }
+            for (i, ke) in sb.lbitmap_ke.iter().enumerate() {
+                km.insert(KeyOwner::LinkBitmap(i as u64), *ke);
+            }
+            if sb.xtbl_len != 0 {
+                km.insert(KeyOwner::XattrTable, sb.xtbl_ke);
+            }
+        }
+        let used: Vec<_> = self.ibitmap.lock().iter_used().collect();
+        for iid in used {
+            if let Some(ke) = Self::data_file_ke(&self.read_itbl(iid)?) {
+                km.insert(KeyOwner::Inode(iid), ke);
+            }
+            let xattr = self.xtbl.lock().entry(iid)?;
+            if xattr.len != 0 {
+                km.insert(KeyOwner::Xattr(iid), xattr.ke);
+            }
+        }
+        Ok(km)
+    }
+
+    fn calc_ke_digest(&self) -> FsResult<Hash256> {
+        let mut digest = Hash256::default();
+        let used: Vec<_> = self.ibitmap.lock().iter_used().collect();
+        for iid in used {
+            let ib = self.read_itbl(iid)?;
+            Self::xor_ke_digest(&mut digest, Self::ke_digest_leaf(iid, &ib)?);
+        }
+        Ok(digest)
+    }
+
     fn fetch_inode(&self, iid: InodeID) -> FsResult<Inode> {
         let ib = self.read_itbl(iid)?;
-        Inode::new_from_raw(
+        let inode = Inode::new_from_raw(
             &ib, iid, self.mode.is_encrypted(),
-            self.sb_meta_for_inode.clone(), self.device.clone(),
-        )
+            self.sb_meta_for_inode.clone(), self.device.clone(), self.ltbl.clone(),
+        )?;
+        // a removed inode is zeroed, a replaced one may have no link till released
+        if inode.nlinks == 0 && !self.sb.read().replaced.contains(&iid) {
+            return Err(FsError::NotFound);
+        }
+        Ok(inode)
     }
 
-    fn write_back_inode(&self, iid: InodeID, inode: Inode) -> FsResult<()> {
-        let ib = inode.destroy()?;
+    fn write_back_inode(&self, iid: InodeID, inode: &mut Inode) -> FsResult<()> {
+        inode.sync_data()?;
+        let ib = inode.sync_meta()?;
         self.write_itbl(iid, &ib)
     }
 
+    // write back an inode popped from icac, if it fails,
+    // put it back as dirty so that it can be retried on next eviction or fsync
+    fn evict_inode(
+        &self, icac: &mut Lru<InodeID, InodeLock>, iid: InodeID, inode: Inode,
+    ) -> FsResult<()> {
+        let mut inode = inode;
+        if let Err(e) = self.write_back_inode(iid, &mut inode) {
+            icac.put_back(iid, InodeLock::new(inode))?;
+            return Err(e);
+        }
+        Ok(())
+    }
+
     fn write_itbl(&self, iid: InodeID, ib: &InodeBytes) -> FsResult<()> {
+        let mut sb = self.sb.write();
+        if let Some(ref mut digest) = sb.ke_digest {
+            // replace the leaf of old inode, which is not in itbl if it's new
+            let pos = iid_to_htree_logi_pos(iid, self.inode_sz);
+            let itbl_end = blk2byte!(self.inode_tbl.lock().logi_len, self.device.blk_sz());
+            if pos + self.inode_sz <= itbl_end as usize {
+                let old = self.read_itbl(iid)?;
+                Self::xor_ke_digest(digest, Self::ke_digest_leaf(iid, &old)?);
+            }
+            Self::xor_ke_digest(digest, Self::ke_digest_leaf(iid, ib)?);
+        }
+        drop(sb);
+
         self.inode_tbl.lock().write_exact(
-            iid_to_htree_logi_pos(iid), ib
+            iid_to_htree_logi_pos(iid, self.inode_sz), ib
         )?;
         Ok(())
     }
 
     fn read_itbl(&self, iid: InodeID) -> FsResult<InodeBytes> {
-        let mut ib = [0u8; INODE_SZ];
+        let mut ib = vec![0u8; self.inode_sz];
         let read = self.inode_tbl.lock().read_exact(
-            iid_to_htree_logi_pos(iid), &mut ib
+            iid_to_htree_logi_pos(iid, self.inode_sz), &mut ib
         )?;
-        assert_eq!(read, INODE_SZ);
+        assert_eq!(read, self.inode_sz);
         Ok(ib)
     }
 
-    fn get_inode(&self, iid: InodeID, dirty: bool) -> FsResult<Arc<RwLock<Inode>>> {
+    fn get_inode(&self, iid: InodeID, dirty: bool) -> FsResult<InodeRef<'_>> {
         let mut icac = self.icac.lock();
         let ainode = if let Some(ainode) = icac.get(&iid)? {
             ainode
         } else {
             // cache miss
-            let ainode = Arc::new(RwLock::new(self.fetch_inode(iid)?));
+            let ainode = Arc::new(InodeLock::new(self.fetch_inode(iid)?));
             if let Some((iid, rw_inode)) = icac.insert_and_get(iid, &ainode)? {
                 // write back inode
-                let inode = rw_inode.into_inner();
-                self.write_back_inode(iid, inode)?;
+                self.evict_inode(&mut icac, iid, rw_inode.into_inner())?;
             }
             ainode
         };
-        if dirty {
+        if dirty && !self.read_only {
             icac.mark_dirty(&iid)?;
         }
-        Ok(ainode)
+        Ok(InodeRef { fs: self, iid, ainode: Some(ainode) })
     }
 
-    fn get_inode_try(&self, iid: InodeID, dirty: bool) -> FsResult<Option<Arc<RwLock<Inode>>>> {
+    fn get_inode_try(&self, iid: InodeID, dirty: bool) -> FsResult<Option<InodeRef<'_>>> {
         let mut icac = self.icac.lock();
         if let Some(ainode) = icac.get(&iid)? {
-            if dirty {
+            if dirty && !self.read_only {
                 icac.mark_dirty(&iid)?;
             }
-            Ok(Some(ainode))
+            Ok(Some(InodeRef { fs: self, iid, ainode: Some(ainode) }))
         } else {
             Ok(None)
         }
     }
 
+    // without icac, pop an inode no one uses and write it back if dirty
+    fn release_inode(&self, iid: InodeID) -> FsResult<()> {
+        let mut icac = self.icac.lock();
+        if let Some(inode) = icac.try_pop_key(&iid, false)? {
+            self.evict_inode(&mut icac, iid, inode.into_inner())?;
+        }
+        Ok(())
+    }
+
     fn insert_inode(&self, iid: InodeID, inode: Inode) -> FsResult<()> {
         let mut icac = self.icac.lock();
-        let ainode = Arc::new(RwLock::new(inode));
-        if let Some((iid, rw_inode)) = icac.insert_and_get(iid, &ainode)? {
-            // write back inode
-            let inode = rw_inode.into_inner();
-            self.write_back_inode(iid, inode)?;
+        if self.no_icac {
+            return self.evict_inode(&mut icac, iid, inode);
         }
+        let ainode = Arc::new(InodeLock::new(inode));
+        let evicted = icac.insert_and_get(iid, &ainode)?;
+        // mark new inode dirty before write back, which may fail
         icac.mark_dirty(&iid)?;
+        if let Some((iid, rw_inode)) = evicted {
+            // write back inode
+            self.evict_inode(&mut icac, iid, rw_inode.into_inner())?;
+        }
         Ok(())
     }
 
-    fn remove_inode(&self, iid: InodeID) -> FsResult<()> {
-        // load inode, ensure its in cache
-        let _ = self.get_inode(iid, false)?;
+    // de cache is keyed by "parent/name", entries are never dirty.
+    // callers hold the write lock of parent inode, so that a lookup never
+    // inserts an entry that a concurrent mutation has just invalidated
+    fn de_cac_key(parent: InodeID, name: &str) -> String {
+        format!("{}/{}", parent, name)
+    }
 
-        let mut icac = self.icac.lock();
-        let lock_inode = icac.try_pop_key(&iid, true)?.unwrap();
-        let ino = lock_inode.into_inner();
+    fn de_cac_get(&self, parent: InodeID, name: &str) -> FsResult<Option<InodeID>> {
+        if let Some(ref de_cac) = self.de_cac {
+            Ok(de_cac.lock().get(&Self::de_cac_key(parent, name))?.map(|iid| *iid))
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn de_cac_insert(&self, parent: InodeID, name: &str, iid: InodeID) -> FsResult<()> {
+        if let Some(ref de_cac) = self.de_cac {
+            let key = Self::de_cac_key(parent, name);
+            let mut lock = de_cac.lock();
+            if lock.get(&key)?.is_none() {
+                lock.insert_and_get(key, &Arc::new(iid))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn de_cac_invalidate(&self, parent: InodeID, name: &str) -> FsResult<()> {
+        if let Some(ref de_cac) = self.de_cac {
+            de_cac.lock().try_pop_key(&Self::de_cac_key(parent, name), true)?;
+        }
+        Ok(())
+    }
+
+    // drop all entries under a removed dir, its iid may be reused later
+    fn de_cac_invalidate_dir(&self, parent: InodeID) -> FsResult<()> {
+        if let Some(ref de_cac) = self.de_cac {
+            let prefix = format!("{}/", parent);
+            let mut lock = de_cac.lock();
+            for k in lock.flush_keys()? {
+                if k.starts_with(&prefix) {
+                    lock.try_pop_key(&k, true)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // the inode is taken out of icac once no one else holds it, then checked by `check`
+    // and removed with icac held, so no one fetches it in between. if others still hold it
+    // after a while, Busy is returned with nothing changed
+    fn remove_inode(
+        &self, iid: InodeID, ainode: Arc<InodeLock>,
+        check: impl FnOnce(&mut Inode) -> FsResult<()>,
+    ) -> FsResult<()> {
+        // others may hold it for a moment, e.g. a create into a dir being removed,
+        // or for long, e.g. a BulkWriter
+        let mut ainode = Some(ainode);
+        let mut taken = None;
+        for _ in 0..MAX_LOOP_CNT {
+            let mut icac = self.icac.lock();
+            // only icac and us
+            if Arc::strong_count(ainode.as_ref().unwrap()) == 2 {
+                drop(ainode.take());
+                let lock_inode = icac.try_pop_key(&iid, true)?.unwrap();
+                taken = Some((icac, lock_inode));
+                break;
+            }
+            drop(icac);
+            #[cfg(feature = "std")]
+            std::thread::yield_now();
+            #[cfg(not(feature = "std"))]
+            core::hint::spin_loop();
+        }
+        let (mut icac, lock_inode) = taken.ok_or(FsError::Busy)?;
+        let mut ino = lock_inode.into_inner();
+        if let Err(e) = check(&mut ino) {
+            self.evict_inode(&mut icac, iid, ino)?;
+            return Err(e);
+        }
 
         if ino.tp == FileType::Reg {
             self.sb.write().files -= 1;
+        } else if ino.tp == FileType::Dir {
+            self.de_cac_invalidate_dir(iid)?;
         }
 
-        // remove data file
+        // remove data file and xattrs
         ino.remove_data_file()?;
+        self.xtbl.lock().set(iid, &[])?;
 
         // zero that disk range and reset bitmap
-        self.write_itbl(iid, &ZERO_INODE)?;
+        self.write_itbl(iid, &vec![0u8; self.inode_sz])?;
+        self.ibitmap.lock().free(iid)?;
 
         Ok(())
     }
 
-    fn wb_sb_file(&self) -> FsResult<FSMode> {
-        // write bitmap
-        let mut ibitmap_blks = self.ibitmap.lock().write()?;
-        let mut ibitmap_ke = Vec::with_capacity(ibitmap_blks.len());
-        self.sb_storage.set_len(1 + ibitmap_blks.len() as u64)?;
-        for (i, blk) in ibitmap_blks.iter_mut().enumerate() {
-            let pos = i as u64 + self.sb.read().ibitmap_start;
+    fn write_bitmap(
+        &self, to: &Arc<dyn RWStorage>, blks: &mut [Box<Block>], start: u64,
+    ) -> FsResult<Vec<KeyEntry>> {
+        let mut ke_list = Vec::with_capacity(blks.len());
+        for (i, blk) in blks.iter_mut().enumerate() {
+            let pos = i as u64 + start;
             let ke = crypto_out(blk,
                 if self.mode.is_encrypted() {
                     Some(self.key_gen.lock().gen_key(pos)?)
@@ -265,19 +842,43 @@ impl RWFS {
                 },
                 pos
             )?.into_key_entry();
-            ibitmap_ke.push(ke);
-            self.sb_storage.write_blk(pos, blk)?;
+            ke_list.push(ke);
+            to.write_blk(pos, blk)?;
+        }
+        Ok(ke_list)
+    }
+
+    fn wb_sb_file(&self) -> FsResult<FSMode> {
+        // write bitmaps, block bitmap right after inode bitmap, then link slot bitmap
+        let mut ibitmap_blks = self.ibitmap.lock().write()?;
+        let mut bbitmap_blks = self.bbitmap.lock().write()?;
+        let mut lbitmap_blks = self.ltbl.lock().write_slots()?;
+        let ibitmap_start = self.sb.read().ibitmap_start;
+        let bbitmap_start = ibitmap_start + ibitmap_blks.len() as u64;
+        let lbitmap_start = bbitmap_start + bbitmap_blks.len() as u64;
+        let new_len = ibitmap_blks.len() + bbitmap_blks.len() + lbitmap_blks.len();
+        // nothing is written if key entries of bitmaps don't fit in the superblock
+        if new_len > max_bitmap_blks(self.device.blk_sz()) {
+            return Err(FsError::FileTooLarge);
         }
+        self.sb_storage.set_len(1 + new_len as u64)?;
+        let ibitmap_ke = self.write_bitmap(&self.sb_storage, &mut ibitmap_blks, ibitmap_start)?;
+        let bbitmap_ke = self.write_bitmap(&self.sb_storage, &mut bbitmap_blks, bbitmap_start)?;
+        let lbitmap_ke = self.write_bitmap(&self.sb_storage, &mut lbitmap_blks, lbitmap_start)?;
         {
             let mut lock = self.sb.write();
-            let new_ib_len = ibitmap_blks.len();
             nf_nb_change(
                 &self.sb_meta_for_inode,
                 0,
-                new_ib_len as isize - lock.ibitmap_len as isize
+                new_len as isize
+                    - (lock.ibitmap_len + lock.bbitmap_len + lock.lbitmap_len) as isize
             )?;
-            lock.ibitmap_len = new_ib_len;
+            lock.ibitmap_len = ibitmap_blks.len();
             lock.ibitmap_ke = ibitmap_ke;
+            lock.bbitmap_len = bbitmap_blks.len();
+            lock.bbitmap_ke = bbitmap_ke;
+            lock.lbitmap_len = lbitmap_blks.len();
+            lock.lbitmap_ke = lbitmap_ke;
         }
 
         // write sb_meta_for_inode back to superblock
@@ -307,62 +908,348 @@ impl RWFS {
     }
 
     fn sync_itbl(&self) -> FsResult<()> {
-        for (iid, i) in self.icac.lock().flush_wb()? {
-            let inode = i.into_inner();
-            self.write_back_inode(iid, inode)?;
+        let mut icac = self.icac.lock();
+        #[allow(unused_mut)]
+        let mut dirty = icac.flush_wb()?;
+        #[cfg(feature = "std")]
+        {
+            let threads = self.flush_threads.load(Ordering::Relaxed);
+            if threads > 1 && dirty.len() > 1 {
+                // write back below is left with nothing to flush in the data
+                if let Err(e) = Self::sync_data_parallel(&mut dirty, threads) {
+                    for (iid, i) in dirty {
+                        icac.put_back(iid, i)?;
+                    }
+                    return Err(e);
+                }
+            }
         }
+        let mut dirty = dirty.into_iter();
+        while let Some((iid, i)) = dirty.next() {
+            if let Err(e) = self.evict_inode(&mut icac, iid, i.into_inner()) {
+                // keep the rest for next fsync
+                for (iid, i) in dirty {
+                    icac.put_back(iid, i)?;
+                }
+                return Err(e);
+            }
+        }
+        drop(icac);
 
         if let Some(ref de_cac) = self.de_cac {
-            de_cac.lock().flush_wb()?;
-            // no write back, because de cache is not a write buffer
+            // de cache is not a write buffer, nothing to write back,
+            // just drop all entries
+            de_cac.lock().flush_no_wb()?;
         }
 
         // flush itbl and store new ke into superblock
         let itbl_mode = self.inode_tbl.lock().flush()?;
         let mut lock = self.sb.write();
         lock.itbl_ke = itbl_mode.into_key_entry();
-        let new_itbl_len = mht::get_phy_nr_blk(self.inode_tbl.lock().logi_len) as usize;
+        let itbl = self.inode_tbl.lock();
+        let new_itbl_len = itbl.shape().get_phy_nr_blk(itbl.logi_len) as usize;
+        drop(itbl);
         nf_nb_change(
             &self.sb_meta_for_inode,
             0,
             new_itbl_len as isize - lock.itbl_len as isize
         )?;
         lock.itbl_len = new_itbl_len;
+        drop(lock);
+
+        // slots are written by inodes above
+        let (ltbl_len, ltbl_ke) = self.ltbl.lock().flush()?;
+        // xattr files are written on every change
+        let (xtbl_len, xtbl_ke) = self.xtbl.lock().flush()?;
+        let mut lock = self.sb.write();
+        lock.ltbl_len = ltbl_len;
+        lock.ltbl_ke = ltbl_ke;
+        lock.xtbl_len = xtbl_len;
+        lock.xtbl_ke = xtbl_ke;
         Ok(())
     }
-}
 
-macro_rules! update_times {
-    ($self:ident, $lock: expr, $($x:expr),* ) => {
+    // link a new inode into parent, or give back what it has taken if that fails
+    fn attach_new(&self, parent: InodeID, name: &str, iid: InodeID, inode: Inode) -> FsResult<()> {
+        let alock = match self.get_inode(parent, true) {
+            Ok(alock) => alock,
+            Err(e) => return self.drop_new(iid, inode, e),
+        };
+        // new inode is inserted under lock of parent, so no one finds it missing
+        let mut lock = alock.write();
+        if let Err(e) = lock.add_child(name, inode.tp, iid) {
+            return self.drop_new(iid, inode, e);
+        }
+        update_times!(self, lock, Atime, Ctime, Mtime);
+        self.insert_inode(iid, inode)
+    }
+
+    // key entries of all bitmap blocks are kept in the superblock block,
+    // an allocation that grows them beyond it is taken back by the caller
+    fn check_bitmap_blks(&self) -> FsResult<()> {
+        let bsize = self.device.blk_sz();
+        let bits = (bsize * 8) as u64;
+        let nr_blk = self.ibitmap.lock().capacity() / bits
+            + self.bbitmap.lock().capacity() / bits
+            + self.ltbl.lock().slots_capacity() / bits;
+        if nr_blk as usize > max_bitmap_blks(bsize) {
+            return Err(FsError::FileTooLarge);
+        }
+        Ok(())
+    }
+
+    fn drop_new(&self, iid: InodeID, inode: Inode, e: FsError) -> FsResult<()> {
+        inode.remove_data_file()?;
+        self.ibitmap.lock().free(iid)?;
+        Err(e)
+    }
+
+    // take name away from the locked parent and drop a link of its inode, the last link
+    // is taken along with the inode itself, so that no child is added into a dir after
+    // its emptiness is checked. nothing is changed if the inode is still in use
+    fn unlink_locked(
+        &self, plock: &mut Inode, parent: InodeID, name: &str, iid: InodeID,
+    ) -> FsResult<()> {
+        if name == "." || name == ".." {
+            return Err(FsError::InvalidParameter);
+        }
+
+        let alock = self.get_inode(iid, true)?;
+        let mut lock = alock.write();
+        lock.check_unlink()?;
+        is_empty_dir(&mut lock)?;
+        if lock.tp != FileType::Dir && lock.nlinks > 1 {
+            plock.remove_child(name)?;
+            lock.nlinks -= 1;
+            update_times!(self, lock, Atime, Ctime);
+        } else {
+            drop(lock);
+            self.remove_inode(iid, alock.into_arc(), |ino| {
+                // a child may be added since the check above
+                is_empty_dir(ino)?;
+                plock.remove_child(name).map(|_| ())
+            })?;
+        }
+        self.de_cac_invalidate(parent, name)?;
+        update_times!(self, plock, Atime, Ctime, Mtime);
+        Ok(())
+    }
+
+    // remove the entry from the locked parent and drop the link along with it, the inode
+    // is kept and recorded in superblock until it's released, even if no link is left.
+    // a dir must be empty, and takes no child once its link is dropped
+    fn detach_replaced(
+        &self, plock: &mut Inode, parent: InodeID, name: &str, iid: InodeID,
+    ) -> FsResult<()> {
+        let ainode = self.get_inode(iid, true)?;
+        let mut ilock = ainode.write();
+        ilock.check_unlink()?;
+        is_empty_dir(&mut ilock)?;
         {
-            let now = $self.time_source.now();
-            $(
-                $lock.set_meta($x(now))?;
-            )*
+            let mut sb = self.sb.write();
+            if sb.replaced.len() >= MAX_REPLACED {
+                return Err(FsError::Busy);
+            }
+            sb.replaced.push(iid);
         }
-    };
+        if let Err(e) = plock.remove_child(name) {
+            self.sb.write().replaced.retain(|r| *r != iid);
+            return Err(e);
+        }
+        ilock.nlinks -= 1;
+        update_times!(self, ilock, Ctime);
+        self.de_cac_invalidate(parent, name)?;
+        update_times!(self, plock, Atime, Ctime, Mtime);
+        Ok(())
+    }
+
+    // undo detach_replaced
+    fn attach_replaced(&self, plock: &mut Inode, name: &str, iid: InodeID) -> FsResult<()> {
+        let ainode = self.get_inode(iid, true)?;
+        let mut ilock = ainode.write();
+        plock.add_child(name, ilock.tp, iid)?;
+        ilock.nlinks += 1;
+        self.sb.write().replaced.retain(|r| *r != iid);
+        Ok(())
+    }
+
+    // take to/newname away under the lock of `to` before src is moved onto it, so that
+    // it can be put back, a dir is only replaced by a dir, and a non-dir by a non-dir
+    fn take_target(
+        &self, tlock: &mut Inode, to: InodeID, newname: &str, iid: InodeID, src_tp: FileType,
+    ) -> FsResult<InodeID> {
+        match (src_tp, self.get_inode(iid, false)?.read().tp) {
+            (FileType::Dir, FileType::Dir) => {},
+            (_, FileType::Dir) => return Err(FsError::IsADirectory),
+            (FileType::Dir, _) => return Err(FsError::NotADirectory),
+            _ => {},
+        }
+        self.detach_replaced(tlock, to, newname, iid)?;
+        Ok(iid)
+    }
+
+    // inodes replaced but never released, e.g. before a crash, have no name any more
+    fn release_pending(&self) -> FsResult<()> {
+        let pending = self.sb.read().replaced.clone();
+        for iid in pending {
+            self.release_replaced(iid)?;
+        }
+        Ok(())
+    }
+
+    // returns the type of the inode
+    fn check_unlink(&self, iid: InodeID) -> FsResult<FileType> {
+        let alock = self.get_inode(iid, false)?;
+        let lock = alock.read();
+        lock.check_unlink()?;
+        Ok(lock.tp)
+    }
+
+    // dirs on the path from iid up to root, iid itself included
+    fn ancestors(&self, mut iid: InodeID) -> FsResult<Vec<InodeID>> {
+        let mut ret = vec![iid];
+        for _ in 0..MAX_LOOP_CNT {
+            if iid == ROOT_INODE_ID {
+                return Ok(ret);
+            }
+            iid = self.get_inode(iid, false)?.write().find_child("..")?
+                .ok_or_else(|| new_error!(FsError::NotFound))?;
+            ret.push(iid);
+        }
+        panic!("Loop exceeds MAX count!");
+    }
+
+    // upper bound of the image size in bytes if this fs is sealed into a ROFS
+    // of the same block size
+    pub fn estimate_ro_size(&self) -> FsResult<u64> {
+        use crate::ro::disk as rod;
+
+        let bsize = self.device.blk_sz();
+        let shape = mht::Shape::full(bsize);
+
+        // count blk 0 and the root inode's blk 1 as full, pack the rest from blk 2
+        let mut itbl_blk = 2u64;
+        let mut itbl_off = 0usize;
+        let mut add_inode = |sz: usize| {
+            if itbl_off + sz > bsize {
+                itbl_blk += 1;
+                itbl_off = 0;
+            }
+            itbl_off += sz;
+        };
+        let mut dtbl_bytes = 0usize;
+        let mut ptbl_bytes = 0usize;
+        let mut data_blks = 0u64;
+
+        let mut visited = alloc::collections::BTreeSet::new();
+        let mut stack = Vec::new();
+        stack.push(ROOT_INODE_ID);
+        while let Some(iid) = stack.pop() {
+            if !visited.insert(iid) {
+                continue;
+            }
+            let children: Vec<_> = self.get_inode(iid, false)?.write().read_child(0, 0)?
+                .into_iter().filter(|de| de.name != "." && de.name != "..").collect();
+
+            let n = children.len();
+            let dir_sz = if n <= rod::DE_INLINE_MAX as usize {
+                size_of::<rod::DInodeBase>() + (n + 2) * size_of::<rod::DirEntry>()
+            } else {
+                dtbl_bytes += (n + 2) * size_of::<rod::DirEntry>();
+                size_of::<rod::DInodeDirBaseNoInline>()
+                    + n.div_ceil(rod::MAX_ENTRY_GROUP_LEN) * size_of::<rod::EntryIndex>()
+            };
+            if iid != ROOT_INODE_ID {
+                add_inode(dir_sz);
+            }
+
+            for de in children {
+                if de.name.len() > rod::DE_MAX_INLINE_NAME {
+                    ptbl_bytes += de.name.len();
+                }
+                match de.tp {
+                    FileType::Dir => stack.push(de.ipos),
+                    FileType::Reg => {
+                        // hard links are expanded into separate files by the builder
+                        let size = self.get_inode(de.ipos, false)?.read().get_meta()?.size;
+                        if size <= rod::DI_REG_INLINE_DATA_MAX {
+                            add_inode(size_of::<rod::DInodeBase>()
+                                + (size as usize).next_multiple_of(rod::INODE_ALIGN));
+                        } else {
+                            add_inode(size_of::<rod::DInodeReg>());
+                            data_blks += shape.get_phy_nr_blk(size.div_ceil(bsize as u64));
+                        }
+                    }
+                    FileType::Lnk => {
+                        let target = self.get_inode(de.ipos, false)?.read().get_link()?;
+                        if target.len() > rod::DI_LNK_MAX_INLINE_NAME {
+                            ptbl_bytes += target.len();
+                        }
+                        add_inode(size_of::<rod::DInodeLnk>());
+                    }
+                }
+            }
+        }
+
+        let tbl_blks = |bytes: usize| shape.get_phy_nr_blk(bytes.div_ceil(bsize) as u64);
+        let total = crate::ro::superblock::TBL_START + shape.get_phy_nr_blk(itbl_blk + 1)
+            + tbl_blks(dtbl_bytes) + tbl_blks(ptbl_bytes) + data_blks;
+        Ok(blk2byte!(total, bsize))
+    }
 }
 
 impl FileSystem for RWFS {
+    fn init(&self) -> FsResult<()> {
+        if self.destroyed.load(Ordering::Acquire) {
+            return Err(FsError::AlreadyDestroyed);
+        }
+        Ok(())
+    }
+
+    fn destroy(&self) -> FsResult<FSMode> {
+        if self.destroyed.swap(true, Ordering::AcqRel) {
+            return Err(FsError::AlreadyDestroyed);
+        }
+        // allow retry if it fails to sync
+        self.seal().inspect_err(|_| self.destroyed.store(false, Ordering::Release))
+    }
+
     fn finfo(&self) -> FsResult<FsInfo> {
-        self.sb.read().get_fsinfo()
+        let (files, ffree) = {
+            let ibitmap = self.ibitmap.lock();
+            (ibitmap.capacity() as usize, ibitmap.count_free())
+        };
+        // the block bitmap is left out, no storage backs its region yet
+        self.sb.read().get_fsinfo(files, ffree)
+    }
+
+    fn capabilities(&self) -> FsResult<FsCapabilities> {
+        let sb = self.sb.read();
+        // file data of rwfs is never compressed
+        Ok(FsCapabilities::new(sb.magic, sb.version, sb.encrypted, sb.bsize, false, !self.read_only))
     }
 
     fn fsync(&self) -> FsResult<FSMode> {
+        // nothing is changed since mount
+        if self.read_only {
+            return Ok(self.mode.clone());
+        }
         self.sync_itbl()?;
         let mode = self.wb_sb_file()?;
+        *self.synced.lock() = mode.clone();
         Ok(mode)
     }
 
     fn iread(&self, iid: InodeID, offset: usize, to: &mut [u8]) -> FsResult<usize> {
         let alock = self.get_inode(iid, true)?;
         let mut lock = alock.write();
-        let read = lock.read_data(offset, to)?;
+        let read = trace_err!(lock.read_data(offset, to))?;
         update_times!(self, lock, Atime);
         Ok(read)
     }
 
     fn iwrite(&self, iid: InodeID, offset: usize, from: &[u8]) -> FsResult<usize> {
+        self.check_writable()?;
         let alock = self.get_inode(iid, true)?;
         let mut lock = alock.write();
         let written = lock.write_data(offset, from)?;
@@ -370,17 +1257,59 @@ impl FileSystem for RWFS {
         Ok(written)
     }
 
+    fn is_inline(&self, iid: InodeID) -> FsResult<bool> {
+        Ok(self.get_inode(iid, false)?.read().is_inline())
+    }
+
+    fn iappend(&self, iid: InodeID, from: &[u8]) -> FsResult<(u64, usize)> {
+        self.check_writable()?;
+        let alock = self.get_inode(iid, true)?;
+        // size is read and extended under the same write lock
+        let mut lock = alock.write();
+        let ret = lock.append_data(from)?;
+        update_times!(self, lock, Atime, Ctime, Mtime);
+        Ok(ret)
+    }
+
+    // in the data of the htree in the inode table file
+    fn inode_tbl_offset(&self, iid: InodeID) -> FsResult<(u64, usize)> {
+        if !self.ibitmap.lock().is_used(iid) {
+            return Err(FsError::NotFound);
+        }
+        Ok((iid_to_htree_logi_pos(iid, self.inode_sz) as u64, self.inode_sz))
+    }
+
     fn get_meta(&self, iid: InodeID) -> FsResult<Metadata> {
+        // stat is not an access, atime is left to reads
+        self.get_inode(iid, false)?.read().get_meta()
+    }
+
+    fn touch_atime(&self, iid: InodeID) -> FsResult<()> {
+        self.check_writable()?;
         let alock = self.get_inode(iid, true)?;
         let mut lock = alock.write();
-        let meta = lock.get_meta()?;
         update_times!(self, lock, Atime);
-        Ok(meta)
+        Ok(())
+    }
+
+    fn get_flags(&self, iid: InodeID) -> FsResult<InodeFlags> {
+        Ok(self.get_inode(iid, false)?.read().get_flags())
+    }
+
+    fn set_flags(&self, iid: InodeID, flags: InodeFlags) -> FsResult<()> {
+        self.check_writable()?;
+        let alock = self.get_inode(iid, true)?;
+        let mut lock = alock.write();
+        lock.set_flags(flags);
+        update_times!(self, lock, Atime, Ctime);
+        Ok(())
     }
 
     fn set_meta(&self, iid: InodeID, set_meta: SetMetadata) -> FsResult<()> {
+        self.check_writable()?;
         let alock = self.get_inode(iid, true)?;
         let mut lock = alock.write();
+        lock.check_set_meta(&set_meta)?;
         lock.set_meta(set_meta.clone())?;
         match set_meta {
             Atime(_) | Ctime(_) | Mtime(_) => {},
@@ -397,7 +1326,54 @@ impl FileSystem for RWFS {
         Ok(pb)
     }
 
+    fn iget_xattr(&self, iid: InodeID, name: &str) -> FsResult<Option<Vec<u8>>> {
+        let alock = self.get_inode(iid, false)?;
+        let _lock = alock.read();
+        let attrs = self.xtbl.lock().get(iid)?;
+        Ok(attrs.into_iter().find(|(n, _)| n == name).map(|(_, v)| v))
+    }
+
+    fn iset_xattr(&self, iid: InodeID, name: &str, value: &[u8]) -> FsResult<()> {
+        self.check_writable()?;
+        check_xattr(name, value)?;
+        let alock = self.get_inode(iid, true)?;
+        let mut lock = alock.write();
+        // fixed along with links of the inode
+        lock.check_unlink()?;
+        let mut xtbl = self.xtbl.lock();
+        let mut attrs = xtbl.get(iid)?;
+        match attrs.iter_mut().find(|(n, _)| n == name) {
+            Some((_, v)) => *v = Vec::from(value),
+            None => attrs.push((String::from(name), Vec::from(value))),
+        }
+        xtbl.set(iid, &attrs)?;
+        update_times!(self, lock, Ctime);
+        Ok(())
+    }
+
+    fn iremove_xattr(&self, iid: InodeID, name: &str) -> FsResult<()> {
+        self.check_writable()?;
+        let alock = self.get_inode(iid, true)?;
+        let mut lock = alock.write();
+        lock.check_unlink()?;
+        let mut xtbl = self.xtbl.lock();
+        let mut attrs = xtbl.get(iid)?;
+        let pos = attrs.iter().position(|(n, _)| n == name).ok_or(FsError::NotFound)?;
+        attrs.remove(pos);
+        xtbl.set(iid, &attrs)?;
+        update_times!(self, lock, Ctime);
+        Ok(())
+    }
+
+    fn ilist_xattr(&self, iid: InodeID) -> FsResult<Vec<String>> {
+        let alock = self.get_inode(iid, false)?;
+        let _lock = alock.read();
+        let attrs = self.xtbl.lock().get(iid)?;
+        Ok(attrs.into_iter().map(|(n, _)| n).collect())
+    }
+
     fn iset_link(&self, iid: InodeID, new_lnk: &str) -> FsResult<()> {
+        self.check_writable()?;
         let alock = self.get_inode(iid, true)?;
         let mut lock = alock.write();
         lock.set_link(new_lnk)?;
@@ -406,6 +1382,9 @@ impl FileSystem for RWFS {
     }
 
     fn isync_meta(&self, iid: InodeID) -> FsResult<()> {
+        if self.read_only {
+            return Ok(());
+        }
         if let Some(lock) = self.get_inode_try(iid, true)? {
             let ib = lock.write().sync_meta()?;
             self.write_itbl(iid, &ib)?;
@@ -415,12 +1394,25 @@ impl FileSystem for RWFS {
     }
 
     fn isync_data(&self, iid: InodeID) -> FsResult<()> {
+        if self.read_only {
+            return Ok(());
+        }
         if let Some(lock) = self.get_inode_try(iid, true)? {
             lock.write().sync_data()?;
         }
         Ok(())
     }
 
+    fn fsync_range(&self, iid: InodeID, offset: usize, len: usize) -> FsResult<()> {
+        if self.read_only {
+            return Ok(());
+        }
+        if let Some(lock) = self.get_inode_try(iid, true)? {
+            lock.write().sync_data_range(offset, len)?;
+        }
+        Ok(())
+    }
+
     fn create(
         &self,
         parent: InodeID,
@@ -430,20 +1422,19 @@ impl FileSystem for RWFS {
         gid: u32,
         perm: FilePerm,
     ) -> FsResult<InodeID> {
+        self.check_writable()?;
+        check_name_len(name)?;
         let iid = self.ibitmap.lock().alloc()?;
         let inode = Inode::new(
             iid, parent, ftype, uid, gid, perm,
             self.mode.is_encrypted(),
-            self.sb_meta_for_inode.clone(), self.device.clone(),
-            self.time_source.now(),
+            self.sb_meta_for_inode.clone(), self.device.clone(), self.ltbl.clone(),
+            self.time_source.now(), self.inode_sz,
         )?;
-
-        let alock = self.get_inode(parent, true)?;
-        let mut lock = alock.write();
-        lock.add_child(name, ftype, iid)?;
-        update_times!(self, lock, Atime, Ctime, Mtime);
-
-        self.insert_inode(iid, inode)?;
+        if let Err(e) = self.check_bitmap_blks() {
+            return self.drop_new(iid, inode, e).map(|_| iid);
+        }
+        self.attach_new(parent, name, iid, inode)?;
 
         if ftype == FileType::Reg {
             self.sb.write().files += 1;
@@ -452,50 +1443,98 @@ impl FileSystem for RWFS {
         Ok(iid)
     }
 
-    fn link(&self, parent: InodeID, name: &str, linkto: InodeID) -> FsResult<()> {
-        let to = self.get_inode(linkto, true)?;
-        let mut lock = to.write();
+    fn create_with_data(
+        &self,
+        parent: InodeID,
+        name: &str,
+        data: &[u8],
+        uid: u32,
+        gid: u32,
+        perm: FilePerm,
+    ) -> FsResult<InodeID> {
+        self.check_writable()?;
+        check_name_len(name)?;
+        let iid = self.ibitmap.lock().alloc()?;
+        let mut inode = Inode::new(
+            iid, parent, FileType::Reg, uid, gid, perm,
+            self.mode.is_encrypted(),
+            self.sb_meta_for_inode.clone(), self.device.clone(), self.ltbl.clone(),
+            self.time_source.now(), self.inode_sz,
+        )?;
+        // the inode is not reachable yet, so a failed write is simply dropped
+        if let Err(e) = self.check_bitmap_blks()
+            .and_then(|_| inode.write_data(0, data))
+            .and_then(|_| inode.sync_data()) {
+            return self.drop_new(iid, inode, e).map(|_| iid);
+        }
+        self.attach_new(parent, name, iid, inode)?;
+        self.sb.write().files += 1;
+        self.isync_meta(iid)?;
+
+        Ok(iid)
+    }
 
+    fn link(&self, parent: InodeID, name: &str, linkto: InodeID) -> FsResult<()> {
+        self.check_writable()?;
+        check_name_len(name)?;
         // hard links not allowed for directories
-        if lock.tp == FileType::Dir {
+        if self.get_inode(linkto, false)?.read().tp == FileType::Dir {
             return Err(FsError::PermissionDenied);
         }
 
-        lock.nlinks += 1;
-        update_times!(self, lock, Atime, Ctime);
-        let tp = lock.tp;
-
+        // parent is locked before the child, as everywhere else
         let alock = self.get_inode(parent, true)?;
-        let mut lock = alock.write();
-        lock.add_child(name, tp, linkto)?;
+        let mut plock = alock.write();
+        let to = self.get_inode(linkto, true)?;
+        let mut lock = to.write();
+        lock.check_unlink()?;
+
+        // entries change, nothing is read
+        plock.add_child(name, lock.tp, linkto)?;
+        update_times!(self, plock, Ctime, Mtime);
+        lock.nlinks += 1;
+        update_times!(self, lock, Ctime);
 
         Ok(())
     }
 
     fn unlink(&self, parent: InodeID, name: &str) -> FsResult<()> {
+        self.check_writable()?;
+        // name is looked up and taken away under the lock of parent
         let alock = self.get_inode(parent, true)?;
         let mut lock = alock.write();
-        let (iid, _) = lock.remove_child(name)?;
-        update_times!(self, lock, Atime, Ctime, Mtime);
-
-        let do_remove = {
-            let inode = self.get_inode(iid, true)?;
-            let mut lock = inode.write();
-            if lock.nlinks == 1 {
-                true
-            } else {
-                lock.nlinks -= 1;
-                update_times!(self, lock, Atime, Ctime);
-                false
-            }
-        };
+        let iid = lock.find_child(name)?.ok_or(FsError::NotFound)?;
+        self.unlink_locked(&mut lock, parent, name, iid)
+    }
 
-        if do_remove {
-            // debug!("unlink do remove parent {} name {:?} iid {}", parent, name, iid);
-            self.remove_inode(iid)?;
+    fn release_replaced(&self, iid: InodeID) -> FsResult<()> {
+        self.check_writable()?;
+        if !self.sb.read().replaced.contains(&iid) {
+            return Err(FsError::InvalidParameter);
+        }
+        // fetched before it's taken off the list, it may have no link
+        let ainode = self.get_inode(iid, true)?;
+        {
+            let mut sb = self.sb.write();
+            let Some(i) = sb.replaced.iter().position(|r| *r == iid) else {
+                return Err(FsError::InvalidParameter);
+            };
+            sb.replaced.remove(i);
         }
 
-        Ok(())
+        // its link is dropped on rename, it may be linked back since
+        if ainode.read().nlinks != 0 {
+            return Ok(());
+        }
+        self.remove_inode(iid, ainode.into_arc(), |ino| {
+            if ino.nlinks != 0 {
+                return Err(FsError::Busy);
+            }
+            Ok(())
+        }).inspect_err(|_| {
+            // keep it for a retry
+            self.sb.write().replaced.push(iid);
+        })
     }
 
     fn symlink(
@@ -506,23 +1545,22 @@ impl FileSystem for RWFS {
         uid: u32,
         gid: u32,
     ) -> FsResult<InodeID> {
+        self.check_writable()?;
+        check_name_len(name)?;
         let iid = self.ibitmap.lock().alloc()?;
         // symlink permissions are always 0777 since on Linux they are not used anyway
         let mut inode = Inode::new(
             iid, parent, FileType::Lnk, uid, gid,
-            FilePerm::from_bits(PERM_MASK).unwrap(),
+            FilePerm::from_bits(0o777).unwrap(),
             self.mode.is_encrypted(),
-            self.sb_meta_for_inode.clone(), self.device.clone(),
-            self.time_source.now(),
+            self.sb_meta_for_inode.clone(), self.device.clone(), self.ltbl.clone(),
+            self.time_source.now(), self.inode_sz,
         )?;
-        inode.set_link(to)?;
-
-        let alock = self.get_inode(parent, true)?;
-        let mut lock = alock.write();
-        lock.add_child(name, FileType::Lnk, iid)?;
-        update_times!(self, lock, Atime, Ctime, Mtime);
+        if let Err(e) = inode.set_link(to).and_then(|_| self.check_bitmap_blks()) {
+            return self.drop_new(iid, inode, e).map(|_| iid);
+        }
 
-        self.insert_inode(iid, inode)?;
+        self.attach_new(parent, name, iid, inode)?;
         Ok(iid)
     }
 
@@ -531,48 +1569,168 @@ impl FileSystem for RWFS {
         from: InodeID, name: &str,
         to: InodeID, newname: &str
     ) -> FsResult<()> {
-        // remove to/newname unless it's a non-empty dir
-        if let Some(iid) = self.lookup(to, newname)? {
-            let meta = self.get_meta(iid)?;
-            if meta.ftype == FileType::Dir && meta.size > 2 * DIRENT_SZ as u64 {
-                return Err(FsError::DirectoryNotEmpty);
-            }
-            self.unlink(to, newname)?;
+        self.check_writable()?;
+        if let Some(replaced) = self.rename_replace(from, name, to, newname)? {
+            self.release_replaced(replaced)?;
+        }
+        Ok(())
+    }
+
+    fn rename_replace(
+        &self,
+        from: InodeID, name: &str,
+        to: InodeID, newname: &str
+    ) -> FsResult<Option<InodeID>> {
+        self.check_writable()?;
+        check_name_len(newname)?;
+        if [name, newname].iter().any(|n| *n == "." || *n == "..") {
+            return Err(FsError::InvalidParameter);
         }
 
-        let from_inode = self.get_inode(from, true)?;
         if from == to {
-            let mut lock = from_inode.write();
-            lock.rename_child(name, newname)?;
+            // names are looked up and changed under the lock of the dir
+            let alock = self.get_inode(from, true)?;
+            let mut lock = alock.write();
+            let src = lock.find_child(name)?.ok_or(FsError::NotFound)?;
+            let target = lock.find_child(newname)?;
+            // renaming to itself (or another link of itself) does nothing
+            if target == Some(src) {
+                return Ok(None);
+            }
+            let src_tp = self.check_unlink(src)?;
+
+            let replaced = match target {
+                Some(iid) => Some(self.take_target(&mut lock, to, newname, iid, src_tp)?),
+                None => None,
+            };
+            // a replaced target is put back if src can not be moved
+            if let Err(e) = lock.rename_child(name, newname) {
+                if let Some(iid) = replaced {
+                    self.attach_replaced(&mut lock, newname, iid)?;
+                }
+                return Err(e);
+            }
+            self.de_cac_invalidate(from, name)?;
             update_times!(self, lock, Atime, Ctime, Mtime);
+            return Ok(replaced);
+        }
+
+        // dirs are only moved across dirs here, one rename at a time, so that the tree
+        // stays as it is below, and both dirs are locked with the ancestor first
+        let _rename = self.rename_lock.lock();
+        let from_up = self.ancestors(from)?;
+        let to_up = self.ancestors(to)?;
+        let afrom = self.get_inode(from, true)?;
+        let ato = self.get_inode(to, true)?;
+        let (mut flock, mut tlock) = if from_up.contains(&to) {
+            let tlock = ato.write();
+            (afrom.write(), tlock)
         } else {
-            let mut lock = from_inode.write();
-            let (iid, tp) = lock.remove_child(name)?;
-            update_times!(self, lock, Atime, Ctime, Mtime);
+            let flock = afrom.write();
+            (flock, ato.write())
+        };
+
+        let src = flock.find_child(name)?.ok_or(FsError::NotFound)?;
+        // a dir can not be moved into its own subtree
+        if to_up.contains(&src) {
+            return Err(FsError::InvalidParameter);
+        }
+        let target = tlock.find_child(newname)?;
+        if target == Some(src) {
+            return Ok(None);
+        }
+        // nor onto one of its ancestors, which is never empty
+        if target.is_some_and(|iid| from_up.contains(&iid)) {
+            return Err(FsError::DirectoryNotEmpty);
+        }
+        let src_tp = self.check_unlink(src)?;
 
-            let alock = self.get_inode(to, true)?;
+        let replaced = match target {
+            Some(iid) => Some(self.take_target(&mut tlock, to, newname, iid, src_tp)?),
+            None => None,
+        };
+        let moved = flock.remove_child(name).and_then(|(iid, tp)| {
+            tlock.add_child(newname, tp, iid).map(|_| (iid, tp)).or_else(|e| {
+                flock.add_child(name, tp, iid)?;
+                Err(e)
+            })
+        });
+        let (iid, tp) = match moved {
+            Ok(moved) => moved,
+            Err(e) => {
+                if let Some(iid) = replaced {
+                    self.attach_replaced(&mut tlock, newname, iid)?;
+                }
+                return Err(e);
+            }
+        };
+        self.de_cac_invalidate(from, name)?;
+        update_times!(self, flock, Atime, Ctime, Mtime);
+        update_times!(self, tlock, Atime, Ctime, Mtime);
+
+        if tp == FileType::Dir {
+            let alock = self.get_inode(iid, true)?;
             let mut lock = alock.write();
-            lock.add_child(newname, tp, iid)?;
-            update_times!(self, lock, Atime, Ctime, Mtime);
+            lock.set_child_ipos("..", to)?;
+            self.de_cac_invalidate(iid, "..")?;
         }
-        Ok(())
+        Ok(replaced)
     }
 
     fn lookup(&self, iid: InodeID, name: &str) -> FsResult<Option<InodeID>> {
-        // Currently we don't use de_cac
         let alock = self.get_inode(iid, true)?;
         let mut lock = alock.write();
-        let ret = lock.find_child(name)?;
+        if self.sync_dir_on_read.load(Ordering::Relaxed) {
+            lock.sync_data()?;
+        }
+        let ret = if let Some(child) = self.de_cac_get(iid, name)? {
+            Some(child)
+        } else {
+            let ret = lock.find_child(name)?;
+            if let Some(child) = ret {
+                self.de_cac_insert(iid, name, child)?;
+            }
+            ret
+        };
         update_times!(self, lock, Atime);
         // debug!("lookup parent {} name {:?} found {:?}", iid, name, ret);
         Ok(ret)
     }
 
+    fn lookup_many(&self, iid: InodeID, names: &[&str]) -> FsResult<Vec<Option<InodeID>>> {
+        let alock = self.get_inode(iid, true)?;
+        let mut lock = alock.write();
+        if self.sync_dir_on_read.load(Ordering::Relaxed) {
+            lock.sync_data()?;
+        }
+        let mut ret = Vec::with_capacity(names.len());
+        for name in names {
+            ret.push(self.de_cac_get(iid, name)?);
+        }
+        // names missed by de cache are found in one scan of dir data
+        let missed: Vec<_> = names.iter().zip(ret.iter())
+            .filter(|(_, r)| r.is_none()).map(|(name, _)| *name).collect();
+        let mut found = lock.find_children(&missed)?.into_iter();
+        for (name, r) in names.iter().zip(ret.iter_mut()) {
+            if r.is_none() {
+                *r = found.next().unwrap();
+                if let Some(child) = *r {
+                    self.de_cac_insert(iid, name, child)?;
+                }
+            }
+        }
+        update_times!(self, lock, Atime);
+        Ok(ret)
+    }
+
     fn listdir(
         &self, iid: InodeID, offset: usize, num: usize,
     ) -> FsResult<Vec<(InodeID, String, FileType)>> {
         let alock = self.get_inode(iid, true)?;
         let mut lock = alock.write();
+        if self.sync_dir_on_read.load(Ordering::Relaxed) {
+            lock.sync_data()?;
+        }
         let l = lock.read_child(offset, num)?.into_iter().map(
             |DirEntry {ipos, tp, name}| (ipos, name.into(), tp)
         ).collect();
@@ -580,6 +1738,43 @@ impl FileSystem for RWFS {
         Ok(l)
     }
 
+    #[cfg(feature = "std")]
+    fn dir_stream(
+        &self, iid: InodeID,
+    ) -> FsResult<DirIter<'_>> {
+        let alock = self.get_inode(iid, true)?;
+        {
+            let mut lock = alock.write();
+            if lock.tp != FileType::Dir {
+                return Err(FsError::NotADirectory);
+            }
+            if self.sync_dir_on_read.load(Ordering::Relaxed) {
+                lock.sync_data()?;
+            }
+            update_times!(self, lock, Atime);
+        }
+        // the inode is held by the stream, and released as usual when it's dropped
+        let per_blk = dirent_per_blk(self.device.blk_sz());
+        Ok(alloc::boxed::Box::new(DirStream::new(move |offset| {
+            Ok(alock.write().read_child(offset, per_blk)?.into_iter().map(
+                |DirEntry {ipos, tp, name}| (ipos, name, tp)
+            ).collect())
+        })))
+    }
+
+    fn read_dirents(
+        &self, iid: InodeID, offset: usize, num: usize,
+    ) -> FsResult<Vec<Dirent>> {
+        let alock = self.get_inode(iid, true)?;
+        let mut lock = alock.write();
+        if self.sync_dir_on_read.load(Ordering::Relaxed) {
+            lock.sync_data()?;
+        }
+        Ok(lock.read_child(offset, num)?.into_iter().map(
+            |DirEntry {ipos, tp, name}| Dirent { ipos, tp, name }
+        ).collect())
+    }
+
     fn fallocate(
         &self,
         iid: InodeID,
@@ -587,6 +1782,7 @@ impl FileSystem for RWFS {
         offset: usize,
         len: usize,
     ) -> FsResult<()> {
+        self.check_writable()?;
         let alock = self.get_inode(iid, true)?;
         let mut lock = alock.write();
         lock.fallocate(mode, offset, len)?;
@@ -595,6 +1791,81 @@ impl FileSystem for RWFS {
     }
 }
 
+// an inode in use, without icac it's written back when dropped by the last user
+struct InodeRef<'a> {
+    fs: &'a RWFS,
+    iid: InodeID,
+    ainode: Option<Arc<InodeLock>>,
+}
+
+impl InodeRef<'_> {
+    // leave the inode where it is on drop
+    fn into_arc(mut self) -> Arc<InodeLock> {
+        self.ainode.take().unwrap()
+    }
+}
+
+impl core::ops::Deref for InodeRef<'_> {
+    type Target = InodeLock;
+
+    fn deref(&self) -> &InodeLock {
+        self.ainode.as_ref().unwrap()
+    }
+}
+
+impl Drop for InodeRef<'_> {
+    fn drop(&mut self) {
+        if self.ainode.take().is_some() && self.fs.no_icac {
+            // a failed write back is kept in icac as dirty, and retried by next fsync
+            let _ = self.fs.release_inode(self.iid);
+        }
+    }
+}
+
+/// holds the write lock of an inode, any other access to it waits for the drop of this,
+/// including one from the same thread
+pub struct BulkWriter<'a> {
+    fs: &'a RWFS,
+    iid: InodeID,
+    // taken on drop
+    guard: Option<InodeWriteGuard>,
+    written: bool,
+}
+
+impl BulkWriter<'_> {
+    fn inode(&mut self) -> &mut Inode {
+        self.guard.as_mut().unwrap()
+    }
+
+    pub fn write_at(&mut self, offset: usize, data: &[u8]) -> FsResult<usize> {
+        let written = self.inode().write_data(offset, data)?;
+        self.written = true;
+        Ok(written)
+    }
+
+    /// update times if anything is written, and write metadata to inode table
+    pub fn finish(mut self) -> FsResult<()> {
+        let (fs, iid, written) = (self.fs, self.iid, self.written);
+        let lock = self.inode();
+        if written {
+            update_times!(fs, lock, Atime, Ctime, Mtime);
+        }
+        let ib = lock.sync_meta()?;
+        fs.write_itbl(iid, &ib)?;
+        fs.icac.lock().unmark_dirty(&iid)?;
+        Ok(())
+    }
+}
+
+impl Drop for BulkWriter<'_> {
+    fn drop(&mut self) {
+        // same as InodeRef
+        if self.guard.take().is_some() && self.fs.no_icac {
+            let _ = self.fs.release_inode(self.iid);
+        }
+    }
+}
+
 // change nr_data_file and blocks in superblock
 pub fn nf_nb_change(
     pointer: &Arc<RwLock<(usize, usize)>>, f: isize, b: isize
@@ -604,3 +1875,25 @@ pub fn nf_nb_change(
     lock.1 = lock.1.checked_add_signed(b).unwrap();
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use crate::*;
+
+    #[test]
+    fn rw_sb_bitmap_ke_overflow() {
+        use crate::rw::superblock::{SuperBlock, max_bitmap_blks};
+
+        let mut sb = SuperBlock {
+            magic: crate::rw::RWFS_MAGIC,
+            bsize: BLK_SZ,
+            ibitmap_start: 1,
+            ibitmap_ke: vec![KeyEntry::default(); max_bitmap_blks(BLK_SZ) - 1],
+            bbitmap_ke: vec![KeyEntry::default()],
+            ..Default::default()
+        };
+        assert!(sb.write().is_ok());
+        sb.lbitmap_ke.push(KeyEntry::default());
+        assert!(matches!(sb.write(), Err(FsError::FileTooLarge)));
+    }
+}
@@ -0,0 +1,393 @@
+use crate::*;
+use crate::crypto::*;
+use super::*;
+use super::xattr::xattr_file_name;
+use super::check::is_not_found;
+
+// every storage is written anew under this suffix before it's swapped in
+const REKEY_SUFFIX: &str = ".rekey";
+// the old copy is kept under this suffix until the swap is done
+const PREV_SUFFIX: &str = ".prev";
+// names of storages being rekeyed, on device beside them
+const REKEY_JOURNAL: &str = "rekey";
+
+fn staged_name(name: &str) -> String {
+    format!("{}{}", name, REKEY_SUFFIX)
+}
+
+fn prev_name(name: &str) -> String {
+    format!("{}{}", name, PREV_SUFFIX)
+}
+
+fn exists(device: &dyn Device, name: &str) -> FsResult<bool> {
+    match device.get_storage_len(name) {
+        Ok(_) => Ok(true),
+        Err(e) if is_not_found(&e) => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+fn remove_if_exists(device: &dyn Device, name: &str) -> FsResult<()> {
+    if exists(device, name)? {
+        device.remove_storage(name)?;
+    }
+    Ok(())
+}
+
+// bytes of the journal at off, it's corrupted if they are not there
+fn take<'a>(buf: &'a [u8], off: &mut usize, len: usize) -> FsResult<&'a [u8]> {
+    let b = buf.get(*off..off.saturating_add(len)).ok_or(FsError::Corrupted)?;
+    *off += len;
+    Ok(b)
+}
+
+fn take_u64(buf: &[u8], off: &mut usize) -> FsResult<u64> {
+    Ok(u64::from_le_bytes(take(buf, off, 8)?.try_into().unwrap()))
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum RekeyState {
+    // copies are being written aside, the old key is the only one
+    Staging = 1,
+    // copies are being swapped in, old ones are kept aside
+    Swapping = 2,
+    // all are swapped in, old copies are left to remove
+    Done = 3,
+}
+
+// like names of storages on device, nothing in it is secret or protected:
+// state, mac of the new superblock, number of names, then each name with its length
+struct RekeyJournal {
+    state: RekeyState,
+    mac: MAC128,
+    // superblock file last
+    names: Vec<String>,
+}
+
+impl RekeyJournal {
+    // written aside and swapped in, so it's never seen half written
+    fn write(&self, device: &dyn Device) -> FsResult<()> {
+        let mut buf = Vec::new();
+        buf.extend_from_slice(&(self.state as u64).to_le_bytes());
+        buf.extend_from_slice(&self.mac);
+        buf.extend_from_slice(&(self.names.len() as u64).to_le_bytes());
+        for name in &self.names {
+            buf.extend_from_slice(&(name.len() as u64).to_le_bytes());
+            buf.extend_from_slice(name.as_bytes());
+        }
+        let bsize = device.blk_sz();
+        let nr_blk = buf.len().div_ceil(bsize);
+        buf.resize(nr_blk * bsize, 0);
+
+        let staged = staged_name(REKEY_JOURNAL);
+        remove_if_exists(device, &staged)?;
+        let to = device.create_rw_storage(&staged)?;
+        to.set_len(nr_blk as u64)?;
+        for (pos, blk) in buf.chunks(bsize).enumerate() {
+            to.write_blk(pos as u64, blk)?;
+        }
+        device.rename_storage(&staged, REKEY_JOURNAL)
+    }
+
+    fn read(device: &dyn Device) -> FsResult<Option<Self>> {
+        if !exists(device, REKEY_JOURNAL)? {
+            return Ok(None);
+        }
+        let from = device.open_rw_storage(REKEY_JOURNAL)?;
+        let mut buf = Vec::new();
+        for pos in 0..from.get_len()?.div_ceil(from.blk_sz() as u64) {
+            buf.extend_from_slice(&from.read_blk(pos)?);
+        }
+
+        let mut off = 0;
+        let state = match take_u64(&buf, &mut off)? {
+            1 => RekeyState::Staging,
+            2 => RekeyState::Swapping,
+            3 => RekeyState::Done,
+            _ => return Err(FsError::Corrupted),
+        };
+        let mac: MAC128 = take(&buf, &mut off, size_of::<MAC128>())?.try_into().unwrap();
+        let mut names = Vec::new();
+        for _ in 0..take_u64(&buf, &mut off)? {
+            let len = take_u64(&buf, &mut off)? as usize;
+            let name = core::str::from_utf8(take(&buf, &mut off, len)?)
+                .map_err(|_| FsError::Corrupted)?;
+            names.push(name.to_string());
+        }
+        Ok(Some(Self { state, mac, names }))
+    }
+
+    // the superblock file is swapped in last
+    fn swap_in(&mut self, device: &dyn Device) -> FsResult<()> {
+        self.state = RekeyState::Swapping;
+        self.write(device)?;
+        for name in &self.names {
+            device.rename_storage(name, &prev_name(name))?;
+            device.rename_storage(&staged_name(name), name)?;
+        }
+        self.state = RekeyState::Done;
+        self.write(device)
+    }
+
+    // every step can be done again, so it's picked up on next mount if it's cut short
+    fn roll_back(&self, device: &dyn Device) -> FsResult<()> {
+        for name in &self.names {
+            if exists(device, &prev_name(name))? {
+                device.rename_storage(&prev_name(name), name)?;
+            }
+            remove_if_exists(device, &staged_name(name))?;
+        }
+        remove_if_exists(device, &staged_name(REKEY_JOURNAL))?;
+        remove_if_exists(device, REKEY_JOURNAL)
+    }
+
+    fn roll_forward(&self, device: &dyn Device) -> FsResult<()> {
+        for name in &self.names {
+            if exists(device, &staged_name(name))? {
+                device.rename_storage(&staged_name(name), name)?;
+            }
+        }
+        self.finish(device)
+    }
+
+    fn finish(&self, device: &dyn Device) -> FsResult<()> {
+        for name in &self.names {
+            remove_if_exists(device, &prev_name(name))?;
+        }
+        remove_if_exists(device, &staged_name(REKEY_JOURNAL))?;
+        remove_if_exists(device, REKEY_JOURNAL)
+    }
+}
+
+/// finish or undo a rekey cut short, before the image is opened.
+/// it's undone unless all is swapped in or it's mounted with the new mode
+pub(crate) fn recover_rekey(device: &dyn Device, mode: &FSMode) -> FsResult<()> {
+    let Some(journal) = RekeyJournal::read(device)? else {
+        // a journal half written is not in effect yet
+        return remove_if_exists(device, &staged_name(REKEY_JOURNAL));
+    };
+    let forward = match journal.state {
+        RekeyState::Staging => false,
+        RekeyState::Swapping => matches!(mode, FSMode::Encrypted(_, mac) if *mac == journal.mac),
+        RekeyState::Done => true,
+    };
+    if forward {
+        journal.roll_forward(device)
+    } else {
+        journal.roll_back(device)
+    }
+}
+
+impl RWFS {
+    /// re-encrypt the whole image under fresh keys, with the superblock under `new_root`,
+    /// returns the mode to mount with, the fs is destroyed by it like by [`FileSystem::destroy`].
+    ///
+    /// every storage is first synced and written anew aside, then swapped in one at a time
+    /// with the superblock last, old copies are kept aside till all are swapped in.
+    /// names of them are kept in a journal on device, so a failure puts the old copies
+    /// back, and the fs is still usable. a crash is picked up on next mount: it's undone
+    /// under the old mode, or finished if all is swapped in. the superblock in place is
+    /// not written, so the mode of the last sync stays valid if nothing changed since
+    pub fn rekey(&self, new_root: Key128) -> FsResult<FSMode> {
+        self.check_writable()?;
+        if !self.mode.is_encrypted() {
+            return Err(FsError::NotSupported);
+        }
+        if self.destroyed.swap(true, Ordering::AcqRel) {
+            return Err(FsError::AlreadyDestroyed);
+        }
+
+        let device = self.device.as_ref();
+        let mut journal = RekeyJournal {
+            state: RekeyState::Staging,
+            mac: MAC128::default(),
+            names: Vec::new(),
+        };
+        // data is synced in place, it's the same as on device if it's synced right before
+        let staged = self.sync_itbl().and_then(|_| {
+            journal.names = self.rekey_names()?;
+            journal.write(device)
+        }).and_then(|_| self.rekey_aside(new_root));
+        let mode = match staged {
+            Ok(mode) => mode,
+            Err(e) => return self.rekey_undo(&journal, e),
+        };
+
+        if let FSMode::Encrypted(_, mac) = &mode {
+            journal.mac = *mac;
+        }
+        if let Err(e) = journal.swap_in(device) {
+            return self.rekey_undo(&journal, e);
+        }
+        // it's under the new key from now on, old copies left are taken on next mount
+        let _ = journal.finish(device);
+        Ok(mode)
+    }
+
+    fn rekey_undo(&self, journal: &RekeyJournal, e: FsError) -> FsResult<FSMode> {
+        // the fs is synced, so it's as it's on device once the old copies are back
+        if journal.roll_back(self.device.as_ref()).is_ok() {
+            self.destroyed.store(false, Ordering::Release);
+        }
+        Err(e)
+    }
+
+    // every storage written anew by rekey_aside
+    fn rekey_names(&self) -> FsResult<Vec<String>> {
+        let mut names = Vec::new();
+        let used: Vec<_> = self.ibitmap.lock().iter_used().collect();
+        for iid in used {
+            let ib = self.read_itbl(iid)?;
+            if Self::data_file_ke(&ib).is_some() {
+                let di = unsafe {
+                    &*(ib.as_ptr() as *const DInodeReg)
+                };
+                names.push(hex::encode_upper(di.data_file));
+            }
+            if self.xtbl.lock().entry(iid)?.len != 0 {
+                names.push(xattr_file_name(iid)?);
+            }
+        }
+        let sb = self.sb.read();
+        names.push(hex::encode_upper(sb.itbl_name));
+        if sb.xtbl_len != 0 {
+            names.push(iid_hash_name(XTBL_IID)?);
+        }
+        if sb.ltbl_len != 0 {
+            names.push(iid_hash_name(LTBL_IID)?);
+        }
+        names.push(SB_FILE_NAME.to_string());
+        Ok(names)
+    }
+
+    // create the storage aside
+    fn rekey_stage(&self, name: &str) -> FsResult<Arc<dyn RWStorage>> {
+        self.device.create_rw_storage(&staged_name(name))
+    }
+
+    // rekey an htree of phy_len blocks aside, return the new copy and its root ke
+    fn rekey_htree(
+        &self, name: &str, phy_len: u64, ke: KeyEntry,
+    ) -> FsResult<(Arc<dyn RWStorage>, KeyEntry)> {
+        let to = self.rekey_stage(name)?;
+        let mut htree = RWHashTree::new(
+            Some(RW_CACHE_CAP_DEFAULT_ITBL),
+            self.device.open_rw_storage(name)?,
+            mht::Shape::full(self.device.blk_sz()).get_logi_nr_blk(phy_len),
+            Some(FSMode::from_key_entry(ke, true)),
+            true,
+        );
+        let ke = htree.rekey_into(&to)?.into_key_entry();
+        Ok((to, ke))
+    }
+
+    // kes are rewritten into a rekeyed table, so it's written under fresh keys once more
+    fn rekey_table(
+        to: Arc<dyn RWStorage>, phy_len: u64, ke: KeyEntry, entries: &[(usize, Vec<u8>)],
+    ) -> FsResult<KeyEntry> {
+        let shape = mht::Shape::full(to.blk_sz());
+        let mut htree = RWHashTree::new(
+            Some(RW_CACHE_CAP_DEFAULT_ITBL),
+            to,
+            shape.get_logi_nr_blk(phy_len),
+            Some(FSMode::from_key_entry(ke, true)),
+            true,
+        );
+        for (pos, entry) in entries {
+            htree.write_exact(*pos, entry)?;
+        }
+        Ok(htree.flush()?.into_key_entry())
+    }
+
+    fn rekey_aside(&self, new_root: Key128) -> FsResult<FSMode> {
+        let mut sb = SuperBlock::new(&self.sb.read().write()?)?;
+
+        // data files and xattr files of all inodes, their kes go to the tables
+        let mut inodes = Vec::new();
+        let mut xattrs = Vec::new();
+        let mut ke_digest = Hash256::default();
+        let used: Vec<_> = self.ibitmap.lock().iter_used().collect();
+        for iid in used {
+            let mut ib = self.read_itbl(iid)?;
+            if let Some(ke) = Self::data_file_ke(&ib) {
+                // ke, name and length of data file are at the same place for reg, dir and lnk
+                let di = unsafe {
+                    &*(ib.as_ptr() as *const DInodeReg)
+                };
+                let name = hex::encode_upper(di.data_file);
+                let new_ke = if get_ftype_from_mode(di.base.mode) == FileType::Lnk {
+                    // a single block, not an htree
+                    let to = self.rekey_stage(&name)?;
+                    let mut blk = self.device.open_rw_storage(&name)?.read_blk(LNK_DATA_FILE_BLK_POS)?;
+                    crypto_in(&mut blk, CryptoHint::from_key_entry(ke, true, LNK_DATA_FILE_BLK_POS))?;
+                    let key = self.key_gen.lock().gen_key(LNK_DATA_FILE_BLK_POS)?;
+                    let mode = crypto_out(&mut blk, Some(key), LNK_DATA_FILE_BLK_POS)?;
+                    to.set_len(1)?;
+                    to.write_blk(LNK_DATA_FILE_BLK_POS, &blk)?;
+                    mode.into_key_entry()
+                } else {
+                    self.rekey_htree(&name, di.len, ke)?.1
+                };
+                let di = unsafe {
+                    &mut *(ib.as_mut_ptr() as *mut DInodeReg)
+                };
+                di.data_file_ke = new_ke;
+                inodes.push((iid_to_htree_logi_pos(iid, self.inode_sz), ib.clone()));
+            }
+            Self::xor_ke_digest(&mut ke_digest, Self::ke_digest_leaf(iid, &ib)?);
+
+            let mut xattr = self.xtbl.lock().entry(iid)?;
+            if xattr.len != 0 {
+                xattr.ke = self.rekey_htree(&xattr_file_name(iid)?, xattr.len, xattr.ke)?.1;
+                xattrs.push((iid as usize * XATTR_ENTRY_SZ, Vec::from(xattr.as_ref())));
+            }
+        }
+        if sb.ke_digest.is_some() {
+            sb.ke_digest = Some(ke_digest);
+        }
+
+        let itbl_name = hex::encode_upper(sb.itbl_name);
+        let (to, ke) = self.rekey_htree(&itbl_name, sb.itbl_len as u64, sb.itbl_ke)?;
+        sb.itbl_ke = Self::rekey_table(to, sb.itbl_len as u64, ke, &inodes)?;
+        if sb.xtbl_len != 0 {
+            let name = iid_hash_name(XTBL_IID)?;
+            let (to, ke) = self.rekey_htree(&name, sb.xtbl_len as u64, sb.xtbl_ke)?;
+            sb.xtbl_ke = Self::rekey_table(to, sb.xtbl_len as u64, ke, &xattrs)?;
+        }
+        // link names only, no kes inside
+        if sb.ltbl_len != 0 {
+            let name = iid_hash_name(LTBL_IID)?;
+            sb.ltbl_ke = self.rekey_htree(&name, sb.ltbl_len as u64, sb.ltbl_ke)?.1;
+        }
+
+        // superblock file goes last, written as fsync does but only aside,
+        // so the one in place stays as it's last synced
+        let to = self.rekey_stage(SB_FILE_NAME)?;
+        let mut ibitmap_blks = self.ibitmap.lock().write()?;
+        let mut bbitmap_blks = self.bbitmap.lock().write()?;
+        let mut lbitmap_blks = self.ltbl.lock().write_slots()?;
+        let old_len = sb.ibitmap_len + sb.bbitmap_len + sb.lbitmap_len;
+        let new_len = ibitmap_blks.len() + bbitmap_blks.len() + lbitmap_blks.len();
+        to.set_len(1 + new_len as u64)?;
+        let ibitmap_start = sb.ibitmap_start;
+        let bbitmap_start = ibitmap_start + ibitmap_blks.len() as u64;
+        let lbitmap_start = bbitmap_start + bbitmap_blks.len() as u64;
+        sb.ibitmap_len = ibitmap_blks.len();
+        sb.ibitmap_ke = self.write_bitmap(&to, &mut ibitmap_blks, ibitmap_start)?;
+        sb.bbitmap_len = bbitmap_blks.len();
+        sb.bbitmap_ke = self.write_bitmap(&to, &mut bbitmap_blks, bbitmap_start)?;
+        sb.lbitmap_len = lbitmap_blks.len();
+        sb.lbitmap_ke = self.write_bitmap(&to, &mut lbitmap_blks, lbitmap_start)?;
+        {
+            let meta = self.sb_meta_for_inode.read();
+            sb.nr_data_file = meta.0;
+            sb.blocks = meta.1 + new_len - old_len;
+        }
+        sb.generation += 1;
+        let mut sb_blk = sb.write()?;
+        let mode = crypto_out(&mut sb_blk, Some(new_root), SUPERBLOCK_POS)?;
+        to.write_blk(SUPERBLOCK_POS, &sb_blk)?;
+
+        Ok(mode)
+    }
+}
@@ -4,6 +4,13 @@ use super::*;
 
 
 pub const SUPERBLOCK_POS: u64 = 0;
+/// max number of inodes replaced by rename and not released yet
+pub const MAX_REPLACED: usize = 16;
+/// key entries of all bitmap blocks follow DSuperBlockBase in the superblock block,
+/// so inode, block and link slot bitmaps can take no more blocks than this together
+pub const fn max_bitmap_blks(bsize: usize) -> usize {
+    (bsize - size_of::<DSuperBlockBase>()) / size_of::<KeyEntry>()
+}
 
 #[derive(Default)]
 pub struct SuperBlock {
@@ -13,6 +20,8 @@ pub struct SuperBlock {
     pub encrypted: bool,
     /// File system type
     pub magic: u64,
+    /// version of on-disk layout, only the current one is opened
+    pub version: u32,
     /// File system block size
     pub bsize: usize,
     /// Total number of blocks on file system in units of `frsize`
@@ -27,12 +36,38 @@ pub struct SuperBlock {
     pub ibitmap_len: usize,
     /// ibitmap blocks ke
     pub ibitmap_ke: Vec<KeyEntry>,
+    /// block bitmap len in blk, it follows ibitmap
+    pub bbitmap_len: usize,
+    /// block bitmap blocks ke
+    pub bbitmap_ke: Vec<KeyEntry>,
+    /// link slot bitmap len in blk, it follows block bitmap
+    pub lbitmap_len: usize,
+    /// link slot bitmap blocks ke
+    pub lbitmap_ke: Vec<KeyEntry>,
     /// itbl data file hash name
     pub itbl_name: Hash256,
     /// length of itbl data file including htree contents
     pub itbl_len: usize,
     /// itbl htree key entry
     pub itbl_ke: KeyEntry,
+    /// digest over key entries of all data files, None if not enabled
+    pub ke_digest: Option<Hash256>,
+    /// size of an inode in itbl, see disk::is_valid_inode_sz
+    pub inode_sz: usize,
+    /// assigned when the image is built and never changed
+    pub uuid: ImageUuid,
+    /// bumped on every destroy
+    pub generation: u64,
+    /// length of link table data file including htree, 0 if it's not created yet
+    pub ltbl_len: usize,
+    /// link table htree key entry
+    pub ltbl_ke: KeyEntry,
+    /// length of xattr table data file including htree, 0 if it's not created yet
+    pub xtbl_len: usize,
+    /// xattr table htree key entry
+    pub xtbl_ke: KeyEntry,
+    /// inodes taken away by rename_replace and not released yet, released on mount
+    pub replaced: Vec<InodeID>,
 }
 
 #[repr(C)]
@@ -40,6 +75,8 @@ pub struct SuperBlock {
 pub struct DSuperBlockBase {
     pub nr_data_file: u64,
     pub magic: u64,
+    // where bsize was in images before it's recorded, so they never match
+    pub version: u64,
     pub bsize: u64,
     pub files: u64,
     pub namemax: u64,
@@ -50,35 +87,70 @@ pub struct DSuperBlockBase {
     pub itbl_name: Hash256,
     pub itbl_len: u64, // including htree
     pub itbl_ke: KeyEntry,
+    pub ke_digest_on: bool,
+    pub ke_digest: Hash256,
+    // always full key entries, short ones are for ro images only
+    pub ke_sz: u64,
+    pub inode_sz: u64,
+    pub bbitmap_len: u64,
+    pub uuid: ImageUuid,
+    pub generation: u64,
+    pub lbitmap_len: u64,
+    pub ltbl_len: u64, // including htree
+    pub ltbl_ke: KeyEntry,
+    pub xtbl_len: u64, // including htree
+    pub xtbl_ke: KeyEntry,
+    pub nr_replaced: u64,
+    pub replaced: [u64; MAX_REPLACED],
     // pub ibitmap_ke: [KeyEntry],
+    // pub bbitmap_ke: [KeyEntry],
+    // pub lbitmap_ke: [KeyEntry],
 }
 rw_as_blob!(DSuperBlockBase);
 
 impl SuperBlock {
-    pub fn new(raw_blk: Block) -> FsResult<Self> {
-        let dsb_base = unsafe {
-            &*(raw_blk.as_ptr() as *const DSuperBlockBase)
+    // raw_blk is as long as the block size the image is opened with
+    pub fn new(raw_blk: &Block) -> FsResult<Self> {
+        // raw_blk is a byte array, it may not be aligned for DSuperBlockBase
+        let dsb_base = &unsafe {
+            core::ptr::read_unaligned(raw_blk.as_ptr() as *const DSuperBlockBase)
         };
 
+        // layout of another version, not corrupted, the rest may be elsewhere
+        if dsb_base.magic == super::RWFS_MAGIC && dsb_base.version != FS_LAYOUT_VERSION as u64 {
+            return Err(FsError::NotSupported);
+        }
+
         // check constants
         if dsb_base.magic != super::RWFS_MAGIC
-            || dsb_base.bsize != BLK_SZ as u64
+            || dsb_base.bsize != raw_blk.len() as u64
             || dsb_base.namemax != NAME_MAX
-            || dsb_base.ibitmap_start != 1 {
+            || dsb_base.ke_sz != KEY_ENTRY_SZ as u64
+            || !disk::is_valid_inode_sz(dsb_base.inode_sz as usize)
+            || dsb_base.ibitmap_start != 1
+            || dsb_base.nr_replaced > MAX_REPLACED as u64 {
             return Err(new_error!(FsError::SuperBlockCheckFailed))
         }
-
-        let ibitmap_ke = Vec::from(unsafe {
+        let nr_ke = dsb_base.ibitmap_len
+            .saturating_add(dsb_base.bbitmap_len)
+            .saturating_add(dsb_base.lbitmap_len);
+        if nr_ke > max_bitmap_blks(raw_blk.len()) as u64 {
+            return Err(new_error!(FsError::SuperBlockCheckFailed))
+        }
+        let ke_list: &[KeyEntry] = unsafe {
             core::slice::from_raw_parts(
                 raw_blk[size_of::<DSuperBlockBase>()..].as_ptr() as *const KeyEntry,
-                dsb_base.ibitmap_len as usize,
+                nr_ke as usize,
             )
-        });
+        };
+        let (ibitmap_ke, rest) = ke_list.split_at(dsb_base.ibitmap_len as usize);
+        let (bbitmap_ke, lbitmap_ke) = rest.split_at(dsb_base.bbitmap_len as usize);
 
         Ok(SuperBlock {
             nr_data_file: dsb_base.nr_data_file as usize,
             encrypted: dsb_base.encrypted,
             magic: dsb_base.magic,
+            version: dsb_base.version as u32,
             bsize: dsb_base.bsize as usize,
             blocks: dsb_base.blocks as usize,
             files: dsb_base.files as usize,
@@ -88,19 +160,37 @@ impl SuperBlock {
             itbl_name: dsb_base.itbl_name,
             itbl_len: dsb_base.itbl_len as usize,
             itbl_ke: dsb_base.itbl_ke,
-            ibitmap_ke,
+            ke_digest: if dsb_base.ke_digest_on {
+                Some(dsb_base.ke_digest)
+            } else {
+                None
+            },
+            ibitmap_ke: Vec::from(ibitmap_ke),
+            bbitmap_len: dsb_base.bbitmap_len as usize,
+            bbitmap_ke: Vec::from(bbitmap_ke),
+            lbitmap_len: dsb_base.lbitmap_len as usize,
+            lbitmap_ke: Vec::from(lbitmap_ke),
+            inode_sz: dsb_base.inode_sz as usize,
+            uuid: dsb_base.uuid,
+            generation: dsb_base.generation,
+            ltbl_len: dsb_base.ltbl_len as usize,
+            ltbl_ke: dsb_base.ltbl_ke,
+            xtbl_len: dsb_base.xtbl_len as usize,
+            xtbl_ke: dsb_base.xtbl_ke,
+            replaced: Vec::from(&dsb_base.replaced[..dsb_base.nr_replaced as usize]),
         })
     }
 
-    pub fn get_fsinfo(&self) -> FsResult<FsInfo> {
+    // inode totals come from the inode bitmap
+    pub fn get_fsinfo(&self, files: usize, ffree: usize) -> FsResult<FsInfo> {
         Ok(FsInfo {
             magic: self.magic,
             bsize: self.bsize,
             blocks: self.blocks,
             bfree: self.get_bfree(),
             bavail: self.get_bfree(),
-            files: self.files,
-            ffree: usize::MAX - self.files,
+            files,
+            ffree,
             frsize: self.bsize,
             namemax: self.namemax,
         })
@@ -112,15 +202,17 @@ impl SuperBlock {
         self.nr_data_file * 64
     }
 
-    pub fn write(&self) -> FsResult<Block> {
-        let mut raw_blk = [0u8; BLK_SZ];
+    pub fn write(&self) -> FsResult<Box<Block>> {
+        let mut raw_blk = new_blk(self.bsize);
 
-        let dsb_base = unsafe {
-            &mut *(raw_blk.as_mut_ptr() as *mut DSuperBlockBase)
+        let mut dsb = unsafe {
+            core::mem::zeroed::<DSuperBlockBase>()
         };
+        let dsb_base = &mut dsb;
 
         dsb_base.nr_data_file = self.nr_data_file as u64;
         dsb_base.magic = self.magic;
+        dsb_base.version = FS_LAYOUT_VERSION as u64;
         dsb_base.bsize = self.bsize as u64;
         dsb_base.files = self.files as u64;
         dsb_base.namemax = self.namemax as u64;
@@ -131,18 +223,41 @@ impl SuperBlock {
         dsb_base.itbl_name = self.itbl_name;
         dsb_base.itbl_len = self.itbl_len as u64;
         dsb_base.itbl_ke = self.itbl_ke;
+        dsb_base.ke_digest_on = self.ke_digest.is_some();
+        dsb_base.ke_digest = self.ke_digest.unwrap_or_default();
+        dsb_base.ke_sz = KEY_ENTRY_SZ as u64;
+        dsb_base.inode_sz = self.inode_sz as u64;
+        dsb_base.bbitmap_len = self.bbitmap_ke.len() as u64;
+        dsb_base.uuid = self.uuid;
+        dsb_base.generation = self.generation;
+        dsb_base.lbitmap_len = self.lbitmap_ke.len() as u64;
+        dsb_base.ltbl_len = self.ltbl_len as u64;
+        dsb_base.ltbl_ke = self.ltbl_ke;
+        dsb_base.xtbl_len = self.xtbl_len as u64;
+        dsb_base.xtbl_ke = self.xtbl_ke;
+        assert!(self.replaced.len() <= MAX_REPLACED);
+        dsb_base.nr_replaced = self.replaced.len() as u64;
+        dsb_base.replaced[..self.replaced.len()].copy_from_slice(&self.replaced);
+        unsafe {
+            core::ptr::write_unaligned(raw_blk.as_mut_ptr() as *mut DSuperBlockBase, dsb);
+        }
 
-        let bytes = self.ibitmap_ke.len() * size_of::<KeyEntry>();
-        let end = size_of::<DSuperBlockBase>() + bytes;
-        assert!(end <= BLK_SZ);
-        raw_blk[size_of::<DSuperBlockBase>()..end].copy_from_slice(
-            unsafe {
-                core::slice::from_raw_parts(
-                    self.ibitmap_ke.as_ptr() as *const u8,
-                    bytes,
-                )
-            }
-        );
+        if self.ibitmap_ke.len() + self.bbitmap_ke.len() + self.lbitmap_ke.len() > max_bitmap_blks(self.bsize) {
+            return Err(FsError::FileTooLarge);
+        }
+        let mut end = size_of::<DSuperBlockBase>();
+        for ke_list in [&self.ibitmap_ke, &self.bbitmap_ke, &self.lbitmap_ke] {
+            let bytes = ke_list.len() * size_of::<KeyEntry>();
+            raw_blk[end..end + bytes].copy_from_slice(
+                unsafe {
+                    core::slice::from_raw_parts(
+                        ke_list.as_ptr() as *const u8,
+                        bytes,
+                    )
+                }
+            );
+            end += bytes;
+        }
 
         Ok(raw_blk)
     }
@@ -0,0 +1,205 @@
+use crate::*;
+use crate::htree::*;
+use crate::storage::*;
+use super::*;
+use alloc::string::String;
+use alloc::vec;
+
+// each xattr is packed as name length(u8), value length(u32), name and value
+const XATTR_HDR_SZ: usize = 1 + size_of::<u32>();
+
+pub type XattrList = Vec<(String, Vec<u8>)>;
+
+/// entries of xattr files of all inodes, xattrs of an inode are read and written
+/// as a whole, so every change is written to its xattr file right away
+pub struct XattrTable {
+    // created when the first xattr is set
+    data: Option<RWHashTree>,
+    htree_org_len: u64, // in blocks
+    encrypted: bool,
+    sb_meta: Arc<RwLock<(usize, usize)>>,
+    device: Arc<dyn Device>,
+}
+
+pub fn xattr_file_name(iid: InodeID) -> FsResult<String> {
+    let mut buf = [0u8; 2 * size_of::<InodeID>()];
+    buf[..size_of::<InodeID>()].copy_from_slice(&XTBL_IID.to_le_bytes());
+    buf[size_of::<InodeID>()..].copy_from_slice(&iid.to_le_bytes());
+    Ok(hex::encode_upper(sha3_256_any(&buf)?))
+}
+
+fn pack(attrs: &[(String, Vec<u8>)]) -> Vec<u8> {
+    let mut buf = Vec::new();
+    for (name, value) in attrs {
+        buf.push(name.len() as u8);
+        buf.extend_from_slice(&(value.len() as u32).to_le_bytes());
+        buf.extend_from_slice(name.as_bytes());
+        buf.extend_from_slice(value);
+    }
+    buf
+}
+
+fn unpack(mut buf: &[u8]) -> FsResult<XattrList> {
+    let mut attrs = Vec::new();
+    while !buf.is_empty() {
+        if buf.len() < XATTR_HDR_SZ {
+            return Err(FsError::Corrupted);
+        }
+        let name_len = buf[0] as usize;
+        let value_len = u32::from_le_bytes(buf[1..XATTR_HDR_SZ].try_into().unwrap()) as usize;
+        buf = &buf[XATTR_HDR_SZ..];
+        if buf.len() < name_len + value_len {
+            return Err(FsError::Corrupted);
+        }
+        let name = core::str::from_utf8(&buf[..name_len]).map_err(|_| FsError::Corrupted)?;
+        attrs.push((String::from(name), Vec::from(&buf[name_len..name_len + value_len])));
+        buf = &buf[name_len + value_len..];
+    }
+    Ok(attrs)
+}
+
+impl XattrTable {
+    pub fn new(
+        len: u64,
+        ke: KeyEntry,
+        encrypted: bool,
+        sb_meta: Arc<RwLock<(usize, usize)>>,
+        device: Arc<dyn Device>,
+    ) -> FsResult<Self> {
+        let data = if len == 0 {
+            None
+        } else {
+            let storage = device.open_rw_storage(&iid_hash_name(XTBL_IID)?)?;
+            let shape = mht::Shape::full(device.blk_sz());
+            if shape.check_phy_nr_blk(len).is_err()
+                || storage.get_len()? != blk2byte!(len, shape.blk_sz()) {
+                return Err(new_error!(FsError::SuperBlockCheckFailed));
+            }
+            Some(RWHashTree::new(
+                Some(RW_CACHE_CAP_DEFAULT_XTBL),
+                storage,
+                shape.get_logi_nr_blk(len),
+                Some(FSMode::from_key_entry(ke, encrypted)),
+                encrypted,
+            ))
+        };
+        Ok(Self {
+            data,
+            htree_org_len: len,
+            encrypted,
+            sb_meta,
+            device,
+        })
+    }
+
+    // an inode beyond the table has no xattr
+    pub fn entry(&mut self, iid: InodeID) -> FsResult<DXattrEntry> {
+        let mut entry = DXattrEntry::default();
+        let pos = iid as usize * XATTR_ENTRY_SZ;
+        if let Some(data) = self.data.as_mut() {
+            if pos + XATTR_ENTRY_SZ <= blk2byte!(data.logi_len, data.shape().blk_sz()) as usize {
+                data.read_exact(pos, entry.as_mut())?;
+            }
+        }
+        Ok(entry)
+    }
+
+    fn set_entry(&mut self, iid: InodeID, entry: &DXattrEntry) -> FsResult<()> {
+        if self.data.is_none() {
+            let storage = self.device.create_rw_storage(&iid_hash_name(XTBL_IID)?)?;
+            nf_nb_change(&self.sb_meta, 1, 0)?;
+            self.data = Some(RWHashTree::new(
+                Some(RW_CACHE_CAP_DEFAULT_XTBL),
+                storage,
+                0,
+                None,
+                self.encrypted,
+            ));
+        }
+        self.data.as_mut().unwrap().write_exact(iid as usize * XATTR_ENTRY_SZ, entry.as_ref())?;
+        Ok(())
+    }
+
+    // xattr file of an inode as an htree, along with its entry
+    fn open_file(&mut self, iid: InodeID) -> FsResult<Option<(RWHashTree, DXattrEntry)>> {
+        let entry = self.entry(iid)?;
+        if entry.len == 0 {
+            return Ok(None);
+        }
+        let storage = self.device.open_rw_storage(&xattr_file_name(iid)?)?;
+        let shape = mht::Shape::full(storage.blk_sz());
+        if shape.check_phy_nr_blk(entry.len).is_err()
+            || storage.get_len()? != blk2byte!(entry.len, shape.blk_sz())
+            || entry.size.div_ceil(shape.blk_sz() as u64) > shape.get_logi_nr_blk(entry.len) {
+            return Err(FsError::Corrupted);
+        }
+        let htree = RWHashTree::new(
+            Some(RW_CACHE_CAP_DEFAULT_XTBL),
+            storage,
+            shape.get_logi_nr_blk(entry.len),
+            Some(FSMode::from_key_entry(entry.ke, self.encrypted)),
+            self.encrypted,
+        );
+        Ok(Some((htree, entry)))
+    }
+
+    // all xattrs of an inode in the order they are set
+    pub fn get(&mut self, iid: InodeID) -> FsResult<XattrList> {
+        let Some((mut htree, entry)) = self.open_file(iid)? else {
+            return Ok(Vec::new());
+        };
+        let mut buf = vec![0u8; entry.size as usize];
+        if htree.read_exact(0, &mut buf)? != buf.len() {
+            return Err(FsError::Corrupted);
+        }
+        unpack(&buf)
+    }
+
+    // replace all xattrs of an inode, the xattr file is removed if there's none left
+    pub fn set(&mut self, iid: InodeID, attrs: &[(String, Vec<u8>)]) -> FsResult<()> {
+        let fname = xattr_file_name(iid)?;
+        let old = self.open_file(iid)?;
+        if attrs.is_empty() {
+            if let Some((htree, entry)) = old {
+                drop(htree);
+                self.device.remove_storage(&fname)?;
+                nf_nb_change(&self.sb_meta, -1, -(entry.len as isize))?;
+                self.set_entry(iid, &DXattrEntry::default())?;
+            }
+            return Ok(());
+        }
+
+        let (mut htree, old_len) = match old {
+            Some((htree, entry)) => (htree, entry.len),
+            None => {
+                let storage = self.device.create_rw_storage(&fname)?;
+                nf_nb_change(&self.sb_meta, 1, 0)?;
+                (RWHashTree::new(Some(RW_CACHE_CAP_DEFAULT_XTBL), storage, 0, None, self.encrypted), 0)
+            }
+        };
+        let packed = pack(attrs);
+        htree.resize(packed.len().div_ceil(htree.shape().blk_sz()) as u64)?;
+        htree.write_exact(0, &packed)?;
+        let ke = htree.flush()?.into_key_entry();
+        let len = htree.shape().get_phy_nr_blk(htree.logi_len);
+        nf_nb_change(&self.sb_meta, 0, len as isize - old_len as isize)?;
+        self.set_entry(iid, &DXattrEntry {
+            ke,
+            len,
+            size: packed.len() as u64,
+            ..Default::default()
+        })
+    }
+
+    // return length and key entry of the htree to keep in superblock
+    pub fn flush(&mut self) -> FsResult<(usize, KeyEntry)> {
+        let Some(data) = self.data.as_mut() else {
+            return Ok((0, KeyEntry::default()));
+        };
+        let ke = data.flush()?.into_key_entry();
+        let len = data.shape().get_phy_nr_blk(data.logi_len);
+        nf_nb_change(&self.sb_meta, 0, len as isize - self.htree_org_len as isize)?;
+        self.htree_org_len = len;
+        Ok((len as usize, ke))
+    }
+}
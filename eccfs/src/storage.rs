@@ -2,39 +2,78 @@ use crate::*;
 
 #[cfg(feature = "std")]
 use std::{
-    fs::{File, OpenOptions},
+    fs::{self, File, OpenOptions},
     io::{prelude::*, SeekFrom},
-    path::Path,
+    path::{Path, PathBuf},
 };
 #[cfg(feature = "std")]
 use std::sync::Mutex;
 #[cfg(feature = "std")]
 use std::os::unix::fs::FileExt;
+#[cfg(feature = "std")]
+use std::vec::Vec;
+#[cfg(feature = "std")]
+use core::mem::size_of;
+#[cfg(feature = "std")]
+use std::sync::mpsc::{sync_channel, SyncSender};
+#[cfg(feature = "std")]
+use core::sync::atomic::{AtomicUsize, Ordering};
 
 extern crate alloc;
 use alloc::sync::Arc;
+use alloc::boxed::Box;
+use alloc::string::{String, ToString};
+use alloc::collections::BTreeMap;
 
 pub trait ROStorage: Send + Sync {
-    fn read_blk(&self, pos: u64) -> FsResult<Block> {
-        let mut blk = [0u8; BLK_SZ] as Block;
+    // block size in bytes, every block read or written here is this long
+    fn blk_sz(&self) -> usize;
+
+    fn read_blk(&self, pos: u64) -> FsResult<Box<Block>> {
+        let mut blk = new_blk(self.blk_sz());
         self.read_blk_to(pos, &mut blk)?;
         Ok(blk)
     }
 
     fn read_blk_to(&self, pos: u64, to: &mut Block) -> FsResult<()>;
+
+    // read adjacent blocks from pos on into one buffer of whole blocks,
+    // backends with high latency should override this to issue one large read
+    fn read_blks_to(&self, pos: u64, to: &mut [u8]) -> FsResult<()> {
+        for (i, blk) in to.chunks_exact_mut(self.blk_sz()).enumerate() {
+            self.read_blk_to(pos + i as u64, blk)?;
+        }
+        Ok(())
+    }
+
+    // told once a block read from here passed its check,
+    // backends keeping what they read should keep only checked blocks
+    fn confirm_blk(&self, _pos: u64) -> FsResult<()> {
+        Ok(())
+    }
 }
 
 pub trait RWStorage: ROStorage + Send + Sync {
     fn write_blk(&self, pos: u64, from: &Block) -> FsResult<()>;
     fn get_len(&self) -> FsResult<u64>;
     fn set_len(&self, nr_blk: u64) -> FsResult<()>;
+
+    // tell the backend these blocks are no longer used, contents become undefined
+    fn discard(&self, _pos: u64, _nr_blk: u64) -> FsResult<()> {
+        Ok(())
+    }
 }
 
 // for rw storage only, it should remember the fs_dir path
 pub trait Device: Send + Sync {
+    // block size of every storage on it
+    fn blk_sz(&self) -> usize;
     fn open_rw_storage(&self, path: &str) -> FsResult<Arc<dyn RWStorage>>;
     fn create_rw_storage(&self, path: &str) -> FsResult<Arc<dyn RWStorage>>;
     fn remove_storage(&self, path: &str) -> FsResult<()>;
+    // replace `to` with `from` atomically, `to` may or may not exist,
+    // so that a storage can be written anew aside and then swapped in
+    fn rename_storage(&self, from: &str, to: &str) -> FsResult<()>;
     fn get_storage_len(&self, path: &str) -> FsResult<u64>;
     fn nr_storage(&self) -> FsResult<usize>;
 }
@@ -43,25 +82,88 @@ pub trait Device: Send + Sync {
 pub struct FileStorage {
     f: Mutex<File>,
     writable: bool,
+    bsize: usize,
 }
 
 #[cfg(feature = "std")]
 impl FileStorage {
     #[allow(unused)]
-    pub fn new(path: &Path, writable: bool) -> FsResult<Self> {
+    pub fn new(path: &Path, writable: bool, bsize: usize) -> FsResult<Self> {
+        check_blk_sz(bsize)?;
         let f = io_try!(OpenOptions::new().read(true).write(writable).open(path));
 
         Ok(Self {
             f: Mutex::new(f),
             writable,
+            bsize,
         })
     }
 }
 
 #[cfg(feature = "std")]
 impl ROStorage for FileStorage {
+    fn blk_sz(&self) -> usize {
+        self.bsize
+    }
+
+    fn read_blk_to(&self, pos: u64, to: &mut Block) -> FsResult<()> {
+        io_try!(mutex_lock!(self.f).read_exact_at(to, blk2byte!(pos, self.bsize)));
+        Ok(())
+    }
+
+    fn read_blks_to(&self, pos: u64, to: &mut [u8]) -> FsResult<()> {
+        io_try!(mutex_lock!(self.f).read_exact_at(to, blk2byte!(pos, self.bsize)));
+        Ok(())
+    }
+}
+
+// maps the whole image file read only, blocks are copied out of the mapping,
+// so there's no syscall per read
+#[cfg(feature = "std")]
+pub struct MmapStorage {
+    // None for an empty file, which can't be mapped
+    map: Option<memmap2::Mmap>,
+    bsize: usize,
+}
+
+#[cfg(feature = "std")]
+impl MmapStorage {
+    pub fn new(path: &Path, bsize: usize) -> FsResult<Self> {
+        check_blk_sz(bsize)?;
+        let f = io_try!(File::open(path));
+        if io_try!(f.metadata()).len() == 0 {
+            return Ok(Self { map: None, bsize });
+        }
+        // the image is not changed under a rofs, the mapping stays valid after the file is closed
+        let map = io_try!(unsafe { memmap2::Mmap::map(&f) });
+        Ok(Self { map: Some(map), bsize })
+    }
+
+    fn slice(&self, pos: u64, len: usize) -> FsResult<&[u8]> {
+        let map = self.map.as_deref().unwrap_or(&[]);
+        let range = usize::try_from(pos).ok()
+            .and_then(|pos| pos.checked_mul(self.bsize))
+            .and_then(|start| Some(start..start.checked_add(len)?));
+        match range {
+            Some(range) if range.end <= map.len() => Ok(&map[range]),
+            _ => Err(FsError::UnexpectedEof),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl ROStorage for MmapStorage {
+    fn blk_sz(&self) -> usize {
+        self.bsize
+    }
+
     fn read_blk_to(&self, pos: u64, to: &mut Block) -> FsResult<()> {
-        io_try!(mutex_lock!(self.f).read_exact_at(to, blk2byte!(pos)));
+        to.copy_from_slice(self.slice(pos, to.len())?);
+        Ok(())
+    }
+
+    fn read_blks_to(&self, pos: u64, to: &mut [u8]) -> FsResult<()> {
+        to.copy_from_slice(self.slice(pos, to.len())?);
         Ok(())
     }
 }
@@ -74,7 +176,7 @@ impl RWStorage for FileStorage {
         }
 
         let cur_len = self.get_len()?;
-        let offset = blk2byte!(pos);
+        let offset = blk2byte!(pos, self.bsize);
 
         // if offset >= cur_len {
         //     debug!("bad: write pos {} cur_len {}", pos, cur_len);
@@ -85,7 +187,7 @@ impl RWStorage for FileStorage {
     }
 
     fn set_len(&self, nr_blk: u64) -> FsResult<()> {
-        let len = blk2byte!(nr_blk);
+        let len = blk2byte!(nr_blk, self.bsize);
         io_try!(mutex_lock!(self.f).set_len(len));
         Ok(())
     }
@@ -93,4 +195,787 @@ impl RWStorage for FileStorage {
     fn get_len(&self) -> FsResult<u64> {
         Ok(io_try!(mutex_lock!(self.f).seek(SeekFrom::End(0))))
     }
+
+    fn discard(&self, pos: u64, nr_blk: u64) -> FsResult<()> {
+        use std::os::unix::io::AsRawFd;
+
+        if nr_blk == 0 {
+            return Ok(());
+        }
+        let ret = unsafe {
+            libc::fallocate(
+                mutex_lock!(self.f).as_raw_fd(),
+                libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE,
+                blk2byte!(pos, self.bsize) as libc::off_t,
+                blk2byte!(nr_blk, self.bsize) as libc::off_t,
+            )
+        };
+        if ret != 0 {
+            let err = std::io::Error::last_os_error();
+            // not every fs can punch holes, discard is only a hint
+            if err.raw_os_error() != Some(libc::EOPNOTSUPP) {
+                return Err(FsError::IOError(err));
+            }
+        }
+        Ok(())
+    }
+}
+
+// retry storage operations on transient io errors
+pub struct RetryStorage<S: ?Sized> {
+    inner: Arc<S>,
+    max_retry: usize,
+    // backoff before the first retry, doubled on every retry, in milliseconds
+    backoff_ms: u64,
+}
+
+impl<S: ?Sized> RetryStorage<S> {
+    pub fn new(inner: Arc<S>, max_retry: usize, backoff_ms: u64) -> Self {
+        Self {
+            inner,
+            max_retry,
+            backoff_ms,
+        }
+    }
+
+    fn retry<T>(&self, mut op: impl FnMut() -> FsResult<T>) -> FsResult<T> {
+        let mut backoff = self.backoff_ms;
+        let mut nr_retry = 0;
+        loop {
+            match op() {
+                Err(e) if nr_retry < self.max_retry && is_retryable(&e) => {
+                    nr_retry += 1;
+                    backoff_for(backoff);
+                    backoff = backoff.saturating_mul(2);
+                }
+                res => return res,
+            }
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+fn is_retryable(e: &FsError) -> bool {
+    use std::io::ErrorKind;
+    match e {
+        FsError::IOError(ioe) => {
+            matches!(
+                ioe.kind(),
+                ErrorKind::Interrupted | ErrorKind::WouldBlock | ErrorKind::TimedOut
+            ) || matches!(
+                ioe.raw_os_error(),
+                Some(libc::EAGAIN) | Some(libc::EINTR) | Some(libc::EIO)
+            )
+        }
+        _ => false,
+    }
+}
+
+#[cfg(not(feature = "std"))]
+fn is_retryable(e: &FsError) -> bool {
+    // no detail in no_std io errors
+    matches!(e, FsError::IOError)
+}
+
+#[cfg(feature = "std")]
+fn backoff_for(ms: u64) {
+    std::thread::sleep(std::time::Duration::from_millis(ms));
+}
+
+#[cfg(not(feature = "std"))]
+fn backoff_for(ms: u64) {
+    for _ in 0..ms.saturating_mul(1000) {
+        core::hint::spin_loop();
+    }
+}
+
+impl<S: ROStorage + ?Sized> ROStorage for RetryStorage<S> {
+    fn blk_sz(&self) -> usize {
+        self.inner.blk_sz()
+    }
+
+    fn read_blk_to(&self, pos: u64, to: &mut Block) -> FsResult<()> {
+        self.retry(|| self.inner.read_blk_to(pos, to))
+    }
+
+    fn confirm_blk(&self, pos: u64) -> FsResult<()> {
+        self.inner.confirm_blk(pos)
+    }
+}
+
+impl<S: RWStorage + ?Sized> RWStorage for RetryStorage<S> {
+    fn write_blk(&self, pos: u64, from: &Block) -> FsResult<()> {
+        self.retry(|| self.inner.write_blk(pos, from))
+    }
+
+    fn get_len(&self) -> FsResult<u64> {
+        self.retry(|| self.inner.get_len())
+    }
+
+    fn set_len(&self, nr_blk: u64) -> FsResult<()> {
+        self.retry(|| self.inner.set_len(nr_blk))
+    }
+
+    fn discard(&self, pos: u64, nr_blk: u64) -> FsResult<()> {
+        self.retry(|| self.inner.discard(pos, nr_blk))
+    }
+}
+
+#[cfg(feature = "std")]
+pub type FetchFn = dyn Fn(u64, &mut Block) -> FsResult<()> + Send + Sync;
+
+// a read only storage with only a part of its blocks present locally,
+// e.g. an image on a remote server. absent blocks are fetched on first read
+// and kept in a cache dir once the reader confirms them. nothing is checked here,
+// the htree reading the block checks it against its parent, so a bad fetch
+// fails there and is never confirmed.
+#[cfg(feature = "std")]
+pub struct LazyStorage {
+    fetch: Box<FetchFn>,
+    state: Mutex<LazyState>,
+    bsize: usize,
+}
+
+#[cfg(feature = "std")]
+struct LazyState {
+    // fetched blocks, at their own position, holes elsewhere
+    blocks: File,
+    // positions of fetched blocks, appended on every fetch
+    present_log: File,
+    present: alloc::collections::BTreeSet<u64>,
+    // fetched blocks waiting for their check, a failed one is fetched again
+    pending: BTreeMap<u64, Box<Block>>,
+}
+
+#[cfg(feature = "std")]
+pub const LAZY_BLOCKS_FILE: &str = "blocks";
+#[cfg(feature = "std")]
+pub const LAZY_PRESENT_FILE: &str = "present";
+
+#[cfg(feature = "std")]
+impl LazyStorage {
+    // blocks fetched before into cache_dir are reused
+    pub fn new(cache_dir: &Path, fetch: Box<FetchFn>, bsize: usize) -> FsResult<Self> {
+        check_blk_sz(bsize)?;
+        let open = |name| OpenOptions::new()
+            .read(true).write(true).create(true).truncate(false)
+            .open(cache_dir.join(name));
+        let blocks = io_try!(open(LAZY_BLOCKS_FILE));
+        let mut present_log = io_try!(open(LAZY_PRESENT_FILE));
+
+        let mut log = Vec::new();
+        io_try!(present_log.read_to_end(&mut log));
+        // drop a torn tail left by a crash
+        let present = log.chunks_exact(size_of::<u64>()).map(
+            |b| u64::from_le_bytes(b.try_into().unwrap())
+        ).collect();
+        io_try!(present_log.set_len((log.len() - log.len() % size_of::<u64>()) as u64));
+        io_try!(present_log.seek(SeekFrom::End(0)));
+
+        Ok(Self {
+            fetch,
+            state: Mutex::new(LazyState {
+                blocks,
+                present_log,
+                present,
+                pending: BTreeMap::new(),
+            }),
+            bsize,
+        })
+    }
+
+    pub fn nr_present(&self) -> FsResult<usize> {
+        Ok(mutex_lock!(self.state).present.len())
+    }
+}
+
+#[cfg(feature = "std")]
+impl ROStorage for LazyStorage {
+    fn blk_sz(&self) -> usize {
+        self.bsize
+    }
+
+    fn read_blk_to(&self, pos: u64, to: &mut Block) -> FsResult<()> {
+        {
+            let state = mutex_lock!(self.state);
+            if state.present.contains(&pos) {
+                io_try!(state.blocks.read_exact_at(to, blk2byte!(pos, self.bsize)));
+                return Ok(());
+            }
+        }
+
+        // a slow fetch should not hold back reads of present blocks
+        (self.fetch)(pos, to)?;
+        mutex_lock!(self.state).pending.insert(pos, Box::from(&*to));
+        Ok(())
+    }
+
+    fn confirm_blk(&self, pos: u64) -> FsResult<()> {
+        let mut state = mutex_lock!(self.state);
+        let blk = match state.pending.remove(&pos) {
+            Some(blk) => blk,
+            None => return Ok(()),
+        };
+        if state.present.contains(&pos) {
+            return Ok(());
+        }
+        // block goes to cache before it's recorded, so a recorded block is always there
+        io_try!(state.blocks.write_all_at(&blk, blk2byte!(pos, self.bsize)));
+        io_try!(state.present_log.write_all(&pos.to_le_bytes()));
+        state.present.insert(pos);
+        Ok(())
+    }
+}
+
+// learns strides of physical reads over the whole image and reads the
+// likely next blocks ahead on a background thread. it only warms its own
+// buffer, blocks still go through htree verification when they are used.
+#[cfg(feature = "std")]
+pub struct PrefetchStorage {
+    inner: Arc<dyn ROStorage>,
+    // blocks read ahead of each detected stride
+    depth: usize,
+    // last read position and the stride that led to it
+    recent: Mutex<(Option<u64>, Option<i64>)>,
+    buf: Arc<PrefetchBuf>,
+    tx: Mutex<SyncSender<u64>>,
+    hits: AtomicUsize,
+}
+
+#[cfg(feature = "std")]
+struct PrefetchBuf {
+    // blocks read ahead, with the order they came in
+    blks: Mutex<BTreeMap<u64, (u64, Box<Block>)>>,
+    next_seq: AtomicUsize,
+    cap: usize,
+}
+
+#[cfg(feature = "std")]
+impl PrefetchBuf {
+    fn fill(&self, inner: &dyn ROStorage, pos: u64) -> FsResult<()> {
+        if mutex_lock!(self.blks).contains_key(&pos) {
+            return Ok(());
+        }
+        let blk = inner.read_blk(pos)?;
+
+        let seq = self.next_seq.fetch_add(1, Ordering::Relaxed) as u64;
+        let mut blks = mutex_lock!(self.blks);
+        // a full buffer makes room by dropping the oldest read ahead, which is
+        // the least likely to be read still, new strides keep being prefetched
+        while blks.len() >= self.cap {
+            let oldest = *blks.iter().min_by_key(|(_, (seq, _))| *seq).unwrap().0;
+            blks.remove(&oldest);
+        }
+        blks.insert(pos, (seq, blk));
+        Ok(())
+    }
+}
+
+#[cfg(feature = "std")]
+impl PrefetchStorage {
+    pub fn new(inner: Arc<dyn ROStorage>, depth: usize) -> Self {
+        let buf = Arc::new(PrefetchBuf {
+            blks: Mutex::new(BTreeMap::new()),
+            next_seq: AtomicUsize::new(0),
+            cap: depth.max(1) * 4,
+        });
+        // positions beyond what the thread keeps up with are dropped, not queued
+        let (tx, rx) = sync_channel::<u64>(depth.max(1));
+
+        let (bg_inner, bg_buf) = (inner.clone(), buf.clone());
+        // exits once the storage is dropped along with the sender
+        std::thread::spawn(move || {
+            for pos in rx {
+                // a failed prefetch is left to the real read
+                let _ = bg_buf.fill(bg_inner.as_ref(), pos);
+            }
+        });
+
+        Self {
+            inner,
+            depth,
+            recent: Mutex::new((None, None)),
+            buf,
+            tx: Mutex::new(tx),
+            hits: AtomicUsize::new(0),
+        }
+    }
+
+    // reads served from the prefetch buffer
+    pub fn hits(&self) -> usize {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    #[cfg(test)]
+    fn is_buffered(&self, pos: u64) -> FsResult<bool> {
+        Ok(mutex_lock!(self.buf.blks).contains_key(&pos))
+    }
+
+    fn learn(&self, pos: u64) -> FsResult<()> {
+        let mut recent = mutex_lock!(self.recent);
+        if let Some(last) = recent.0 {
+            let stride = pos as i64 - last as i64;
+            if stride != 0 && recent.1 == Some(stride) {
+                let tx = mutex_lock!(self.tx);
+                for i in 1..=self.depth as i64 {
+                    let next = pos as i64 + stride * i;
+                    if next < 0 {
+                        break;
+                    }
+                    // queue is full or thread panicked, prefetch is best effort
+                    let _ = tx.try_send(next as u64);
+                }
+            }
+            recent.1 = Some(stride);
+        }
+        recent.0 = Some(pos);
+        Ok(())
+    }
+}
+
+#[cfg(feature = "std")]
+impl ROStorage for PrefetchStorage {
+    fn blk_sz(&self) -> usize {
+        self.inner.blk_sz()
+    }
+
+    fn read_blk_to(&self, pos: u64, to: &mut Block) -> FsResult<()> {
+        let prefetched = mutex_lock!(self.buf.blks).remove(&pos);
+        match prefetched {
+            Some((_, blk)) => {
+                to.copy_from_slice(&blk);
+                self.hits.fetch_add(1, Ordering::Relaxed);
+            }
+            None => self.inner.read_blk_to(pos, to)?,
+        }
+        self.learn(pos)
+    }
+
+    fn confirm_blk(&self, pos: u64) -> FsResult<()> {
+        self.inner.confirm_blk(pos)
+    }
+}
+
+// blocks of a storage kept in memory, those never written read as zeros
+pub struct MemStorage {
+    inner: spin::RwLock<MemBlocks>,
+    bsize: usize,
+}
+
+#[derive(Default)]
+struct MemBlocks {
+    blks: BTreeMap<u64, Box<Block>>,
+    nr_blk: u64,
+}
+
+impl MemStorage {
+    pub fn new(bsize: usize) -> Self {
+        Self {
+            inner: spin::RwLock::new(MemBlocks::default()),
+            bsize,
+        }
+    }
+}
+
+impl ROStorage for MemStorage {
+    fn blk_sz(&self) -> usize {
+        self.bsize
+    }
+
+    fn read_blk_to(&self, pos: u64, to: &mut Block) -> FsResult<()> {
+        let inner = self.inner.read();
+        if pos >= inner.nr_blk {
+            return Err(FsError::UnexpectedEof);
+        }
+        match inner.blks.get(&pos) {
+            Some(blk) => to.copy_from_slice(blk),
+            None => to.fill(0),
+        }
+        Ok(())
+    }
+}
+
+impl RWStorage for MemStorage {
+    fn write_blk(&self, pos: u64, from: &Block) -> FsResult<()> {
+        let mut inner = self.inner.write();
+        if pos >= inner.nr_blk {
+            return Err(FsError::UnexpectedEof);
+        }
+        inner.blks.insert(pos, from.into());
+        Ok(())
+    }
+
+    fn get_len(&self) -> FsResult<u64> {
+        Ok(blk2byte!(self.inner.read().nr_blk, self.bsize))
+    }
+
+    fn set_len(&self, nr_blk: u64) -> FsResult<()> {
+        let mut inner = self.inner.write();
+        inner.blks.split_off(&nr_blk);
+        inner.nr_blk = nr_blk;
+        Ok(())
+    }
+
+    fn discard(&self, pos: u64, nr_blk: u64) -> FsResult<()> {
+        let mut inner = self.inner.write();
+        let mut tail = inner.blks.split_off(&pos);
+        inner.blks.append(&mut tail.split_off(&(pos + nr_blk)));
+        Ok(())
+    }
+}
+
+// device of storages all in memory, by name, gone when the last reference is dropped
+pub struct MemDevice {
+    storages: spin::Mutex<BTreeMap<String, Arc<MemStorage>>>,
+    bsize: usize,
+}
+
+impl MemDevice {
+    pub fn new(bsize: usize) -> Self {
+        Self {
+            storages: spin::Mutex::new(BTreeMap::new()),
+            bsize,
+        }
+    }
+}
+
+impl Device for MemDevice {
+    fn blk_sz(&self) -> usize {
+        self.bsize
+    }
+
+    fn open_rw_storage(&self, path: &str) -> FsResult<Arc<dyn RWStorage>> {
+        match self.storages.lock().get(path) {
+            Some(s) => Ok(s.clone()),
+            None => Err(FsError::NotFound),
+        }
+    }
+
+    fn create_rw_storage(&self, path: &str) -> FsResult<Arc<dyn RWStorage>> {
+        let mut storages = self.storages.lock();
+        if storages.contains_key(path) {
+            return Err(FsError::AlreadyExists);
+        }
+        let s = Arc::new(MemStorage::new(self.bsize));
+        storages.insert(path.to_string(), s.clone());
+        Ok(s)
+    }
+
+    fn remove_storage(&self, path: &str) -> FsResult<()> {
+        self.storages.lock().remove(path).ok_or(FsError::NotFound)?;
+        Ok(())
+    }
+
+    fn rename_storage(&self, from: &str, to: &str) -> FsResult<()> {
+        let mut storages = self.storages.lock();
+        let s = storages.remove(from).ok_or(FsError::NotFound)?;
+        storages.insert(to.to_string(), s);
+        Ok(())
+    }
+
+    fn get_storage_len(&self, path: &str) -> FsResult<u64> {
+        self.open_rw_storage(path)?.get_len()
+    }
+
+    fn nr_storage(&self) -> FsResult<usize> {
+        Ok(self.storages.lock().len())
+    }
+}
+
+// device of a rwfs image dir, every storage is a file under it
+#[cfg(feature = "std")]
+pub struct FileDevice {
+    dir: PathBuf,
+    pool: Option<Arc<HandlePool>>,
+    bsize: usize,
+}
+
+#[cfg(feature = "std")]
+impl FileDevice {
+    pub fn new(dir: &Path, bsize: usize) -> FsResult<Self> {
+        check_blk_sz(bsize)?;
+        if !io_try!(fs::metadata(dir)).is_dir() {
+            return Err(FsError::NotADirectory);
+        }
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            pool: None,
+            bsize,
+        })
+    }
+
+    // keep at most max_open files open, however many storages are in use,
+    // the least recently used one is closed and reopened on its next access
+    pub fn with_max_open(dir: &Path, bsize: usize, max_open: usize) -> FsResult<Self> {
+        let max_open = core::num::NonZeroUsize::new(max_open)
+            .ok_or(FsError::InvalidParameter)?;
+        Ok(Self {
+            pool: Some(Arc::new(HandlePool {
+                open: Mutex::new(::lru::LruCache::new(max_open)),
+                bsize,
+            })),
+            ..Self::new(dir, bsize)?
+        })
+    }
+
+    // files kept open by the pool, None if not bounded
+    pub fn nr_open(&self) -> FsResult<Option<usize>> {
+        match &self.pool {
+            Some(pool) => Ok(Some(mutex_lock!(pool.open).len())),
+            None => Ok(None),
+        }
+    }
+
+    fn storage(&self, p: PathBuf) -> FsResult<Arc<dyn RWStorage>> {
+        match &self.pool {
+            Some(pool) => {
+                // fail here rather than on first access if it can not be opened
+                pool.get(&p)?;
+                Ok(Arc::new(PooledStorage {
+                    path: p,
+                    pool: pool.clone(),
+                }))
+            }
+            None => Ok(Arc::new(FileStorage::new(&p, true, self.bsize)?)),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl Device for FileDevice {
+    fn blk_sz(&self) -> usize {
+        self.bsize
+    }
+
+    fn open_rw_storage(&self, path: &str) -> FsResult<Arc<dyn RWStorage>> {
+        self.storage(self.dir.join(path))
+    }
+
+    fn create_rw_storage(&self, path: &str) -> FsResult<Arc<dyn RWStorage>> {
+        let p = self.dir.join(path);
+        io_try!(OpenOptions::new().write(true).create_new(true).open(&p));
+        self.storage(p)
+    }
+
+    fn remove_storage(&self, path: &str) -> FsResult<()> {
+        let p = self.dir.join(path);
+        if let Some(pool) = &self.pool {
+            pool.close(&p)?;
+        }
+        io_try!(fs::remove_file(p));
+        Ok(())
+    }
+
+    // atomic as long as the dir is on one filesystem
+    fn rename_storage(&self, from: &str, to: &str) -> FsResult<()> {
+        let (from, to) = (self.dir.join(from), self.dir.join(to));
+        if let Some(pool) = &self.pool {
+            pool.close(&from)?;
+            pool.close(&to)?;
+        }
+        io_try!(fs::rename(from, to));
+        Ok(())
+    }
+
+    fn get_storage_len(&self, path: &str) -> FsResult<u64> {
+        Ok(io_try!(fs::metadata(self.dir.join(path))).len())
+    }
+
+    fn nr_storage(&self) -> FsResult<usize> {
+        Ok(io_try!(fs::read_dir(&self.dir)).count())
+    }
+}
+
+// open files of a FileDevice, by path
+#[cfg(feature = "std")]
+struct HandlePool {
+    open: Mutex<::lru::LruCache<PathBuf, Arc<FileStorage>>>,
+    bsize: usize,
+}
+
+#[cfg(feature = "std")]
+impl HandlePool {
+    fn get(&self, p: &Path) -> FsResult<Arc<FileStorage>> {
+        let mut open = mutex_lock!(self.open);
+        if let Some(f) = open.get(p) {
+            return Ok(f.clone());
+        }
+        let f = Arc::new(FileStorage::new(p, true, self.bsize)?);
+        // an evicted file is closed after the last access in flight
+        open.push(p.to_path_buf(), f.clone());
+        Ok(f)
+    }
+
+    fn close(&self, p: &Path) -> FsResult<()> {
+        mutex_lock!(self.open).pop(p);
+        Ok(())
+    }
+}
+
+// a file of a FileDevice with bounded open files, taken from the pool on every access
+#[cfg(feature = "std")]
+struct PooledStorage {
+    path: PathBuf,
+    pool: Arc<HandlePool>,
+}
+
+#[cfg(feature = "std")]
+impl ROStorage for PooledStorage {
+    fn blk_sz(&self) -> usize {
+        self.pool.bsize
+    }
+
+    fn read_blk_to(&self, pos: u64, to: &mut Block) -> FsResult<()> {
+        self.pool.get(&self.path)?.read_blk_to(pos, to)
+    }
+
+    fn read_blks_to(&self, pos: u64, to: &mut [u8]) -> FsResult<()> {
+        self.pool.get(&self.path)?.read_blks_to(pos, to)
+    }
+}
+
+#[cfg(feature = "std")]
+impl RWStorage for PooledStorage {
+    fn write_blk(&self, pos: u64, from: &Block) -> FsResult<()> {
+        self.pool.get(&self.path)?.write_blk(pos, from)
+    }
+
+    fn get_len(&self) -> FsResult<u64> {
+        self.pool.get(&self.path)?.get_len()
+    }
+
+    fn set_len(&self, nr_blk: u64) -> FsResult<()> {
+        self.pool.get(&self.path)?.set_len(nr_blk)
+    }
+
+    fn discard(&self, pos: u64, nr_blk: u64) -> FsResult<()> {
+        self.pool.get(&self.path)?.discard(pos, nr_blk)
+    }
+}
+
+#[cfg(feature = "std")]
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::*;
+    use std::fs;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    struct FlakyStorage {
+        nr_fail: AtomicUsize,
+    }
+
+    impl ROStorage for FlakyStorage {
+        fn blk_sz(&self) -> usize {
+            BLK_SZ
+        }
+
+        fn read_blk_to(&self, pos: u64, to: &mut Block) -> FsResult<()> {
+            if self.nr_fail.load(Ordering::SeqCst) > 0 {
+                self.nr_fail.fetch_sub(1, Ordering::SeqCst);
+                return Err(FsError::IOError(std::io::ErrorKind::Interrupted.into()));
+            }
+            to.fill(pos as u8);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn retry_transient_error() -> FsResult<()> {
+        let flaky = Arc::new(FlakyStorage { nr_fail: AtomicUsize::new(2) });
+        let storage = RetryStorage::new(flaky.clone(), 3, 1);
+        assert_eq!(*storage.read_blk(7)?, [7u8; BLK_SZ]);
+
+        flaky.nr_fail.store(2, Ordering::SeqCst);
+        let storage = RetryStorage::new(flaky, 1, 1);
+        assert!(storage.read_blk(7).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn strided_reads_hit_prefetch() -> FsResult<()> {
+        let inner = Arc::new(FlakyStorage { nr_fail: AtomicUsize::new(0) });
+        let storage = PrefetchStorage::new(inner, 4);
+
+        for pos in [1, 4, 7] {
+            assert_eq!(*storage.read_blk(pos)?, [pos as u8; BLK_SZ]);
+        }
+        assert_eq!(storage.hits(), 0);
+
+        // stride 3 is seen twice, the next blocks are read ahead
+        let start = std::time::Instant::now();
+        while !storage.is_buffered(10)? {
+            assert!(start.elapsed() < std::time::Duration::from_secs(5));
+            std::thread::sleep(std::time::Duration::from_millis(1));
+        }
+        assert_eq!(*storage.read_blk(10)?, [10u8; BLK_SZ]);
+        assert_eq!(storage.hits(), 1);
+
+        // a random read is served from the inner storage
+        assert_eq!(*storage.read_blk(100)?, [100u8; BLK_SZ]);
+        assert_eq!(storage.hits(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn prefetch_evicts_oldest_when_full() -> FsResult<()> {
+        let inner = Arc::new(FlakyStorage { nr_fail: AtomicUsize::new(0) });
+        // room for 4 blocks read ahead
+        let storage = PrefetchStorage::new(inner, 1);
+
+        // each run of stride 10 reads one block ahead, none of them is used
+        for base in [0u64, 100, 200, 300, 400] {
+            for pos in [base, base + 10, base + 20] {
+                storage.read_blk(pos)?;
+            }
+            let start = std::time::Instant::now();
+            while !storage.is_buffered(base + 30)? {
+                assert!(start.elapsed() < std::time::Duration::from_secs(5));
+                std::thread::sleep(std::time::Duration::from_millis(1));
+            }
+        }
+        assert!(!storage.is_buffered(30)?);
+        for base in [100, 200, 300] {
+            assert!(storage.is_buffered(base + 30)?);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn device_rename_storage() {
+        use std::sync::Arc;
+
+        let base = temp_dir("rename_storage");
+        let dir = base.join("dev");
+        fs::create_dir(&dir).unwrap();
+        let packed_path = base.join("packed");
+        fs::File::create(&packed_path).unwrap();
+        let devs: [Box<dyn Device>; 3] = [
+            Box::new(FileDevice::new(&dir, BLK_SZ).unwrap()),
+            Box::new(FileDevice::with_max_open(&dir, BLK_SZ, 1).unwrap()),
+            Box::new(PackedDevice::format(
+                Arc::new(FileStorage::new(&packed_path, true, BLK_SZ).unwrap())
+            ).unwrap()),
+        ];
+        for dev in devs.iter() {
+            for (name, fill) in [("new", 1u8), ("old", 2u8)] {
+                let s = dev.create_rw_storage(name).unwrap();
+                s.set_len(2).unwrap();
+                s.write_blk(1, &[fill; BLK_SZ]).unwrap();
+            }
+            // replace an existing one, then move to a fresh name
+            dev.rename_storage("new", "old").unwrap();
+            assert!(dev.open_rw_storage("new").is_err());
+            assert_eq!(dev.nr_storage().unwrap(), 1);
+            dev.rename_storage("old", "moved").unwrap();
+            assert!(dev.open_rw_storage("old").is_err());
+            let s = dev.open_rw_storage("moved").unwrap();
+            assert_eq!(s.get_len().unwrap(), 2 * BLK_SZ as u64);
+            assert_eq!(*s.read_blk(1).unwrap(), [1u8; BLK_SZ]);
+            assert!(dev.rename_storage("old", "new").is_err());
+            dev.remove_storage("moved").unwrap();
+        }
+
+        fs::remove_dir_all(&base).unwrap();
+    }
 }
@@ -0,0 +1,95 @@
+//! shared setup of unit tests
+use crate::*;
+use std::fs;
+use std::path::PathBuf;
+
+pub fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(
+        format!("eccfs-{}-{}", name, std::process::id())
+    );
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+// crash injection for rw write paths: every storage op that changes the
+// image counts as a write, once armed the device fails all writes after
+// the given number of them, as if the backing store went away, or takes
+// them without keeping any, as if it lost power with them in its cache
+pub struct FailState {
+    armed: std::sync::atomic::AtomicBool,
+    lose: std::sync::atomic::AtomicBool,
+    left: std::sync::atomic::AtomicUsize,
+}
+
+impl FailState {
+    pub fn new() -> std::sync::Arc<Self> {
+        std::sync::Arc::new(Self {
+            armed: std::sync::atomic::AtomicBool::new(false),
+            lose: std::sync::atomic::AtomicBool::new(false),
+            left: std::sync::atomic::AtomicUsize::new(0),
+        })
+    }
+
+    pub fn fail_after(&self, nr_write: usize) {
+        self.lose.store(false, std::sync::atomic::Ordering::SeqCst);
+        self.left.store(nr_write, std::sync::atomic::Ordering::SeqCst);
+        self.armed.store(true, std::sync::atomic::Ordering::SeqCst);
+    }
+
+    // writes after nr_write still succeed, but nothing reaches the image
+    pub fn stop_after(&self, nr_write: usize) {
+        self.fail_after(nr_write);
+        self.lose.store(true, std::sync::atomic::Ordering::SeqCst);
+    }
+
+    // writes left before it fails, none once it's tripped
+    pub fn left(&self) -> usize {
+        self.left.load(std::sync::atomic::Ordering::SeqCst)
+    }
+
+    // whether the write goes to the image
+    fn write(&self) -> FsResult<bool> {
+        use std::sync::atomic::Ordering;
+        if self.armed.load(Ordering::SeqCst)
+            && self.left.fetch_update(
+                Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1),
+            ).is_err() {
+            if self.lose.load(Ordering::SeqCst) {
+                return Ok(false);
+            }
+            return Err(FsError::IOError(std::io::ErrorKind::BrokenPipe.into()));
+        }
+        Ok(true)
+    }
+}
+
+pub struct FailStorage(pub std::sync::Arc<dyn RWStorage>, pub std::sync::Arc<FailState>);
+
+impl ROStorage for FailStorage {
+    fn blk_sz(&self) -> usize {
+        self.0.blk_sz()
+    }
+
+    fn read_blk_to(&self, pos: u64, to: &mut Block) -> FsResult<()> {
+        self.0.read_blk_to(pos, to)
+    }
+}
+
+impl RWStorage for FailStorage {
+    fn write_blk(&self, pos: u64, from: &Block) -> FsResult<()> {
+        if !self.1.write()? {
+            return Ok(());
+        }
+        self.0.write_blk(pos, from)
+    }
+    fn get_len(&self) -> FsResult<u64> {
+        self.0.get_len()
+    }
+    fn set_len(&self, nr_blk: u64) -> FsResult<()> {
+        if !self.1.write()? {
+            return Ok(());
+        }
+        self.0.set_len(nr_blk)
+    }
+}
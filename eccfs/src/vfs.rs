@@ -28,13 +28,68 @@ pub struct FsInfo {
     pub frsize: usize,
 }
 
+/// version of on-disk layout
+pub const FS_LAYOUT_VERSION: u32 = 12;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum CipherAlgo {
+    Aes128Gcm,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum HashAlgo {
+    Sha3_256,
+    /// sha3-256 truncated to 128 bits, in images of short key entries
+    Sha3_256Trunc128,
+}
+
+/// what an opened fs is capable of, derived from its superblock
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct FsCapabilities {
+    /// File system type
+    pub magic: u64,
+    /// cipher of blocks, None if in integrity only mode
+    pub cipher: Option<CipherAlgo>,
+    /// hash of blocks, None if in encrypted mode, where MAC is used instead
+    pub hash: Option<HashAlgo>,
+    /// File system block size
+    pub bsize: usize,
+    /// whether file data is compressed
+    pub compression: bool,
+    /// version of on-disk layout
+    pub version: u32,
+    /// whether this fs accepts modifications
+    pub writable: bool,
+}
+
+impl FsCapabilities {
+    /// `version` and `compression` are as recorded in the superblock of an image
+    pub fn new(
+        magic: u64, version: u32, encrypted: bool, bsize: usize, compression: bool, writable: bool,
+    ) -> Self {
+        Self {
+            magic,
+            cipher: if encrypted { Some(CipherAlgo::Aes128Gcm) } else { None },
+            hash: if encrypted { None } else { Some(HashAlgo::Sha3_256) },
+            bsize,
+            compression,
+            version,
+            writable,
+        }
+    }
+}
+
 pub trait FileSystem: Sync + Send {
-    /// init fs
+    /// init fs, calling it more than once is the same as calling it once,
+    /// return AlreadyDestroyed after destroy
     fn init(&self) -> FsResult<()> {
         Ok(())
     }
 
-    /// destroy this fs, called before all worklaods are finished for this fs
+    /// destroy this fs, called after all workloads are finished for this fs,
+    /// fsync is done inside, so callers need not fsync before,
+    /// only the first successful call returns the new mode,
+    /// later calls return AlreadyDestroyed
     fn destroy(&self) -> FsResult<FSMode> {
         self.fsync()
     }
@@ -44,6 +99,11 @@ pub trait FileSystem: Sync + Send {
         Err(FsError::NotSupported)
     }
 
+    /// get effective mode and capabilities of this fs
+    fn capabilities(&self) -> FsResult<FsCapabilities> {
+        Err(FsError::NotSupported)
+    }
+
     /// sync all filesystem, including metadata and user data
     fn fsync(&self) -> FsResult<FSMode> {
         Err(FsError::NotSupported)
@@ -59,11 +119,47 @@ pub trait FileSystem: Sync + Send {
         Err(FsError::NotSupported)
     }
 
-    /// get metadata of inode
+    /// write at the end of inode atomically, return offset written at and bytes written
+    fn iappend(&self, _iid: InodeID, _from: &[u8]) -> FsResult<(u64, usize)> {
+        Err(FsError::NotSupported)
+    }
+
+    /// whether data of inode is stored inline in the inode rather than a separate htree
+    fn is_inline(&self, _iid: InodeID) -> FsResult<bool> {
+        Err(FsError::NotSupported)
+    }
+
+    /// where inode is stored, for debugging with raw images: byte offset of it
+    /// in the data of the inode table htree, not in the image, and its on-disk size
+    fn inode_tbl_offset(&self, _iid: InodeID) -> FsResult<(u64, usize)> {
+        Err(FsError::NotSupported)
+    }
+
+    /// get metadata of inode, without updating its atime
     fn get_meta(&self, _iid: InodeID) -> FsResult<Metadata> {
         Err(FsError::NotSupported)
     }
 
+    /// get metadata of many inodes, result of each is in the order of `iids`
+    fn get_meta_many(&self, iids: &[InodeID]) -> FsResult<Vec<(InodeID, FsResult<Metadata>)>> {
+        Ok(iids.iter().map(|&iid| (iid, self.get_meta(iid))).collect())
+    }
+
+    /// update atime of inode to now, as a read of it does
+    fn touch_atime(&self, _iid: InodeID) -> FsResult<()> {
+        Err(FsError::NotSupported)
+    }
+
+    /// get flags of inode
+    fn get_flags(&self, _iid: InodeID) -> FsResult<InodeFlags> {
+        Err(FsError::NotSupported)
+    }
+
+    /// set flags of inode, allowed even if it is immutable, so the flag can be cleared
+    fn set_flags(&self, _iid: InodeID, _flags: InodeFlags) -> FsResult<()> {
+        Err(FsError::NotSupported)
+    }
+
     /// set metadata of inode
     fn set_meta(&self, _iid: InodeID, _set_md: SetMetadata) -> FsResult<()> {
         Err(FsError::NotSupported)
@@ -78,6 +174,27 @@ pub trait FileSystem: Sync + Send {
         Err(FsError::NotSupported)
     }
 
+    /// value of an extended attribute of inode, None if it's not set
+    fn iget_xattr(&self, _iid: InodeID, _name: &str) -> FsResult<Option<Vec<u8>>> {
+        Err(FsError::NotSupported)
+    }
+
+    /// set an extended attribute of inode, replacing its value if it's set,
+    /// name and value are checked by [`check_xattr`]
+    fn iset_xattr(&self, _iid: InodeID, _name: &str, _value: &[u8]) -> FsResult<()> {
+        Err(FsError::NotSupported)
+    }
+
+    /// remove an extended attribute of inode, NotFound if it's not set
+    fn iremove_xattr(&self, _iid: InodeID, _name: &str) -> FsResult<()> {
+        Err(FsError::NotSupported)
+    }
+
+    /// names of all extended attributes of inode, in the order they are first set
+    fn ilist_xattr(&self, _iid: InodeID) -> FsResult<Vec<String>> {
+        Err(FsError::NotSupported)
+    }
+
     /// sync metadata of this inode
     fn isync_meta(&self, _iid: InodeID) -> FsResult<()> {
         Err(FsError::NotSupported)
@@ -88,6 +205,11 @@ pub trait FileSystem: Sync + Send {
         Err(FsError::NotSupported)
     }
 
+    /// sync user data in this byte range of an inode, data out of range may stay buffered
+    fn fsync_range(&self, iid: InodeID, _offset: usize, _len: usize) -> FsResult<()> {
+        self.isync_data(iid)
+    }
+
     /// create inode
     fn create(
         &self,
@@ -101,7 +223,22 @@ pub trait FileSystem: Sync + Send {
         Err(FsError::NotSupported)
     }
 
-    /// create hard link
+    /// create a reg file with its contents as one unit, the name shows up only
+    /// after the data is written and synced, and nothing is left if any step fails
+    fn create_with_data(
+        &self,
+        _parent: InodeID,
+        _name: &str,
+        _data: &[u8],
+        _uid: u32,
+        _gid: u32,
+        _perm: FilePerm,
+    ) -> FsResult<InodeID> {
+        Err(FsError::NotSupported)
+    }
+
+    /// create hard link, CrossDevice if linkto cannot have a name under parent,
+    /// e.g. it's in a lower layer of an overlay
     fn link(&self, _parent: InodeID, _name: &str, _linkto: InodeID) -> FsResult<()> {
         Err(FsError::NotSupported)
     }
@@ -132,11 +269,60 @@ pub trait FileSystem: Sync + Send {
         Err(FsError::NotSupported)
     }
 
+    /// rename, but a replaced non-dir target is returned instead of being deleted,
+    /// its link is dropped but it's kept alive until release_replaced, so it can be
+    /// linked back on rollback. an empty dir target is still removed at once
+    fn rename_replace(
+        &self,
+        _from: InodeID, _name: &str,
+        _to: InodeID, _newname: &str
+    ) -> FsResult<Option<InodeID>> {
+        Err(FsError::NotSupported)
+    }
+
+    /// give up an inode returned by rename_replace, it's deleted if it's not
+    /// linked back since, fs may do it on next mount if it's never called
+    fn release_replaced(&self, _iid: InodeID) -> FsResult<()> {
+        Err(FsError::NotSupported)
+    }
+
     /// lookup name in inode only if inode is a dir
     fn lookup(&self, _iid: InodeID, _name: &str) -> FsResult<Option<InodeID>> {
         Err(FsError::NotSupported)
     }
 
+    /// lookup many names in one dir inode, result of each is in the order of `names`
+    fn lookup_many(&self, iid: InodeID, names: &[&str]) -> FsResult<Vec<Option<InodeID>>> {
+        names.iter().map(|name| self.lookup(iid, name)).collect()
+    }
+
+    /// parent of a dir inode, the parent of root is root itself
+    fn get_parent(&self, iid: InodeID) -> FsResult<InodeID> {
+        self.lookup(iid, "..")?.ok_or(FsError::NotFound)
+    }
+
+    /// resolve `path` relative to dir `start`, or to root if it begins with '/',
+    /// "." and empty components are skipped, ".." goes to the parent,
+    /// a trailing '/' requires the target to be a dir, symlinks are not followed
+    fn resolve_path(&self, start: InodeID, path: &str) -> FsResult<InodeID> {
+        let mut iid = if path.starts_with('/') { ROOT_INODE_ID } else { start };
+        for name in path.split('/').filter(|name| !name.is_empty()) {
+            // every component is looked up in a dir, "." and ".." included
+            if self.get_meta(iid)?.ftype != FileType::Dir {
+                return Err(FsError::NotADirectory);
+            }
+            iid = match name {
+                "." => iid,
+                ".." => self.get_parent(iid)?,
+                _ => self.lookup(iid, name)?.ok_or(FsError::NotFound)?,
+            };
+        }
+        if path.ends_with('/') && self.get_meta(iid)?.ftype != FileType::Dir {
+            return Err(FsError::NotADirectory);
+        }
+        Ok(iid)
+    }
+
     /// list all entries in inode only if it's a dir
     fn listdir(
         &self,
@@ -147,6 +333,44 @@ pub trait FileSystem: Sync + Send {
         Err(FsError::NotSupported)
     }
 
+    /// entries as [`FileSystem::listdir`] along with metadata of each, as readdirplus does,
+    /// metadata is fetched by [`FileSystem::get_meta_many`] in one pass
+    fn listdir_plus(
+        &self,
+        iid: InodeID,
+        offset: usize,
+        num: usize, // 0 means as many as possible
+    ) -> FsResult<Vec<(InodeID, String, FileType, Metadata)>> {
+        let entries = self.listdir(iid, offset, num)?;
+        let iids: Vec<_> = entries.iter().map(|(iid, _, _)| *iid).collect();
+        let metas = self.get_meta_many(&iids)?;
+        entries.into_iter().zip(metas).map(
+            |((iid, name, tp), (_, meta))| Ok((iid, name, tp, meta?))
+        ).collect()
+    }
+
+    /// entries of a dir one at a time, in the same order as [`FileSystem::listdir`],
+    /// the dir is held and read a block of entries at a time till the end,
+    /// it may be dropped at any point, an error ends it
+    #[cfg(feature = "std")]
+    fn dir_stream(
+        &self,
+        _iid: InodeID,
+    ) -> FsResult<DirIter<'_>> {
+        Err(FsError::NotSupported)
+    }
+
+    /// dir entries as stored, in the same order as [`FileSystem::listdir`],
+    /// without touching atime or any child inode
+    fn read_dirents(
+        &self,
+        _iid: InodeID,
+        _offset: usize,
+        _num: usize, // 0 means as many as possible
+    ) -> FsResult<Vec<Dirent>> {
+        Err(FsError::NotSupported)
+    }
+
     fn next_entry(
         &self,
         iid: InodeID,
@@ -161,6 +385,48 @@ pub trait FileSystem: Sync + Send {
         }
     }
 
+    /// depth first walk from `start`, every inode below is visited once,
+    /// even if it has many hard links, `start` itself is visited first with empty path
+    fn walk(
+        &self,
+        start: InodeID,
+        f: &mut dyn FnMut(WalkEntry) -> FsResult<WalkControl>,
+    ) -> FsResult<()> {
+        let mut visited = alloc::collections::BTreeSet::new();
+        let mut stack = Vec::new();
+        stack.push((start, String::new()));
+        while let Some((iid, path)) = stack.pop() {
+            if !visited.insert(iid) {
+                continue;
+            }
+            let meta = self.get_meta(iid)?;
+            let is_dir = meta.ftype == FileType::Dir;
+            match f(WalkEntry { iid, path: path.clone(), meta })? {
+                WalkControl::Continue => (),
+                WalkControl::SkipSubtree => continue,
+                WalkControl::Stop => return Ok(()),
+            }
+            if !is_dir {
+                continue;
+            }
+
+            // push in reverse, so that children are visited in listdir order
+            let children = self.listdir(iid, 0, 0)?;
+            for (child, name, _) in children.into_iter().rev() {
+                if name == "." || name == ".." || visited.contains(&child) {
+                    continue;
+                }
+                let child_path = if path.is_empty() {
+                    name
+                } else {
+                    alloc::format!("{}/{}", path, name)
+                };
+                stack.push((child, child_path));
+            }
+        }
+        Ok(())
+    }
+
     /// fallocate
     fn fallocate(
         &self,
@@ -173,6 +439,86 @@ pub trait FileSystem: Sync + Send {
     }
 }
 
+/// a dir entry read by [`FileSystem::read_dirents`], wherever it's stored,
+/// . and .. are the first two, in either order
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Dirent {
+    pub ipos: InodeID,
+    pub tp: FileType,
+    pub name: String,
+}
+
+/// an inode met by [`FileSystem::walk`]
+#[derive(Debug, Clone)]
+pub struct WalkEntry {
+    pub iid: InodeID,
+    /// relative to the start of walk, joined by '/'
+    pub path: String,
+    pub meta: Metadata,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum WalkControl {
+    Continue,
+    /// do not go into this dir, no effect for other types
+    SkipSubtree,
+    /// end the walk now
+    Stop,
+}
+
+/// attributes of inodes cached in front of a [`FileSystem`], for callers like FUSE
+/// that ask for them far more often than they change. nothing is watched, every op
+/// that changes an inode must [`AttrCache::invalidate`] it, including reads, lookups
+/// and listings that update its atime
+pub struct AttrCache {
+    inner: spin::Mutex<AttrCacheInner>,
+}
+
+struct AttrCacheInner {
+    attrs: ::lru::LruCache<InodeID, Metadata>,
+    // bumped on every invalidation, a get_meta that raced with one is not cached
+    generation: u64,
+}
+
+impl AttrCache {
+    pub fn new(cap: usize) -> FsResult<Self> {
+        let cap = core::num::NonZeroUsize::new(cap).ok_or(FsError::InvalidParameter)?;
+        Ok(Self {
+            inner: spin::Mutex::new(AttrCacheInner {
+                attrs: ::lru::LruCache::new(cap),
+                generation: 0,
+            }),
+        })
+    }
+
+    pub fn getattr(&self, fs: &dyn FileSystem, iid: InodeID) -> FsResult<Metadata> {
+        let generation = {
+            let mut inner = self.inner.lock();
+            if let Some(meta) = inner.attrs.get(&iid) {
+                return Ok(meta.clone());
+            }
+            inner.generation
+        };
+
+        let meta = fs.get_meta(iid)?;
+        let mut inner = self.inner.lock();
+        if inner.generation == generation {
+            inner.attrs.put(iid, meta.clone());
+        }
+        Ok(meta)
+    }
+
+    pub fn invalidate(&self, iid: InodeID) {
+        let mut inner = self.inner.lock();
+        inner.attrs.pop(&iid);
+        inner.generation += 1;
+    }
+
+    pub fn generation(&self) -> u64 {
+        self.inner.lock().generation
+    }
+}
+
 #[derive(Copy, Clone, Debug, Eq, PartialEq, Default)]
 pub enum FileType {
     #[default] Reg,
@@ -215,6 +561,9 @@ impl Into<fuser::FileType> for FileType {
 bitflags! {
     #[derive(Debug, Eq, PartialEq, Clone, Copy)]
     pub struct FilePerm: u16 {
+        const S_ISUID = 0o4000;
+        const S_ISGID = 0o2000;
+        const S_ISVTX = 0o1000;
         const U_R = 0o0400;
         const U_W = 0o0200;
         const U_X = 0o0100;
@@ -227,7 +576,21 @@ bitflags! {
     }
 }
 
-pub const PERM_MASK: u16 = 0o0777;
+bitflags! {
+    /// inode flags, same bits as FS_IOC_GETFLAGS of Linux
+    #[derive(Debug, Eq, PartialEq, Clone, Copy, Default)]
+    pub struct InodeFlags: u32 {
+        /// content and metadata can not be changed, nor can it be unlinked
+        const IMMUTABLE = 0x10;
+        /// can only be written at the end
+        const APPEND_ONLY = 0x20;
+        /// not a candidate for backup
+        const NO_DUMP = 0x40;
+    }
+}
+
+// permission bits together with setuid, setgid and sticky bits
+pub const PERM_MASK: u16 = 0o7777;
 
 pub fn get_ftype_from_mode(mode: u16) -> FileType {
     FileType::from(mode >> 12)
@@ -255,6 +618,22 @@ pub fn get_mode_from_libc_mode(libc_mode: u32) -> u16 {
     (tp << 12) | ((libc_mode & PERM_MASK as u32) as u16)
 }
 
+pub const XATTR_NAME_MAX: usize = 255;
+pub const XATTR_SIZE_MAX: usize = 65536;
+
+// an empty value is fine, an empty name is not
+pub fn check_xattr(name: &str, value: &[u8]) -> FsResult<()> {
+    if name.is_empty() {
+        Err(FsError::InvalidParameter)
+    } else if name.len() > XATTR_NAME_MAX {
+        Err(FsError::NameTooLong)
+    } else if value.len() > XATTR_SIZE_MAX {
+        Err(FsError::FileTooLarge)
+    } else {
+        Ok(())
+    }
+}
+
 #[derive(Debug, Eq, PartialEq, Clone)]
 pub struct Metadata {
     /// Inode number
@@ -263,12 +642,16 @@ pub struct Metadata {
     pub size: u64,
     /// Size in blocks
     pub blocks: u64,
+    /// Block size of the fs, the unit of `blocks`
+    pub blksize: u32,
     /// Time of last access
     pub atime: u32,
     /// Time of last modification
     pub mtime: u32,
     /// Time of last change
     pub ctime: u32,
+    /// Time of creation
+    pub btime: u32,
     /// Type of file
     pub ftype: FileType,
     /// Permission
@@ -291,15 +674,14 @@ impl Into<fuser::FileAttr> for Metadata {
             atime: self.atime,
             ctime: self.ctime,
             mtime: self.mtime,
-            // BAD: mtime is the oldest time among the three, use it as crtime
-            crtime: self.mtime,
+            crtime: self.btime,
             kind: self.ftype.into(),
             perm: self.perm.bits(),
             nlink: self.nlinks as u32,
             uid: self.uid,
             gid: self.gid,
             rdev: 0,
-            blksize: BLK_SZ as u32,
+            blksize: self.blksize,
             flags: 0,
         }
     }
@@ -321,12 +703,185 @@ pub trait TimeSource: Send + Sync {
     fn now(&self) -> u32;
 }
 
+/// time source from system clock, in seconds since UNIX epoch
+#[cfg(feature = "std")]
+pub struct SystemTimeSource;
+
+#[cfg(feature = "std")]
+impl TimeSource for SystemTimeSource {
+    fn now(&self) -> u32 {
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_secs() as u32)
+            .unwrap_or(0)
+    }
+}
+
+#[cfg(feature = "std")]
+pub static SYSTEM_TIME: SystemTimeSource = SystemTimeSource;
+
+/// entries of a dir from [`FileSystem::dir_stream`]
+#[cfg(feature = "std")]
+pub type DirIter<'a> = alloc::boxed::Box<
+    dyn Iterator<Item = FsResult<(InodeID, String, FileType)>> + 'a
+>;
+
+/// entries of a dir for [`FileSystem::dir_stream`], each batch is fetched by
+/// `next_batch` from the offset of entries so far, till an empty one or an error
+#[cfg(feature = "std")]
+pub struct DirStream<F> {
+    next_batch: F,
+    offset: usize,
+    batch: alloc::vec::IntoIter<(InodeID, String, FileType)>,
+    done: bool,
+}
+
+#[cfg(feature = "std")]
+impl<F> DirStream<F>
+where
+    F: FnMut(usize) -> FsResult<Vec<(InodeID, String, FileType)>>,
+{
+    pub fn new(next_batch: F) -> Self {
+        Self {
+            next_batch,
+            offset: 0,
+            batch: Vec::new().into_iter(),
+            done: false,
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl<F> Iterator for DirStream<F>
+where
+    F: FnMut(usize) -> FsResult<Vec<(InodeID, String, FileType)>>,
+{
+    type Item = FsResult<(InodeID, String, FileType)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(entry) = self.batch.next() {
+            return Some(Ok(entry));
+        }
+        if self.done {
+            return None;
+        }
+        match (self.next_batch)(self.offset) {
+            Ok(batch) if batch.is_empty() => {
+                self.done = true;
+                None
+            }
+            Ok(batch) => {
+                self.offset += batch.len();
+                self.batch = batch.into_iter();
+                self.batch.next().map(Ok)
+            }
+            Err(e) => {
+                self.done = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+/// a file of a fs as std::io Read, Seek and BufRead, by iread from a cursor.
+/// it may be seeked beyond the end, where reads return nothing
+#[cfg(feature = "std")]
+pub struct InodeReader {
+    fs: alloc::sync::Arc<dyn FileSystem>,
+    iid: InodeID,
+    pos: u64,
+    // a block read from buf_pos on for BufRead, dropped on seek
+    buf: Vec<u8>,
+    buf_pos: u64,
+}
+
+#[cfg(feature = "std")]
+impl InodeReader {
+    pub fn new(fs: alloc::sync::Arc<dyn FileSystem>, iid: InodeID) -> Self {
+        Self {
+            fs,
+            iid,
+            pos: 0,
+            buf: Vec::new(),
+            buf_pos: 0,
+        }
+    }
+
+    pub fn iid(&self) -> InodeID {
+        self.iid
+    }
+
+    // bytes buffered from pos on
+    fn buffered(&self) -> &[u8] {
+        let end = self.buf_pos + self.buf.len() as u64;
+        if self.buf_pos <= self.pos && self.pos < end {
+            &self.buf[(self.pos - self.buf_pos) as usize..]
+        } else {
+            &[]
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::io::Read for InodeReader {
+    fn read(&mut self, to: &mut [u8]) -> std::io::Result<usize> {
+        let buffered = self.buffered();
+        let read = if !buffered.is_empty() {
+            let n = buffered.len().min(to.len());
+            to[..n].copy_from_slice(&buffered[..n]);
+            n
+        } else {
+            self.fs.iread(self.iid, self.pos as usize, to)?
+        };
+        self.pos += read as u64;
+        Ok(read)
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::io::BufRead for InodeReader {
+    fn fill_buf(&mut self) -> std::io::Result<&[u8]> {
+        if self.buffered().is_empty() {
+            self.buf.resize(BLK_SZ, 0);
+            let read = self.fs.iread(self.iid, self.pos as usize, &mut self.buf)?;
+            self.buf.truncate(read);
+            self.buf_pos = self.pos;
+        }
+        Ok(self.buffered())
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.pos += amt.min(self.buffered().len()) as u64;
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::io::Seek for InodeReader {
+    fn seek(&mut self, to: std::io::SeekFrom) -> std::io::Result<u64> {
+        let (base, off) = match to {
+            std::io::SeekFrom::Start(pos) => (pos, 0),
+            std::io::SeekFrom::Current(off) => (self.pos, off),
+            std::io::SeekFrom::End(off) => (self.fs.get_meta(self.iid)?.size, off),
+        };
+        self.pos = base.checked_add_signed(off).ok_or_else(|| std::io::Error::new(
+            std::io::ErrorKind::InvalidInput, "seek before the start of file",
+        ))?;
+        self.buf.clear();
+        Ok(self.pos)
+    }
+}
+
 #[derive(Debug)]
 pub enum FallocateMode {
     Alloc,
     // AllocKeepSize,
     ZeroRange,
     // ZeroRangeKeepSize,
+    // both take block aligned ranges
+    CollapseRange,
+    InsertRange,
+    // zero a range and free its whole blocks, size is kept
+    PunchHole,
 }
 
 pub fn check_access(